@@ -0,0 +1,197 @@
+//! Measures how many concurrent video queries and streaming sessions one process handles.
+//!
+//! Run with `cargo bench --bench concurrency`.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use esr_lib::{
+    message::{
+        answer::Answer,
+        query::Query,
+        rtsp::{RequestType, RtspRequest, RtspResponse, StreamingMode},
+    },
+    o_node::neighbour::Neighbour,
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    shutdown::Shutdown,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+const METRICS_PORT: u16 = 19001;
+const STREAMING_PORT: u16 = 19000;
+const RP_PORT: u16 = 19010;
+const QUERIES_PER_CLIENT: usize = 20;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+const LEVELS: [usize; 4] = [1, 16, 128, 512];
+
+fn scratch_dir() {
+    let dir = std::env::temp_dir().join("esr_tp_bench");
+    std::fs::create_dir_all(dir.join("videos")).unwrap();
+
+    let mut video = Vec::new();
+    for _ in 0..2000 {
+        video.extend(b"00512");
+        video.extend([0x42; 512]);
+    }
+    std::fs::write(dir.join("videos").join("movie.Mjpeg"), video).unwrap();
+
+    std::env::set_current_dir(&dir).unwrap();
+}
+
+/// Sends queries one after the other, returns the time taken and how many went unanswered.
+async fn query_client(rp: SocketAddr) -> (Duration, usize) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let query = bincode::serialize(&Query::new_file_query("movie.Mjpeg", None)).unwrap();
+    let mut buffer = [0; 1024];
+    let mut lost = 0;
+    let start = Instant::now();
+
+    for _ in 0..QUERIES_PER_CLIENT {
+        socket.send_to(&query, rp).await.unwrap();
+
+        let Ok(n) = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buffer)).await else {
+            lost += 1;
+            continue;
+        };
+        let answer: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n.unwrap()]).unwrap();
+        assert!(answer.status().is_ok());
+    }
+
+    (start.elapsed(), lost)
+}
+
+async fn rtsp_request(stream: &mut TcpStream, request: RtspRequest) -> RtspResponse {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+/// Opens an on demand session and keeps it playing until `hold` is over.
+async fn session(rtp_port: u16, hold: Duration) -> Duration {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, STREAMING_PORT))
+        .await
+        .unwrap();
+
+    let file = "movie.Mjpeg".to_string();
+    let setup = RtspRequest::new(RequestType::Setup, file.clone(), 1, rtp_port)
+        .with_mode(Some(StreamingMode::OnDemand));
+    assert!(rtsp_request(&mut stream, setup).await.succeded());
+
+    let play = RtspRequest::new(RequestType::Play, file.clone(), 2, rtp_port);
+    assert!(rtsp_request(&mut stream, play).await.succeded());
+    let elapsed = start.elapsed();
+
+    tokio::time::sleep(hold).await;
+
+    let teardown = RtspRequest::new(RequestType::Teardown, file, 3, rtp_port);
+    assert!(rtsp_request(&mut stream, teardown).await.succeded());
+
+    elapsed
+}
+
+fn report(name: &str, level: usize, operations: usize, elapsed: Duration, latencies: &[Duration]) {
+    let max = latencies.iter().max().copied().unwrap_or_default();
+    let mean = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+
+    println!(
+        "{:<9} concurrency {:>4}: {:>8.0} ops/s, mean {:>9.3?}, max {:>9.3?}",
+        name,
+        level,
+        operations as f64 / elapsed.as_secs_f64(),
+        mean,
+        max
+    );
+}
+
+async fn bench_queries() {
+    let rp = SocketAddr::from((Ipv4Addr::LOCALHOST, RP_PORT));
+
+    for level in LEVELS {
+        let start = Instant::now();
+        let clients: Vec<_> = (0..level).map(|_| tokio::spawn(query_client(rp))).collect();
+
+        let mut latencies = Vec::new();
+        let mut lost = 0;
+        for client in clients {
+            let (elapsed, client_lost) = client.await.unwrap();
+            latencies.push(elapsed / QUERIES_PER_CLIENT as u32);
+            lost += client_lost;
+        }
+
+        report(
+            "queries",
+            level,
+            level * QUERIES_PER_CLIENT - lost,
+            start.elapsed(),
+            &latencies,
+        );
+        if lost > 0 {
+            println!("{:>33} {} queries unanswered", "", lost);
+        }
+    }
+}
+
+async fn bench_sessions() {
+    let hold = Duration::from_millis(500);
+
+    for level in LEVELS {
+        // Every viewer's packets end up in a socket that is never read
+        let sink = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let rtp_port = sink.local_addr().unwrap().port();
+
+        let start = Instant::now();
+        let sessions: Vec<_> = (0..level)
+            .map(|_| tokio::spawn(session(rtp_port, hold)))
+            .collect();
+
+        let mut latencies = Vec::new();
+        for session in sessions {
+            latencies.push(session.await.unwrap());
+        }
+
+        report("sessions", level, level, start.elapsed() - hold, &latencies);
+    }
+}
+
+fn main() {
+    scratch_dir();
+    let shutdown = Shutdown::new();
+    let server = Server::new(METRICS_PORT, STREAMING_PORT, StreamingPolicy::default()).unwrap();
+    let rp = RP::new(RPArgs::parse_from([
+        "rp",
+        "--port",
+        &RP_PORT.to_string(),
+        "--servers",
+        &format!("127.0.0.1:{}", METRICS_PORT),
+    ]));
+
+    std::thread::scope(|s| {
+        s.spawn(|| server.run(&shutdown));
+        std::thread::sleep(Duration::from_millis(300));
+        s.spawn(|| rp.run(&shutdown));
+        std::thread::sleep(Duration::from_millis(300));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            bench_queries().await;
+            bench_sessions().await;
+        });
+
+        shutdown.trigger();
+    });
+}
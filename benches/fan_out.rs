@@ -0,0 +1,88 @@
+//! Measures how fast one packet is fanned out to 1 to 1000 subscribers.
+//!
+//! Run with `cargo bench --bench fan_out`.
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use esr_lib::server::fan_out::{self, BufferPool, SubscriberList};
+
+const PACKET_SIZE: usize = 1400;
+const RECEIVERS: usize = 16;
+const SENDS_PER_LEVEL: usize = 200_000;
+const LEVELS: [usize; 4] = [1, 10, 100, 1000];
+
+/// Subscribers are spread over a few sockets, so the benchmark does not run out of descriptors.
+fn subscribers(receivers: &[UdpSocket], count: usize) -> SubscriberList {
+    let subscribers = SubscriberList::default();
+
+    for receiver in receivers.iter().cycle().take(count) {
+        subscribers.add(receiver.local_addr().unwrap());
+    }
+
+    subscribers
+}
+
+fn one_by_one(socket: &tokio::net::UdpSocket, packet: &[u8], destinations: &[SocketAddr]) -> usize {
+    destinations
+        .iter()
+        .filter(|destination| socket.try_send_to(packet, **destination).is_ok())
+        .count()
+}
+
+fn report(name: &str, level: usize, sent: usize, elapsed: Duration) {
+    println!(
+        "{:<11} {:>4} subscribers: {:>10.0} packets/s, {:>8.1} MB/s",
+        name,
+        level,
+        sent as f64 / elapsed.as_secs_f64(),
+        (sent * PACKET_SIZE) as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let receivers: Vec<UdpSocket> = (0..RECEIVERS)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+        .collect();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.writable().await.unwrap();
+        let pool = BufferPool::new(PACKET_SIZE, 8);
+
+        for level in LEVELS {
+            let subscribers = subscribers(&receivers, level);
+            let rounds = SENDS_PER_LEVEL / level;
+
+            let start = Instant::now();
+            let mut sent = 0;
+            for _ in 0..rounds {
+                let packet = vec![0x42; PACKET_SIZE];
+                sent += one_by_one(&socket, &packet, &subscribers.snapshot());
+                // Gives the reactor a turn, like a forwarding worker between packets
+                tokio::task::yield_now().await;
+            }
+            report("one by one", level, sent, start.elapsed());
+
+            let start = Instant::now();
+            let mut sent = 0;
+            for _ in 0..rounds {
+                let mut buffer = pool.take();
+                buffer.spare_mut().fill(0x42);
+                buffer.set_len(PACKET_SIZE);
+                let packet = buffer.share();
+
+                sent += fan_out::send_to_all(&socket, &packet, &subscribers.snapshot());
+                tokio::task::yield_now().await;
+            }
+            report("batched", level, sent, start.elapsed());
+        }
+    });
+}
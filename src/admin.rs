@@ -0,0 +1,298 @@
+use std::{
+    fmt,
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{error, info};
+
+use crate::{o_node::neighbour::Neighbour, runtime::TaskPool, settings, shutdown::Shutdown};
+
+/// Maximum number of admin connections served at the same time.
+const MAX_ADMIN_CONNECTIONS: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("Not supported by this component")]
+    Unsupported,
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// Commands understood by the admin endpoint, sent one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Status,
+    DropSession { file: String, client: SocketAddr },
+    Disconnect(Neighbour),
+    Reload,
+    Record { file: String, recording: String },
+    StopRecording { file: String },
+}
+
+impl FromStr for AdminCommand {
+    type Err = AdminError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["status"] => Ok(Self::Status),
+            ["drop-session", file, client] => Ok(Self::DropSession {
+                file: file.to_string(),
+                client: client
+                    .parse()
+                    .map_err(|_| AdminError::InvalidArgument(client.to_string()))?,
+            }),
+            ["disconnect", neighbour] => Ok(Self::Disconnect(
+                neighbour.parse().map_err(AdminError::InvalidArgument)?,
+            )),
+            ["reload"] => Ok(Self::Reload),
+            ["record", file, recording] => Ok(Self::Record {
+                file: file.to_string(),
+                recording: recording.to_string(),
+            }),
+            ["stop-recording", file] => Ok(Self::StopRecording {
+                file: file.to_string(),
+            }),
+            _ => Err(AdminError::UnknownCommand(s.trim().to_string())),
+        }
+    }
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status => write!(f, "status"),
+            Self::DropSession { file, client } => write!(f, "drop-session {} {}", file, client),
+            Self::Disconnect(neighbour) => write!(f, "disconnect {}", neighbour),
+            Self::Reload => write!(f, "reload"),
+            Self::Record { file, recording } => write!(f, "record {} {}", file, recording),
+            Self::StopRecording { file } => write!(f, "stop-recording {}", file),
+        }
+    }
+}
+
+/// Answer to a command, written as a single line of JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminResponse {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+/// What a component lets its operator inspect and change while it runs.
+///
+/// Components only implement the commands that make sense for them, the
+/// others are answered with `AdminError::Unsupported`.
+pub trait Admin: Sync {
+    /// State of the component, as shown by `esr-ctl status`.
+    fn status(&self) -> impl Future<Output = serde_json::Value>;
+
+    /// Stops streaming `file` to `client`.
+    fn drop_session(
+        &self,
+        _file: &str,
+        _client: SocketAddr,
+    ) -> impl Future<Output = Result<(), AdminError>> {
+        async { Err(AdminError::Unsupported) }
+    }
+
+    /// Stops sending queries to `neighbour`, until the configuration is reloaded.
+    fn disconnect(&self, _neighbour: &Neighbour) -> impl Future<Output = Result<(), AdminError>> {
+        async { Err(AdminError::Unsupported) }
+    }
+
+    fn reload(&self) -> impl Future<Output = Result<(), AdminError>> {
+        async { Err(AdminError::Unsupported) }
+    }
+
+    /// Starts recording the stream of `file` passing through the component to `recording`.
+    fn record(
+        &self,
+        _file: &str,
+        _recording: &str,
+    ) -> impl Future<Output = Result<(), AdminError>> {
+        async { Err(AdminError::Unsupported) }
+    }
+
+    /// Stops recording the stream of `file`, returns the name it was recorded under.
+    fn stop_recording(&self, _file: &str) -> impl Future<Output = Result<String, AdminError>> {
+        async { Err(AdminError::Unsupported) }
+    }
+}
+
+async fn execute(admin: &impl Admin, line: &str) -> AdminResponse {
+    let result = match line.parse() {
+        Ok(AdminCommand::Status) => Ok(admin.status().await),
+        Ok(AdminCommand::DropSession { file, client }) => admin
+            .drop_session(&file, client)
+            .await
+            .map(|_| serde_json::Value::Null),
+        Ok(AdminCommand::Disconnect(neighbour)) => admin
+            .disconnect(&neighbour)
+            .await
+            .map(|_| serde_json::Value::Null),
+        Ok(AdminCommand::Reload) => admin.reload().await.map(|_| serde_json::Value::Null),
+        Ok(AdminCommand::Record { file, recording }) => admin
+            .record(&file, &recording)
+            .await
+            .map(|_| serde_json::Value::Null),
+        Ok(AdminCommand::StopRecording { file }) => admin
+            .stop_recording(&file)
+            .await
+            .map(serde_json::Value::String),
+        Err(error) => Err(error),
+    };
+
+    match result {
+        Ok(value) => AdminResponse::Ok(value),
+        Err(error) => AdminResponse::Error(error.to_string()),
+    }
+}
+
+async fn admin_connection(
+    admin: &impl Admin,
+    stream: tokio::net::TcpStream,
+    shutdown: &Shutdown,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+
+    loop {
+        let line = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => return Ok(()),
+            },
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        info!("Admin command: {}", line);
+        let response = execute(admin, &line).await;
+
+        let mut answer = serde_json::to_vec(&response).expect("Error serializing response");
+        answer.push(b'\n');
+        writer.write_all(&answer).await?;
+    }
+}
+
+/// Serves admin commands on `port`, only reachable from this host.
+pub async fn serve(admin: &impl Admin, port: u16, shutdown: &Shutdown) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Error binding the admin socket: {}", error);
+            return;
+        }
+    };
+    info!("Admin interface listening on port {}", port);
+
+    let mut tasks = TaskPool::new(MAX_ADMIN_CONNECTIONS);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tasks.next() => {}
+            result = listener.accept(), if !tasks.is_full() => match result {
+                Ok((stream, _)) => tasks.push(async move {
+                    if let Err(error) = admin_connection(admin, stream, shutdown).await {
+                        error!("Error in admin connection {}", error);
+                    }
+                }),
+                Err(error) => error!("Error accepting admin connection {}", error),
+            },
+        }
+    }
+
+    tasks.drain().await;
+}
+
+/// Serves admin commands on `port` if there is one, otherwise waits for the shutdown.
+pub async fn serve_on(admin: &impl Admin, port: Option<u16>, shutdown: &Shutdown) {
+    match port {
+        Some(port) => serve(admin, port, shutdown).await,
+        None => shutdown.cancelled().await,
+    }
+}
+
+/// Sends `command` to the admin endpoint at `address` and waits for the answer.
+pub fn send(address: SocketAddr, command: &AdminCommand) -> std::io::Result<AdminResponse> {
+    let mut stream = TcpStream::connect(address)?;
+    // Reloads may wait on the bootstrapper, which has its own timeout
+    stream.set_read_timeout(Some(2 * settings::get().network.request_timeout()))?;
+
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    serde_json::from_str(&line)
+        .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdminCommand, AdminError};
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            AdminCommand::Status,
+            AdminCommand::DropSession {
+                file: "movie.Mjpeg".to_string(),
+                client: "10.0.0.20:25000".parse().unwrap(),
+            },
+            AdminCommand::Disconnect("10.0.0.1:8554".parse().unwrap()),
+            AdminCommand::Reload,
+            AdminCommand::Record {
+                file: "movie.Mjpeg".to_string(),
+                recording: "replay.Mjpeg".to_string(),
+            },
+            AdminCommand::StopRecording {
+                file: "movie.Mjpeg".to_string(),
+            },
+        ];
+
+        for command in commands {
+            assert_eq!(
+                command.to_string().parse::<AdminCommand>().unwrap(),
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        assert!(matches!(
+            "restart".parse::<AdminCommand>(),
+            Err(AdminError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            "drop-session movie.Mjpeg".parse::<AdminCommand>(),
+            Err(AdminError::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            "drop-session movie.Mjpeg nowhere".parse::<AdminCommand>(),
+            Err(AdminError::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,9 +1,14 @@
 use clap::Parser;
-use esr_lib::video_player::{Args, VideoPlayer};
+use esr_lib::{
+    logging,
+    video_player::{Args, VideoPlayer},
+};
 
 fn main() {
     let args = Args::parse();
 
-    println!("Hello World");
+    logging::init(args.log_filter()).expect("Invalid log filter");
+    args.settings().init().expect("Error reading the settings");
+
     VideoPlayer::run(args);
 }
@@ -0,0 +1,71 @@
+use std::net::{IpAddr, SocketAddr};
+
+use clap::{Parser, Subcommand};
+use esr_lib::{
+    admin::{self, AdminCommand, AdminResponse},
+    o_node::neighbour::Neighbour,
+};
+
+/// Inspects and controls a running node, RP or server through its admin port.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(long, default_value = "127.0.0.1")]
+    host: IpAddr,
+
+    /// Admin port of the component
+    #[clap(short, long)]
+    port: u16,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shows neighbours, streams, their viewers and counters
+    Status,
+    /// Stops streaming a file to a client
+    DropSession { file: String, client: SocketAddr },
+    /// Stops sending queries to a neighbour
+    Disconnect { neighbour: Neighbour },
+    /// Reads the configuration again
+    Reload,
+    /// Records a stream passing through the component, under a new file name
+    Record { file: String, recording: String },
+    /// Stops recording a stream, the recording can then be served
+    StopRecording { file: String },
+}
+
+impl From<Command> for AdminCommand {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Status => AdminCommand::Status,
+            Command::DropSession { file, client } => AdminCommand::DropSession { file, client },
+            Command::Disconnect { neighbour } => AdminCommand::Disconnect(neighbour),
+            Command::Reload => AdminCommand::Reload,
+            Command::Record { file, recording } => AdminCommand::Record { file, recording },
+            Command::StopRecording { file } => AdminCommand::StopRecording { file },
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let address = SocketAddr::new(args.host, args.port);
+
+    match admin::send(address, &args.command.into()) {
+        Ok(AdminResponse::Ok(serde_json::Value::Null)) => println!("Done"),
+        Ok(AdminResponse::Ok(value)) => println!(
+            "{}",
+            serde_json::to_string_pretty(&value).expect("Values are always serializable")
+        ),
+        Ok(AdminResponse::Error(error)) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        Err(error) => {
+            eprintln!("Error talking to {}: {}", address, error);
+            std::process::exit(1);
+        }
+    }
+}
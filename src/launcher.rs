@@ -0,0 +1,270 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    time::Duration,
+};
+
+use clap::Parser;
+use esr_lib::{
+    logging,
+    message::{
+        auth::{Authenticator, KeyConfiguration},
+        Message,
+    },
+    o_node::{
+        bootstraper_node::BootstraperNode,
+        std_node::StdNode,
+        topology::{NodeSpec, Role, Topology},
+    },
+    resolve::{Endpoint, Host},
+    runtime,
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    settings::{self, SettingsArgs},
+    shutdown::Shutdown,
+};
+use futures_util::future::join_all;
+use thiserror::Error;
+use tracing::{error, info};
+
+/// Runs every node of a topology in this process, each on its own port of the loopback interface.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Topology to run, its interfaces are replaced by the loopback interface
+    topology: String,
+
+    /// Port of the first node in name order, the others and the bootstraping service take the next ones
+    #[clap(long, default_value = "9000")]
+    first_port: u16,
+
+    /// Runs the nodes on ::1 rather than 127.0.0.1
+    #[clap(long)]
+    ipv6: bool,
+
+    /// Which logs to show, such as `debug` or `warn,esr_lib::server=trace`, overrides RUST_LOG
+    #[clap(long)]
+    log: Option<String>,
+
+    #[command(flatten)]
+    settings: SettingsArgs,
+}
+
+#[derive(Debug, Error)]
+enum LauncherError {
+    #[error("The topology needs more ports than there are from port {0}")]
+    NotEnoughPorts(u16),
+}
+
+type Task<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + 'a>>;
+
+/// Waits until something listens on `port` of `loopback`.
+async fn wait_for(loopback: IpAddr, port: u16) -> Result<(), String> {
+    runtime::with_timeout(settings::get().network.request_timeout(), async {
+        while tokio::net::TcpStream::connect((loopback, port))
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| format!("Nothing listening on port {}", port))
+}
+
+/// Joins the overlay as `name` and relays until `shutdown`.
+async fn relay(
+    name: String,
+    node: &NodeSpec,
+    bootstraper: SocketAddr,
+    shutdown: &Shutdown,
+) -> Result<(), String> {
+    wait_for(bootstraper.ip(), bootstraper.port()).await?;
+
+    let bootstraper = bootstraper.to_string();
+    let node_id = Some(name.clone());
+    let answer = tokio::task::spawn_blocking({
+        let bootstraper = bootstraper.clone();
+        let node_id = node_id.clone();
+        move || {
+            StdNode::ask_neighbours(&Default::default(), bootstraper, node_id)
+                .map_err(|error| error.to_string())
+        }
+    })
+    .await
+    .map_err(|error| error.to_string())??;
+
+    let bootstrap = answer
+        .payload()
+        .ok_or_else(|| format!("{} was refused by the bootstrapper", name))?;
+
+    StdNode::new(node.port, &bootstrap.neighbours)
+        .with_authenticator(Authenticator::new(bootstrap.keys.clone()))
+        .with_bootstraper(Some(bootstraper))
+        .with_node_id(node_id)
+        .serve(shutdown)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+/// Serves queries once the content servers linked to it are up.
+async fn rp(
+    topology: &Topology,
+    loopback: IpAddr,
+    node: &NodeSpec,
+    shutdown: &Shutdown,
+) -> Result<(), String> {
+    let servers: Vec<&NodeSpec> = node
+        .links
+        .iter()
+        .filter_map(|link| topology.nodes.get(&link.node))
+        .filter(|target| target.role == Role::Server)
+        .collect();
+
+    for server in &servers {
+        wait_for(loopback, server.port).await?;
+    }
+
+    let servers = servers
+        .iter()
+        .map(|server| Endpoint::new(Host::Ip(loopback), server.port))
+        .collect();
+
+    RP::new(RPArgs::new(node.port, servers))
+        .serve(shutdown)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+fn main() -> Result<(), LauncherError> {
+    let args = Args::parse();
+
+    logging::init(args.log.as_deref()).expect("Invalid log filter");
+    args.settings.init().expect("Error reading the settings");
+
+    let loopback = if args.ipv6 {
+        IpAddr::V6(Ipv6Addr::LOCALHOST)
+    } else {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    };
+    let topology = Topology::from_file(&args.topology).expect("Error reading the topology");
+
+    // Nodes, the bootstraping service and the streaming port of every server
+    let servers = topology
+        .nodes
+        .values()
+        .filter(|node| node.role == Role::Server)
+        .count();
+    let last_port = u16::try_from(topology.nodes.len() + servers)
+        .ok()
+        .and_then(|ports| args.first_port.checked_add(ports))
+        .ok_or(LauncherError::NotEnoughPorts(args.first_port))?;
+
+    let topology = topology.on_loopback(loopback, args.first_port);
+    topology.validate().expect("Invalid topology");
+
+    // Ports after those of the nodes, a server listens for metrics on its node's port
+    let mut free_ports = (args.first_port..=last_port).skip(topology.nodes.len());
+    let bootstraping_port = free_ports.next().unwrap();
+    let streaming_ports: Vec<(&String, u16)> = topology
+        .nodes
+        .iter()
+        .filter(|(_, node)| node.role == Role::Server)
+        .map(|(name, _)| name)
+        .zip(&mut free_ports)
+        .collect();
+
+    let bootstraper = BootstraperNode::new(
+        bootstraping_port,
+        topology.nodes[&topology.bootstrapper].port,
+        topology.clone(),
+        KeyConfiguration::default(),
+    )
+    .expect("Invalid topology");
+
+    let servers: Vec<Server> = streaming_ports
+        .iter()
+        .map(|(name, streaming_port)| {
+            Server::new(
+                topology.nodes[*name].port,
+                *streaming_port,
+                StreamingPolicy::default(),
+            )
+            .expect("Error creating server")
+        })
+        .collect();
+
+    println!("{:<12} {:<8} ADDRESS", "NODE", "ROLE");
+    for (name, node) in &topology.nodes {
+        println!(
+            "{:<12} {:<8} {}",
+            name,
+            node.role.to_string(),
+            SocketAddr::new(loopback, node.port)
+        );
+    }
+    println!(
+        "Clients enter the overlay at {}, the bootstraping service is on port {}",
+        SocketAddr::new(loopback, topology.nodes[&topology.bootstrapper].port),
+        bootstraping_port
+    );
+
+    let shutdown = Shutdown::on_signals().expect("Error setting signal handler");
+
+    runtime::block_on(async {
+        let mut tasks: Vec<(String, Task)> = vec![(
+            topology.bootstrapper.clone(),
+            Box::pin(async {
+                bootstraper
+                    .serve(&shutdown)
+                    .await
+                    .map_err(|error| error.to_string())
+            }),
+        )];
+
+        for ((name, _), server) in streaming_ports.iter().zip(&servers) {
+            tasks.push((
+                name.to_string(),
+                Box::pin(async {
+                    server
+                        .serve(&shutdown)
+                        .await
+                        .map_err(|error| error.to_string())
+                }),
+            ));
+        }
+
+        for (name, node) in &topology.nodes {
+            let task: Task = match node.role {
+                Role::Rp => Box::pin(rp(&topology, loopback, node, &shutdown)),
+                Role::Relay if *name != topology.bootstrapper => Box::pin(relay(
+                    name.clone(),
+                    node,
+                    SocketAddr::new(loopback, bootstraping_port),
+                    &shutdown,
+                )),
+                _ => continue,
+            };
+            tasks.push((name.clone(), task));
+        }
+
+        info!("Running {} nodes on the loopback interface", tasks.len());
+
+        // Nodes stop together, whether on a signal or because one of them failed
+        join_all(tasks.into_iter().map(|(name, task)| {
+            let shutdown = &shutdown;
+            async move {
+                if let Err(error) = task.await {
+                    error!(node = %name, "Node stopped: {}", error);
+                }
+                shutdown.trigger();
+            }
+        }))
+        .await;
+    });
+
+    Ok(())
+}
@@ -1,6 +1,14 @@
+pub mod admin;
+pub mod logging;
+pub mod message;
 pub mod o_node;
+pub mod resolve;
+pub mod runtime;
 pub mod server;
+pub mod settings;
+pub mod shutdown;
+pub mod telemetry;
+pub mod transport;
 pub mod video;
-pub mod message;
 
 pub mod video_player;
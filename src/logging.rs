@@ -0,0 +1,32 @@
+use tracing_subscriber::EnvFilter;
+
+/// Filter used when neither the command line nor `RUST_LOG` give one.
+pub const DEFAULT_FILTER: &str = "info";
+
+/// Sets up the logs of the process, written to stderr with their level, time and spans.
+///
+/// `filter` follows the `RUST_LOG` syntax, such as `warn,esr_lib::o_node=debug`.
+/// Without one `RUST_LOG` is used, then `DEFAULT_FILTER`.
+pub fn init(filter: Option<&str>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => {
+            EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(DEFAULT_FILTER))?
+        }
+    };
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init()
+}
+
+#[cfg(test)]
+mod test {
+    use super::init;
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        assert!(init(Some("esr_lib=loud")).is_err());
+    }
+}
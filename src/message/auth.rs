@@ -0,0 +1,489 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwap;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::warn;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Messages older or newer than this, according to their timestamp, are rejected.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// Number of sequence numbers below the highest one seen that are still accepted.
+const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Malformed message: {0}")]
+    Malformed(#[from] bincode::Error),
+    #[error("No key to authenticate messages from {0}")]
+    UnknownSender(IpAddr),
+    #[error("No key to authenticate messages from node {0}")]
+    UnknownNode(String),
+    #[error("Invalid message authentication code")]
+    InvalidTag,
+    #[error("Replayed message")]
+    Replayed,
+    #[error("Message outside of the accepted time window")]
+    Expired,
+}
+
+/// Keys a component uses to sign its messages and verify the ones it receives.
+///
+/// Messages are signed with `own` if present, otherwise with `shared`, and
+/// carry `node` when signed with `own`. A message naming a node listed in
+/// `peers` must be signed with that node's key, any other message must be
+/// signed with the shared key. Nodes are told apart by name, as several of
+/// them may share an address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyRing {
+    #[serde(default)]
+    pub shared: Option<String>,
+    #[serde(default)]
+    pub own: Option<String>,
+    /// Name in the topology of the node `own` belongs to.
+    #[serde(default)]
+    pub node: Option<String>,
+    #[serde(default)]
+    pub peers: HashMap<String, String>,
+}
+
+impl KeyRing {
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.is_none() && self.own.is_none() && self.peers.is_empty()
+    }
+}
+
+/// Keys of the whole overlay, kept by the bootstrapper and handed out to each node.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyConfiguration {
+    #[serde(default)]
+    pub shared: Option<String>,
+    /// Keys of the nodes that have their own, by name in the topology.
+    #[serde(default)]
+    pub nodes: HashMap<String, String>,
+}
+
+impl KeyConfiguration {
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+
+    /// Keys given to `node`: its own, its neighbours' and the shared one.
+    pub fn key_ring_for(&self, node: &str, neighbours: &[&str]) -> KeyRing {
+        let peers = neighbours
+            .iter()
+            .filter_map(|neighbour| {
+                self.nodes
+                    .get(*neighbour)
+                    .map(|key| (neighbour.to_string(), key.clone()))
+            })
+            .collect();
+        let own = self.nodes.get(node).cloned();
+
+        KeyRing {
+            shared: self.shared.clone(),
+            node: own.as_ref().map(|_| node.to_string()),
+            own,
+            peers,
+        }
+    }
+}
+
+/// Message wrapped with what the receiver needs to authenticate it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    /// Node whose own key signed the message, `None` for the shared key.
+    node: Option<String>,
+    sender: u64,
+    sequence: u64,
+    timestamp: u64,
+    payload: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+impl Envelope {
+    fn mac(
+        key: &str,
+        node: Option<&str>,
+        sender: u64,
+        sequence: u64,
+        timestamp: u64,
+        payload: &[u8],
+    ) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+        let node = node.unwrap_or_default();
+        mac.update(&(node.len() as u64).to_be_bytes());
+        mac.update(node.as_bytes());
+        mac.update(&sender.to_be_bytes());
+        mac.update(&sequence.to_be_bytes());
+        mac.update(&timestamp.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Sequence numbers already received from one sender.
+#[derive(Debug)]
+pub(crate) struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+    last_used: Instant,
+}
+
+impl ReplayWindow {
+    pub(crate) fn new(sequence: u64) -> Self {
+        Self {
+            highest: sequence,
+            seen: 1,
+            last_used: Instant::now(),
+        }
+    }
+
+    /// Records `sequence`, failing if it was already received or is too old to tell.
+    pub(crate) fn accept(&mut self, sequence: u64) -> bool {
+        self.last_used = Instant::now();
+
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                1
+            } else {
+                (self.seen << shift) | 1
+            };
+            self.highest = sequence;
+            return true;
+        }
+
+        let offset = self.highest - sequence;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Signs outgoing messages and authenticates incoming ones.
+///
+/// Without keys messages are sent and accepted as plain bincode.
+#[derive(Debug)]
+pub struct Authenticator {
+    keys: ArcSwap<KeyRing>,
+    sender: u64,
+    sequence: AtomicU64,
+    windows: Mutex<HashMap<u64, ReplayWindow>>,
+    rejected: AtomicU64,
+}
+
+impl Default for Authenticator {
+    fn default() -> Self {
+        Self::new(KeyRing::default())
+    }
+}
+
+impl Authenticator {
+    pub fn new(keys: KeyRing) -> Self {
+        Self {
+            keys: ArcSwap::from_pointee(keys),
+            sender: rand::thread_rng().gen(),
+            sequence: AtomicU64::new(1),
+            windows: Mutex::new(HashMap::new()),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Loads the keys from `path`, without a path messages are not authenticated.
+    pub fn from_key_file(path: Option<impl AsRef<Path>>) -> std::io::Result<Self> {
+        match path {
+            Some(path) => Ok(Self::new(KeyRing::from_file(path)?)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.load().is_empty()
+    }
+
+    pub fn keys(&self) -> Arc<KeyRing> {
+        self.keys.load_full()
+    }
+
+    /// Replaces the keys, messages in flight signed with the old ones will be rejected.
+    pub fn set_keys(&self, keys: KeyRing) {
+        self.keys.store(Arc::new(keys));
+    }
+
+    /// Number of messages rejected because they could not be authenticated.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn seal<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        let payload = bincode::serialize(message).expect("Error serializing message");
+
+        let keys = self.keys.load();
+        let (key, node) = match (keys.own.as_ref(), keys.shared.as_ref()) {
+            (Some(own), _) => (own, keys.node.clone()),
+            (None, Some(shared)) => (shared, None),
+            (None, None) => return payload,
+        };
+
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let timestamp = now();
+        let tag = Envelope::mac(
+            key,
+            node.as_deref(),
+            self.sender,
+            sequence,
+            timestamp,
+            &payload,
+        )
+        .finalize()
+        .into_bytes()
+        .to_vec();
+
+        let envelope = Envelope {
+            node,
+            sender: self.sender,
+            sequence,
+            timestamp,
+            payload,
+            tag,
+        };
+
+        bincode::serialize(&envelope).expect("Error serializing message")
+    }
+
+    /// Authenticates and decodes a message received from `from`.
+    pub fn open<T: DeserializeOwned>(&self, data: &[u8], from: IpAddr) -> Result<T, AuthError> {
+        if !self.is_enabled() {
+            return Ok(bincode::deserialize(data)?);
+        }
+
+        let result = self.verify(data, from);
+        if result.is_err() {
+            let rejected = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Rejected unauthenticated message from {} ({} so far)",
+                from, rejected
+            );
+        }
+
+        Ok(bincode::deserialize(&result?)?)
+    }
+
+    fn verify(&self, data: &[u8], from: IpAddr) -> Result<Vec<u8>, AuthError> {
+        let envelope: Envelope = bincode::deserialize(data)?;
+
+        // Only the holder of a node's key can sign a message under its name
+        let keys = self.keys.load();
+        let key = match envelope.node.as_deref() {
+            Some(node) => keys
+                .peers
+                .get(node)
+                .ok_or_else(|| AuthError::UnknownNode(node.to_string()))?,
+            None => keys.shared.as_ref().ok_or(AuthError::UnknownSender(from))?,
+        };
+
+        Envelope::mac(
+            key,
+            envelope.node.as_deref(),
+            envelope.sender,
+            envelope.sequence,
+            envelope.timestamp,
+            &envelope.payload,
+        )
+        .verify_slice(&envelope.tag)
+        .map_err(|_| AuthError::InvalidTag)?;
+
+        if now().abs_diff(envelope.timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return Err(AuthError::Expired);
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+        if !windows.contains_key(&envelope.sender) {
+            // Senders quiet for longer than a message may live can't be replayed anymore
+            windows.retain(|_, window| window.last_used.elapsed() < 2 * MAX_CLOCK_SKEW);
+            windows.insert(envelope.sender, ReplayWindow::new(envelope.sequence));
+        } else if !windows
+            .get_mut(&envelope.sender)
+            .is_some_and(|window| window.accept(envelope.sequence))
+        {
+            return Err(AuthError::Replayed);
+        }
+
+        Ok(envelope.payload)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::message::query::Query;
+
+    use super::{AuthError, Authenticator, KeyConfiguration, KeyRing};
+
+    const NODE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    fn shared(key: &str) -> Authenticator {
+        Authenticator::new(KeyRing {
+            shared: Some(key.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn accepts_signed_messages() {
+        let sender = shared("secret");
+        let receiver = shared("secret");
+
+        let query = Query::new_file_query("movie.Mjpeg", None);
+        let received: Query = receiver.open(&sender.seal(&query), NODE).unwrap();
+
+        assert_eq!(received.query_file(), Some("movie.Mjpeg"));
+        assert_eq!(receiver.rejected(), 0);
+    }
+
+    #[test]
+    fn rejects_unsigned_and_forged_messages() {
+        let receiver = shared("secret");
+        let query = Query::new_file_query("movie.Mjpeg", None);
+
+        let unsigned = bincode::serialize(&query).unwrap();
+        assert!(receiver.open::<Query>(&unsigned, NODE).is_err());
+
+        let forged = shared("guess").seal(&query);
+        assert!(matches!(
+            receiver.open::<Query>(&forged, NODE),
+            Err(AuthError::InvalidTag)
+        ));
+
+        assert_eq!(receiver.rejected(), 2);
+    }
+
+    #[test]
+    fn rejects_replayed_messages() {
+        let sender = shared("secret");
+        let receiver = shared("secret");
+
+        let first = sender.seal(&Query::new_file_query("a.Mjpeg", None));
+        let second = sender.seal(&Query::new_file_query("b.Mjpeg", None));
+
+        // Out of order delivery is fine, receiving the same message twice is not
+        assert!(receiver.open::<Query>(&second, NODE).is_ok());
+        assert!(receiver.open::<Query>(&first, NODE).is_ok());
+        assert!(matches!(
+            receiver.open::<Query>(&first, NODE),
+            Err(AuthError::Replayed)
+        ));
+        assert_eq!(receiver.rejected(), 1);
+    }
+
+    fn node(name: &str, key: &str) -> Authenticator {
+        Authenticator::new(KeyRing {
+            own: Some(key.to_string()),
+            node: Some(name.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn peers_must_use_their_own_key() {
+        let receiver = Authenticator::new(KeyRing {
+            shared: Some("secret".to_string()),
+            peers: [
+                ("relay1".to_string(), "first key".to_string()),
+                ("relay2".to_string(), "second key".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        });
+
+        // Nodes sharing an address are told apart by name
+        let query = Query::new_file_query("movie.Mjpeg", None);
+        assert!(receiver
+            .open::<Query>(&node("relay1", "first key").seal(&query), NODE)
+            .is_ok());
+        assert!(receiver
+            .open::<Query>(&node("relay2", "second key").seal(&query), NODE)
+            .is_ok());
+
+        // The shared key, or another node's, is not enough to speak for a node with its own key
+        let impostor = node("relay1", "secret").seal(&query);
+        assert!(receiver.open::<Query>(&impostor, NODE).is_err());
+        let neighbour = node("relay1", "second key").seal(&query);
+        assert!(receiver.open::<Query>(&neighbour, NODE).is_err());
+        let unknown = node("relay3", "first key").seal(&query);
+        assert!(matches!(
+            receiver.open::<Query>(&unknown, NODE),
+            Err(AuthError::UnknownNode(_))
+        ));
+
+        assert!(receiver
+            .open::<Query>(&shared("secret").seal(&query), NODE)
+            .is_ok());
+    }
+
+    #[test]
+    fn nodes_on_one_address_get_their_own_keys() {
+        let configuration = KeyConfiguration {
+            shared: Some("secret".to_string()),
+            nodes: [
+                ("o1".to_string(), "first key".to_string()),
+                ("o2".to_string(), "second key".to_string()),
+            ]
+            .into(),
+        };
+
+        let o1 = Authenticator::new(configuration.key_ring_for("o1", &["o2", "rp"]));
+        let o2 = Authenticator::new(configuration.key_ring_for("o2", &["o1"]));
+        assert_eq!(o1.keys().own.as_deref(), Some("first key"));
+        assert_eq!(o1.keys().peers.len(), 1);
+
+        // Nodes without a key of their own sign with the shared one, under no name
+        let rp = configuration.key_ring_for("rp", &["o1"]);
+        assert_eq!(rp.node, None);
+
+        let query = Query::new_file_query("movie.Mjpeg", None);
+        assert!(o2.open::<Query>(&o1.seal(&query), NODE).is_ok());
+        assert!(o1.open::<Query>(&o2.seal(&query), NODE).is_ok());
+        assert!(o1
+            .open::<Query>(&Authenticator::new(rp).seal(&query), NODE)
+            .is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Identity a viewer presents to content servers.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    user: String,
+    token: String,
+}
+
+impl Credentials {
+    pub fn new(user: String, token: String) -> Self {
+        Self { user, token }
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,300 @@
+//! Forward error correction of the RTP media path, in the spirit of RFC 5109.
+//!
+//! The origin sends a parity packet after every block of media packets, the
+//! XOR of all of them along with the XOR of their lengths. A viewer missing a
+//! single packet of a block rebuilds it from the others and the parity. Parity
+//! covers the packets as sent, protected or not, so relays forward it as is.
+
+use std::collections::{HashSet, VecDeque};
+
+use super::rtp::{packet_id, HEADER_SIZE};
+
+/// Payload type marking parity packets, media packets never use it.
+pub const FEC_PAYLOAD_TYPE: u8 = 127;
+
+/// Packets in the block and XOR of their lengths, after the RTP header.
+const FEC_HEADER_SIZE: usize = 3;
+/// Media packets remembered to rebuild a lost one, a few blocks worth.
+const HISTORY: usize = 256;
+
+/// Whether `packet` carries the parity of a block rather than media.
+pub fn is_parity(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE + FEC_HEADER_SIZE && packet[1] & 0x7F == FEC_PAYLOAD_TYPE
+}
+
+/// Running XOR of the packets of a block.
+#[derive(Debug, Default)]
+struct Parity {
+    ssrc: u32,
+    base: u16,
+    count: u8,
+    length: u16,
+    data: Vec<u8>,
+}
+
+impl Parity {
+    fn add(&mut self, packet: &[u8]) {
+        if self.data.len() < packet.len() {
+            self.data.resize(packet.len(), 0);
+        }
+
+        for (parity, byte) in self.data.iter_mut().zip(packet) {
+            *parity ^= byte;
+        }
+
+        self.length ^= packet.len() as u16;
+        self.count += 1;
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + FEC_HEADER_SIZE + self.data.len());
+        packet.extend([2 << 6, FEC_PAYLOAD_TYPE]);
+        packet.extend(self.base.to_be_bytes());
+        packet.extend([0; 4]);
+        packet.extend(self.ssrc.to_be_bytes());
+        packet.push(self.count);
+        packet.extend(self.length.to_be_bytes());
+        packet.extend(&self.data);
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        if !is_parity(packet) {
+            return None;
+        }
+
+        let (ssrc, base) = packet_id(packet)?;
+        let header = &packet[HEADER_SIZE..HEADER_SIZE + FEC_HEADER_SIZE];
+
+        Some(Self {
+            ssrc,
+            base,
+            count: header[0],
+            length: u16::from_be_bytes([header[1], header[2]]),
+            data: packet[HEADER_SIZE + FEC_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Adds a parity packet after every `block` media packets of a stream.
+#[derive(Debug)]
+pub struct FecEncoder {
+    block: u8,
+    parity: Option<Parity>,
+}
+
+impl FecEncoder {
+    pub fn new(block: u8) -> Self {
+        Self {
+            block,
+            parity: None,
+        }
+    }
+
+    /// Adds `packet` to the current block, returns its parity once the block is full.
+    pub fn push(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let (ssrc, sequence) = packet_id(packet)?;
+
+        // Blocks cover consecutive packets, a gap starts a new one
+        let parity = match self.parity.take() {
+            Some(parity)
+                if parity.ssrc == ssrc
+                    && parity.base.wrapping_add(parity.count as u16) == sequence =>
+            {
+                self.parity.insert(parity)
+            }
+            _ => self.parity.insert(Parity {
+                ssrc,
+                base: sequence,
+                ..Default::default()
+            }),
+        };
+
+        parity.add(packet);
+
+        if parity.count < self.block {
+            return None;
+        }
+
+        self.parity.take().map(|parity| parity.encode())
+    }
+}
+
+/// Rebuilds the media packets lost in a block from its parity.
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    history: VecDeque<((u32, u16), Vec<u8>)>,
+    seen: HashSet<(u32, u16)>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Media packets rebuilt since the decoder was created.
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Takes a packet as received, returns the media packets it carries or rebuilds.
+    ///
+    /// Copies of packets already handed out are dropped, so a packet that
+    /// arrives after being rebuilt is returned only once.
+    pub fn receive(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if let Some(parity) = Parity::decode(packet) {
+            let rebuilt = self.rebuild(parity)?;
+            self.recovered += 1;
+            return self.remember(rebuilt);
+        }
+
+        self.remember(packet.to_vec())
+    }
+
+    fn remember(&mut self, packet: Vec<u8>) -> Option<Vec<u8>> {
+        let id = packet_id(&packet)?;
+
+        if !self.seen.insert(id) {
+            return None;
+        }
+
+        if self.history.len() == HISTORY {
+            if let Some((old, _)) = self.history.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+
+        self.history.push_back((id, packet.clone()));
+        Some(packet)
+    }
+
+    /// The packet of the block that is missing, if it is the only one.
+    fn rebuild(&self, mut parity: Parity) -> Option<Vec<u8>> {
+        let mut missing = None;
+
+        for offset in 0..parity.count as u16 {
+            let id = (parity.ssrc, parity.base.wrapping_add(offset));
+
+            match self.history.iter().find(|(other, _)| *other == id) {
+                Some((_, packet)) => {
+                    for (byte, other) in parity.data.iter_mut().zip(packet) {
+                        *byte ^= other;
+                    }
+                    parity.length ^= packet.len() as u16;
+                }
+                None if missing.is_none() => missing = Some(id),
+                None => return None,
+            }
+        }
+
+        missing?;
+
+        let length = parity.length as usize;
+        if length < HEADER_SIZE || length > parity.data.len() {
+            return None;
+        }
+
+        parity.data.truncate(length);
+        Some(parity.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::{
+        rtp::{RtpPacket, RtpPacketBuilder},
+        srtp::MediaKey,
+    };
+
+    use super::{is_parity, FecDecoder, FecEncoder};
+
+    fn packets(count: u16) -> Vec<Vec<u8>> {
+        (1..=count)
+            .map(|number| {
+                RtpPacketBuilder::new(&vec![number as u8; 10 + number as usize], 26)
+                    .sequence_number(number)
+                    .build()
+                    .transmit_data()
+            })
+            .collect()
+    }
+
+    /// Sends `packets` through an encoder, dropping the ones `lost` picks.
+    fn send(block: u8, packets: &[Vec<u8>], lost: impl Fn(usize) -> bool) -> Vec<Vec<u8>> {
+        let mut encoder = FecEncoder::new(block);
+        let mut decoder = FecDecoder::new();
+        let mut received = Vec::new();
+
+        let sent = packets.iter().flat_map(|packet| {
+            let parity = encoder.push(packet);
+            std::iter::once(packet.clone()).chain(parity)
+        });
+
+        for (n, packet) in sent.enumerate() {
+            if !lost(n) {
+                received.extend(decoder.receive(&packet));
+            }
+        }
+
+        received.sort_by_key(|packet| u16::from_be_bytes([packet[2], packet[3]]));
+        received
+    }
+
+    #[test]
+    fn single_losses_in_a_block_are_rebuilt() {
+        let packets = packets(12);
+
+        // Blocks of 4 go out as 5 packets, drop one media packet of each
+        let received = send(4, &packets, |n| n % 5 == n / 5 % 4);
+        assert_eq!(received, packets);
+
+        // Lost parity costs nothing
+        let received = send(4, &packets, |n| n % 5 == 4);
+        assert_eq!(received, packets);
+    }
+
+    #[test]
+    fn blocks_missing_several_packets_stay_incomplete() {
+        let packets = packets(8);
+
+        let received = send(4, &packets, |n| n == 0 || n == 1);
+
+        assert_eq!(received, packets[2..]);
+    }
+
+    #[test]
+    fn late_copies_of_rebuilt_packets_are_dropped() {
+        let packets = packets(2);
+        let mut encoder = FecEncoder::new(2);
+        let mut decoder = FecDecoder::new();
+
+        assert!(encoder.push(&packets[0]).is_none());
+        let parity = encoder.push(&packets[1]).unwrap();
+        assert!(is_parity(&parity));
+
+        assert_eq!(decoder.receive(&packets[1]).as_ref(), Some(&packets[1]));
+        assert_eq!(decoder.receive(&parity).as_ref(), Some(&packets[0]));
+        assert_eq!(decoder.receive(&packets[0]), None);
+        assert_eq!(decoder.recovered(), 1);
+    }
+
+    #[test]
+    fn protected_packets_are_rebuilt_without_the_key() {
+        let key = MediaKey::generate();
+        let sender = key.sender();
+        let receiver = key.receiver();
+        let protected: Vec<_> = packets(3)
+            .iter()
+            .map(|packet| sender.protect(packet))
+            .collect();
+
+        let received = send(3, &protected, |n| n == 1);
+
+        let rebuilt = RtpPacket::decode(&receiver.unprotect(&received[1]).unwrap());
+        assert_eq!(
+            rebuilt.payload(),
+            RtpPacket::decode(&packets(3)[1]).payload()
+        );
+    }
+}
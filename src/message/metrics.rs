@@ -25,6 +25,7 @@ pub struct MetricsResponse {
     already_streaming: bool,
     nr_videos_available: usize,
     nr_videos_already_streaming: usize,
+    nr_on_demand_sessions: usize,
     streaming_port: u16,
 }
 
@@ -34,6 +35,7 @@ impl MetricsResponse {
         already_streaming: bool,
         nr_videos_available: usize,
         nr_videos_already_streaming: usize,
+        nr_on_demand_sessions: usize,
         streaming_port: u16,
     ) -> Self {
         Self {
@@ -41,6 +43,7 @@ impl MetricsResponse {
             already_streaming,
             nr_videos_available,
             nr_videos_already_streaming,
+            nr_on_demand_sessions,
             streaming_port,
         }
     }
@@ -48,7 +51,9 @@ impl MetricsResponse {
     pub fn metric_calculation(&self) -> f32 {
         let nr_videos_available_ratio = self.nr_videos_available as f32 * 0.3;
         let nr_videos_already_streaming_ratio = self.nr_videos_already_streaming as f32 * 0.7;
+        let nr_on_demand_sessions_ratio = self.nr_on_demand_sessions as f32 * 0.7;
         nr_videos_already_streaming_ratio
+            + nr_on_demand_sessions_ratio
             + nr_videos_available_ratio
             + self.already_streaming as u32 as f32
     }
@@ -69,6 +74,10 @@ impl MetricsResponse {
         self.nr_videos_already_streaming
     }
 
+    pub fn nr_on_demand_sessions(&self) -> usize {
+        self.nr_on_demand_sessions
+    }
+
     pub fn set_video_found(&mut self, video_found: bool) {
         self.video_found = video_found;
     }
@@ -1,9 +1,14 @@
 use serde::{Deserialize, Serialize};
 
 pub mod answer;
+pub mod auth;
+pub mod credentials;
+pub mod fec;
+pub mod nack;
 pub mod query;
 pub mod rtp;
 pub mod rtsp;
+pub mod srtp;
 pub mod metrics;
 
 pub trait Message<T>: std::fmt::Debug + Clone + Serialize + for<'de> Deserialize<'de> {
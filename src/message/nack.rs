@@ -0,0 +1,238 @@
+//! Generic NACK feedback of the RTP media path, as in RFC 4585.
+//!
+//! A receiver that sees a gap in the sequence numbers of a stream asks the
+//! hop it receives the stream from for the missing packets, again every
+//! `streaming.nack_interval_ms`, until `streaming.repair_timeout_ms` passes.
+//! NACKs travel on the media sockets, framed like media packets.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use super::rtp::HEADER_SIZE;
+
+/// RTCP payload type of transport layer feedback, which RTP packets never use.
+pub const NACK_PAYLOAD_TYPE: u8 = 205;
+/// Feedback message type of a generic NACK.
+const GENERIC_NACK: u8 = 1;
+/// Larger gaps are treated as a restart of the stream rather than loss.
+const MAX_GAP: u16 = 64;
+
+/// Whether `packet` asks for packets again rather than carrying media.
+pub fn is_nack(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE && packet[1] == NACK_PAYLOAD_TYPE
+}
+
+/// Packets of a stream a receiver asks for again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nack {
+    ssrc: u32,
+    lost: Vec<u16>,
+}
+
+impl Nack {
+    pub fn new(ssrc: u32, mut lost: Vec<u16>) -> Self {
+        lost.sort_unstable();
+        lost.dedup();
+
+        Self { ssrc, lost }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sequence numbers of the lost packets.
+    pub fn lost(&self) -> &[u16] {
+        &self.lost
+    }
+
+    /// Lost packets as RTCP feedback, each entry covering a packet and the 16 after it.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries: Vec<(u16, u16)> = Vec::new();
+
+        for &sequence in &self.lost {
+            match entries.last_mut() {
+                Some((first, mask)) if sequence.wrapping_sub(*first) <= 16 => {
+                    *mask |= 1 << (sequence.wrapping_sub(*first) - 1)
+                }
+                _ => entries.push((sequence, 0)),
+            }
+        }
+
+        // Length in 32 bit words, minus one
+        let length = (2 + entries.len()) as u16;
+
+        let mut packet = Vec::with_capacity(HEADER_SIZE + 4 * entries.len());
+        packet.extend([2 << 6 | GENERIC_NACK, NACK_PAYLOAD_TYPE]);
+        packet.extend(length.to_be_bytes());
+        packet.extend([0; 4]);
+        packet.extend(self.ssrc.to_be_bytes());
+
+        for (first, mask) in entries {
+            packet.extend(first.to_be_bytes());
+            packet.extend(mask.to_be_bytes());
+        }
+
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        if !is_nack(packet) || packet[0] & 0x1F != GENERIC_NACK {
+            return None;
+        }
+
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let mut lost = Vec::new();
+
+        for entry in packet[HEADER_SIZE..].chunks_exact(4) {
+            let first = u16::from_be_bytes([entry[0], entry[1]]);
+            let mask = u16::from_be_bytes([entry[2], entry[3]]);
+
+            lost.push(first);
+            lost.extend(
+                (0..16)
+                    .filter(|bit| mask & (1 << bit) != 0)
+                    .map(|bit| first.wrapping_add(bit + 1)),
+            );
+        }
+
+        Some(Self::new(ssrc, lost))
+    }
+}
+
+#[derive(Debug)]
+struct Repair {
+    deadline: Instant,
+    next_request: Instant,
+}
+
+/// Finds the packets missing from the streams a hop receives, and when to ask for them.
+#[derive(Debug)]
+pub struct LossTracker {
+    highest: HashMap<u32, u16>,
+    missing: BTreeMap<(u32, u16), Repair>,
+    timeout: Duration,
+    interval: Duration,
+}
+
+impl LossTracker {
+    /// Asks for lost packets every `interval`, for at most `timeout`.
+    pub fn new(timeout: Duration, interval: Duration) -> Self {
+        Self {
+            highest: HashMap::new(),
+            missing: BTreeMap::new(),
+            timeout,
+            interval,
+        }
+    }
+
+    /// Packets still being asked for.
+    pub fn missing(&self) -> usize {
+        self.missing.len()
+    }
+
+    /// Whether the packet is still being asked for.
+    pub fn is_missing(&self, id: (u32, u16)) -> bool {
+        self.missing.contains_key(&id)
+    }
+
+    /// Records a packet received at `now`, any gap before it is lost.
+    pub fn observe(&mut self, (ssrc, sequence): (u32, u16), now: Instant) {
+        if self.timeout.is_zero() {
+            return;
+        }
+
+        let Some(highest) = self.highest.get_mut(&ssrc) else {
+            self.highest.insert(ssrc, sequence);
+            return;
+        };
+
+        let ahead = sequence.wrapping_sub(*highest);
+        if ahead == 0 || ahead > u16::MAX / 2 {
+            self.missing.remove(&(ssrc, sequence));
+            return;
+        }
+
+        if ahead <= MAX_GAP {
+            for gap in 1..ahead {
+                let repair = Repair {
+                    deadline: now + self.timeout,
+                    next_request: now,
+                };
+                self.missing
+                    .insert((ssrc, highest.wrapping_add(gap)), repair);
+            }
+        }
+
+        *highest = sequence;
+    }
+
+    /// Lost packets to ask for at `now`, packets lost for too long are given up.
+    pub fn due(&mut self, now: Instant) -> Vec<Nack> {
+        self.missing.retain(|_, repair| repair.deadline > now);
+
+        let mut lost: BTreeMap<u32, Vec<u16>> = BTreeMap::new();
+        for (&(ssrc, sequence), repair) in self.missing.iter_mut() {
+            if repair.next_request <= now {
+                repair.next_request = now + self.interval;
+                lost.entry(ssrc).or_default().push(sequence);
+            }
+        }
+
+        lost.into_iter()
+            .map(|(ssrc, lost)| Nack::new(ssrc, lost))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{is_nack, LossTracker, Nack};
+
+    #[test]
+    fn nacks_round_trip() {
+        let nack = Nack::new(7, vec![3, 1, 17, 18, 40, 65535, 2]);
+
+        let encoded = nack.encode();
+        assert!(is_nack(&encoded));
+        // 1 to 17 share an entry, 18, 40 and 65535 need one each
+        assert_eq!(encoded.len(), 12 + 4 * 4);
+
+        assert_eq!(Nack::decode(&encoded), Some(nack));
+        assert_eq!(Nack::decode(&encoded[..8]), None);
+    }
+
+    #[test]
+    fn gaps_are_asked_for_until_they_are_filled_or_too_old() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut tracker = LossTracker::new(ms(100), ms(40));
+
+        for sequence in [1, 2, 5, 6] {
+            tracker.observe((9, sequence), start);
+        }
+        assert_eq!(tracker.due(start), vec![Nack::new(9, vec![3, 4])]);
+        assert_eq!(tracker.due(start + ms(20)), vec![]);
+
+        tracker.observe((9, 3), start + ms(30));
+        assert_eq!(tracker.due(start + ms(40)), vec![Nack::new(9, vec![4])]);
+        assert_eq!(tracker.due(start + ms(100)), vec![]);
+        assert_eq!(tracker.missing(), 0);
+
+        // Sequence numbers wrap around
+        for sequence in [65534, 65535, 1] {
+            tracker.observe((10, sequence), start);
+        }
+        assert_eq!(tracker.due(start), vec![Nack::new(10, vec![0])]);
+
+        // Late packets and restarted streams are no loss
+        tracker.observe((10, 0), start);
+        tracker.observe((10, 65000), start);
+        tracker.observe((10, 30000), start);
+        assert_eq!(tracker.missing(), 0);
+    }
+}
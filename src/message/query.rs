@@ -1,9 +1,9 @@
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::o_node::neighbour::Neighbour;
+use crate::{o_node::neighbour::Neighbour, settings};
 
-use super::{Message, Status};
+use super::{credentials::Credentials, Message, Status};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FileQuery {
@@ -40,11 +40,26 @@ impl FileQuery {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QueryType {
-    #[default]
-    Neighbours,
+    /// Asks the bootstrapper for the neighbours of the node named `node` in the topology,
+    /// or of the only node with the sender's address without a name.
+    Neighbours {
+        node: Option<String>,
+    },
     File(FileQuery),
+    /// Asks a node for the streams it relays.
+    Status,
+    /// Asks the bootstrapper for the topology, along with the state of every node if `live`.
+    Topology {
+        live: bool,
+    },
+}
+
+impl Default for QueryType {
+    fn default() -> Self {
+        Self::Neighbours { node: None }
+    }
 }
 
 impl QueryType {
@@ -69,6 +84,8 @@ pub struct Query {
     query_type: QueryType,
     status: Status,
     payload: Option<String>,
+    credentials: Option<Credentials>,
+    hops_left: u8,
 }
 
 impl Message<String> for Query {
@@ -89,6 +106,8 @@ impl Query {
             query_type,
             status: Status::Query,
             payload,
+            credentials: None,
+            hops_left: settings::get().queries.max_hops,
         }
     }
 
@@ -101,9 +120,40 @@ impl Query {
             query_type,
             status: Status::Query,
             payload,
+            credentials: None,
+            hops_left: settings::get().queries.max_hops,
         }
     }
 
+    pub fn with_hop_limit(mut self, hops: u8) -> Self {
+        self.hops_left = hops;
+        self
+    }
+
+    pub fn hops_left(&self) -> u8 {
+        self.hops_left
+    }
+
+    /// Counts one more hop, returns false if the query may not travel any further.
+    pub fn hop(&mut self) -> bool {
+        if self.hops_left == 0 {
+            return false;
+        }
+
+        self.hops_left -= 1;
+        true
+    }
+
+    /// Identity of the viewer looking for the file, carried along with the query.
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
     pub fn query_type(&self) -> &QueryType {
         &self.query_type
     }
@@ -1,5 +1,19 @@
 use serde::{Deserialize, Serialize};
 
+/// Size of the fixed RTP header.
+pub const HEADER_SIZE: usize = 12;
+
+/// Stream and sequence number of a packet, read from its RTP header.
+pub fn packet_id(packet: &[u8]) -> Option<(u32, u16)> {
+    if packet.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+    Some((ssrc, sequence))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RtpPacket {
     version: u8,
@@ -1,10 +1,16 @@
 use core::fmt;
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::o_node::neighbour::Neighbour;
+use crate::{
+    message::{
+        credentials::Credentials,
+        srtp::{KeyShare, WrappedKey},
+    },
+    o_node::neighbour::Neighbour,
+};
 
 #[derive(Error, Debug, Clone)]
 pub enum RtpParsingError {
@@ -21,6 +27,12 @@ pub enum RequestType {
     Play,
     Pause,
     Teardown,
+    /// Moves a session to another rendition of its file.
+    Switch,
+    /// Starts or stops recording the stream of a session on the node it was sent to.
+    Record,
+    /// Counts a viewer a relay serves against its user, nothing is streamed for it.
+    Admission,
 }
 
 impl fmt::Display for RequestType {
@@ -30,6 +42,9 @@ impl fmt::Display for RequestType {
             Self::Play => write!(f, "PLAY"),
             Self::Pause => write!(f, "PAUSE"),
             Self::Teardown => write!(f, "TEARDOWN"),
+            Self::Switch => write!(f, "SWITCH"),
+            Self::Record => write!(f, "RECORD"),
+            Self::Admission => write!(f, "ADMISSION"),
         }
     }
 }
@@ -43,24 +58,74 @@ impl FromStr for RequestType {
             "PLAY" => Ok(Self::Play),
             "PAUSE" => Ok(Self::Pause),
             "TEARDOWN" => Ok(Self::Teardown),
+            "SWITCH" => Ok(Self::Switch),
+            "RECORD" => Ok(Self::Record),
+            "ADMISSION" => Ok(Self::Admission),
             _ => Err(RtpParsingError::InvalidRequestType(s.to_string())),
         }
     }
 }
 
+/// How a content server delivers a file to a viewer.
+///
+/// `Broadcast` viewers share one stream (and one position) per file, while
+/// `OnDemand` viewers get their own stream starting at the beginning of the file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum StreamingMode {
+    #[default]
+    Broadcast,
+    OnDemand,
+}
+
+impl fmt::Display for StreamingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Broadcast => write!(f, "broadcast"),
+            Self::OnDemand => write!(f, "on-demand"),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid streaming mode: {0}, expected broadcast or on-demand")]
+pub struct StreamingModeError(String);
+
+impl FromStr for StreamingMode {
+    type Err = StreamingModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(Self::Broadcast),
+            "on-demand" => Ok(Self::OnDemand),
+            _ => Err(StreamingModeError(s.to_string())),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum Status {
     Ok = 200,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
     FileNotFound = 404,
+    /// The session is not in a state that takes the request, such as a play before any setup.
+    MethodNotValid = 455,
     ConnectionError = 500,
+    ServiceUnavailable = 503,
 }
 
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Ok => write!(f, "OK"),
+            Self::BadRequest => write!(f, "BAD REQUEST"),
+            Self::Unauthorized => write!(f, "UNAUTHORIZED"),
+            Self::Forbidden => write!(f, "FORBIDDEN"),
             Self::FileNotFound => write!(f, "NOT FOUND"),
+            Self::MethodNotValid => write!(f, "METHOD NOT VALID IN THIS STATE"),
             Self::ConnectionError => write!(f, "CONNECTION ERROR"),
+            Self::ServiceUnavailable => write!(f, "SERVICE UNAVAILABLE"),
         }
     }
 }
@@ -70,6 +135,11 @@ pub struct RtspResponse {
     status: Status,
     sequence: u32,
     session: u32,
+    mode: StreamingMode,
+    media_keys: Vec<WrappedKey>,
+    rendition: Option<u32>,
+    renditions: Vec<u32>,
+    frames: Option<u32>,
 }
 
 impl RtspResponse {
@@ -78,9 +148,63 @@ impl RtspResponse {
             status,
             sequence,
             session,
+            mode: StreamingMode::default(),
+            media_keys: Vec::new(),
+            rendition: None,
+            renditions: Vec::new(),
+            frames: None,
         }
     }
 
+    pub fn with_mode(mut self, mode: StreamingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Key of the stream just set up, sealed for each share the SETUP carried.
+    pub fn with_media_keys(mut self, media_keys: Vec<WrappedKey>) -> Self {
+        self.media_keys = media_keys;
+        self
+    }
+
+    pub fn media_keys(&self) -> &[WrappedKey] {
+        &self.media_keys
+    }
+
+    pub fn mode(&self) -> StreamingMode {
+        self.mode
+    }
+
+    /// Rendition the session is served, `None` being the file itself.
+    pub fn with_rendition(mut self, rendition: Option<u32>) -> Self {
+        self.rendition = rendition;
+        self
+    }
+
+    pub fn rendition(&self) -> Option<u32> {
+        self.rendition
+    }
+
+    /// Lower qualities the file is also available in, best first.
+    pub fn with_renditions(mut self, renditions: Vec<u32>) -> Self {
+        self.renditions = renditions;
+        self
+    }
+
+    pub fn renditions(&self) -> &[u32] {
+        &self.renditions
+    }
+
+    /// Frames in the title set up, for relays to tell when they have relayed all of it.
+    pub fn with_frames(mut self, frames: Option<u32>) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    pub fn frames(&self) -> Option<u32> {
+        self.frames
+    }
+
     pub fn succeded(&self) -> bool {
         self.status == Status::Ok
     }
@@ -111,6 +235,13 @@ pub struct RtspRequest {
     seq_number: u32,
     port_rtp: u16,
     servers_to_contact: Vec<Neighbour>,
+    mode: Option<StreamingMode>,
+    credentials: Option<Credentials>,
+    trace_id: u32,
+    rendition: Option<u32>,
+    recording: Option<String>,
+    time_shift: Duration,
+    key_shares: Vec<KeyShare>,
 }
 
 impl fmt::Display for RtspRequest {
@@ -152,9 +283,80 @@ impl RtspRequest {
             seq_number,
             port_rtp,
             servers_to_contact,
+            ..Default::default()
         }
     }
 
+    /// Requests a specific streaming mode, `None` lets the content server decide.
+    pub fn with_mode(mut self, mode: Option<StreamingMode>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> Option<StreamingMode> {
+        self.mode
+    }
+
+    /// Identity of the viewer, checked by the content server on SETUP.
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Id the logs of every hop of a session are tagged with, that of the query that found the file.
+    pub fn with_trace_id(mut self, trace_id: u32) -> Self {
+        self.trace_id = trace_id;
+        self
+    }
+
+    pub fn trace_id(&self) -> u32 {
+        self.trace_id
+    }
+
+    /// Quality to watch the file in, see `video::rendition`. `None` asks for the file itself.
+    pub fn with_rendition(mut self, rendition: Option<u32>) -> Self {
+        self.rendition = rendition;
+        self
+    }
+
+    pub fn rendition(&self) -> Option<u32> {
+        self.rendition
+    }
+
+    /// Name the stream is recorded under on RECORD, `None` stops recording it.
+    pub fn with_recording(mut self, recording: Option<String>) -> Self {
+        self.recording = recording;
+        self
+    }
+
+    pub fn recording(&self) -> Option<&str> {
+        self.recording.as_deref()
+    }
+
+    /// How far behind the live broadcast to watch, asked on SETUP.
+    pub fn with_time_shift(mut self, time_shift: Duration) -> Self {
+        self.time_shift = time_shift;
+        self
+    }
+
+    pub fn time_shift(&self) -> Duration {
+        self.time_shift
+    }
+
+    /// Shares the key of the stream is sealed for on SETUP, see `srtp::KeyRequest`.
+    pub fn with_key_shares(mut self, key_shares: Vec<KeyShare>) -> Self {
+        self.key_shares = key_shares;
+        self
+    }
+
+    pub fn key_shares(&self) -> &[KeyShare] {
+        &self.key_shares
+    }
+
     pub fn request_type(&self) -> &RequestType {
         &self.request_type
     }
@@ -170,11 +372,11 @@ impl RtspRequest {
     pub fn port_rtp(&self) -> u16 {
         self.port_rtp
     }
-    
+
     pub fn next_server(&mut self) -> Option<Neighbour> {
         self.servers_to_contact.pop()
     }
-    
+
     pub fn servers_to_connect(&self) -> &Vec<Neighbour> {
         &self.servers_to_contact
     }
@@ -219,3 +421,20 @@ impl FromStr for RtspRequest {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{StreamingMode, StreamingModeError};
+
+    #[test]
+    fn streaming_modes_parse_from_their_names() {
+        for mode in [StreamingMode::Broadcast, StreamingMode::OnDemand] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+
+        assert_eq!(
+            "live".parse::<StreamingMode>(),
+            Err(StreamingModeError("live".to_string()))
+        );
+    }
+}
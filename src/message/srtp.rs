@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::auth::ReplayWindow;
+
+/// Size of the fixed RTP header, which is authenticated but sent in the clear.
+const HEADER_SIZE: usize = 12;
+const INDEX_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// Label the keys sealing stream keys for their recipients are derived under.
+const WRAP_LABEL: &[u8] = b"esr media key";
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("Packet is too short to be protected")]
+    TooShort,
+    #[error("Invalid packet authentication tag")]
+    InvalidTag,
+    #[error("Replayed packet")]
+    Replayed,
+}
+
+/// Key and salt protecting the RTP packets of a stream, sealed for each viewer on SETUP.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaKey {
+    key: [u8; 16],
+    salt: [u8; 12],
+}
+
+impl MediaKey {
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+
+        Self {
+            key: rng.gen(),
+            salt: rng.gen(),
+        }
+    }
+
+    pub fn sender(&self) -> SrtpSender {
+        SrtpSender::new(self)
+    }
+
+    pub fn receiver(&self) -> SrtpReceiver {
+        SrtpReceiver::new(self)
+    }
+
+    fn cipher(&self) -> Aes128Gcm {
+        Aes128Gcm::new(&self.key.into())
+    }
+
+    /// Seals the key for each of `shares`, skipping the ones no key can be agreed with.
+    pub fn wrap(&self, shares: &[KeyShare]) -> Vec<WrappedKey> {
+        shares
+            .iter()
+            .filter_map(|share| self.wrap_for(share))
+            .collect()
+    }
+
+    fn wrap_for(&self, share: &KeyShare) -> Option<WrappedKey> {
+        let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let sender = PublicKey::from(&secret).to_bytes();
+
+        // Low order points would agree on a key everybody knows
+        let shared = secret.diffie_hellman(&PublicKey::from(share.0));
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let plain = [&self.key[..], &self.salt[..]].concat();
+        let sealed = wrapping_cipher(shared.as_bytes(), &sender, share)
+            .encrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &plain,
+                    aad: &share.0,
+                },
+            )
+            .expect("Error sealing the key");
+
+        Some(WrappedKey {
+            recipient: *share,
+            sender,
+            sealed,
+        })
+    }
+}
+
+impl fmt::Debug for MediaKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MediaKey { .. }")
+    }
+}
+
+/// Cipher sealing a stream key for `recipient`, each one is used once as `sender` is ephemeral.
+fn wrapping_cipher(shared: &[u8; 32], sender: &[u8; 32], recipient: &KeyShare) -> Aes128Gcm {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(shared).expect("HMAC accepts keys of any size");
+    mac.update(WRAP_LABEL);
+    mac.update(sender);
+    mac.update(&recipient.0);
+    let bytes = mac.finalize().into_bytes();
+
+    let mut key = [0; 16];
+    key.copy_from_slice(&bytes[..16]);
+    Aes128Gcm::new(&key.into())
+}
+
+/// Public key the stream key is sealed for, sent on SETUP by the viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyShare([u8; 32]);
+
+/// Stream key sealed for the holder of a `KeyRequest`.
+///
+/// Relays forward it to the viewer without being able to open it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    recipient: KeyShare,
+    sender: [u8; 32],
+    sealed: Vec<u8>,
+}
+
+/// Ephemeral secret a stream key is asked for with, on a single SETUP.
+///
+/// Viewers send its share, and content servers seal the key of the stream
+/// for it, so only the viewer can open it. Relays only ask for the key of
+/// the streams they cache or record, and keep it in the clear.
+pub struct KeyRequest {
+    secret: EphemeralSecret,
+    share: KeyShare,
+}
+
+impl KeyRequest {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let share = KeyShare(PublicKey::from(&secret).to_bytes());
+
+        Self { secret, share }
+    }
+
+    pub fn share(&self) -> KeyShare {
+        self.share
+    }
+
+    /// Opens the key sealed for this request among `keys`.
+    pub fn open(self, keys: &[WrappedKey]) -> Option<MediaKey> {
+        let wrapped = keys.iter().find(|key| key.recipient == self.share)?;
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(wrapped.sender));
+        let plain = wrapping_cipher(shared.as_bytes(), &wrapped.sender, &self.share)
+            .decrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: &wrapped.sealed,
+                    aad: &self.share.0,
+                },
+            )
+            .ok()?;
+
+        let (key, salt) = plain.split_at(16);
+
+        Some(MediaKey {
+            key: key.try_into().ok()?,
+            salt: salt.try_into().ok()?,
+        })
+    }
+}
+
+impl Default for KeyRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for KeyRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRequest")
+            .field("share", &self.share)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Secret a content server derives the key of each broadcast from.
+///
+/// Every viewer of a broadcast must get the same key, even if they set up
+/// before the stream starts, so the key depends only on the file.
+pub struct MasterKey {
+    secret: [u8; 32],
+}
+
+impl Default for MasterKey {
+    fn default() -> Self {
+        Self {
+            secret: rand::thread_rng().gen(),
+        }
+    }
+}
+
+impl MasterKey {
+    pub fn derive(&self, label: &str) -> MediaKey {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any size");
+        mac.update(label.as_bytes());
+        let bytes = mac.finalize().into_bytes();
+
+        let mut key = [0; 16];
+        let mut salt = [0; 12];
+        key.copy_from_slice(&bytes[..16]);
+        salt.copy_from_slice(&bytes[16..28]);
+
+        MediaKey { key, salt }
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey { .. }")
+    }
+}
+
+/// Nonce of a packet, unique as long as `ssrc` is not reused with the same key.
+fn nonce(salt: &[u8; 12], ssrc: [u8; 4], index: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = *salt;
+
+    for (byte, value) in nonce
+        .iter_mut()
+        .zip(ssrc.into_iter().chain(index.to_be_bytes()))
+    {
+        *byte ^= value;
+    }
+
+    nonce.into()
+}
+
+/// Encrypts and authenticates the payload of outgoing RTP packets.
+///
+/// A protected packet is the RTP header, with this sender's SSRC, followed by
+/// the packet index and the encrypted payload with its tag. Relays forward it
+/// as is, only viewers need the key.
+pub struct SrtpSender {
+    cipher: Aes128Gcm,
+    salt: [u8; 12],
+    ssrc: [u8; 4],
+    index: AtomicU64,
+}
+
+impl SrtpSender {
+    fn new(key: &MediaKey) -> Self {
+        Self {
+            cipher: key.cipher(),
+            salt: key.salt,
+            // Streams sharing a key are told apart by their SSRC
+            ssrc: rand::thread_rng().gen(),
+            index: AtomicU64::new(0),
+        }
+    }
+
+    pub fn protect(&self, rtp: &[u8]) -> Vec<u8> {
+        let index = self.index.fetch_add(1, Ordering::Relaxed);
+        let header_size = rtp.len().min(HEADER_SIZE);
+
+        let mut packet = Vec::with_capacity(rtp.len() + INDEX_SIZE + TAG_SIZE);
+        packet.extend(&rtp[..header_size]);
+        packet.resize(HEADER_SIZE, 0);
+        packet[8..HEADER_SIZE].copy_from_slice(&self.ssrc);
+        packet.extend(index.to_be_bytes());
+
+        let payload = Payload {
+            msg: &rtp[header_size..],
+            aad: &packet,
+        };
+        let encrypted = self
+            .cipher
+            .encrypt(&nonce(&self.salt, self.ssrc, index), payload)
+            .expect("Error encrypting packet");
+
+        packet.extend(encrypted);
+        packet
+    }
+}
+
+impl fmt::Debug for SrtpSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrtpSender")
+            .field("ssrc", &u32::from_be_bytes(self.ssrc))
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Authenticates and decrypts incoming RTP packets, dropping replayed ones.
+pub struct SrtpReceiver {
+    cipher: Aes128Gcm,
+    salt: [u8; 12],
+    windows: Mutex<HashMap<[u8; 4], ReplayWindow>>,
+}
+
+impl SrtpReceiver {
+    fn new(key: &MediaKey) -> Self {
+        Self {
+            cipher: key.cipher(),
+            salt: key.salt,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the plain RTP packet.
+    pub fn unprotect(&self, packet: &[u8]) -> Result<Vec<u8>, MediaError> {
+        if packet.len() < HEADER_SIZE + INDEX_SIZE + TAG_SIZE {
+            return Err(MediaError::TooShort);
+        }
+
+        let (aad, encrypted) = packet.split_at(HEADER_SIZE + INDEX_SIZE);
+        let mut ssrc = [0; 4];
+        ssrc.copy_from_slice(&aad[8..HEADER_SIZE]);
+        let mut index = [0; INDEX_SIZE];
+        index.copy_from_slice(&aad[HEADER_SIZE..]);
+        let index = u64::from_be_bytes(index);
+
+        let payload = self
+            .cipher
+            .decrypt(
+                &nonce(&self.salt, ssrc, index),
+                Payload {
+                    msg: encrypted,
+                    aad,
+                },
+            )
+            .map_err(|_| MediaError::InvalidTag)?;
+
+        // Only authentic packets may move the window, otherwise anyone could shift it
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(&ssrc) {
+            Some(window) => {
+                if !window.accept(index) {
+                    return Err(MediaError::Replayed);
+                }
+            }
+            None => {
+                windows.insert(ssrc, ReplayWindow::new(index));
+            }
+        }
+
+        let mut rtp = Vec::with_capacity(HEADER_SIZE + payload.len());
+        rtp.extend(&aad[..HEADER_SIZE]);
+        rtp.extend(payload);
+        Ok(rtp)
+    }
+}
+
+impl fmt::Debug for SrtpReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrtpReceiver").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::rtp::{RtpPacket, RtpPacketBuilder};
+
+    use super::{KeyRequest, MasterKey, MediaError, MediaKey};
+
+    fn frame(number: u16) -> Vec<u8> {
+        RtpPacketBuilder::new(&[number as u8; 100], 26)
+            .sequence_number(number)
+            .build()
+            .transmit_data()
+    }
+
+    #[test]
+    fn protected_packets_round_trip() {
+        let key = MediaKey::generate();
+        let sender = key.sender();
+        let receiver = key.receiver();
+
+        let protected = sender.protect(&frame(7));
+        assert!(!protected.windows(100).any(|window| window == [7; 100]));
+
+        let packet = RtpPacket::decode(&receiver.unprotect(&protected).unwrap());
+        assert_eq!(packet.payload(), &[7; 100]);
+    }
+
+    #[test]
+    fn tampered_packets_are_dropped() {
+        let key = MediaKey::generate();
+        let sender = key.sender();
+        let receiver = key.receiver();
+
+        let protected = sender.protect(&frame(1));
+
+        for position in [2, 15, 40, protected.len() - 1] {
+            let mut tampered = protected.clone();
+            tampered[position] ^= 1;
+            assert!(matches!(
+                receiver.unprotect(&tampered),
+                Err(MediaError::InvalidTag)
+            ));
+        }
+
+        let other = MediaKey::generate().receiver();
+        assert!(other.unprotect(&protected).is_err());
+        assert!(matches!(
+            receiver.unprotect(&protected[..20]),
+            Err(MediaError::TooShort)
+        ));
+
+        // Rejected packets leave the stream untouched
+        assert!(receiver.unprotect(&protected).is_ok());
+    }
+
+    #[test]
+    fn replayed_packets_are_dropped() {
+        let key = MediaKey::generate();
+        let sender = key.sender();
+        let receiver = key.receiver();
+
+        let first = sender.protect(&frame(1));
+        let second = sender.protect(&frame(2));
+
+        assert!(receiver.unprotect(&second).is_ok());
+        assert!(receiver.unprotect(&first).is_ok());
+        assert!(matches!(
+            receiver.unprotect(&first),
+            Err(MediaError::Replayed)
+        ));
+        assert!(matches!(
+            receiver.unprotect(&second),
+            Err(MediaError::Replayed)
+        ));
+    }
+
+    #[test]
+    fn broadcasts_share_a_key_but_not_a_stream() {
+        let master = MasterKey::default();
+        assert_eq!(master.derive("movie.Mjpeg"), master.derive("movie.Mjpeg"));
+        assert_ne!(master.derive("movie.Mjpeg"), master.derive("other.Mjpeg"));
+
+        // A restarted broadcast starts again at index 0, under a new SSRC
+        let key = master.derive("movie.Mjpeg");
+        let receiver = key.receiver();
+        let before = key.sender().protect(&frame(1));
+        let after = key.sender().protect(&frame(1));
+
+        assert_ne!(before, after);
+        assert!(receiver.unprotect(&before).is_ok());
+        assert!(receiver.unprotect(&after).is_ok());
+    }
+
+    #[test]
+    fn keys_are_only_opened_by_their_recipient() {
+        let key = MediaKey::generate();
+        let viewer = KeyRequest::new();
+        let other = KeyRequest::new();
+
+        let wrapped = key.wrap(&[viewer.share()]);
+        assert_eq!(wrapped.len(), 1);
+
+        assert!(other.open(&wrapped).is_none());
+
+        let mut tampered = wrapped.clone();
+        tampered[0].sealed[0] ^= 1;
+        assert!(KeyRequest::new().open(&tampered).is_none());
+
+        assert_eq!(viewer.open(&wrapped), Some(key));
+    }
+}
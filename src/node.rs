@@ -1,15 +1,119 @@
+use std::io::Write;
+
 use clap::Parser;
-use esr_lib::o_node::{config::Configuration, create_node, NodeCreationError};
+use esr_lib::{
+    logging,
+    o_node::{
+        bootstraper_node::BootstraperNode,
+        config::{Configuration, NodeFunction, TopologyCommand},
+        create_node,
+        export::{ExportFormat, Snapshot},
+        topology::Topology,
+    },
+    shutdown::Shutdown,
+};
 
-fn main() -> Result<(), NodeCreationError> {
-    let config = Configuration::parse();
+/// Prints the issues found in `topology`, returns whether none of them is an error.
+fn check_topology(topology: &str) -> bool {
+    let topology = match Topology::from_file(topology) {
+        Ok(topology) => topology,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+
+    let issues = topology.check();
+    for issue in &issues {
+        let severity = if issue.is_error() { "error" } else { "warning" };
+        println!("{}: {}", severity, issue);
+    }
+
+    // Host names are looked up as the bootstrapper would on start
+    let unresolved = topology.resolve().err();
+    if let Some(error) = &unresolved {
+        println!("error: {}", error);
+    }
+
+    println!(
+        "{} nodes, {} issues found",
+        topology.nodes.len(),
+        issues.len() + usize::from(unresolved.is_some())
+    );
+
+    !issues.iter().any(|issue| issue.is_error()) && unresolved.is_none()
+}
+
+/// Writes the snapshot of a topology file or running bootstrapper, returns whether it succeeded.
+fn export_topology(
+    topology: Option<&str>,
+    bootstraper: Option<&str>,
+    live: bool,
+    format: ExportFormat,
+    output: Option<&str>,
+) -> bool {
+    let snapshot = match (topology, bootstraper) {
+        (_, Some(bootstraper)) => BootstraperNode::ask_snapshot(bootstraper, live),
+        (Some(_), None) if live => {
+            Err("Only a running bootstrapper knows the live topology".into())
+        }
+        (Some(topology), None) => Topology::from_file(topology)
+            .map(|topology| Snapshot {
+                topology,
+                live: None,
+            })
+            .map_err(|error| error.into()),
+        (None, None) => unreachable!("Clap requires a topology or a bootstrapper"),
+    };
 
-    match create_node(config) {
-        Ok(node) => {
-            node.run()?;
+    let export = match snapshot {
+        Ok(snapshot) => snapshot.render(format),
+        Err(error) => {
+            eprintln!("Error getting the topology: {}", error);
+            return false;
         }
-        Err(err) => println!("Error creating node: {:?}", err),
     };
 
+    let written = match output {
+        Some(output) => std::fs::write(output, export),
+        None => std::io::stdout().write_all(export.as_bytes()),
+    };
+
+    written
+        .map_err(|error| eprintln!("Error writing the export: {}", error))
+        .is_ok()
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Configuration::parse();
+
+    logging::init(config.log.as_deref()).expect("Invalid log filter");
+    config.settings.init().expect("Error reading the settings");
+
+    if let NodeFunction::Topology { command } = &config.node_function {
+        let succeeded = match command {
+            TopologyCommand::Check { topology } => check_topology(topology),
+            TopologyCommand::Export {
+                topology,
+                bootstraper,
+                live,
+                format,
+                output,
+            } => export_topology(
+                topology.as_deref(),
+                bootstraper.as_deref(),
+                *live,
+                *format,
+                output.as_deref(),
+            ),
+        };
+        std::process::exit(if succeeded { 0 } else { 1 });
+    }
+
+    let shutdown = Shutdown::on_signals().expect("Error setting signal handler");
+
+    let node = create_node(config)?;
+    node.run(&shutdown)?;
+
     Ok(())
 }
@@ -1,109 +1,423 @@
 use std::{
-    collections::HashMap,
     fs::File,
     io::{BufReader, Read, Write},
-    net::{IpAddr, TcpListener, TcpStream},
-    str::FromStr,
+    net::{IpAddr, SocketAddr},
+    sync::RwLock,
 };
 
-use crate::message::{answer::Answer, query::Query, Status};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, info, warn};
+
+use crate::{
+    admin::{self, Admin, AdminError},
+    message::{
+        answer::Answer,
+        auth::{KeyConfiguration, KeyRing},
+        query::{Query, QueryType},
+        Status,
+    },
+    resolve::Host,
+    runtime::{self, TaskPool},
+    settings,
+    shutdown::Shutdown,
+    transport::{Listener, Stream, Transport},
+};
 
 use super::{
     config::{Configuration, NodeFunction},
+    errors::VideoQueryError,
+    export::{self, Snapshot},
     neighbour::Neighbour,
     std_node::StdNode,
+    topology::{Topology, TopologyError},
     Node, NodeCreationError,
 };
 
+/// What a node receives from the bootstrapper when it joins the overlay.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Bootstrap {
+    pub neighbours: Vec<Neighbour>,
+    pub keys: KeyRing,
+}
+
+/// Reads and validates a topology, its warnings are only printed.
+fn load_topology(path: &str) -> Result<Topology, Box<dyn std::error::Error>> {
+    let file =
+        File::open(path).map_err(|_err| NodeCreationError::InexistentTopology(path.to_string()))?;
+
+    let topology = Topology::from_reader(BufReader::new(file))?;
+    for issue in topology.check().iter().filter(|issue| !issue.is_error()) {
+        warn!("Topology warning: {}", issue);
+    }
+    topology.validate()?;
+
+    Ok(topology)
+}
+
+fn load_keys(path: Option<&str>) -> std::io::Result<KeyConfiguration> {
+    match path {
+        Some(path) => KeyConfiguration::from_file(path),
+        None => Ok(KeyConfiguration::default()),
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BootstraperNode {
     bootstraping_port: u16,
-    topology: HashMap<IpAddr, Vec<Neighbour>>,
+    topology_file: String,
+    keys_file: Option<String>,
+    /// Topology as written, host names included.
+    configured: RwLock<Topology>,
+    /// Topology with its host names looked up, the one handed out to nodes.
+    resolved: RwLock<Topology>,
+    keys: RwLock<KeyConfiguration>,
     std_node: StdNode,
+    admin_port: Option<u16>,
 }
 
 impl BootstraperNode {
-    fn boostraping_service(&self, mut stream: TcpStream) -> std::io::Result<()> {
+    /// Bootstrapper handing out `topology` and `keys`, which are not read from files.
+    pub fn new(
+        bootstraping_port: u16,
+        port: u16,
+        topology: Topology,
+        keys: KeyConfiguration,
+    ) -> Result<Self, TopologyError> {
+        topology.validate()?;
+
+        let node = BootstraperNode {
+            bootstraping_port,
+            std_node: StdNode::new(port, &[]),
+            ..Default::default()
+        };
+        node.apply(topology, keys)?;
+
+        Ok(node)
+    }
+
+    /// Opens every socket of the bootstrapper and its node through `transport`.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.std_node = std::mem::take(&mut self.std_node).with_transport(transport);
+        self
+    }
+
+    /// Starts handing out `topology` and `keys`, the bootstrapper's own node included.
+    fn apply(&self, topology: Topology, keys: KeyConfiguration) -> Result<(), TopologyError> {
+        let resolved = topology.resolve()?;
+
+        *self.keys.write().unwrap() = keys;
+        *self.configured.write().unwrap() = topology;
+        self.hand_out(resolved);
+
+        Ok(())
+    }
+
+    /// Hands out the addresses of `resolved`, to the bootstrapper's own node too.
+    fn hand_out(&self, resolved: Topology) {
+        let neighbours = resolved.neighbours_of(&resolved.bootstrapper);
+        let keys = self.keys.read().unwrap().key_ring_for(
+            &resolved.bootstrapper,
+            &resolved.neighbour_names(&resolved.bootstrapper),
+        );
+
+        self.std_node.authenticator().set_keys(keys);
+        self.std_node.set_neighbours(neighbours);
+
+        *self.resolved.write().unwrap() = resolved;
+    }
+
+    /// Looks the host names of the topology up again every `network.resolve_interval_ms`.
+    ///
+    /// Addresses that can't be looked up keep their previous value.
+    async fn resolve_periodically(&self, shutdown: &Shutdown) {
+        let mut interval = tokio::time::interval(settings::get().network.resolve_interval());
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            let configured = self.configured.read().unwrap().clone();
+            let lookup = configured.clone();
+            let resolved = match tokio::task::spawn_blocking(move || lookup.resolve()).await {
+                Ok(Ok(resolved)) => resolved,
+                Ok(Err(error)) => {
+                    warn!("Keeping the previous addresses of the topology: {}", error);
+                    continue;
+                }
+                Err(error) => {
+                    error!("Error resolving the topology: {}", error);
+                    continue;
+                }
+            };
+
+            // A reload may have replaced the topology while it was looked up
+            if *self.configured.read().unwrap() != configured
+                || *self.resolved.read().unwrap() == resolved
+            {
+                continue;
+            }
+
+            info!("Host names of the topology resolve to new addresses");
+            self.hand_out(resolved);
+        }
+    }
+
+    /// Name in the topology of the node joining from `address`.
+    ///
+    /// Nodes sharing an address with other overlay nodes must give their name.
+    fn identify(&self, node: Option<&str>, address: IpAddr) -> Result<String, VideoQueryError> {
+        let resolved = self.resolved.read().unwrap();
+
+        if let Some(node) = node {
+            return resolved
+                .nodes
+                .get(node)
+                .filter(|spec| {
+                    spec.role.is_overlay() && spec.interfaces.contains(&Host::Ip(address))
+                })
+                .map(|_| node.to_string())
+                .ok_or_else(|| VideoQueryError::UnknownNodeId(node.to_string(), address));
+        }
+
+        match resolved.overlay_nodes_at(address).as_slice() {
+            [] => Err(VideoQueryError::UnknownNode(address)),
+            [node] => Ok(node.to_string()),
+            _ => Err(VideoQueryError::AmbiguousNode(address)),
+        }
+    }
+
+    async fn boostraping_service(&self, mut stream: Stream) -> Result<(), VideoQueryError> {
         let mut buffer = [0; 1024];
 
-        let n = stream.read(&mut buffer)?;
+        let n = runtime::with_timeout(
+            settings::get().network.request_timeout(),
+            stream.read(&mut buffer),
+        )
+        .await?;
+
+        // Connections closed right away only check that the bootstrapper is up
+        if n == 0 {
+            return Ok(());
+        }
+
+        let message: Query = bincode::deserialize(&buffer[..n])
+            .map_err(|_| VideoQueryError::ErrorDeserializingQuery)?;
+
+        if let QueryType::Topology { live } = *message.query_type() {
+            let answer = Answer::from_message(message, self.snapshot(live).await, Status::Ok);
 
-        let message: Query =
-            bincode::deserialize(&buffer[..n]).expect("Error deserializing message");
+            // Sent as JSON, whose optional fields bincode can't skip
+            stream
+                .write_all(&serde_json::to_vec(&answer).expect("Error serializing answer"))
+                .await?;
+
+            return Ok(());
+        }
 
         let ip_client = stream.peer_addr()?.ip();
 
-        let neighbours = self
-            .topology
-            .get(&ip_client)
-            .expect("Error getting neighbours");
+        let node = match message.query_type() {
+            QueryType::Neighbours { node } => node.as_deref(),
+            _ => None,
+        };
+        let node = self.identify(node, ip_client)?;
+        let (neighbours, keys) = {
+            let resolved = self.resolved.read().unwrap();
+            let keys = self
+                .keys
+                .read()
+                .unwrap()
+                .key_ring_for(&node, &resolved.neighbour_names(&node));
+
+            (resolved.neighbours_of(&node), keys)
+        };
+        info!(%node, address = %ip_client, "Node joined the overlay");
 
-        let answer = Answer::from_message(message, neighbours.to_owned(), Status::Ok);
+        // Joining nodes have no keys yet, so this exchange is not authenticated
+        let bootstrap = Bootstrap { neighbours, keys };
+        let answer = Answer::from_message(message, bootstrap, Status::Ok);
 
-        let _ = stream.write(&bincode::serialize(&answer).expect("Error serializing answer"))?;
+        stream
+            .write_all(&bincode::serialize(&answer).expect("Error serializing answer"))
+            .await?;
 
         Ok(())
     }
-}
-
-impl Node for BootstraperNode {
-    fn from_configuration(
-        configuration: Configuration,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        if let NodeFunction::Bootstraper { ref topology, port } = configuration.node_function {
-            let file = File::open(topology)
-                .map_err(|_err| NodeCreationError::InexistentTopology(topology.clone()))?;
-
-            let topology: HashMap<IpAddr, Vec<Neighbour>> =
-                serde_json::from_reader(BufReader::new(file)).unwrap();
 
-            let ip = IpAddr::from_str("0.0.0.0").unwrap();
+    /// The resolved topology, along with what each node reports if `live`.
+    pub async fn snapshot(&self, live: bool) -> Snapshot {
+        let configured = self.resolved.read().unwrap().clone();
 
-            let neighbours = topology
-                .get(&ip)
-                .expect("Error getting my own neighbours")
-                .clone();
+        let live = if live {
+            let mut reports = export::collect(
+                self.std_node.transport(),
+                &configured,
+                self.std_node.authenticator(),
+                settings::get().network.status_timeout(),
+            )
+            .await;
 
-            let std_node = StdNode::new(configuration.port, &neighbours);
+            // The bootstrapper knows its own state, whatever port the topology gives it
+            if let Some(report) = reports.get_mut(&configured.bootstrapper) {
+                report.rtt_ms = Some(0.0);
+                report.status = Some(self.std_node.status().await);
+            }
 
-            Ok(BootstraperNode {
-                bootstraping_port: port,
-                topology,
-                std_node,
-            })
+            Some(reports)
         } else {
-            panic!("Expected a bootstraper node configuration");
+            None
+        };
+
+        Snapshot {
+            topology: configured,
+            live,
         }
     }
 
-    fn run(&self) -> Result<(), NodeCreationError> {
-        std::thread::scope(|s| {
-            //bootstraping thread
-            s.spawn(|| {
-                let socket = TcpListener::bind(("0.0.0.0", self.bootstraping_port))
-                    .expect("Error binding bootstraping socket");
-                println!(
-                    "Bootstraper Node listening at port {}",
-                    self.bootstraping_port
-                );
-
-                for stream in socket.incoming() {
-                    s.spawn(|| {
-                        if let Err(error) = self.boostraping_service(stream.unwrap()) {
-                            println!("Error in bootstraping service {}", error)
+    /// Asks the bootstrapper at `bootstraper_ip` for its snapshot of the overlay.
+    pub fn ask_snapshot(
+        bootstraper_ip: &str,
+        live: bool,
+    ) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let query = Query::new(QueryType::Topology { live }, None);
+
+        let mut stream = std::net::TcpStream::connect(bootstraper_ip)
+            .map_err(NodeCreationError::ErrorConnectingBootstraper)?;
+        stream.set_read_timeout(Some(settings::get().network.request_timeout()))?;
+
+        stream.write_all(&bincode::serialize(&query)?)?;
+
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer)?;
+
+        let mut answer: Answer<Snapshot> = serde_json::from_slice(&buffer)?;
+
+        Ok(std::mem::take(answer.payload_mut()))
+    }
+
+    async fn bootstraping_listener(&self, socket: Listener, shutdown: &Shutdown) {
+        info!(
+            "Bootstraper Node listening at port {}",
+            self.bootstraping_port
+        );
+
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => {}
+                result = socket.accept(), if !tasks.is_full() => {
+                    let stream = match result {
+                        Ok((stream, _)) => stream,
+                        Err(error) => {
+                            error!("Error accepting connection {}", error);
+                            continue;
+                        }
+                    };
+
+                    tasks.push(async move {
+                        if let Err(error) = self.boostraping_service(stream).await {
+                            error!("Error in bootstraping service {}", error)
                         }
                     });
                 }
-            });
+            }
+        }
+
+        tasks.drain().await;
+    }
 
-            //std thread
-            s.spawn(|| self.std_node.run());
-        });
+    /// Runs the bootstraping service and the bootstrapper's node on the current runtime.
+    pub async fn serve(&self, shutdown: &Shutdown) -> Result<(), NodeCreationError> {
+        let socket = self
+            .std_node
+            .transport()
+            .listen(self.bootstraping_port)
+            .await
+            .map_err(NodeCreationError::ErrorBindingSocket)?;
 
-        Ok(())
+        let (_, std_node, _, _) = tokio::join!(
+            self.bootstraping_listener(socket, shutdown),
+            self.std_node.serve(shutdown),
+            self.resolve_periodically(shutdown),
+            admin::serve_on(self, self.admin_port, shutdown),
+        );
+
+        std_node
+    }
+}
+
+impl Node for BootstraperNode {
+    fn from_configuration(
+        configuration: Configuration,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if let NodeFunction::Bootstraper {
+            ref topology,
+            port,
+            ref keys,
+        } = configuration.node_function
+        {
+            let node = BootstraperNode {
+                bootstraping_port: port,
+                topology_file: topology.clone(),
+                keys_file: keys.clone(),
+                // The bootstrapper's metrics are served along with those of its node
+                std_node: StdNode::new(configuration.port, &[])
+                    .with_query_limits(configuration.query_limits())
+                    .with_prometheus_port(configuration.prometheus_port),
+                admin_port: configuration.admin_port,
+                ..Default::default()
+            };
+
+            node.apply(load_topology(topology)?, load_keys(keys.as_deref())?)?;
+
+            Ok(node)
+        } else {
+            Err(NodeCreationError::WrongFunction.into())
+        }
     }
 
-    fn neighbours(&self) -> &[super::neighbour::Neighbour] {
+    fn run(&self, shutdown: &Shutdown) -> Result<(), NodeCreationError> {
+        runtime::block_on(self.serve(shutdown))
+    }
+
+    fn neighbours(&self) -> Vec<Neighbour> {
         return self.std_node.neighbours();
     }
 }
+
+impl Admin for BootstraperNode {
+    async fn status(&self) -> serde_json::Value {
+        json!({
+            "bootstraping_port": self.bootstraping_port,
+            "topology": self.topology_file,
+            "nodes": self.configured.read().unwrap().nodes.len(),
+            "node": Admin::status(&self.std_node).await,
+        })
+    }
+
+    async fn drop_session(&self, file: &str, client: SocketAddr) -> Result<(), AdminError> {
+        self.std_node.drop_session(file, client).await
+    }
+
+    async fn disconnect(&self, neighbour: &Neighbour) -> Result<(), AdminError> {
+        self.std_node.disconnect(neighbour).await
+    }
+
+    /// Reads the topology and keys again, nodes get them when they next join or reload.
+    async fn reload(&self) -> Result<(), AdminError> {
+        let topology = load_topology(&self.topology_file)
+            .map_err(|error| AdminError::Failed(error.to_string()))?;
+        let keys = load_keys(self.keys_file.as_deref())
+            .map_err(|error| AdminError::Failed(error.to_string()))?;
+
+        self.apply(topology, keys)
+            .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+}
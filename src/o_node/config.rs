@@ -1,24 +1,94 @@
 use clap::{Parser, Subcommand};
 
+use crate::settings::{self, SettingsArgs};
+
+use super::{
+    export::ExportFormat,
+    query_guard::{self, QueryLimits},
+};
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Configuration {
     ///Port in which this server will be listening to
+    #[clap(default_value_t = 8554)]
     pub port: u16,
+    /// Queries per second accepted from each source, overrides `queries.rate`
+    #[clap(long, global = true, value_parser = query_guard::parse_rate)]
+    pub query_rate: Option<f64>,
+    /// Queries a source may send at once before being rate limited, overrides `queries.burst`
+    #[clap(long, global = true, value_parser = query_guard::parse_burst)]
+    pub query_burst: Option<f64>,
+    /// Port of the loopback interface to serve admin commands on
+    #[clap(long, global = true)]
+    pub admin_port: Option<u16>,
+    /// Port to serve Prometheus metrics on, under /metrics
+    #[clap(long, global = true)]
+    pub prometheus_port: Option<u16>,
+    /// Which logs to show, such as `debug` or `warn,esr_lib::o_node=trace`, overrides RUST_LOG
+    #[clap(long, global = true)]
+    pub log: Option<String>,
+    #[command(flatten)]
+    pub settings: SettingsArgs,
     #[command(subcommand)]
     pub node_function: NodeFunction,
 }
 
+impl Configuration {
+    pub fn query_limits(&self) -> QueryLimits {
+        let limits = settings::get().queries.limits();
+
+        QueryLimits {
+            rate: self.query_rate.unwrap_or(limits.rate),
+            burst: self.query_burst.unwrap_or(limits.burst),
+            ..limits
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum NodeFunction {
     NonBootstraper {
         /// Node to communicate in order to get neighboors
         bootstraper_ip: String,
+        /// Name of this node in the topology, needed when other nodes share its address
+        #[clap(long)]
+        id: Option<String>,
     },
     Bootstraper {
         /// File containing topology in order to serve as boostraper for other nodes
         port: u16,
         topology: String,
+        /// File with the shared and per node keys handed out to the nodes
+        #[clap(long)]
+        keys: Option<String>,
+    },
+    /// Inspect a topology file without starting a node
+    Topology {
+        #[command(subcommand)]
+        command: TopologyCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TopologyCommand {
+    /// Report the inconsistencies of a topology, fails if any of them is an error
+    Check { topology: String },
+    /// Export a topology file, or the one of a running bootstrapper, as DOT or JSON
+    Export {
+        #[clap(required_unless_present = "bootstraper")]
+        topology: Option<String>,
+        /// Bootstrapper to ask for the topology, as ip:port
+        #[clap(long, conflicts_with = "topology")]
+        bootstraper: Option<String>,
+        /// Include the streams and round trip times reported by each node, needs a bootstrapper
+        #[clap(long)]
+        live: bool,
+        #[clap(short, long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// File to write the export to, standard output if missing
+        #[clap(short, long)]
+        output: Option<String>,
     },
 }
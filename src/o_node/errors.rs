@@ -1,9 +1,37 @@
+use std::net::IpAddr;
+
 use thiserror::Error;
 
+use crate::message::auth::AuthError;
+
 #[derive(Debug, Error)]
 pub enum VideoQueryError {
     #[error("Error deserialing query")]
     ErrorDeserializingQuery,
-    #[error("Error deserialing answer")]
-    ErrorDeserializingAnswer,
+    #[error("Expected a file query")]
+    NotAFileQuery,
+    #[error("Node {0} is not part of the topology")]
+    UnknownNode(IpAddr),
+    #[error("Node {0} is not an overlay node of the topology with address {1}")]
+    UnknownNodeId(String, IpAddr),
+    #[error("Several nodes of the topology have address {0}, the node must give its id")]
+    AmbiguousNode(IpAddr),
+    #[error("Network error: {0}")]
+    Network(#[from] std::io::Error),
+    #[error("Unauthenticated message: {0}")]
+    Unauthenticated(#[from] AuthError),
+}
+
+impl VideoQueryError {
+    /// Label the error is counted under in the exported metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ErrorDeserializingQuery => "malformed_message",
+            Self::NotAFileQuery => "not_a_file_query",
+            Self::UnknownNode(_) | Self::UnknownNodeId(..) => "unknown_node",
+            Self::AmbiguousNode(_) => "ambiguous_node",
+            Self::Network(_) => "network",
+            Self::Unauthenticated(_) => "unauthenticated",
+        }
+    }
 }
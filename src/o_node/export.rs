@@ -0,0 +1,408 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    message::{
+        answer::Answer,
+        auth::Authenticator,
+        query::{Query, QueryType},
+        Message,
+    },
+    resolve::{Endpoint, Host},
+    runtime,
+    transport::Transport,
+};
+
+use super::{
+    neighbour::Neighbour,
+    topology::{Role, Topology},
+};
+
+/// Colours the stream trees of different files are told apart by.
+const STREAM_COLOURS: [&str; 6] = ["red", "blue", "darkgreen", "orange", "purple", "brown"];
+
+/// Streams a node relays and who it knows of, as answered to status queries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub neighbours: Vec<Neighbour>,
+    pub streams: Vec<StreamStatus>,
+}
+
+/// One hop of the tree a file is streamed through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamStatus {
+    pub file: String,
+    pub upstream: Option<SocketAddr>,
+    pub viewers: Vec<SocketAddr>,
+    /// Packets received from upstream by the current forwarding worker.
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// What the bootstrapper learnt about a node, `status` is missing if it did not answer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeReport {
+    pub address: Neighbour,
+    pub rtt_ms: Option<f64>,
+    pub status: Option<NodeStatus>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Dot,
+    Json,
+}
+
+/// The configured topology and, if gathered, the state of each of its nodes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub topology: Topology,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub live: Option<BTreeMap<String, NodeReport>>,
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\\\""))
+}
+
+fn shape(role: Role) -> &'static str {
+    match role {
+        Role::Rp => "hexagon",
+        Role::Server => "box",
+        Role::Relay => "ellipse",
+        Role::Client => "plaintext",
+    }
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / 1_000_000.0
+}
+
+impl Snapshot {
+    pub fn render(&self, format: ExportFormat) -> String {
+        match format {
+            ExportFormat::Dot => self.to_dot(),
+            ExportFormat::Json => self.to_json(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Snapshots are always serializable")
+    }
+
+    /// Graphviz graph of the overlay, with the stream trees drawn over the configured links.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph overlay {\n");
+        let topology = &self.topology;
+
+        let owners: HashMap<IpAddr, &str> = topology
+            .nodes
+            .iter()
+            .flat_map(|(name, node)| {
+                node.interfaces
+                    .iter()
+                    .filter_map(Host::ip)
+                    .map(move |ip| (ip, name.as_str()))
+            })
+            .collect();
+
+        for (name, node) in &topology.nodes {
+            let mut label = format!("{}\\n{}", name, node.role);
+            if let Some(address) = node.interfaces.first() {
+                let _ = write!(label, "\\n{}", Endpoint::new(address.clone(), node.port));
+            }
+
+            let mut attributes = format!("shape={}", shape(node.role));
+            if *name == topology.bootstrapper {
+                attributes.push_str(", peripheries=2");
+            }
+
+            match self.live.as_ref().and_then(|live| live.get(name)) {
+                Some(NodeReport {
+                    rtt_ms: Some(rtt), ..
+                }) => {
+                    let _ = write!(label, "\\nrtt {:.1} ms", rtt);
+                }
+                Some(_) => attributes.push_str(", style=dashed, color=gray"),
+                None => {}
+            }
+
+            let _ = writeln!(
+                dot,
+                "  {} [label={}, {}];",
+                quote(name),
+                quote(&label),
+                attributes
+            );
+        }
+
+        for (name, node) in &topology.nodes {
+            for link in &node.links {
+                let Some(target) = topology.nodes.get(&link.node) else {
+                    continue;
+                };
+                let symmetric = target.links.iter().any(|back| back.node == *name);
+
+                // Symmetric links are drawn once, by the end whose name comes first
+                if symmetric && *name > link.node {
+                    continue;
+                }
+
+                let mut label = format!("cost {}", link.cost);
+                if let Some(bandwidth) = link.bandwidth {
+                    let _ = write!(label, ", {} Mbit/s", bandwidth);
+                }
+
+                let style = if symmetric {
+                    "dir=none"
+                } else {
+                    "style=dashed"
+                };
+                let _ = writeln!(
+                    dot,
+                    "  {} -> {} [{}, label={}];",
+                    quote(name),
+                    quote(&link.node),
+                    style,
+                    quote(&label)
+                );
+            }
+        }
+
+        let Some(live) = &self.live else {
+            dot.push_str("}\n");
+            return dot;
+        };
+
+        let name_of = |address: SocketAddr| {
+            owners
+                .get(&address.ip())
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| address.to_string())
+        };
+
+        let mut files: Vec<&str> = live
+            .values()
+            .filter_map(|report| report.status.as_ref())
+            .flat_map(|status| status.streams.iter().map(|stream| stream.file.as_str()))
+            .collect();
+        files.sort_unstable();
+        files.dedup();
+
+        for (name, report) in live {
+            let Some(status) = &report.status else {
+                continue;
+            };
+
+            for stream in &status.streams {
+                let colour = files
+                    .iter()
+                    .position(|file| *file == stream.file)
+                    .map(|i| STREAM_COLOURS[i % STREAM_COLOURS.len()])
+                    .unwrap_or("black");
+
+                if let Some(upstream) = stream.upstream {
+                    let label = format!(
+                        "{}\\n{} packets, {:.1} MB",
+                        stream.file,
+                        stream.packets,
+                        megabytes(stream.bytes)
+                    );
+                    let _ = writeln!(
+                        dot,
+                        "  {} -> {} [color={}, fontcolor={}, label={}];",
+                        quote(&name_of(upstream)),
+                        quote(name),
+                        colour,
+                        colour,
+                        quote(&label)
+                    );
+                }
+
+                for viewer in &stream.viewers {
+                    let _ = writeln!(
+                        dot,
+                        "  {} -> {} [color={}];",
+                        quote(name),
+                        quote(&name_of(*viewer)),
+                        colour
+                    );
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Asks a node for its status, `None` if it does not answer within `timeout`.
+async fn ask_status(
+    transport: &Transport,
+    address: &Neighbour,
+    auth: &Authenticator,
+    timeout: Duration,
+) -> Option<(NodeStatus, Duration)> {
+    let socket = transport.bind_udp(0).ok()?;
+    let query = auth.seal(&Query::new(QueryType::Status, None));
+    let mut buffer = vec![0; u16::MAX as usize];
+
+    let sent = Instant::now();
+    let (n, from) = runtime::with_timeout(timeout, async {
+        socket.send_to(&query, address.address()).await?;
+        socket.recv_from(&mut buffer).await
+    })
+    .await
+    .map_err(|error| warn!("No status from {:?}: {}", address, error))
+    .ok()?;
+    let rtt = sent.elapsed();
+
+    let answer: Answer<NodeStatus> = auth
+        .open(&buffer[..n], from.ip())
+        .map_err(|error| warn!("Dropping status from {:?}: {}", from, error))
+        .ok()?;
+
+    Some((answer.payload()?.clone(), rtt))
+}
+
+/// Gathers the status of every overlay node of the resolved `topology`, all of them at once.
+pub async fn collect(
+    transport: &Transport,
+    topology: &Topology,
+    auth: &Authenticator,
+    timeout: Duration,
+) -> BTreeMap<String, NodeReport> {
+    let nodes: Vec<(&String, Neighbour)> = topology
+        .nodes
+        .iter()
+        .filter(|(_, node)| node.role.is_overlay())
+        .filter_map(|(name, node)| {
+            let address = node.interfaces.first()?.ip()?;
+            Some((name, Neighbour::new_with_port(address, node.port)))
+        })
+        .collect();
+
+    let statuses = join_all(
+        nodes
+            .iter()
+            .map(|(_, address)| ask_status(transport, address, auth, timeout)),
+    )
+    .await;
+
+    nodes
+        .into_iter()
+        .zip(statuses)
+        .map(|((name, address), status)| {
+            let report = NodeReport {
+                address,
+                rtt_ms: status.as_ref().map(|(_, rtt)| rtt.as_secs_f64() * 1000.0),
+                status: status.map(|(status, _)| status),
+            };
+            (name.clone(), report)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::o_node::{neighbour::Neighbour, topology::Topology};
+
+    use super::{NodeReport, NodeStatus, Snapshot, StreamStatus};
+
+    const TOPOLOGY: &str = r#"{
+        "bootstrapper": "o1",
+        "nodes": {
+            "o1": {
+                "interfaces": ["10.0.0.1"],
+                "links": [{ "node": "rp", "cost": 2, "bandwidth": 100 }, { "node": "c1" }]
+            },
+            "rp": { "role": "rp", "interfaces": ["10.0.0.2"], "links": [{ "node": "o1", "cost": 2, "bandwidth": 100 }] },
+            "c1": { "role": "client", "interfaces": ["10.0.1.20"] }
+        }
+    }"#;
+
+    fn snapshot() -> Snapshot {
+        let topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+
+        let stream = StreamStatus {
+            file: "movie.Mjpeg".to_string(),
+            upstream: Some("10.0.0.2:8554".parse().unwrap()),
+            viewers: vec!["10.0.1.20:5000".parse().unwrap()],
+            packets: 10,
+            bytes: 2_500_000,
+        };
+        let live = BTreeMap::from([
+            (
+                "o1".to_string(),
+                NodeReport {
+                    address: Neighbour::new_with_port("10.0.0.1".parse().unwrap(), 8554),
+                    rtt_ms: Some(1.3),
+                    status: Some(NodeStatus {
+                        neighbours: Vec::new(),
+                        streams: vec![stream],
+                    }),
+                },
+            ),
+            (
+                "rp".to_string(),
+                NodeReport {
+                    address: Neighbour::new_with_port("10.0.0.2".parse().unwrap(), 8554),
+                    rtt_ms: None,
+                    status: None,
+                },
+            ),
+        ]);
+
+        Snapshot {
+            topology,
+            live: Some(live),
+        }
+    }
+
+    #[test]
+    fn dot_marks_roles_links_and_streams() {
+        let dot = snapshot().to_dot();
+
+        assert!(dot.starts_with("digraph overlay {"));
+        assert!(dot.contains(
+            r#""o1" [label="o1\nrelay\n10.0.0.1:8554\nrtt 1.3 ms", shape=ellipse, peripheries=2];"#
+        ));
+        assert!(dot.contains(
+            r#""rp" [label="rp\nrp\n10.0.0.2:8554", shape=hexagon, style=dashed, color=gray];"#
+        ));
+        // The link to the client is only listed by o1
+        assert!(dot.contains(r#""o1" -> "rp" [dir=none, label="cost 2, 100 Mbit/s"];"#));
+        assert!(dot.contains(r#""o1" -> "c1" [style=dashed, label="cost 1"];"#));
+        assert!(!dot.contains(r#""rp" -> "o1" [dir=none"#));
+        assert!(dot.contains(
+            r#""rp" -> "o1" [color=red, fontcolor=red, label="movie.Mjpeg\n10 packets, 2.5 MB"];"#
+        ));
+        assert!(dot.contains(r#""o1" -> "c1" [color=red];"#));
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let snapshot = snapshot();
+        let json = snapshot.to_json();
+
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), snapshot);
+
+        let configured = Snapshot {
+            live: None,
+            ..snapshot
+        };
+        assert!(!configured.to_json().contains("\"live\""));
+    }
+}
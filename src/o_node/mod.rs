@@ -1,14 +1,19 @@
 pub mod bootstraper_node;
 pub mod config;
 mod errors;
+pub mod export;
 pub mod neighbour;
+pub mod query_guard;
 pub mod std_node;
+pub mod topology;
 
 use std::fmt::Debug;
 
 use config::{Configuration, NodeFunction};
 use thiserror::Error;
 
+use crate::shutdown::Shutdown;
+
 use self::{bootstraper_node::BootstraperNode, neighbour::Neighbour, std_node::StdNode};
 
 #[derive(Debug, Error)]
@@ -21,6 +26,10 @@ pub enum NodeCreationError {
     ErrorConnectingBootstraper(std::io::Error),
     #[error("Error deserializing the ip addresses")]
     ErrorDeserializingIpAddresses(bincode::Error),
+    #[error("The bootstrapper answered without a configuration")]
+    EmptyBootstrap,
+    #[error("The configuration is for another kind of node")]
+    WrongFunction,
 }
 
 pub fn create_node(
@@ -32,6 +41,7 @@ pub fn create_node(
         NodeFunction::NonBootstraper { .. } => {
             StdNode::from_configuration(configuration).map(|node| Box::new(node) as Box<dyn Node>)
         }
+        NodeFunction::Topology { .. } => Err("Topology commands do not create a node".into()),
     }
 }
 
@@ -40,7 +50,8 @@ pub trait Node: Debug {
     where
         Self: Sized;
 
-    fn neighbours(&self) -> &[Neighbour];
+    fn neighbours(&self) -> Vec<Neighbour>;
 
-    fn run(&self) -> Result<(), NodeCreationError>;
+    /// Runs the node until `shutdown` is triggered.
+    fn run(&self, shutdown: &Shutdown) -> Result<(), NodeCreationError>;
 }
@@ -1,10 +1,13 @@
 use std::{
+    fmt,
     net::{IpAddr, SocketAddr},
     str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
 
+use crate::settings;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Neighbour {
     host: IpAddr,
@@ -23,33 +26,39 @@ impl From<SocketAddr> for Neighbour {
 impl FromStr for Neighbour {
     type Err = String;
 
+    /// Parses `ip`, `ip:port` or `[ipv6]:port`, IPv6 addresses need brackets to be given a port.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(':').collect();
+        let s = s.trim();
 
-        if parts.len() > 2 || parts.is_empty() {
-            return Err("Invalid input Format. Expected: ip_addr, port".to_string());
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(address.into());
         }
 
-        let ip_addr = IpAddr::from_str(parts[0].trim()).map_err(|err| err.to_string())?;
-
-        let port = parts
-            .get(1)
-            .unwrap_or(&"8000")
-            .parse::<u16>()
-            .map_err(|e| e.to_string())?;
+        let host = s
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(s);
 
-        Ok(Neighbour {
-            host: ip_addr,
-            port,
+        IpAddr::from_str(host).map(Self::new).map_err(|_| {
+            format!(
+                "Invalid address {}. Expected: ip_addr, ip_addr:port or [ipv6_addr]:port",
+                s
+            )
         })
     }
 }
 
+impl fmt::Display for Neighbour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", SocketAddr::new(self.host, self.port))
+    }
+}
+
 impl Neighbour {
     pub fn new(ip_address: IpAddr) -> Self {
         Self {
             host: ip_address,
-            port: 8000,
+            port: settings::get().network.default_port,
         }
     }
 
@@ -64,3 +73,44 @@ impl Neighbour {
         (self.host, self.port)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::settings;
+
+    use super::Neighbour;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn addresses_of_both_ip_versions_are_parsed() {
+        let default_port = settings::get().network.default_port;
+        let cases = [
+            ("10.0.0.1:9000", ip("10.0.0.1"), 9000),
+            (" 10.0.0.1 ", ip("10.0.0.1"), default_port),
+            ("[::1]:9000", ip("::1"), 9000),
+            ("[fe80::1]", ip("fe80::1"), default_port),
+            ("2001:db8::1", ip("2001:db8::1"), default_port),
+        ];
+
+        for (input, host, port) in cases {
+            let neighbour: Neighbour = input.parse().unwrap();
+            assert_eq!(neighbour.address(), (host, port), "{}", input);
+        }
+
+        assert!("10.0.0.1:port".parse::<Neighbour>().is_err());
+        assert!("[::1:9000".parse::<Neighbour>().is_err());
+    }
+
+    #[test]
+    fn neighbours_are_written_as_they_are_parsed() {
+        for input in ["10.0.0.1:9000", "[::1]:9000", "[2001:db8::1]:8554"] {
+            let neighbour: Neighbour = input.parse().unwrap();
+            assert_eq!(neighbour.to_string(), input);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::message::{query::Query, Message};
+
+/// How many queries a node accepts and for how long it remembers them.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLimits {
+    /// Queries per second accepted from each source.
+    pub rate: f64,
+    /// Queries a source may send at once before being limited to `rate`.
+    pub burst: f64,
+    /// How long a query id is remembered, copies arriving within it are not forwarded.
+    pub duplicate_ttl: Duration,
+}
+
+/// Limits a token bucket can't enforce.
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum QueryLimitsError {
+    #[error("Invalid query rate {0}, expected more than 0 queries per second")]
+    Rate(f64),
+    #[error("Invalid query burst {0}, expected at least 1 query")]
+    Burst(f64),
+}
+
+impl QueryLimits {
+    /// Checks that sources get tokens back and can hold at least one.
+    pub fn validate(&self) -> Result<(), QueryLimitsError> {
+        if !self.rate.is_finite() || self.rate <= 0.0 {
+            return Err(QueryLimitsError::Rate(self.rate));
+        }
+        if !self.burst.is_finite() || self.burst < 1.0 {
+            return Err(QueryLimitsError::Burst(self.burst));
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a `--query-rate` flag.
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate = value.parse::<f64>().map_err(|error| error.to_string())?;
+    QueryLimits {
+        rate,
+        ..Default::default()
+    }
+    .validate()
+    .map_err(|error| error.to_string())?;
+
+    Ok(rate)
+}
+
+/// Reads a `--query-burst` flag.
+pub fn parse_burst(value: &str) -> Result<f64, String> {
+    let burst = value.parse::<f64>().map_err(|error| error.to_string())?;
+    QueryLimits {
+        burst,
+        ..Default::default()
+    }
+    .validate()
+    .map_err(|error| error.to_string())?;
+
+    Ok(burst)
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            rate: 100.0,
+            burst: 200.0,
+            duplicate_ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Whether a query may be forwarded, and why not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryVerdict {
+    Accept,
+    Duplicate,
+    HopLimit,
+}
+
+impl fmt::Display for QueryVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accept => write!(f, "accepted"),
+            Self::Duplicate => write!(f, "already seen"),
+            Self::HopLimit => write!(f, "hop limit reached"),
+        }
+    }
+}
+
+/// Number of queries dropped or answered without being forwarded, by reason.
+#[derive(Debug, Default)]
+pub struct QueryCounters {
+    duplicates: AtomicU64,
+    hop_limited: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+impl QueryCounters {
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    pub fn hop_limited(&self) -> u64 {
+        self.hop_limited.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+}
+
+impl Serialize for QueryCounters {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut counters = serializer.serialize_struct("QueryCounters", 3)?;
+        counters.serialize_field("duplicates", &self.duplicates())?;
+        counters.serialize_field("hop_limited", &self.hop_limited())?;
+        counters.serialize_field("rate_limited", &self.rate_limited())?;
+        counters.end()
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limits: &QueryLimits, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * limits.rate).min(limits.burst);
+        self.updated = now;
+    }
+}
+
+/// Ids of the queries received recently and when they were received.
+#[derive(Debug)]
+struct DuplicateCache {
+    ids: HashMap<u32, Instant>,
+    purged: Instant,
+}
+
+impl Default for DuplicateCache {
+    fn default() -> Self {
+        Self {
+            ids: HashMap::new(),
+            purged: Instant::now(),
+        }
+    }
+}
+
+/// Protects a node from query storms.
+///
+/// Sources are rate limited with a token bucket each, and queries already
+/// seen or out of hops are answered without being forwarded again, which
+/// keeps loops in the topology from amplifying them.
+#[derive(Debug)]
+pub struct QueryGuard {
+    limits: ArcSwap<QueryLimits>,
+    seen: Mutex<DuplicateCache>,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    counters: QueryCounters,
+}
+
+impl Default for QueryGuard {
+    fn default() -> Self {
+        Self::new(QueryLimits::default())
+    }
+}
+
+impl QueryGuard {
+    pub fn new(limits: QueryLimits) -> Self {
+        Self {
+            limits: ArcSwap::from_pointee(limits),
+            seen: Mutex::default(),
+            buckets: Mutex::default(),
+            counters: QueryCounters::default(),
+        }
+    }
+
+    pub fn limits(&self) -> QueryLimits {
+        **self.limits.load()
+    }
+
+    /// Replaces the limits, sources keep the tokens they have left.
+    pub fn set_limits(&self, limits: QueryLimits) {
+        self.limits.store(Arc::new(limits));
+    }
+
+    pub fn counters(&self) -> &QueryCounters {
+        &self.counters
+    }
+
+    /// Takes a token from `source`, queries from sources out of tokens are dropped.
+    pub fn allow(&self, source: IpAddr) -> bool {
+        let now = Instant::now();
+        let limits = self.limits();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets that refilled completely are no different from new ones
+        if buckets.len() > 1024 {
+            let full =
+                Duration::try_from_secs_f64(limits.burst / limits.rate).unwrap_or(Duration::MAX);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < full);
+        }
+
+        let bucket = buckets.entry(source).or_insert(TokenBucket {
+            tokens: limits.burst,
+            updated: now,
+        });
+        bucket.refill(&limits, now);
+
+        if bucket.tokens < 1.0 {
+            self.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Decides whether `query` may be forwarded, remembering it if so.
+    pub fn check(&self, query: &Query) -> QueryVerdict {
+        if query.hops_left() == 0 {
+            self.counters.hop_limited.fetch_add(1, Ordering::Relaxed);
+            return QueryVerdict::HopLimit;
+        }
+
+        let now = Instant::now();
+        let ttl = self.limits().duplicate_ttl;
+        let mut seen = self.seen.lock().unwrap();
+
+        if now.duration_since(seen.purged) > ttl {
+            seen.ids
+                .retain(|_, received| now.duration_since(*received) < ttl);
+            seen.purged = now;
+        }
+
+        match seen.ids.get(&query.id()) {
+            Some(received) if now.duration_since(*received) < ttl => {
+                self.counters.duplicates.fetch_add(1, Ordering::Relaxed);
+                QueryVerdict::Duplicate
+            }
+            _ => {
+                seen.ids.insert(query.id(), now);
+                QueryVerdict::Accept
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use crate::message::query::Query;
+
+    use super::{parse_burst, parse_rate, QueryGuard, QueryLimits, QueryLimitsError, QueryVerdict};
+
+    const SOURCE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn sources_are_rate_limited() {
+        let guard = QueryGuard::new(QueryLimits {
+            rate: 10.0,
+            burst: 5.0,
+            ..Default::default()
+        });
+
+        let accepted = (0..20).filter(|_| guard.allow(SOURCE)).count();
+        assert_eq!(accepted, 5);
+        assert_eq!(guard.counters().rate_limited(), 15);
+
+        // Other sources have their own bucket
+        assert!(guard.allow("10.0.0.2".parse().unwrap()));
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(guard.allow(SOURCE));
+    }
+
+    #[test]
+    fn limits_without_a_rate_or_a_burst_are_rejected() {
+        let limits = QueryLimits {
+            rate: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(limits.validate(), Err(QueryLimitsError::Rate(0.0)));
+        assert!(QueryLimits {
+            burst: 0.5,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("many").is_err());
+        assert!(parse_burst("0").is_err());
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        assert_eq!(parse_burst("1"), Ok(1.0));
+
+        // Guards given them anyway never refill, and forget no source
+        let guard = QueryGuard::new(QueryLimits {
+            burst: 1.0,
+            ..limits
+        });
+        for n in 0..=255u8 {
+            for m in 0..=4u8 {
+                assert!(guard.allow(IpAddr::V4(std::net::Ipv4Addr::new(10, 1, m, n))));
+            }
+        }
+        assert!(!guard.allow("10.1.0.0".parse().unwrap()));
+    }
+
+    #[test]
+    fn duplicates_are_not_forwarded_until_forgotten() {
+        let guard = QueryGuard::new(QueryLimits {
+            duplicate_ttl: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let query = Query::new_file_query("movie.Mjpeg", None);
+
+        assert_eq!(guard.check(&query), QueryVerdict::Accept);
+        assert_eq!(guard.check(&query.clone()), QueryVerdict::Duplicate);
+        assert_eq!(
+            guard.check(&Query::new_file_query("movie.Mjpeg", None)),
+            QueryVerdict::Accept
+        );
+        assert_eq!(guard.counters().duplicates(), 1);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(guard.check(&query), QueryVerdict::Accept);
+    }
+
+    #[test]
+    fn queries_out_of_hops_are_not_forwarded() {
+        let guard = QueryGuard::default();
+        let mut query = Query::new_file_query("movie.Mjpeg", None).with_hop_limit(1);
+
+        assert!(query.hop());
+        assert!(!query.hop());
+        assert_eq!(guard.check(&query), QueryVerdict::HopLimit);
+        assert_eq!(guard.counters().hop_limited(), 1);
+    }
+}
@@ -1,23 +1,45 @@
 use std::{
     collections::HashMap,
     io::{Read, Write},
-    net::{SocketAddr, TcpStream, UdpSocket},
-    str::FromStr,
-    sync::Mutex,
-    time::Duration,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
+use serde_json::json;
+
+use tokio::sync::Mutex;
+
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
 use crate::{
-    message::{answer::Answer, query::Query, query::QueryType, Message, Status},
-    o_node::{errors::VideoQueryError, NodeCreationError},
+    admin::{self, Admin, AdminError},
+    message::{
+        answer::Answer,
+        auth::Authenticator,
+        query::{Query, QueryType},
+        Message, Status,
+    },
+    o_node::{
+        errors::VideoQueryError,
+        query_guard::{QueryGuard, QueryLimits, QueryVerdict},
+        NodeCreationError,
+    },
+    runtime::{self, TaskPool},
     server::{
-        server_worker::streaming_intermediate_worker::StreamingWorker,
+        edge_cache::EdgeCache, server_worker::streaming_intermediate_worker::StreamingWorker,
         transmission_channel::TransmissionChannel,
     },
+    settings,
+    shutdown::Shutdown,
+    telemetry,
+    transport::{DatagramSocket, Transport},
 };
 
 use super::{
+    bootstraper_node::Bootstrap,
     config::{Configuration, NodeFunction},
+    export::NodeStatus,
     neighbour::Neighbour,
     Node,
 };
@@ -25,25 +47,96 @@ use super::{
 #[derive(Debug, Default)]
 pub struct StdNode {
     port: u16,
-    neighbours: Vec<Neighbour>,
+    neighbours: RwLock<Vec<Neighbour>>,
     streaming_workers: Mutex<HashMap<String, TransmissionChannel>>,
+    cache: Arc<EdgeCache>,
+    auth: Authenticator,
+    guard: QueryGuard,
+    bootstraper_ip: Option<String>,
+    node_id: Option<String>,
+    admin_port: Option<u16>,
+    prometheus_port: Option<u16>,
+    transport: Transport,
 }
 
 impl StdNode {
     pub fn new(port: u16, neighbours: &[Neighbour]) -> Self {
         Self {
             port,
-            neighbours: neighbours.to_owned(),
+            neighbours: RwLock::new(neighbours.to_owned()),
             ..Default::default()
         }
     }
 
+    pub fn with_authenticator(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn authenticator(&self) -> &Authenticator {
+        &self.auth
+    }
+
+    pub fn with_query_limits(mut self, limits: QueryLimits) -> Self {
+        self.guard = QueryGuard::new(limits);
+        self
+    }
+
+    pub fn query_guard(&self) -> &QueryGuard {
+        &self.guard
+    }
+
+    /// Replaces the neighbours queries are forwarded to.
+    pub fn set_neighbours(&self, neighbours: Vec<Neighbour>) {
+        info!("My neighbours {:?}", neighbours);
+        *self.neighbours.write().unwrap() = neighbours;
+    }
+
+    /// Bootstrapper the neighbours and keys are fetched again from on reload.
+    pub fn with_bootstraper(mut self, bootstraper_ip: Option<String>) -> Self {
+        self.bootstraper_ip = bootstraper_ip;
+        self
+    }
+
+    /// Name the node joins the overlay with on reload.
+    pub fn with_node_id(mut self, node_id: Option<String>) -> Self {
+        self.node_id = node_id;
+        self
+    }
+
+    /// Serves admin commands on `port` of the loopback interface.
+    pub fn with_admin_port(mut self, port: Option<u16>) -> Self {
+        self.admin_port = port;
+        self
+    }
+
+    /// Serves the metrics of the whole process on `port`.
+    pub fn with_prometheus_port(mut self, port: Option<u16>) -> Self {
+        self.prometheus_port = port;
+        self
+    }
+
+    /// Opens every socket of the node, and of its relays, through `transport`.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// Joins the overlay as the node named `node_id` in the topology, or as the node with this address.
     pub fn ask_neighbours(
+        transport: &Transport,
         bootstraper_ip: String,
-    ) -> Result<Answer<Vec<Neighbour>>, Box<dyn std::error::Error>> {
-        let query = Query::new(QueryType::Neighbours, None);
+        node_id: Option<String>,
+    ) -> Result<Answer<Bootstrap>, Box<dyn std::error::Error>> {
+        let query = Query::new(QueryType::Neighbours { node: node_id }, None);
 
-        let mut stream = TcpStream::connect(bootstraper_ip)
+        let mut stream = settings::get()
+            .retry
+            .retry_blocking(|| transport.connect_blocking(bootstraper_ip.as_str()))
             .map_err(NodeCreationError::ErrorConnectingBootstraper)?;
 
         stream
@@ -54,171 +147,398 @@ impl StdNode {
 
         let n = stream.read(&mut buffer)?;
 
-        let answer: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n])
+        let answer: Answer<Bootstrap> = bincode::deserialize(&buffer[..n])
             .map_err(NodeCreationError::ErrorDeserializingIpAddresses)?;
 
         Ok(answer)
     }
 
-    fn find_best_path(
+    async fn find_best_path(
         &self,
         message: &mut Query,
     ) -> Result<(Answer<Vec<Neighbour>>, SocketAddr), VideoQueryError> {
+        let started = Instant::now();
+        let file_query = message
+            .query_type()
+            .file_query()
+            .ok_or(VideoQueryError::NotAFileQuery)?;
+
         let neighbours: Vec<Neighbour> = self
             .neighbours
+            .read()
+            .unwrap()
             .iter()
-            .filter(|neighbour| {
-                !message
-                    .query_type()
-                    .file_query()
-                    .unwrap()
-                    .visited_neighbour(neighbour)
-            })
+            .filter(|neighbour| !file_query.visited_neighbour(neighbour))
             .cloned()
             .collect();
 
-        let data = message.query_type_mut().file_query_mut().unwrap();
+        let data = message
+            .query_type_mut()
+            .file_query_mut()
+            .ok_or(VideoQueryError::NotAFileQuery)?;
 
         data.add_neighbours(&neighbours);
+        message.hop();
 
         let message_clone = message.clone();
-        println!("Sending message to neighbours {:?}", message_clone);
+        debug!("Sending message to neighbours {:?}", message_clone);
 
-        let message_encode = bincode::serialize(&message_clone)
-            .map_err(|_| VideoQueryError::ErrorDeserializingAnswer)?;
+        let message_encode = self.auth.seal(&message_clone);
 
         drop(message_clone);
 
-        let query_socket = UdpSocket::bind(("0.0.0.0", 0)).unwrap();
+        let query_socket = self.transport.bind_udp(0)?;
+        let sent = Instant::now();
 
         for neighbour in &neighbours {
             let neighbour_addr = neighbour.address();
 
-            query_socket
-                .send_to(&message_encode, neighbour_addr)
-                .unwrap();
-            println!("Sent to {:?}", neighbour_addr);
+            match query_socket.send_to(&message_encode, neighbour_addr).await {
+                Ok(_) => debug!(neighbour = ?neighbour_addr, "Sent query"),
+                Err(error) => error!(neighbour = ?neighbour_addr, %error, "Error sending query"),
+            }
         }
 
         let mut buffer = [0; 1024];
         let mut count = 0;
-        query_socket
-            .set_read_timeout(Duration::from_secs(1).into())
-            .unwrap();
+        let query_timeout = settings::get().network.query_timeout();
 
         while count < neighbours.len() {
-            let answer = query_socket.recv_from(&mut buffer);
-            if answer.is_err() {
-                count += 1;
-                continue;
-            }
-            let (n, addr) = answer.unwrap();
-            let message: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n]).unwrap();
+            count += 1;
+
+            let answer =
+                runtime::with_timeout(query_timeout, query_socket.recv_from(&mut buffer)).await;
+
+            let (n, addr) = match answer {
+                Ok(answer) => answer,
+                Err(_) => continue,
+            };
+
+            let message: Answer<Vec<Neighbour>> = match self.auth.open(&buffer[..n], addr.ip()) {
+                Ok(message) => message,
+                Err(error) => {
+                    let error = VideoQueryError::from(error);
+                    telemetry::count_error(error.kind());
+                    warn!("Dropping answer from {:?}: {}", addr, error);
+                    continue;
+                }
+            };
+
+            // Answers come after the neighbour searched its own part of the overlay,
+            // so this is an upper bound of the round trip time
+            telemetry::registry()
+                .gauge(
+                    "esr_neighbour_rtt_seconds",
+                    "Time the last query forwarded to each neighbour took to be answered",
+                    &[("neighbour", &addr.to_string())],
+                )
+                .set(sent.elapsed().as_secs_f64());
 
             if message.status().is_ok() {
-                println!("Received message from {:?}", addr);
+                debug!(from = %addr, "Received answer");
+                telemetry::query_answered("found", started.elapsed());
                 return Ok((message, addr));
             }
-            count += 1;
         }
 
-        query_socket.set_read_timeout(None).unwrap();
-
+        telemetry::query_answered("not_found", started.elapsed());
         Ok((
             Answer::from_message(message.clone(), Vec::new(), Status::VideoNotFound),
-            SocketAddr::from_str("0.0.0.0:0").expect("Error parsing address"),
+            SocketAddr::from(([0, 0, 0, 0], 0)),
         ))
     }
 
-    fn handle_video_request(
+    async fn handle_video_request(
         &self,
-        socket: &UdpSocket,
+        socket: &DatagramSocket,
         mut message: Query,
         addr: SocketAddr,
     ) -> Result<(), VideoQueryError> {
-        let transmits_file = self
-            .streaming_workers
-            .lock()
-            .expect("Error acquiring lock")
-            .contains_key(message.query_type().file_query().unwrap().file());
+        let file = message.query_file().ok_or(VideoQueryError::NotAFileQuery)?;
+
+        // Whole titles in the cache are streamed from it
+        let transmits_file =
+            self.streaming_workers.lock().await.contains_key(file) || self.cache.contains(file);
 
         let answer = if transmits_file {
+            telemetry::query_answered("local", Duration::ZERO);
             let answer = Answer::<Vec<Neighbour>>::from_message(message, Vec::new(), Status::Ok);
 
-            bincode::serialize(&answer).map_err(|_| VideoQueryError::ErrorDeserializingQuery)?
+            self.auth.seal(&answer)
         } else {
-            let (mut selected_answer, server_addr) = self.find_best_path(&mut message)?;
-            if selected_answer.status().is_ok() {
-                println!("Selected node to stream {:?}", server_addr);
+            match self.guard.check(&message) {
+                QueryVerdict::Accept => {
+                    let (mut selected_answer, server_addr) =
+                        self.find_best_path(&mut message).await?;
+                    if selected_answer.status().is_ok() {
+                        info!(node = %server_addr, "Selected node to stream");
+
+                        selected_answer
+                            .payload_mut()
+                            .push(Neighbour::from(server_addr));
+                    }
+
+                    self.auth.seal(&selected_answer)
+                }
+                verdict => {
+                    // Answering right away spares the sender from waiting for a timeout
+                    warn!(%verdict, "Not forwarding query");
+                    telemetry::query_answered("rejected", Duration::ZERO);
+                    let answer = Answer::<Vec<Neighbour>>::from_message(
+                        message,
+                        Vec::new(),
+                        Status::VideoNotFound,
+                    );
 
-                selected_answer
-                    .payload_mut()
-                    .push(Neighbour::from(server_addr));
+                    self.auth.seal(&answer)
+                }
             }
-
-            bincode::serialize(&selected_answer)
-                .map_err(|_| VideoQueryError::ErrorDeserializingQuery)?
         };
 
-        let _ = socket.send_to(&answer, addr).unwrap();
+        socket.send_to(&answer, addr).await?;
 
         Ok(())
     }
-}
 
-impl Node for StdNode {
-    fn from_configuration(configuration: Configuration) -> Result<Self, Box<dyn std::error::Error>>
-    where
-        Self: Sized,
-    {
-        if let NodeFunction::NonBootstraper { bootstraper_ip } = configuration.node_function {
-            let answer = StdNode::ask_neighbours(bootstraper_ip)?;
+    /// Streams relayed by this node, for the bootstrapper to draw the overlay.
+    pub async fn status(&self) -> NodeStatus {
+        let neighbours = self.neighbours();
+
+        NodeStatus {
+            neighbours,
+            streams: self
+                .streaming_workers
+                .lock()
+                .await
+                .values()
+                .map(TransmissionChannel::status)
+                .collect(),
+        }
+    }
 
-            let neighbours = answer.payload().expect("Expected payload");
-            println!("My neighbours {:?}", neighbours);
+    async fn handle_status_request(
+        &self,
+        socket: &DatagramSocket,
+        message: Query,
+        addr: SocketAddr,
+    ) -> Result<(), VideoQueryError> {
+        let answer = Answer::from_message(message, self.status().await, Status::Ok);
 
-            Ok(StdNode::new(configuration.port, neighbours))
-        } else {
-            panic!("Expected a non bootstraper node configuration");
+        socket.send_to(&self.auth.seal(&answer), addr).await?;
+
+        Ok(())
+    }
+
+    /// Answers video and status queries, at most `network.max_concurrent_tasks` of them at a time.
+    async fn query_service(&self, socket: DatagramSocket, shutdown: &Shutdown) {
+        info!("Standard Node listening at port {}", self.port);
+
+        let mut buffer = [0; 1024];
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => {}
+                result = socket.recv_from(&mut buffer), if !tasks.is_full() => {
+                    let (size, addr) = match result {
+                        Ok(result) => result,
+                        Err(error) => {
+                            error!("Error receing message {}", error);
+                            continue;
+                        }
+                    };
+
+                    // Checked before authenticating, which is the expensive part of a query
+                    if !self.guard.allow(addr.ip()) {
+                        continue;
+                    }
+
+                    let message: Query = match self.auth.open(&buffer[..size], addr.ip()) {
+                        Ok(message) => message,
+                        Err(error) => {
+                            let error = VideoQueryError::from(error);
+                            telemetry::count_error(error.kind());
+                            warn!("Dropping message from {:?}: {}", addr, error);
+                            continue;
+                        }
+                    };
+
+                    let socket = &socket;
+                    let span = info_span!("query", id = message.id(), from = %addr);
+                    tasks.push(async move {
+                        let result = match message.query_type() {
+                            QueryType::Status => self.handle_status_request(socket, message, addr).await,
+                            _ => self.handle_video_request(socket, message, addr).await,
+                        };
+
+                        match result {
+                            Ok(_) => debug!("Message handled sucessfully"),
+                            Err(error) => {
+                                telemetry::count_error(error.kind());
+                                error!("Error handling the message {}", error)
+                            }
+                        }
+                    }.instrument(span));
+                }
+            }
         }
+
+        tasks.drain().await;
+        info!("Standard Node stopped listening at port {}", self.port);
     }
 
-    fn run(&self) -> Result<(), NodeCreationError> {
-        let socket = UdpSocket::bind(("0.0.0.0", self.port))
+    /// Runs the query and streaming services on the current runtime.
+    pub async fn serve(&self, shutdown: &Shutdown) -> Result<(), NodeCreationError> {
+        let socket = self
+            .transport
+            .bind_udp(self.port)
+            .map_err(NodeCreationError::ErrorBindingSocket)?;
+        let listener = self
+            .transport
+            .listen(self.port)
+            .await
             .map_err(NodeCreationError::ErrorBindingSocket)?;
 
-        std::thread::scope(|s| {
-            println!("Standard Node listening at port {}", self.port);
-            s.spawn(|| loop {
-                let mut buffer = [0; 1024];
-
-                let result = socket.recv_from(&mut buffer);
-
-                if let Ok((size, addr)) = result {
-                    let message: Query = bincode::deserialize(&buffer[..size])
-                        .map_err(|_| VideoQueryError::ErrorDeserializingQuery)
-                        .expect("Error deserializing message");
-                    let socket_ref = &socket;
-                    s.spawn(
-                        move || match self.handle_video_request(socket_ref, message, addr) {
-                            Ok(_) => println!("Message handled sucessfully"),
-                            Err(error) => eprintln!("Error handling the message {}", error),
-                        },
-                    );
-                } else {
-                    eprintln!("Error receing message, no bytes provided");
-                }
-            });
+        let streaming_worker = StreamingWorker::new(
+            self.port,
+            &self.streaming_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .with_cache(&self.cache);
+
+        tokio::join!(
+            self.query_service(socket, shutdown),
+            streaming_worker.run(listener, shutdown),
+            admin::serve_on(self, self.admin_port, shutdown),
+            telemetry::serve_on(self.prometheus_port, shutdown),
+        );
+
+        Ok(())
+    }
+}
+
+impl Admin for StdNode {
+    async fn status(&self) -> serde_json::Value {
+        json!({
+            "port": self.port,
+            "node": self.status().await,
+            "queries": self.guard.counters(),
+            "cache": self.cache.status(),
+            "rejected_messages": self.auth.rejected(),
+        })
+    }
+
+    async fn drop_session(&self, file: &str, client: SocketAddr) -> Result<(), AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.streaming_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .with_cache(&self.cache)
+        .drop_session(file, client)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+
+    async fn record(&self, file: &str, recording: &str) -> Result<(), AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.streaming_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .start_recording(file, recording)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+
+    async fn stop_recording(&self, file: &str) -> Result<String, AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.streaming_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .stop_recording(file)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))?
+        .ok_or_else(|| AdminError::NotFound(format!("Recording of {}", file)))
+    }
+
+    async fn disconnect(&self, neighbour: &Neighbour) -> Result<(), AdminError> {
+        let mut neighbours = self.neighbours.write().unwrap();
+        let count = neighbours.len();
+
+        neighbours.retain(|n| n != neighbour);
+
+        if neighbours.len() == count {
+            return Err(AdminError::NotFound(format!("Neighbour {:?}", neighbour)));
+        }
+        Ok(())
+    }
+
+    /// Asks the bootstrapper for the neighbours and keys again.
+    async fn reload(&self) -> Result<(), AdminError> {
+        let bootstraper_ip = self
+            .bootstraper_ip
+            .clone()
+            .ok_or_else(|| AdminError::Failed("No bootstrapper to reload from".to_string()))?;
+
+        let transport = self.transport.clone();
+        let node_id = self.node_id.clone();
+        let bootstrap = tokio::task::spawn_blocking(move || {
+            StdNode::ask_neighbours(&transport, bootstraper_ip, node_id)
+                .map_err(|error| error.to_string())
+        })
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))?
+        .map_err(|error| AdminError::Failed(format!("Error asking the bootstrapper: {}", error)))?;
+
+        let bootstrap = bootstrap.payload().cloned().unwrap_or_default();
 
-            s.spawn(|| {
-                StreamingWorker::new(self.port, &self.streaming_workers).run();
-            });
-        });
+        self.set_neighbours(bootstrap.neighbours);
+        self.auth.set_keys(bootstrap.keys);
 
         Ok(())
     }
+}
+
+impl Node for StdNode {
+    fn from_configuration(configuration: Configuration) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        Self: Sized,
+    {
+        if let NodeFunction::NonBootstraper {
+            ref bootstraper_ip,
+            ref id,
+        } = configuration.node_function
+        {
+            let answer =
+                StdNode::ask_neighbours(&Transport::Os, bootstraper_ip.clone(), id.clone())?;
+
+            let bootstrap = answer.payload().ok_or(NodeCreationError::EmptyBootstrap)?;
+            info!("My neighbours {:?}", bootstrap.neighbours);
+
+            Ok(StdNode::new(configuration.port, &bootstrap.neighbours)
+                .with_authenticator(Authenticator::new(bootstrap.keys.clone()))
+                .with_query_limits(configuration.query_limits())
+                .with_bootstraper(Some(bootstraper_ip.clone()))
+                .with_node_id(id.clone())
+                .with_admin_port(configuration.admin_port)
+                .with_prometheus_port(configuration.prometheus_port))
+        } else {
+            Err(NodeCreationError::WrongFunction.into())
+        }
+    }
+
+    fn run(&self, shutdown: &Shutdown) -> Result<(), NodeCreationError> {
+        runtime::block_on(self.serve(shutdown))
+    }
 
-    fn neighbours(&self) -> &[Neighbour] {
-        &self.neighbours
+    fn neighbours(&self) -> Vec<Neighbour> {
+        self.neighbours.read().unwrap().clone()
     }
 }
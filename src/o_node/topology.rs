@@ -0,0 +1,694 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    net::IpAddr,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::resolve::{Endpoint, Host, ResolveError};
+
+use super::neighbour::Neighbour;
+
+/// Name the bootstrapper is given in topologies written in the old format, unless they use `"::"`.
+const LEGACY_BOOTSTRAPPER: &str = "0.0.0.0";
+
+#[derive(Debug, Error)]
+pub enum TopologyError {
+    #[error("Error reading the topology: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Error parsing the topology: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid topology: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    Invalid(Vec<TopologyIssue>),
+    #[error("{0}")]
+    Resolve(#[from] ResolveError),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Rp,
+    Server,
+    #[default]
+    Relay,
+    Client,
+}
+
+impl Role {
+    /// Whether nodes with this role answer queries, and so are handed out as neighbours.
+    pub fn is_overlay(&self) -> bool {
+        matches!(self, Self::Rp | Self::Relay)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rp => write!(f, "rp"),
+            Self::Server => write!(f, "server"),
+            Self::Relay => write!(f, "relay"),
+            Self::Client => write!(f, "client"),
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    8554
+}
+
+fn default_cost() -> u32 {
+    1
+}
+
+/// One end of a link, as seen from the node that lists it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    /// Name of the node at the other end.
+    pub node: String,
+    /// Interface of `node` the link reaches, its first one if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<Host>,
+    #[serde(default = "default_cost")]
+    pub cost: u32,
+    /// Capacity of the link in Mbit/s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeSpec {
+    #[serde(default)]
+    pub role: Role,
+    /// Addresses or host names of the node, the first one is used unless a link says otherwise.
+    pub interfaces: Vec<Host>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+/// Problem found in a topology, only errors keep the bootstrapper from starting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopologyIssue {
+    UnknownBootstrapper(String),
+    NoInterfaces(String),
+    /// Two nodes listen on the same host and port.
+    SharedInterface {
+        address: Endpoint,
+        nodes: (String, String),
+    },
+    SelfLink(String),
+    UnknownNode {
+        from: String,
+        to: String,
+    },
+    UnknownInterface {
+        from: String,
+        to: String,
+        address: Host,
+    },
+    DuplicateLink {
+        from: String,
+        to: String,
+    },
+    Asymmetric {
+        from: String,
+        to: String,
+    },
+    MismatchedLink {
+        from: String,
+        to: String,
+    },
+}
+
+impl TopologyIssue {
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            Self::DuplicateLink { .. } | Self::Asymmetric { .. } | Self::MismatchedLink { .. }
+        )
+    }
+}
+
+impl fmt::Display for TopologyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownBootstrapper(name) => write!(f, "bootstrapper {} is not a node", name),
+            Self::NoInterfaces(name) => write!(f, "{} has no interfaces", name),
+            Self::SharedInterface { address, nodes } => {
+                write!(
+                    f,
+                    "{} is an interface of both {} and {}",
+                    address, nodes.0, nodes.1
+                )
+            }
+            Self::SelfLink(name) => write!(f, "{} is linked to itself", name),
+            Self::UnknownNode { from, to } => {
+                write!(f, "{} is linked to unknown node {}", from, to)
+            }
+            Self::UnknownInterface { from, to, address } => {
+                write!(
+                    f,
+                    "{} reaches {} through {}, which is not one of its interfaces",
+                    from, to, address
+                )
+            }
+            Self::DuplicateLink { from, to } => write!(f, "{} lists {} more than once", from, to),
+            Self::Asymmetric { from, to } => write!(
+                f,
+                "{} is linked to {} but not the other way around",
+                from, to
+            ),
+            Self::MismatchedLink { from, to } => {
+                write!(
+                    f,
+                    "{} and {} disagree on the cost or bandwidth of their link",
+                    from, to
+                )
+            }
+        }
+    }
+}
+
+/// Nodes of the overlay, their roles and how they are linked.
+///
+/// Older topologies, which map the address of each node to its neighbours and
+/// name the bootstrapper `"0.0.0.0"` or `"::"`, are read as relays named after their address.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    pub bootstrapper: String,
+    pub nodes: BTreeMap<String, NodeSpec>,
+}
+
+impl Topology {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TopologyError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self, TopologyError> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+
+        if value.get("nodes").is_some() {
+            Ok(serde_json::from_value(value)?)
+        } else {
+            Ok(Self::from_legacy(serde_json::from_value(value)?))
+        }
+    }
+
+    fn from_legacy(neighbours: HashMap<IpAddr, Vec<Neighbour>>) -> Self {
+        let bootstrapper = neighbours
+            .keys()
+            .find(|address| address.is_unspecified())
+            .map_or(LEGACY_BOOTSTRAPPER.to_string(), IpAddr::to_string);
+
+        let mut nodes: BTreeMap<String, NodeSpec> = neighbours
+            .keys()
+            .map(|address| {
+                let node = NodeSpec {
+                    role: Role::Relay,
+                    interfaces: vec![Host::Ip(*address)],
+                    port: default_port(),
+                    links: Vec::new(),
+                };
+                (address.to_string(), node)
+            })
+            .collect();
+
+        for (address, neighbours) in neighbours {
+            for neighbour in neighbours {
+                let (host, port) = neighbour.address();
+
+                // Neighbours that never join through the bootstrapper are only known by their address
+                let target = nodes.entry(host.to_string()).or_insert(NodeSpec {
+                    role: Role::Relay,
+                    interfaces: vec![Host::Ip(host)],
+                    port,
+                    links: Vec::new(),
+                });
+                target.port = port;
+
+                nodes
+                    .get_mut(&address.to_string())
+                    .expect("Every address has a node")
+                    .links
+                    .push(Link {
+                        node: host.to_string(),
+                        interface: None,
+                        cost: default_cost(),
+                        bandwidth: None,
+                    });
+            }
+        }
+
+        Self {
+            bootstrapper,
+            nodes,
+        }
+    }
+
+    /// Every inconsistency in the topology, errors and warnings alike.
+    pub fn check(&self) -> Vec<TopologyIssue> {
+        let mut issues = Vec::new();
+
+        if !self.nodes.contains_key(&self.bootstrapper) {
+            issues.push(TopologyIssue::UnknownBootstrapper(
+                self.bootstrapper.clone(),
+            ));
+        }
+
+        // Nodes are told apart by their port too, so several may share a host
+        let mut owners: HashMap<Endpoint, &str> = HashMap::new();
+        for (name, node) in &self.nodes {
+            if node.interfaces.is_empty() {
+                issues.push(TopologyIssue::NoInterfaces(name.clone()));
+            }
+
+            for host in &node.interfaces {
+                let address = Endpoint::new(host.clone(), node.port);
+                match owners.insert(address.clone(), name) {
+                    Some(owner) if owner != name => issues.push(TopologyIssue::SharedInterface {
+                        address,
+                        nodes: (owner.to_string(), name.clone()),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+
+        for (name, node) in &self.nodes {
+            for (i, link) in node.links.iter().enumerate() {
+                let from = name.clone();
+                let to = link.node.clone();
+
+                if link.node == *name {
+                    issues.push(TopologyIssue::SelfLink(from));
+                    continue;
+                }
+
+                if node.links[..i].iter().any(|other| other.node == link.node) {
+                    issues.push(TopologyIssue::DuplicateLink { from, to });
+                    continue;
+                }
+
+                let Some(target) = self.nodes.get(&link.node) else {
+                    issues.push(TopologyIssue::UnknownNode { from, to });
+                    continue;
+                };
+
+                if let Some(address) = &link.interface {
+                    if !target.interfaces.contains(address) {
+                        issues.push(TopologyIssue::UnknownInterface {
+                            from: from.clone(),
+                            to: to.clone(),
+                            address: address.clone(),
+                        });
+                    }
+                }
+
+                match target.links.iter().find(|back| back.node == *name) {
+                    None => issues.push(TopologyIssue::Asymmetric { from, to }),
+                    // Reported once, by the end whose name comes first
+                    Some(back) if *name < link.node => {
+                        if back.cost != link.cost || back.bandwidth != link.bandwidth {
+                            issues.push(TopologyIssue::MismatchedLink { from, to });
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        issues
+    }
+
+    /// Fails with the errors found by `check`, if any.
+    pub fn validate(&self) -> Result<(), TopologyError> {
+        let errors: Vec<TopologyIssue> = self
+            .check()
+            .into_iter()
+            .filter(TopologyIssue::is_error)
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(TopologyError::Invalid(errors))
+        }
+    }
+
+    /// Same topology with the host names replaced by every address they resolve to.
+    ///
+    /// A link through a named interface reaches the first address of the name.
+    pub fn resolve(&self) -> Result<Topology, ResolveError> {
+        let mut resolved = self.clone();
+        // Several nodes and links usually name the same host
+        let mut lookups: HashMap<Host, Vec<IpAddr>> = HashMap::new();
+        let mut lookup = |host: &Host| -> Result<Vec<IpAddr>, ResolveError> {
+            if let Some(addresses) = lookups.get(host) {
+                return Ok(addresses.clone());
+            }
+            let addresses = host.resolve()?;
+            lookups.insert(host.clone(), addresses.clone());
+            Ok(addresses)
+        };
+
+        for node in resolved.nodes.values_mut() {
+            let mut interfaces = Vec::new();
+            for host in &node.interfaces {
+                for address in lookup(host)? {
+                    if !interfaces.contains(&Host::Ip(address)) {
+                        interfaces.push(Host::Ip(address));
+                    }
+                }
+            }
+            node.interfaces = interfaces;
+
+            for link in &mut node.links {
+                if let Some(host) = &link.interface {
+                    link.interface = lookup(host)?.first().copied().map(Host::Ip);
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Neighbours handed out to `name`, only nodes that answer queries are included.
+    ///
+    /// Interfaces given by name are skipped, the topology must be resolved first.
+    pub fn neighbours_of(&self, name: &str) -> Vec<Neighbour> {
+        let Some(node) = self.nodes.get(name) else {
+            return Vec::new();
+        };
+
+        node.links
+            .iter()
+            .filter_map(|link| {
+                let target = self.nodes.get(&link.node)?;
+                let address = link
+                    .interface
+                    .as_ref()
+                    .or(target.interfaces.first())
+                    .and_then(Host::ip)?;
+
+                target
+                    .role
+                    .is_overlay()
+                    .then(|| Neighbour::new_with_port(address, target.port))
+            })
+            .collect()
+    }
+
+    /// Names of the overlay nodes `name` links to, whose keys it is handed.
+    pub fn neighbour_names(&self, name: &str) -> Vec<&str> {
+        let Some(node) = self.nodes.get(name) else {
+            return Vec::new();
+        };
+
+        node.links
+            .iter()
+            .filter(|link| {
+                self.nodes
+                    .get(&link.node)
+                    .is_some_and(|target| target.role.is_overlay())
+            })
+            .map(|link| link.node.as_str())
+            .collect()
+    }
+
+    /// Neighbours of the overlay nodes, by the addresses they may join from.
+    pub fn neighbour_map(&self) -> HashMap<IpAddr, Vec<Neighbour>> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.role.is_overlay())
+            .flat_map(|(name, node)| {
+                let neighbours = self.neighbours_of(name);
+                node.interfaces
+                    .iter()
+                    .filter_map(Host::ip)
+                    .map(move |address| (address, neighbours.clone()))
+            })
+            .collect()
+    }
+
+    /// Overlay nodes with `address` as one of their interfaces.
+    pub fn overlay_nodes_at(&self, address: IpAddr) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| {
+                node.role.is_overlay() && node.interfaces.contains(&Host::Ip(address))
+            })
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Same topology with every node on the `loopback` address, each on its own port.
+    ///
+    /// Ports are given in the order of the node names, starting from `first_port`.
+    pub fn on_loopback(&self, loopback: IpAddr, first_port: u16) -> Topology {
+        let mut topology = self.clone();
+
+        for (port, node) in (first_port..=u16::MAX).zip(topology.nodes.values_mut()) {
+            node.interfaces = vec![Host::Ip(loopback)];
+            node.port = port;
+
+            for link in &mut node.links {
+                link.interface = None;
+            }
+        }
+
+        topology
+    }
+
+    /// Address the bootstrapper's own keys are found under.
+    pub fn bootstrapper_address(&self) -> Option<IpAddr> {
+        self.nodes
+            .get(&self.bootstrapper)
+            .and_then(|node| node.interfaces.first())
+            .and_then(Host::ip)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::{
+        o_node::neighbour::Neighbour,
+        resolve::{Host, ResolveError},
+    };
+
+    use super::{Topology, TopologyError, TopologyIssue};
+
+    const TOPOLOGY: &str = r#"{
+        "bootstrapper": "o1",
+        "nodes": {
+            "o1": {
+                "interfaces": ["10.0.0.1", "10.0.1.1"],
+                "links": [
+                    { "node": "o2", "cost": 2, "bandwidth": 100 },
+                    { "node": "rp", "interface": "10.0.2.2" },
+                    { "node": "c1" }
+                ]
+            },
+            "o2": {
+                "interfaces": ["10.0.0.2"],
+                "links": [{ "node": "o1", "cost": 2, "bandwidth": 100 }]
+            },
+            "rp": {
+                "role": "rp",
+                "interfaces": ["10.0.3.2", "10.0.2.2"],
+                "port": 8555,
+                "links": [{ "node": "o1" }, { "node": "s1" }]
+            },
+            "s1": { "role": "server", "interfaces": ["10.0.4.2"], "links": [{ "node": "rp" }] },
+            "c1": { "role": "client", "interfaces": ["10.0.1.20"], "links": [{ "node": "o1" }] }
+        }
+    }"#;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn host(host: &str) -> Host {
+        host.parse().unwrap()
+    }
+
+    #[test]
+    fn neighbours_are_overlay_nodes_on_every_interface() {
+        let topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+        assert!(topology.check().is_empty());
+
+        let expected = vec![
+            Neighbour::new_with_port(ip("10.0.0.2"), 8554),
+            Neighbour::new_with_port(ip("10.0.2.2"), 8555),
+        ];
+        let neighbours = topology.neighbour_map();
+        assert_eq!(neighbours[&ip("10.0.0.1")], expected);
+        assert_eq!(neighbours[&ip("10.0.1.1")], expected);
+
+        // Servers and clients never join the overlay
+        assert!(!neighbours.contains_key(&ip("10.0.4.2")));
+        assert!(!neighbours.contains_key(&ip("10.0.1.20")));
+        assert_eq!(topology.bootstrapper_address(), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn inconsistencies_are_reported() {
+        let mut topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+        let o2 = topology.nodes.get_mut("o2").unwrap();
+        o2.links[0].cost = 3;
+        o2.interfaces.push(host("10.0.1.1"));
+        // Nodes on different ports may share an interface
+        topology
+            .nodes
+            .get_mut("rp")
+            .unwrap()
+            .interfaces
+            .push(host("10.0.0.1"));
+        topology.nodes.get_mut("s1").unwrap().links[0].node = "s2".to_string();
+        topology.nodes.get_mut("c1").unwrap().links[0].interface = Some(host("10.0.9.9"));
+        topology.nodes.get_mut("rp").unwrap().links.pop();
+
+        let issues = topology.check();
+        assert!(issues.contains(&TopologyIssue::MismatchedLink {
+            from: "o1".to_string(),
+            to: "o2".to_string()
+        }));
+        assert!(issues.contains(&TopologyIssue::SharedInterface {
+            address: "10.0.1.1:8554".parse().unwrap(),
+            nodes: ("o1".to_string(), "o2".to_string())
+        }));
+        assert!(issues.contains(&TopologyIssue::UnknownNode {
+            from: "s1".to_string(),
+            to: "s2".to_string()
+        }));
+        assert!(issues.contains(&TopologyIssue::UnknownInterface {
+            from: "c1".to_string(),
+            to: "o1".to_string(),
+            address: host("10.0.9.9")
+        }));
+        assert_eq!(issues.len(), 4);
+
+        match topology.validate() {
+            Err(TopologyError::Invalid(errors)) => assert_eq!(errors.len(), 3),
+            other => panic!("Expected an invalid topology, got {:?}", other),
+        }
+
+        topology.bootstrapper = "o3".to_string();
+        assert!(topology
+            .check()
+            .contains(&TopologyIssue::UnknownBootstrapper("o3".to_string())));
+    }
+
+    #[test]
+    fn loopback_topologies_tell_nodes_apart_by_port() {
+        for loopback in [ip("127.0.0.1"), ip("::1")] {
+            let topology = Topology::from_reader(TOPOLOGY.as_bytes())
+                .unwrap()
+                .on_loopback(loopback, 9000);
+            assert!(topology.check().is_empty());
+
+            let ports: Vec<u16> = topology.nodes.values().map(|node| node.port).collect();
+            assert_eq!(ports, vec![9000, 9001, 9002, 9003, 9004]);
+            assert!(topology
+                .nodes
+                .values()
+                .all(|node| node.interfaces == vec![Host::Ip(loopback)]));
+
+            // Clients and servers never join, so they don't make the address ambiguous
+            assert_eq!(topology.overlay_nodes_at(loopback), vec!["o1", "o2", "rp"]);
+            assert_eq!(
+                topology.neighbours_of("o1"),
+                vec![
+                    Neighbour::new_with_port(loopback, 9002),
+                    Neighbour::new_with_port(loopback, 9003),
+                ]
+            );
+            assert_eq!(topology.neighbour_names("o1"), vec!["o2", "rp"]);
+        }
+    }
+
+    #[test]
+    fn host_names_resolve_to_every_address() {
+        let mut topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+        let o2 = topology.nodes.get_mut("o2").unwrap();
+        o2.interfaces = vec![host("localhost")];
+        o2.links[0].interface = Some(host("o1.invalid"));
+        topology
+            .nodes
+            .get_mut("o1")
+            .unwrap()
+            .interfaces
+            .push(host("o1.invalid"));
+        assert!(topology.check().is_empty());
+
+        // Names are only looked up once resolved
+        assert_eq!(
+            topology.neighbours_of("o1"),
+            vec![Neighbour::new_with_port(ip("10.0.2.2"), 8555)]
+        );
+        assert!(matches!(
+            topology.resolve(),
+            Err(ResolveError::Lookup { name, .. }) if name == "o1.invalid"
+        ));
+
+        topology.nodes.get_mut("o1").unwrap().interfaces.pop();
+        topology.nodes.get_mut("o2").unwrap().links[0].interface = None;
+        let resolved = topology.resolve().unwrap();
+
+        let interfaces = &resolved.nodes["o2"].interfaces;
+        assert!(!interfaces.is_empty());
+        assert!(interfaces
+            .iter()
+            .all(|interface| interface.ip().is_some_and(|ip| ip.is_loopback())));
+        assert_eq!(
+            resolved.neighbours_of("o1")[0],
+            Neighbour::new_with_port(interfaces[0].ip().unwrap(), 8554)
+        );
+        assert_eq!(resolved.nodes["o1"], topology.nodes["o1"]);
+    }
+
+    #[test]
+    fn legacy_topologies_are_still_read() {
+        let legacy = r#"{
+            "0.0.0.0": [{ "host": "10.0.2.1", "port": 8554 }],
+            "10.0.2.1": [{ "host": "10.0.5.10", "port": 8555 }]
+        }"#;
+        let topology = Topology::from_reader(legacy.as_bytes()).unwrap();
+
+        assert_eq!(topology.bootstrapper_address(), Some(ip("0.0.0.0")));
+        assert_eq!(
+            topology.neighbour_map()[&ip("10.0.2.1")],
+            vec![Neighbour::new_with_port(ip("10.0.5.10"), 8555)]
+        );
+        assert!(topology.validate().is_ok());
+        assert!(topology.check().contains(&TopologyIssue::Asymmetric {
+            from: "10.0.2.1".to_string(),
+            to: "10.0.5.10".to_string()
+        }));
+    }
+
+    #[test]
+    fn legacy_topologies_may_use_ipv6() {
+        let legacy = r#"{
+            "::": [{ "host": "fd00::1", "port": 8554 }],
+            "fd00::1": [{ "host": "::", "port": 8554 }, { "host": "fd00::2", "port": 8554 }],
+            "fd00::2": [{ "host": "fd00::1", "port": 8554 }]
+        }"#;
+        let topology = Topology::from_reader(legacy.as_bytes()).unwrap();
+
+        assert_eq!(topology.bootstrapper, "::");
+        assert_eq!(topology.bootstrapper_address(), Some(ip("::")));
+        assert_eq!(
+            topology.neighbours_of("fd00::2"),
+            vec![Neighbour::new_with_port(ip("fd00::1"), 8554)]
+        );
+        assert!(topology.check().is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Hosts given by name in the configuration, and their resolution to addresses.
+//!
+//! Names are looked up through the system resolver, so entries of `/etc/hosts`
+//! work, and every address a name has is kept, IPv4 and IPv6 alike. Names are
+//! looked up each time they are resolved, components that use them resolve them
+//! again every `network.resolve_interval_ms`.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{o_node::neighbour::Neighbour, settings};
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("{0:?} is neither an address nor a host name")]
+    InvalidHost(String),
+    #[error("Invalid port in {0:?}")]
+    InvalidPort(String),
+    #[error("Error resolving {name}: {source}")]
+    Lookup { name: String, source: io::Error },
+    #[error("{0} does not resolve to any address")]
+    NoAddresses(String),
+}
+
+/// Host of a node or server, by address or by name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl Host {
+    /// Address of the host, if it was not given by name.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ip(ip) => Some(*ip),
+            Self::Name(_) => None,
+        }
+    }
+
+    /// Every address of the host, in the order the resolver prefers them.
+    pub fn resolve(&self) -> Result<Vec<IpAddr>, ResolveError> {
+        let name = match self {
+            Self::Ip(ip) => return Ok(vec![*ip]),
+            Self::Name(name) => name,
+        };
+
+        let addresses =
+            (name.as_str(), 0)
+                .to_socket_addrs()
+                .map_err(|source| ResolveError::Lookup {
+                    name: name.clone(),
+                    source,
+                })?;
+
+        let mut ips: Vec<IpAddr> = Vec::new();
+        for address in addresses {
+            if !ips.contains(&address.ip()) {
+                ips.push(address.ip());
+            }
+        }
+
+        if ips.is_empty() {
+            return Err(ResolveError::NoAddresses(name.clone()));
+        }
+
+        Ok(ips)
+    }
+
+    /// Same as `resolve`, without blocking the runtime while the resolver answers.
+    pub async fn lookup(&self) -> Result<Vec<IpAddr>, ResolveError> {
+        if let Self::Ip(ip) = self {
+            return Ok(vec![*ip]);
+        }
+
+        let host = self.clone();
+        tokio::task::spawn_blocking(move || host.resolve())
+            .await
+            .map_err(|error| ResolveError::Lookup {
+                name: self.to_string(),
+                source: io::Error::other(error),
+            })?
+    }
+}
+
+/// Whether `name` may be looked up, following the rules of DNS labels.
+fn is_host_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+impl FromStr for Host {
+    type Err = ResolveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Ok(ip) = IpAddr::from_str(s) {
+            return Ok(Self::Ip(ip));
+        }
+
+        if is_host_name(s) {
+            Ok(Self::Name(s.to_ascii_lowercase()))
+        } else {
+            Err(ResolveError::InvalidHost(s.to_string()))
+        }
+    }
+}
+
+impl TryFrom<String> for Host {
+    type Error = ResolveError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Host> for String {
+    fn from(value: Host) -> Self {
+        value.to_string()
+    }
+}
+
+impl From<IpAddr> for Host {
+    fn from(value: IpAddr) -> Self {
+        Self::Ip(value)
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{}", ip),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Host and port of a server or neighbour as given in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    host: Host,
+    port: u16,
+}
+
+impl Endpoint {
+    pub fn new(host: Host, port: u16) -> Self {
+        Self { host, port }
+    }
+
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Every address the endpoint can be reached at, see `Host::lookup`.
+    pub async fn lookup(&self) -> Result<Vec<SocketAddr>, ResolveError> {
+        Ok(self
+            .host
+            .lookup()
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, self.port))
+            .collect())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ResolveError;
+
+    /// Parses `host` or `host:port`, IPv6 addresses need brackets to be given a port.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(Self::new(Host::Ip(address.ip()), address.port()));
+        }
+
+        let default_port = settings::get().network.default_port;
+        if let Some(bracketed) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            return Ok(Self::new(bracketed.parse()?, default_port));
+        }
+
+        // A single colon separates the port, IPv6 addresses have several
+        match s.split_once(':') {
+            Some((host, port)) if !port.contains(':') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| ResolveError::InvalidPort(s.to_string()))?;
+                Ok(Self::new(host.parse()?, port))
+            }
+            _ => Ok(Self::new(s.parse()?, default_port)),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host {
+            Host::Ip(ip) => write!(f, "{}", SocketAddr::new(ip, self.port)),
+            Host::Name(ref name) => write!(f, "{}:{}", name, self.port),
+        }
+    }
+}
+
+impl From<Neighbour> for Endpoint {
+    fn from(value: Neighbour) -> Self {
+        let (ip, port) = value.address();
+        Self::new(Host::Ip(ip), port)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::settings;
+
+    use super::{Endpoint, Host, ResolveError};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn endpoints_are_parsed_by_address_or_name() {
+        let default_port = settings::get().network.default_port;
+        let cases = [
+            ("10.0.0.1:9000", Host::Ip(ip("10.0.0.1")), 9000),
+            ("[::1]:9000", Host::Ip(ip("::1")), 9000),
+            ("2001:db8::1", Host::Ip(ip("2001:db8::1")), default_port),
+            (
+                "Server-1.local:9000",
+                Host::Name("server-1.local".into()),
+                9000,
+            ),
+            ("rp", Host::Name("rp".into()), default_port),
+        ];
+
+        for (input, host, port) in cases {
+            let endpoint: Endpoint = input.parse().unwrap();
+            assert_eq!(endpoint, Endpoint::new(host, port), "{}", input);
+        }
+
+        assert!(matches!(
+            "rp:port".parse::<Endpoint>(),
+            Err(ResolveError::InvalidPort(_))
+        ));
+        for input in ["", "-rp", "two words", "rp..local"] {
+            assert!(
+                matches!(input.parse::<Host>(), Err(ResolveError::InvalidHost(_))),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn names_resolve_through_the_system_resolver() {
+        let addresses = Host::Name("localhost".into()).resolve().unwrap();
+        assert!(addresses.iter().all(IpAddr::is_loopback));
+        assert!(!addresses.is_empty());
+
+        assert_eq!(
+            Host::Ip(ip("10.0.0.1")).resolve().unwrap(),
+            vec![ip("10.0.0.1")]
+        );
+        assert!(matches!(
+            Host::Name("no-such-host.invalid".into()).resolve(),
+            Err(ResolveError::Lookup { .. })
+        ));
+    }
+}
@@ -1,12 +1,25 @@
 use clap::Parser;
-use esr_lib::server::rp::{RPArgs, RP};
+use esr_lib::{
+    logging,
+    message::auth::Authenticator,
+    server::{
+        errors::StreamingError,
+        rp::{RPArgs, RP},
+    },
+    shutdown::Shutdown,
+};
 
+fn main() -> Result<(), StreamingError> {
+    let args = RPArgs::parse();
 
-fn main() {
+    logging::init(args.log_filter()).expect("Invalid log filter");
+    args.settings().init().expect("Error reading the settings");
 
-    let args = RPArgs::parse();
-    
-    let rp = RP::new(args);
-    
-    rp.run();
-}
\ No newline at end of file
+    let shutdown = Shutdown::on_signals().expect("Error setting signal handler");
+
+    let auth = Authenticator::from_key_file(args.key_file()).expect("Error reading the key file");
+
+    let rp = RP::new(args).with_authenticator(auth);
+
+    rp.run(&shutdown)
+}
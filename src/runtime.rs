@@ -0,0 +1,62 @@
+use std::{future::Future, time::Duration};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+
+/// Runs `future` to completion on a new multi threaded runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Error creating the async runtime")
+        .block_on(future)
+}
+
+/// Fails with `TimedOut` if `future` does not complete within `timeout`.
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Request timed out"))?
+}
+
+/// Bounded set of tasks polled by the service that spawned them.
+///
+/// Tasks may borrow from the service, which lets handlers keep using `&self`
+/// instead of moving the service state behind an `Arc`.
+pub struct TaskPool<F> {
+    tasks: FuturesUnordered<F>,
+    limit: usize,
+}
+
+impl<F: Future<Output = ()>> TaskPool<F> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            tasks: FuturesUnordered::new(),
+            limit,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tasks.len() >= self.limit
+    }
+
+    pub fn push(&mut self, task: F) {
+        self.tasks.push(task);
+    }
+
+    /// Drives the tasks until one of them finishes, never completes if there are none.
+    pub async fn next(&mut self) {
+        if self.tasks.is_empty() {
+            std::future::pending::<()>().await;
+        }
+
+        self.tasks.next().await;
+    }
+
+    /// Waits for every task still running.
+    pub async fn drain(mut self) {
+        while self.tasks.next().await.is_some() {}
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use crate::{
+    message::{credentials::Credentials, rtsp::Status},
+    telemetry::{self, Gauge},
+};
+
+/// Pattern that matches every file.
+const ANY_FILE: &str = "*";
+
+fn active_sessions() -> Arc<Gauge> {
+    telemetry::registry().gauge(
+        "esr_active_sessions",
+        "Viewer sessions open on this server",
+        &[],
+    )
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AccessError {
+    #[error("Missing or invalid credentials")]
+    Unauthorized,
+    #[error("{user} is not allowed to watch {file}")]
+    Forbidden { user: String, file: String },
+    #[error("{0} reached the maximum number of concurrent sessions")]
+    TooManySessions(String),
+    #[error("{user} is not allowed to record {file}")]
+    RecordingForbidden { user: String, file: String },
+}
+
+impl AccessError {
+    /// RTSP status the viewer is answered with.
+    pub fn status(&self) -> Status {
+        match self {
+            Self::Unauthorized => Status::Unauthorized,
+            Self::Forbidden { .. } | Self::TooManySessions(_) | Self::RecordingForbidden { .. } => {
+                Status::Forbidden
+            }
+        }
+    }
+}
+
+/// Files a user or group may watch, whether it may record them too and how
+/// many sessions it may have open at once.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Grant {
+    #[serde(default)]
+    files: Vec<String>,
+    #[serde(default)]
+    record: bool,
+    #[serde(default)]
+    max_sessions: Option<usize>,
+}
+
+impl Grant {
+    fn allows(&self, file: &str) -> bool {
+        self.files.iter().any(|f| f == ANY_FILE || f == file)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    token: String,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(flatten)]
+    grant: Grant,
+}
+
+/// Users, their groups and what each of them may watch, as read from the policy file.
+///
+/// A user may watch the files granted to it or to any of its groups, and record
+/// those of a grant with `record` set. Its own session limit wins over the ones
+/// of its groups, of which the largest applies.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    users: HashMap<String, User>,
+    #[serde(default)]
+    groups: HashMap<String, Grant>,
+}
+
+impl AccessPolicy {
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+
+    /// Grants of the user `credentials` belong to, its own one first.
+    fn grants(&self, credentials: &Credentials) -> Result<Vec<&Grant>, AccessError> {
+        let user = self
+            .users
+            .get(credentials.user())
+            // Compared in constant time so that the time taken tells nothing about the token
+            .filter(|user| bool::from(user.token.as_bytes().ct_eq(credentials.token().as_bytes())))
+            .ok_or(AccessError::Unauthorized)?;
+
+        Ok(std::iter::once(&user.grant)
+            .chain(
+                user.groups
+                    .iter()
+                    .filter_map(|group| self.groups.get(group)),
+            )
+            .collect())
+    }
+
+    /// Checks that `credentials` may watch `file`, returns the user's session limit.
+    pub fn authorize(
+        &self,
+        credentials: Option<&Credentials>,
+        file: &str,
+    ) -> Result<Option<usize>, AccessError> {
+        let credentials = credentials.ok_or(AccessError::Unauthorized)?;
+        let grants = self.grants(credentials)?;
+        let (user, groups) = (grants[0], &grants[1..]);
+
+        if !grants.iter().any(|grant| grant.allows(file)) {
+            return Err(AccessError::Forbidden {
+                user: credentials.user().to_string(),
+                file: file.to_string(),
+            });
+        }
+
+        let group_limit = groups.iter().filter_map(|group| group.max_sessions).max();
+
+        Ok(user.max_sessions.or(group_limit))
+    }
+
+    /// Checks that `credentials` may record `file`, which a grant must allow along with watching it.
+    pub fn authorize_recording(
+        &self,
+        credentials: Option<&Credentials>,
+        file: &str,
+    ) -> Result<(), AccessError> {
+        let credentials = credentials.ok_or(AccessError::Unauthorized)?;
+        let grants = self.grants(credentials)?;
+
+        if !grants
+            .iter()
+            .any(|grant| grant.record && grant.allows(file))
+        {
+            return Err(AccessError::RecordingForbidden {
+                user: credentials.user().to_string(),
+                file: file.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Enforces the access policy of a content server and counts each user's open sessions.
+///
+/// Without a policy every viewer may watch every file.
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    policy: RwLock<Option<AccessPolicy>>,
+    sessions: Mutex<HashMap<String, usize>>,
+}
+
+impl AccessControl {
+    pub fn new(policy: Option<AccessPolicy>) -> Self {
+        Self {
+            policy: RwLock::new(policy),
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the policy, sessions already open are kept.
+    pub fn set_policy(&self, policy: Option<AccessPolicy>) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    /// Opens a session for the viewer, it counts towards the user's limit until dropped.
+    pub fn open_session(
+        &self,
+        credentials: Option<&Credentials>,
+        file: &str,
+    ) -> Result<Session<'_>, AccessError> {
+        let policy = self.policy.read().unwrap();
+        let Some(policy) = policy.as_ref() else {
+            return Ok(Session::new(self, None));
+        };
+
+        let max_sessions = policy.authorize(credentials, file)?;
+        let user = credentials
+            .map(|c| c.user().to_string())
+            .unwrap_or_default();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let open = sessions.entry(user.clone()).or_default();
+
+        if max_sessions.is_some_and(|max| *open >= max) {
+            return Err(AccessError::TooManySessions(user));
+        }
+        *open += 1;
+
+        Ok(Session::new(self, Some(user)))
+    }
+
+    /// Checks that the viewer may record `file`, anyone may without a policy.
+    pub fn authorize_recording(
+        &self,
+        credentials: Option<&Credentials>,
+        file: &str,
+    ) -> Result<(), AccessError> {
+        match self.policy.read().unwrap().as_ref() {
+            Some(policy) => policy.authorize_recording(credentials, file),
+            None => Ok(()),
+        }
+    }
+
+    /// Users with sessions open and how many each of them has.
+    pub fn sessions(&self) -> HashMap<String, usize> {
+        self.sessions.lock().unwrap().clone()
+    }
+
+    /// Number of sessions `user` has open.
+    pub fn open_sessions(&self, user: &str) -> usize {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(user)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// Session of a viewer, closed when dropped.
+#[derive(Debug)]
+pub struct Session<'a> {
+    control: &'a AccessControl,
+    user: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(control: &'a AccessControl, user: Option<String>) -> Self {
+        active_sessions().inc();
+
+        Self { control, user }
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        active_sessions().dec();
+
+        let Some(user) = &self.user else {
+            return;
+        };
+
+        let mut sessions = self.control.sessions.lock().unwrap();
+        if let Some(open) = sessions.get_mut(user) {
+            *open -= 1;
+
+            if *open == 0 {
+                sessions.remove(user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::credentials::Credentials;
+
+    use super::{AccessControl, AccessError, AccessPolicy};
+
+    const POLICY: &str = r#"{
+        "users": {
+            "alice": { "token": "a", "files": ["movie.Mjpeg"], "max_sessions": 1 },
+            "bob": { "token": "b", "groups": ["staff", "guests"] }
+        },
+        "groups": {
+            "staff": { "files": ["*"], "record": true, "max_sessions": 3 },
+            "guests": { "files": ["movie.Mjpeg"], "max_sessions": 1 }
+        }
+    }"#;
+
+    fn credentials(user: &str, token: &str) -> Option<Credentials> {
+        Some(Credentials::new(user.to_string(), token.to_string()))
+    }
+
+    #[test]
+    fn users_need_valid_credentials() {
+        let policy: AccessPolicy = serde_json::from_str(POLICY).unwrap();
+
+        assert_eq!(
+            policy.authorize(None, "movie.Mjpeg"),
+            Err(AccessError::Unauthorized)
+        );
+        assert_eq!(
+            policy.authorize(credentials("alice", "b").as_ref(), "movie.Mjpeg"),
+            Err(AccessError::Unauthorized)
+        );
+        assert_eq!(
+            policy.authorize(credentials("carol", "c").as_ref(), "movie.Mjpeg"),
+            Err(AccessError::Unauthorized)
+        );
+    }
+
+    #[test]
+    fn files_are_granted_to_users_and_groups() {
+        let policy: AccessPolicy = serde_json::from_str(POLICY).unwrap();
+
+        let alice = credentials("alice", "a");
+        assert_eq!(policy.authorize(alice.as_ref(), "movie.Mjpeg"), Ok(Some(1)));
+        assert!(matches!(
+            policy.authorize(alice.as_ref(), "other.Mjpeg"),
+            Err(AccessError::Forbidden { .. })
+        ));
+
+        // The largest limit among the user's groups applies
+        let bob = credentials("bob", "b");
+        assert_eq!(policy.authorize(bob.as_ref(), "other.Mjpeg"), Ok(Some(3)));
+    }
+
+    #[test]
+    fn recording_needs_its_own_grant() {
+        let policy: AccessPolicy = serde_json::from_str(POLICY).unwrap();
+
+        assert_eq!(
+            policy.authorize_recording(None, "movie.Mjpeg"),
+            Err(AccessError::Unauthorized)
+        );
+        assert!(matches!(
+            policy.authorize_recording(credentials("alice", "a").as_ref(), "movie.Mjpeg"),
+            Err(AccessError::RecordingForbidden { .. })
+        ));
+        assert_eq!(
+            policy.authorize_recording(credentials("bob", "b").as_ref(), "other.Mjpeg"),
+            Ok(())
+        );
+        assert!(AccessControl::default()
+            .authorize_recording(None, "movie.Mjpeg")
+            .is_ok());
+    }
+
+    #[test]
+    fn sessions_are_limited_per_user() {
+        let control = AccessControl::new(Some(serde_json::from_str(POLICY).unwrap()));
+        let alice = credentials("alice", "a");
+
+        let session = control.open_session(alice.as_ref(), "movie.Mjpeg").unwrap();
+        assert_eq!(
+            control
+                .open_session(alice.as_ref(), "movie.Mjpeg")
+                .unwrap_err(),
+            AccessError::TooManySessions("alice".to_string())
+        );
+        assert_eq!(control.open_sessions("alice"), 1);
+
+        drop(session);
+        assert_eq!(control.open_sessions("alice"), 0);
+        assert!(control.open_session(alice.as_ref(), "movie.Mjpeg").is_ok());
+    }
+
+    #[test]
+    fn everything_is_allowed_without_a_policy() {
+        let control = AccessControl::default();
+
+        let sessions: Vec<_> = (0..5)
+            .map(|_| control.open_session(None, "movie.Mjpeg").unwrap())
+            .collect();
+
+        assert_eq!(sessions.len(), 5);
+        assert_eq!(control.open_sessions(""), 0);
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::{
+    server::server_worker::streaming_worker::transmission_worker,
+    settings::{self, EvictionPolicy},
+    telemetry,
+    transport::Stream,
+    video::recorder::{self, Recorder, IN_PROGRESS},
+};
+
+/// Frames a fill holds while a lost one is repaired, it is abandoned past that.
+const MAX_PENDING: usize = 256;
+
+/// Bytes of the length prefix written before each frame.
+const PREFIX: u64 = 5;
+
+#[derive(Debug)]
+struct CachedTitle {
+    bytes: u64,
+    origin: SocketAddr,
+    last_used: u64,
+    uses: u64,
+}
+
+/// Viewer streamed from the cache, counted by its content server until the session ends.
+#[derive(Debug)]
+struct CachedSession {
+    channel: Arc<transmission_worker::TransmissionChannel>,
+    _admission: Stream,
+}
+
+/// Titles a relay keeps on disk to stream them to its viewers itself.
+///
+/// A title is cached the first time it is relayed whole to an on demand
+/// viewer, along with the content server it came from, which still admits
+/// every viewer streamed from the cache. When the titles outgrow the quota
+/// the ones watched the longest ago, or the fewest times, make room.
+#[derive(Debug)]
+pub struct EdgeCache {
+    dir: PathBuf,
+    quota: u64,
+    policy: EvictionPolicy,
+    titles: Mutex<HashMap<String, CachedTitle>>,
+    /// Bytes written so far by each fill in progress, they count against the quota too.
+    filling: Mutex<HashMap<String, u64>>,
+    sessions: Mutex<HashMap<String, CachedSession>>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for EdgeCache {
+    fn default() -> Self {
+        let cache = &settings::get().cache;
+        Self::new(&cache.dir, cache.quota_bytes(), cache.policy)
+    }
+}
+
+impl EdgeCache {
+    /// Cache of at most `quota` bytes in `dir`, 0 caching nothing.
+    pub fn new(dir: &Path, quota: u64, policy: EvictionPolicy) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            quota,
+            policy,
+            titles: Mutex::new(HashMap::new()),
+            filling: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.quota > 0
+    }
+
+    /// Whether `title` is cached whole.
+    pub fn contains(&self, title: &str) -> bool {
+        self.titles.lock().unwrap().contains_key(title)
+    }
+
+    /// Where `title` is kept and the content server that admits its viewers, if cached.
+    pub fn lookup(&self, title: &str) -> Option<(PathBuf, SocketAddr)> {
+        let titles = self.titles.lock().unwrap();
+
+        titles
+            .get(title)
+            .map(|cached| (self.dir.join(title), cached.origin))
+    }
+
+    /// Content server `title` was cached from.
+    pub fn origin(&self, title: &str) -> Option<SocketAddr> {
+        self.lookup(title).map(|(_, origin)| origin)
+    }
+
+    /// Starts caching `title`, of `frames` frames, as it is relayed from `origin`.
+    ///
+    /// Counts as a session the cache missed. Returns `None` when the cache is
+    /// disabled, the title is not a plain file name, or it is already cached or
+    /// being cached.
+    pub fn fill(
+        self: &Arc<Self>,
+        title: &str,
+        frames: u32,
+        origin: SocketAddr,
+    ) -> Option<CacheFill> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        telemetry::cache_lookup("miss");
+
+        if frames == 0
+            || !recorder::is_valid_name(title)
+            || self.contains(title)
+            || self
+                .filling
+                .lock()
+                .unwrap()
+                .insert(title.to_string(), 0)
+                .is_some()
+        {
+            return None;
+        }
+
+        let mut fill = CacheFill {
+            cache: Arc::clone(self),
+            title: title.to_string(),
+            origin,
+            frames,
+            ssrc: None,
+            // Sessions start from the first frame, sent as packet 1
+            next: 1,
+            pending: HashMap::new(),
+            written: 0,
+            bytes: 0,
+            recorder: None,
+        };
+
+        // What an earlier run left is not known to be whole
+        let _ = fs::remove_file(self.dir.join(title));
+        let _ = fs::remove_file(self.dir.join(format!("{}{}", title, IN_PROGRESS)));
+
+        match fs::create_dir_all(&self.dir).and_then(|_| Recorder::create_in(&self.dir, title)) {
+            Ok(recorder) => fill.recorder = Some(recorder),
+            Err(error) => {
+                warn!("Not caching {}: {}", title, error);
+                return None;
+            }
+        }
+
+        info!("Caching {} from {}", title, origin);
+        Some(fill)
+    }
+
+    /// Makes room for `bytes` more of `title`, which is being filled, evicting cached titles.
+    ///
+    /// Returns false when the fills in progress leave no room for them.
+    fn reserve(&self, title: &str, bytes: u64) -> bool {
+        let mut titles = self.titles.lock().unwrap();
+        let mut filling = self.filling.lock().unwrap();
+
+        let reserved: u64 = filling.values().sum();
+        if !self.make_room(&mut titles, reserved + bytes) {
+            return false;
+        }
+
+        *filling.entry(title.to_string()).or_default() += bytes;
+        true
+    }
+
+    /// Keeps `title`, just written whole, in the room it took while being filled.
+    fn store(&self, title: &str, bytes: u64, origin: SocketAddr) {
+        let mut titles = self.titles.lock().unwrap();
+        let mut filling = self.filling.lock().unwrap();
+
+        filling.remove(title);
+        let reserved: u64 = filling.values().sum();
+        self.make_room(&mut titles, reserved + bytes);
+
+        titles.insert(
+            title.to_string(),
+            CachedTitle {
+                bytes,
+                origin,
+                last_used: self.tick(),
+                uses: 0,
+            },
+        );
+    }
+
+    /// Evicts titles until `bytes` more fit in the quota, returns false if they never do.
+    fn make_room(&self, titles: &mut HashMap<String, CachedTitle>, bytes: u64) -> bool {
+        let mut used: u64 = titles.values().map(|cached| cached.bytes).sum();
+
+        while used + bytes > self.quota {
+            let Some(victim) = self.victim(titles) else {
+                return false;
+            };
+
+            if let Some(evicted) = titles.remove(&victim) {
+                used -= evicted.bytes;
+            }
+            if let Err(error) = fs::remove_file(self.dir.join(&victim)) {
+                warn!("Error removing {} from the cache: {}", victim, error);
+            }
+
+            telemetry::cache_evictions().inc();
+            info!("Evicted {} from the cache", victim);
+        }
+
+        true
+    }
+
+    /// Title to evict first, as the policy says.
+    fn victim(&self, titles: &HashMap<String, CachedTitle>) -> Option<String> {
+        titles
+            .iter()
+            .min_by_key(|(_, cached)| match self.policy {
+                EvictionPolicy::Lru => (0, cached.last_used),
+                EvictionPolicy::Lfu => (cached.uses, cached.last_used),
+            })
+            .map(|(title, _)| title.clone())
+    }
+
+    /// Counts a viewer of `title`.
+    fn watched(&self, title: &str) {
+        if let Some(cached) = self.titles.lock().unwrap().get_mut(title) {
+            cached.last_used = self.tick();
+            cached.uses += 1;
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Streams `title` from the cache to the viewer of session `key`, through `channel`.
+    ///
+    /// The session counts as a hit, it is admitted for as long as `admission` stays open.
+    pub fn add_session(
+        &self,
+        key: String,
+        title: &str,
+        channel: Arc<transmission_worker::TransmissionChannel>,
+        admission: Stream,
+    ) {
+        self.watched(title);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        telemetry::cache_lookup("hit");
+
+        let session = CachedSession {
+            channel,
+            _admission: admission,
+        };
+
+        if let Some(previous) = self.sessions.lock().unwrap().insert(key, session) {
+            previous.channel.stop();
+        }
+    }
+
+    /// Channel streaming session `key` from the cache.
+    pub fn session(&self, key: &str) -> Option<Arc<transmission_worker::TransmissionChannel>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|session| Arc::clone(&session.channel))
+    }
+
+    /// Ends session `key`, returns false if there was none.
+    pub fn remove_session(&self, key: &str) -> bool {
+        match self.sessions.lock().unwrap().remove(key) {
+            Some(session) => {
+                session.channel.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends every session streamed from the cache.
+    pub fn close_sessions(&self) {
+        for (_, session) in self.sessions.lock().unwrap().drain() {
+            session.channel.stop();
+        }
+    }
+
+    /// Titles cached and how often the cache served the sessions set up here.
+    pub fn status(&self) -> Value {
+        let titles = self.titles.lock().unwrap();
+
+        json!({
+            "quota": self.quota,
+            "bytes": titles.values().map(|cached| cached.bytes).sum::<u64>(),
+            "filling": self.filling.lock().unwrap().values().sum::<u64>(),
+            "hits": self.hits.load(Ordering::Relaxed),
+            "misses": self.misses.load(Ordering::Relaxed),
+            "sessions": self.sessions.lock().unwrap().len(),
+            "titles": titles
+                .iter()
+                .map(|(title, cached)| {
+                    (
+                        title.clone(),
+                        json!({
+                            "bytes": cached.bytes,
+                            "uses": cached.uses,
+                            "origin": cached.origin,
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        })
+    }
+}
+
+/// Writes a title to the cache as it is relayed, in order from its first frame.
+///
+/// Frames arriving after a lost one are held until it is repaired. The fill
+/// is abandoned, and what was written deleted, when the repair takes too
+/// long, the title outgrows the quota or another stream shows up.
+#[derive(Debug)]
+pub struct CacheFill {
+    cache: Arc<EdgeCache>,
+    title: String,
+    origin: SocketAddr,
+    frames: u32,
+    ssrc: Option<u32>,
+    next: u16,
+    pending: HashMap<u16, Vec<u8>>,
+    written: u32,
+    bytes: u64,
+    recorder: Option<Recorder>,
+}
+
+impl CacheFill {
+    /// Takes the frame sent as packet `id`, returns false once the fill is over.
+    pub fn write(&mut self, (ssrc, sequence): (u32, u16), frame: &[u8]) -> bool {
+        if *self.ssrc.get_or_insert(ssrc) != ssrc {
+            return self.abandon("the stream changed");
+        }
+
+        let ahead = sequence.wrapping_sub(self.next);
+        if ahead > u16::MAX / 2 {
+            return true;
+        }
+        if ahead as usize >= MAX_PENDING {
+            return self.abandon("a lost frame was not repaired");
+        }
+
+        self.pending.insert(sequence, frame.to_vec());
+
+        while let Some(frame) = self.pending.remove(&self.next) {
+            let bytes = PREFIX + frame.len() as u64;
+            if !self.cache.reserve(&self.title, bytes) {
+                return self.abandon("it does not fit in the cache");
+            }
+            self.bytes += bytes;
+
+            let Some(recorder) = self.recorder.as_mut() else {
+                return false;
+            };
+            if let Err(error) = recorder.write((ssrc, self.next), &frame) {
+                return self.abandon(error);
+            }
+
+            self.next = self.next.wrapping_add(1);
+            self.written += 1;
+
+            if self.written == self.frames {
+                self.complete();
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn complete(&mut self) {
+        let Some(mut recorder) = self.recorder.take() else {
+            return;
+        };
+
+        if let Err(error) = recorder.finish() {
+            error!("Error caching {}: {}", self.title, error);
+            return;
+        }
+
+        info!("Cached {} frames of {}", self.written, self.title);
+        self.cache.store(&self.title, self.bytes, self.origin);
+    }
+
+    fn abandon(&mut self, reason: impl Display) -> bool {
+        info!("Not caching {}: {}", self.title, reason);
+
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(error) = recorder.discard() {
+                warn!("Error removing {} from the cache: {}", self.title, error);
+            }
+        }
+
+        false
+    }
+}
+
+impl Drop for CacheFill {
+    fn drop(&mut self) {
+        if self.recorder.is_some() {
+            self.abandon("the session ended first");
+        }
+
+        self.cache.filling.lock().unwrap().remove(&self.title);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, path::Path, sync::Arc};
+
+    use crate::settings::EvictionPolicy;
+
+    use super::EdgeCache;
+
+    /// Title kept of two, when a third one needs room.
+    fn kept(policy: EvictionPolicy) -> Vec<&'static str> {
+        let origin: SocketAddr = "10.0.0.20:9001".parse().unwrap();
+        let cache = EdgeCache::new(Path::new("esr_cache_unused"), 100, policy);
+
+        cache.store("early.Mjpeg", 40, origin);
+        cache.store("late.Mjpeg", 40, origin);
+
+        // The early title is watched more often, the late one last
+        for title in ["early.Mjpeg", "early.Mjpeg", "late.Mjpeg"] {
+            cache.watched(title);
+        }
+        cache.store("new.Mjpeg", 40, origin);
+
+        ["early.Mjpeg", "late.Mjpeg", "new.Mjpeg"]
+            .into_iter()
+            .filter(|title| cache.contains(title))
+            .collect()
+    }
+
+    #[test]
+    fn titles_outside_the_cache_are_neither_cached_nor_removed() {
+        let dir = std::env::temp_dir().join("esr_cache_outside");
+        std::fs::create_dir_all(dir.join("cache")).unwrap();
+        std::fs::write(dir.join("kept.Mjpeg"), "000021").unwrap();
+
+        let cache = Arc::new(EdgeCache::new(&dir.join("cache"), 100, EvictionPolicy::Lru));
+        let origin: SocketAddr = "10.0.0.20:9001".parse().unwrap();
+
+        assert!(cache.fill("../kept.Mjpeg", 1, origin).is_none());
+        assert!(dir.join("kept.Mjpeg").exists());
+    }
+
+    #[test]
+    fn fills_in_progress_count_against_the_quota() {
+        let dir = std::env::temp_dir().join("esr_cache_filling");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let cache = Arc::new(EdgeCache::new(&dir, 100, EvictionPolicy::Lru));
+        let origin: SocketAddr = "10.0.0.20:9001".parse().unwrap();
+        let frame = [b'x'; 40];
+
+        let mut first = cache.fill("first.Mjpeg", 3, origin).unwrap();
+        let mut second = cache.fill("second.Mjpeg", 3, origin).unwrap();
+        assert!(first.write((1, 1), &frame));
+        assert!(first.write((1, 2), &frame));
+
+        // The first fill holds 90 of the 100 bytes, none of them cached yet to be evicted
+        assert!(!second.write((2, 1), &frame));
+        assert!(!dir.join("second.Mjpeg.part").exists());
+    }
+
+    #[test]
+    fn policies_evict_the_least_recently_or_least_often_watched() {
+        assert_eq!(kept(EvictionPolicy::Lru), ["late.Mjpeg", "new.Mjpeg"]);
+        assert_eq!(kept(EvictionPolicy::Lfu), ["early.Mjpeg", "new.Mjpeg"]);
+    }
+}
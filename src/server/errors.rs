@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+use crate::{message::auth::AuthError, resolve::ResolveError};
+
+/// Errors raised while handling streaming, metrics and query traffic on relays and servers.
+#[derive(Debug, Error)]
+pub enum StreamingError {
+    #[error("Malformed message: {0}")]
+    MalformedMessage(#[from] bincode::Error),
+    #[error("Network error: {0}")]
+    Network(#[from] std::io::Error),
+    #[error("Expected a file query")]
+    NotAFileQuery,
+    #[error("There is no channel streaming {0}")]
+    ChannelNotFound(String),
+    #[error("Another channel was set up as {0} meanwhile")]
+    ChannelTaken(String),
+    #[error("{0} is not watching {1}")]
+    ClientNotFound(SocketAddr, String),
+    #[error("There is no server to contact for {0}")]
+    NoServerToContact(String),
+    #[error("Not connected to server {0}")]
+    ServerNotConnected(String),
+    #[error("{0}")]
+    Resolve(#[from] ResolveError),
+    #[error("Request received before the session was set up")]
+    SessionNotSetup,
+    #[error("Unauthenticated message: {0}")]
+    Unauthenticated(#[from] AuthError),
+    #[error("Error recording: {0}")]
+    Recording(std::io::Error),
+}
+
+impl StreamingError {
+    /// Label the error is counted under in the exported metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MalformedMessage(_) => "malformed_message",
+            Self::Network(_) => "network",
+            Self::NotAFileQuery => "not_a_file_query",
+            Self::ChannelNotFound(_) => "channel_not_found",
+            Self::ChannelTaken(_) => "channel_taken",
+            Self::ClientNotFound(..) => "client_not_found",
+            Self::NoServerToContact(_) => "no_server_to_contact",
+            Self::ServerNotConnected(_) => "server_not_connected",
+            Self::Resolve(_) => "resolve",
+            Self::SessionNotSetup => "session_not_setup",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::Recording(_) => "recording",
+        }
+    }
+}
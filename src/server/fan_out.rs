@@ -0,0 +1,368 @@
+use std::{
+    net::SocketAddr,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwap;
+use tokio::net::UdpSocket;
+use tracing::{error, warn};
+
+/// Number of destinations handed to the kernel in a single call.
+pub const BATCH_SIZE: usize = 64;
+
+/// Reusable buffers for the packets a worker forwards.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps at most `capacity` idle buffers of `buffer_size` bytes.
+    pub fn new(buffer_size: usize, capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            buffer_size,
+            capacity,
+        })
+    }
+
+    /// Takes an idle buffer, allocating one only if the pool is empty.
+    pub fn take(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self
+            .buffers
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.buffer_size]);
+
+        PooledBuffer {
+            buffer,
+            len: 0,
+            pool: Arc::clone(self),
+        }
+    }
+
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Buffer that returns to its pool once the last reference to it is dropped.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    len: usize,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Whole buffer, to be filled before calling `set_len`.
+    pub fn spare_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(self.buffer.len());
+    }
+
+    /// Freezes the buffer so it can be shared by every destination.
+    pub fn share(self) -> SharedPacket {
+        Arc::new(self)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buffer));
+    }
+}
+
+/// Packet sent to many destinations without being copied.
+pub type SharedPacket = Arc<PooledBuffer>;
+
+/// Destinations of a stream, read without locking on every packet.
+///
+/// Adding or removing a subscriber copies the list, which is rare compared
+/// to the number of packets forwarded to it.
+#[derive(Debug, Default)]
+pub struct SubscriberList {
+    addresses: ArcSwap<Vec<SocketAddr>>,
+}
+
+impl SubscriberList {
+    pub fn new(addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            addresses: ArcSwap::from_pointee(addresses),
+        }
+    }
+
+    /// Adds a subscriber and returns how many there are.
+    pub fn add(&self, address: SocketAddr) -> usize {
+        let addresses = self.addresses.rcu(|addresses| {
+            let mut addresses = Vec::clone(addresses);
+            addresses.push(address);
+            addresses
+        });
+
+        addresses.len() + 1
+    }
+
+    /// Removes a subscriber and returns how many are left.
+    pub fn remove(&self, address: SocketAddr) -> usize {
+        let addresses = self.addresses.rcu(|addresses| {
+            let mut addresses = Vec::clone(addresses);
+            addresses.retain(|&a| a != address);
+            addresses
+        });
+
+        addresses.iter().filter(|&&a| a != address).count()
+    }
+
+    pub fn snapshot(&self) -> Arc<Vec<SocketAddr>> {
+        self.addresses.load_full()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.load().is_empty()
+    }
+}
+
+/// Sends `packet` to every destination without waiting on the socket.
+///
+/// Destinations left when the socket buffer fills up lose this packet.
+/// Returns how many destinations the packet was handed to.
+pub fn send_to_all(socket: &UdpSocket, packet: &[u8], destinations: &[SocketAddr]) -> usize {
+    let mut sent = 0;
+
+    for batch in destinations.chunks(BATCH_SIZE) {
+        let mut remaining = batch;
+
+        while !remaining.is_empty() {
+            match send_batch(socket, packet, remaining) {
+                Ok(n) => {
+                    sent += n;
+                    remaining = &remaining[n..];
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    warn!(
+                        "Dropping packet for {} clients: socket is busy",
+                        destinations.len() - sent
+                    );
+                    return sent;
+                }
+                Err(error) => {
+                    error!("Error sending packet to {}: {}", remaining[0], error);
+                    remaining = &remaining[1..];
+                }
+            }
+        }
+    }
+
+    sent
+}
+
+/// Sends to as many destinations as possible in one `sendmmsg` call.
+#[cfg(target_os = "linux")]
+fn send_batch(
+    socket: &UdpSocket,
+    packet: &[u8],
+    destinations: &[SocketAddr],
+) -> std::io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let addresses: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+        destinations.iter().map(raw_address).collect();
+
+    // Every message points at the same buffer
+    let mut iov = libc::iovec {
+        iov_base: packet.as_ptr() as *mut libc::c_void,
+        iov_len: packet.len(),
+    };
+
+    let mut messages: Vec<libc::mmsghdr> = addresses
+        .iter()
+        .map(|(address, length)| {
+            // SAFETY: an all zero msghdr is a valid empty message
+            let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+            header.msg_name = address as *const libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_namelen = *length;
+            header.msg_iov = &mut iov;
+            header.msg_iovlen = 1;
+
+            libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    socket.try_io(tokio::io::Interest::WRITABLE, || {
+        // SAFETY: every message points to an address and to `iov`, both outlive the call
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+            )
+        };
+
+        if sent < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn raw_address(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: sockaddr_storage is plain data, large enough for any address family
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let length = match address {
+        SocketAddr::V4(address) => {
+            let raw = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: address.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(address.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: sockaddr_in fits in sockaddr_storage
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, raw) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let raw = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: address.port().to_be(),
+                sin6_flowinfo: address.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: address.ip().octets(),
+                },
+                sin6_scope_id: address.scope_id(),
+            };
+            // SAFETY: sockaddr_in6 fits in sockaddr_storage
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, raw) };
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, length as libc::socklen_t)
+}
+
+/// Sends to the first destination, platforms without `sendmmsg` have no batching.
+#[cfg(not(target_os = "linux"))]
+fn send_batch(
+    socket: &UdpSocket,
+    packet: &[u8],
+    destinations: &[SocketAddr],
+) -> std::io::Result<usize> {
+    socket.try_send_to(packet, destinations[0]).map(|_| 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::Duration};
+
+    use super::{send_to_all, BufferPool, SubscriberList, BATCH_SIZE};
+
+    #[test]
+    fn subscribers_are_added_and_removed() {
+        let first: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let subscribers = SubscriberList::default();
+
+        assert_eq!(subscribers.add(first), 1);
+        assert_eq!(subscribers.add(second), 2);
+
+        let snapshot = subscribers.snapshot();
+        assert_eq!(subscribers.remove(first), 1);
+
+        // Packets already being sent keep the list they started with
+        assert_eq!(*snapshot, vec![first, second]);
+        assert_eq!(*subscribers.snapshot(), vec![second]);
+        assert_eq!(subscribers.remove(second), 0);
+        assert!(subscribers.is_empty());
+    }
+
+    #[test]
+    fn buffers_return_to_the_pool() {
+        let pool = BufferPool::new(16, 2);
+
+        let mut buffer = pool.take();
+        buffer.spare_mut()[..3].copy_from_slice(b"abc");
+        buffer.set_len(3);
+
+        let packet = buffer.share();
+        let copy = packet.clone();
+        assert_eq!(&packet[..], b"abc");
+
+        drop(packet);
+        assert_eq!(pool.idle(), 0);
+        drop(copy);
+        assert_eq!(pool.idle(), 1);
+
+        let reused = pool.take();
+        assert_eq!(reused.len(), 0);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn every_destination_receives_the_packet() {
+        let receivers: Vec<std::net::UdpSocket> = (0..BATCH_SIZE + 3)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let destinations: Vec<SocketAddr> = receivers
+            .iter()
+            .map(|receiver| receiver.local_addr().unwrap())
+            .collect();
+
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.writable().await.unwrap();
+        assert_eq!(
+            send_to_all(&sender, b"frame", &destinations),
+            destinations.len()
+        );
+
+        let mut buffer = [0; 16];
+        for receiver in &receivers {
+            receiver
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let n = receiver.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..n], b"frame");
+        }
+    }
+}
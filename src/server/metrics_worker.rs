@@ -1,81 +1,126 @@
 use std::{
     collections::HashMap,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
     sync::{Arc, Mutex},
 };
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{error, warn};
+
 use crate::{
-    message::metrics::{MetricsRequest, MetricsResponse},
+    message::{
+        auth::Authenticator,
+        metrics::{MetricsRequest, MetricsResponse},
+    },
+    runtime::TaskPool,
+    settings,
+    shutdown::Shutdown,
+    transport::{Listener, Stream},
     video::video_stream::VideoStream,
 };
 
+use super::errors::StreamingError;
 use super::server_worker::streaming_worker::transmission_worker::TransmissionChannel;
 
 #[derive(Debug)]
 pub struct MetricsWorker<'a> {
-    metrics_listener: TcpListener,
+    metrics_listener: Listener,
     streaming_port: u16,
-    videos_available: Vec<String>,
     video_workers: &'a Mutex<HashMap<String, Arc<TransmissionChannel>>>,
+    on_demand_sessions: &'a Mutex<HashMap<u32, Arc<TransmissionChannel>>>,
+    auth: &'a Authenticator,
 }
 
 impl<'a> MetricsWorker<'a> {
     pub fn new(
         streaming_port: u16,
-        metrics_listener: TcpListener,
-        videos_available: Vec<String>,
+        metrics_listener: Listener,
         video_workers: &'a Mutex<HashMap<String, Arc<TransmissionChannel>>>,
+        on_demand_sessions: &'a Mutex<HashMap<u32, Arc<TransmissionChannel>>>,
+        auth: &'a Authenticator,
     ) -> Self {
         Self {
             video_workers,
+            on_demand_sessions,
+            auth,
             streaming_port,
             metrics_listener,
-            videos_available,
         }
     }
 
-    fn handle_client(&self, mut stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    async fn handle_client(
+        &self,
+        mut stream: Stream,
+        shutdown: &Shutdown,
+    ) -> Result<(), StreamingError> {
+        let peer = stream.peer_addr()?.ip();
+
         loop {
             let mut buffer = [0; 1024];
 
-            let n = stream.read(&mut buffer)?;
+            let n = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                result = stream.read(&mut buffer) => result?,
+            };
+
+            if n == 0 {
+                return Ok(());
+            }
 
-            let metrics_request: MetricsRequest = bincode::deserialize(&buffer[..n])?;
+            let metrics_request: MetricsRequest = match self.auth.open(&buffer[..n], peer) {
+                Ok(request) => request,
+                Err(error) => {
+                    warn!("Dropping metrics request: {}", StreamingError::from(error));
+                    continue;
+                }
+            };
 
             let video_file = metrics_request.video_file();
 
             let video_found = VideoStream::file_exists(video_file);
-            let lock_guard = self.video_workers.lock().expect("Error aquiring the lock");
-            let already_streaming = lock_guard.contains_key(video_file);
-            let nr_videos_already_streaming = lock_guard.len();
-            drop(lock_guard);
+            let (already_streaming, nr_videos_already_streaming) = {
+                let lock_guard = self.video_workers.lock().expect("Error aquiring the lock");
+                (lock_guard.contains_key(video_file), lock_guard.len())
+            };
+
+            let nr_on_demand_sessions = self
+                .on_demand_sessions
+                .lock()
+                .expect("Error aquiring the lock")
+                .len();
 
             let metrics_response = MetricsResponse::new(
                 video_found,
                 already_streaming,
-                self.videos_available.len(),
+                VideoStream::available().map_or(0, |files| files.len()),
                 nr_videos_already_streaming,
+                nr_on_demand_sessions,
                 self.streaming_port,
             );
 
-            let metrics_response = bincode::serialize(&metrics_response)?;
+            let metrics_response = self.auth.seal(&metrics_response);
 
-            let _ = stream.write(&metrics_response)?;
+            stream.write_all(&metrics_response).await?;
         }
     }
 
-    pub fn run(&self) {
-        std::thread::scope(|s| {
-            for stream in self.metrics_listener.incoming() {
-                let stream = stream.unwrap();
+    pub async fn run(&self, shutdown: &Shutdown) {
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
 
-                s.spawn(move || {
-                    if let Err(error) = self.handle_client(stream) {
-                        println!("Error processing the request: {:?}", error);
-                    }
-                });
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => {}
+                result = self.metrics_listener.accept(), if !tasks.is_full() => match result {
+                    Ok((stream, _)) => tasks.push(async move {
+                        if let Err(error) = self.handle_client(stream, shutdown).await {
+                            error!("Error processing the request: {:?}", error);
+                        }
+                    }),
+                    Err(error) => error!("Error accepting connection {}", error),
+                },
             }
-        });
+        }
+
+        tasks.drain().await;
     }
 }
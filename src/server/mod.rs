@@ -1,81 +1,312 @@
 use std::{
     collections::HashMap,
-    fs,
-    path::Path,
+    net::SocketAddr,
     sync::{Arc, Mutex},
 };
 
+pub mod access;
+pub mod edge_cache;
+pub mod errors;
+pub mod fan_out;
 mod metrics_worker;
+pub mod retransmission;
 pub mod rp;
+pub mod send_queue;
 pub mod server_worker;
+pub mod time_shift;
 pub mod transmission_channel;
 
-use crate::server::server_worker::streaming_worker::StreamingWorker;
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::{
+    admin::{self, Admin, AdminError},
+    message::{auth::Authenticator, rtsp::StreamingMode, srtp::MasterKey},
+    runtime::{self, TaskPool},
+    server::{
+        access::{AccessControl, AccessPolicy},
+        errors::StreamingError,
+        server_worker::streaming_worker::StreamingWorker,
+    },
+    settings,
+    shutdown::Shutdown,
+    telemetry,
+    transport::{Listener, Transport},
+    video::video_stream::VideoStream,
+};
 
 use self::server_worker::streaming_worker::transmission_worker::TransmissionChannel;
 
+/// Decides which streaming mode a content server uses for each file.
+#[derive(Debug, Default, Clone)]
+pub struct StreamingPolicy {
+    default_mode: StreamingMode,
+    on_demand_files: Vec<String>,
+}
+
+impl StreamingPolicy {
+    pub fn new(default_mode: StreamingMode, on_demand_files: Vec<String>) -> Self {
+        Self {
+            default_mode,
+            on_demand_files,
+        }
+    }
+
+    /// The mode asked by the client wins, then the per file configuration, then the default.
+    pub fn mode_for(&self, file: &str, requested: Option<StreamingMode>) -> StreamingMode {
+        if let Some(mode) = requested {
+            return mode;
+        }
+
+        if self.on_demand_files.iter().any(|f| f == file) {
+            StreamingMode::OnDemand
+        } else {
+            self.default_mode
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Server {
     metrics_port: u16,
     streaming_port: u16,
-    files_available: Vec<String>,
+    streaming_policy: StreamingPolicy,
     video_workers: Mutex<HashMap<String, Arc<TransmissionChannel>>>,
+    on_demand_sessions: Mutex<HashMap<u32, Arc<TransmissionChannel>>>,
+    auth: Authenticator,
+    master_key: MasterKey,
+    access: AccessControl,
+    access_policy_file: Option<String>,
+    admin_port: Option<u16>,
+    prometheus_port: Option<u16>,
+    transport: Transport,
 }
 
 impl Server {
-    pub fn new(metrics_port: u16, streaming_port: u16) -> std::io::Result<Self> {
-        let files_available = Self::get_files_available()?;
+    pub fn new(
+        metrics_port: u16,
+        streaming_port: u16,
+        streaming_policy: StreamingPolicy,
+    ) -> std::io::Result<Self> {
+        // Files are listed on every request, recordings join them as they finish
+        VideoStream::available()?;
 
         Ok(Self {
             metrics_port,
             streaming_port,
-            files_available,
+            streaming_policy,
             ..Default::default()
         })
     }
 
-    fn get_files_available() -> std::io::Result<Vec<String>> {
-        Ok(fs::read_dir(Path::new("videos"))?
-            .map(|entry| {
-                entry
-                    .unwrap()
-                    .file_name()
-                    .into_string()
-                    .unwrap()
-                    .to_string()
-            })
-            .collect())
-    }
-
-    pub fn run(&self) {
-        std::thread::scope(|s| {
-            let streaming_listener =
-                std::net::TcpListener::bind(("0.0.0.0", self.streaming_port)).unwrap();
-            println!("Streaming socket listening on port {}", self.streaming_port);
-
-            let metrics_listener =
-                std::net::TcpListener::bind(("0.0.0.0", self.metrics_port)).unwrap();
-            println!("Metrics socket listening on port {}", self.metrics_port);
-
-            let streaming_port = streaming_listener.local_addr().unwrap().port();
-
-            s.spawn(move || {
-                metrics_worker::MetricsWorker::new(
-                    streaming_port,
-                    metrics_listener,
-                    self.files_available.clone(),
-                    &self.video_workers,
-                )
-                .run();
-            });
-
-            for stream in streaming_listener.incoming() {
-                let stream = stream.unwrap();
-                s.spawn(move || {
-                    let mut worker = StreamingWorker::new(stream, &self.video_workers);
-                    worker.run();
-                });
+    pub fn with_authenticator(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Restricts which files each user may watch, without a policy anyone may watch anything.
+    pub fn with_access_policy(mut self, policy: Option<AccessPolicy>) -> Self {
+        self.access = AccessControl::new(policy);
+        self
+    }
+
+    /// Reads the access policy from `path`, which is read again on reloads.
+    pub fn with_access_policy_file(mut self, path: Option<String>) -> std::io::Result<Self> {
+        let policy = path.as_ref().map(AccessPolicy::from_file).transpose()?;
+
+        self.access = AccessControl::new(policy);
+        self.access_policy_file = path;
+        Ok(self)
+    }
+
+    pub fn with_admin_port(mut self, admin_port: Option<u16>) -> Self {
+        self.admin_port = admin_port;
+        self
+    }
+
+    pub fn with_prometheus_port(mut self, prometheus_port: Option<u16>) -> Self {
+        self.prometheus_port = prometheus_port;
+        self
+    }
+
+    /// Opens every socket of the server through `transport`.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Serves viewers until `shutdown` is triggered, then stops every transmission.
+    pub fn run(&self, shutdown: &Shutdown) -> Result<(), StreamingError> {
+        runtime::block_on(self.serve(shutdown))
+    }
+
+    /// Same as `run`, on the current runtime.
+    pub async fn serve(&self, shutdown: &Shutdown) -> Result<(), StreamingError> {
+        let streaming_listener = self.transport.listen(self.streaming_port).await?;
+        info!("Streaming socket listening on port {}", self.streaming_port);
+
+        let metrics_listener = self.transport.listen(self.metrics_port).await?;
+        info!("Metrics socket listening on port {}", self.metrics_port);
+
+        let streaming_port = streaming_listener.local_addr()?.port();
+
+        let metrics_worker = metrics_worker::MetricsWorker::new(
+            streaming_port,
+            metrics_listener,
+            &self.video_workers,
+            &self.on_demand_sessions,
+            &self.auth,
+        );
+
+        tokio::join!(
+            metrics_worker.run(shutdown),
+            self.streaming_service(streaming_listener, shutdown),
+            admin::serve_on(self, self.admin_port, shutdown),
+            telemetry::serve_on(self.prometheus_port, shutdown),
+        );
+
+        self.stop_transmissions();
+
+        Ok(())
+    }
+
+    /// Serves at most `network.max_concurrent_tasks` viewers at a time.
+    async fn streaming_service(&self, listener: Listener, shutdown: &Shutdown) {
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => {}
+                result = listener.accept(), if !tasks.is_full() => match result {
+                    Ok((stream, _)) => tasks.push(async move {
+                        let mut worker = StreamingWorker::new(
+                            stream,
+                            &self.video_workers,
+                            &self.on_demand_sessions,
+                            &self.streaming_policy,
+                            &self.auth,
+                            &self.master_key,
+                            &self.access,
+                        )
+                        .with_transport(self.transport.clone());
+                        worker.run(shutdown).await;
+                    }),
+                    Err(error) => error!("Error accepting connection {}", error),
+                },
+            }
+        }
+
+        tasks.drain().await;
+    }
+
+    fn broadcast(&self, file: &str) -> Result<Arc<TransmissionChannel>, AdminError> {
+        self.video_workers
+            .lock()
+            .unwrap()
+            .get(file)
+            .cloned()
+            .ok_or_else(|| AdminError::NotFound(format!("Broadcast of {}", file)))
+    }
+
+    fn stop_transmissions(&self) {
+        for (file, channel) in self.video_workers.lock().unwrap().drain() {
+            info!("Stopping transmission of {}", file);
+            channel.stop();
+        }
+
+        for (session_id, channel) in self.on_demand_sessions.lock().unwrap().drain() {
+            info!("Stopping on demand session {}", session_id);
+            channel.stop();
+        }
+    }
+}
+
+impl Admin for Server {
+    async fn status(&self) -> serde_json::Value {
+        let broadcasts: HashMap<String, Vec<SocketAddr>> = self
+            .video_workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(file, channel)| (file.clone(), channel.clients()))
+            .collect();
+        let on_demand: HashMap<u32, Vec<SocketAddr>> = self
+            .on_demand_sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(session_id, channel)| (*session_id, channel.clients()))
+            .collect();
+        let recordings: HashMap<String, String> = self
+            .video_workers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(file, channel)| Some((file.clone(), channel.recording()?)))
+            .collect();
+
+        json!({
+            "streaming_port": self.streaming_port,
+            "metrics_port": self.metrics_port,
+            "files": VideoStream::available().unwrap_or_default(),
+            "broadcasts": broadcasts,
+            "on_demand": on_demand,
+            "recordings": recordings,
+            "sessions": self.access.sessions(),
+            "rejected_messages": self.auth.rejected(),
+        })
+    }
+
+    /// Removes `client` from the broadcast of `file`, or stops its on demand session.
+    async fn drop_session(&self, file: &str, client: SocketAddr) -> Result<(), AdminError> {
+        let client_addr = (client.ip(), client.port());
+
+        if let Some(channel) = self.video_workers.lock().unwrap().get(file) {
+            if channel.clients().contains(&client) {
+                channel.remove_client(client_addr);
+                return Ok(());
             }
-        });
+        }
+
+        let mut sessions = self.on_demand_sessions.lock().unwrap();
+        let session_id = sessions
+            .iter()
+            .find(|(_, channel)| channel.title() == file && channel.clients().contains(&client))
+            .map(|(session_id, _)| *session_id)
+            .ok_or_else(|| AdminError::NotFound(format!("Session of {} for {}", client, file)))?;
+
+        if let Some(channel) = sessions.remove(&session_id) {
+            channel.stop();
+        }
+        Ok(())
+    }
+
+    /// Records the broadcast of `file`, on demand sessions are only recorded through RECORD.
+    async fn record(&self, file: &str, recording: &str) -> Result<(), AdminError> {
+        self.broadcast(file)?
+            .start_recording(recording)
+            .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+
+    async fn stop_recording(&self, file: &str) -> Result<String, AdminError> {
+        self.broadcast(file)?
+            .stop_recording()
+            .map_err(|error| AdminError::Failed(error.to_string()))?
+            .ok_or_else(|| AdminError::NotFound(format!("Recording of {}", file)))
+    }
+
+    /// Reads the access policy again, sessions already open are kept.
+    async fn reload(&self) -> Result<(), AdminError> {
+        let path = self
+            .access_policy_file
+            .as_ref()
+            .ok_or_else(|| AdminError::Failed("No access policy file to reload".to_string()))?;
+
+        let policy =
+            AccessPolicy::from_file(path).map_err(|error| AdminError::Failed(error.to_string()))?;
+        self.access.set_policy(Some(policy));
+
+        Ok(())
     }
 }
@@ -0,0 +1,117 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Deref,
+};
+
+use crate::message::{fec, rtp};
+
+/// Last media packets a hop sent, to send them again when they are reported lost.
+///
+/// Packets are kept as sent, size prefix included, and looked up by stream and
+/// sequence number. Parity packets are never kept, a lost one is not worth
+/// asking for.
+#[derive(Debug)]
+pub struct RetransmissionBuffer<P> {
+    packets: HashMap<(u32, u16), P>,
+    order: VecDeque<(u32, u16)>,
+    capacity: usize,
+}
+
+impl<P> RetransmissionBuffer<P>
+where
+    P: Deref + Clone,
+    P::Target: AsRef<[u8]>,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Keeps `packet`, dropping the oldest one if full.
+    ///
+    /// Returns false if the packet was already kept, so copies are not forwarded twice.
+    pub fn insert(&mut self, packet: P) -> bool {
+        let Some(id) = (*packet).as_ref().get(8..).and_then(media_id) else {
+            return true;
+        };
+
+        if self.packets.contains_key(&id) {
+            return false;
+        }
+
+        if self.capacity == 0 {
+            return true;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.packets.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(id);
+        self.packets.insert(id, packet);
+        true
+    }
+
+    pub fn get(&self, ssrc: u32, sequence: u16) -> Option<P> {
+        self.packets.get(&(ssrc, sequence)).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// Stream and sequence number of a media packet, parity has none worth keeping.
+pub fn media_id(packet: &[u8]) -> Option<(u32, u16)> {
+    if fec::is_parity(packet) {
+        return None;
+    }
+
+    rtp::packet_id(packet)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        message::{fec::FecEncoder, rtp::RtpPacketBuilder},
+        video::packet_source,
+    };
+
+    use super::RetransmissionBuffer;
+
+    fn packet(sequence: u16) -> Vec<u8> {
+        packet_source::frame(
+            &RtpPacketBuilder::new(&[sequence as u8; 20], 26)
+                .sequence_number(sequence)
+                .build()
+                .transmit_data(),
+        )
+    }
+
+    #[test]
+    fn only_the_latest_media_packets_are_kept() {
+        let mut buffer = RetransmissionBuffer::new(2);
+
+        assert!(buffer.insert(packet(1)));
+        assert!(buffer.insert(packet(2)));
+        assert!(!buffer.insert(packet(2)));
+        assert!(buffer.insert(packet(3)));
+
+        assert_eq!(buffer.get(0, 1), None);
+        assert_eq!(buffer.get(0, 3), Some(packet(3)));
+        assert_eq!(buffer.len(), 2);
+
+        let parity = FecEncoder::new(1).push(&packet(4)[8..]).unwrap();
+        assert!(buffer.insert(packet_source::frame(&parity)));
+        assert_eq!(buffer.get(0, 4), None);
+    }
+}
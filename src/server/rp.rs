@@ -1,25 +1,38 @@
-use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{TcpStream, UdpSocket},
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
 
 use clap::Parser;
+use futures_util::future::join_all;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::{
+    admin::{self, Admin, AdminError},
     message::{
         answer::Answer,
+        auth::{Authenticator, KeyRing},
         metrics::{MetricsRequest, MetricsResponse},
-        query::Query,
-        Status,
+        query::{Query, QueryType},
+        Message, Status,
     },
-    o_node::neighbour::Neighbour,
+    o_node::{
+        export::NodeStatus,
+        neighbour::Neighbour,
+        query_guard::{self, QueryGuard, QueryLimits},
+    },
+    resolve::Endpoint,
+    runtime::{self, TaskPool},
+    settings::{self, QuerySettings, SettingsArgs},
+    shutdown::Shutdown,
+    telemetry,
+    transport::{DatagramSocket, Stream, Transport},
 };
 
 use super::{
-    server_worker::streaming_intermediate_worker::StreamingWorker,
+    errors::StreamingError, server_worker::streaming_intermediate_worker::StreamingWorker,
     transmission_channel::TransmissionChannel,
 };
 
@@ -27,157 +40,561 @@ use super::{
 pub struct RPArgs {
     #[clap(short, long, default_value = "8554")]
     port: u16,
+    /// Metrics address of each content server, its host may be a name
+    #[clap(short, long)]
+    servers: Vec<Endpoint>,
+    /// File with the keys used to authenticate messages
     #[clap(short, long)]
-    servers: Vec<Neighbour>,
+    key_file: Option<String>,
+    /// Queries per second accepted from each source, overrides `queries.rate`
+    #[clap(long, value_parser = query_guard::parse_rate)]
+    query_rate: Option<f64>,
+    /// Queries a source may send at once before being rate limited, overrides `queries.burst`
+    #[clap(long, value_parser = query_guard::parse_burst)]
+    query_burst: Option<f64>,
+    /// Port of the admin interface, only reachable from this host
+    #[clap(long)]
+    admin_port: Option<u16>,
+    /// Port to serve Prometheus metrics on, under /metrics
+    #[clap(long)]
+    prometheus_port: Option<u16>,
+    /// Which logs to show, such as `debug` or `warn,esr_lib::server=trace`, overrides RUST_LOG
+    #[clap(long)]
+    log: Option<String>,
+    #[command(flatten)]
+    settings: SettingsArgs,
+}
+
+impl RPArgs {
+    /// Arguments of an RP on `port` using `servers`, everything else left to the settings.
+    pub fn new(port: u16, servers: Vec<Endpoint>) -> Self {
+        Self {
+            port,
+            servers,
+            key_file: None,
+            query_rate: None,
+            query_burst: None,
+            admin_port: None,
+            prometheus_port: None,
+            log: None,
+            settings: SettingsArgs::default(),
+        }
+    }
+
+    /// File the keys are read from, at startup and on reloads.
+    pub fn with_key_file(mut self, key_file: Option<String>) -> Self {
+        self.key_file = key_file;
+        self
+    }
+
+    pub fn key_file(&self) -> Option<&str> {
+        self.key_file.as_deref()
+    }
+
+    pub fn log_filter(&self) -> Option<&str> {
+        self.log.as_deref()
+    }
+
+    pub fn settings(&self) -> &SettingsArgs {
+        &self.settings
+    }
+
+    pub fn query_limits(&self) -> QueryLimits {
+        self.query_limits_in(&settings::get().queries)
+    }
+
+    /// Limits of `queries`, with the ones given as flags instead.
+    fn query_limits_in(&self, queries: &QuerySettings) -> QueryLimits {
+        let limits = queries.limits();
+
+        QueryLimits {
+            rate: self.query_rate.unwrap_or(limits.rate),
+            burst: self.query_burst.unwrap_or(limits.burst),
+            ..limits
+        }
+    }
+}
+
+/// Metrics connection to a content server, made again when its host name moves.
+#[derive(Debug)]
+struct ServerConnection {
+    endpoint: Endpoint,
+    stream: Mutex<Option<Stream>>,
 }
 
 #[derive(Debug)]
 pub struct RP {
-    content_servers: Vec<Neighbour>,
+    content_servers: Vec<ServerConnection>,
     port: u16,
     transmission_workers: Mutex<HashMap<String, TransmissionChannel>>,
+    auth: Authenticator,
+    guard: QueryGuard,
+    admin_port: Option<u16>,
+    prometheus_port: Option<u16>,
+    transport: Transport,
+    /// Arguments the RP was started with, the files they name are read again on reloads.
+    args: RPArgs,
 }
 
 impl RP {
-    pub fn new(args: RPArgs) -> Self {
+    pub fn new(mut args: RPArgs) -> Self {
+        let guard = QueryGuard::new(args.query_limits());
+
         Self {
-            content_servers: args.servers,
+            content_servers: std::mem::take(&mut args.servers)
+                .into_iter()
+                .map(|endpoint| ServerConnection {
+                    endpoint,
+                    stream: Mutex::new(None),
+                })
+                .collect(),
             port: args.port,
             transmission_workers: Mutex::new(HashMap::new()),
+            auth: Authenticator::default(),
+            guard,
+            admin_port: args.admin_port,
+            prometheus_port: args.prometheus_port,
+            transport: Transport::default(),
+            args,
         }
     }
 
-    fn video_query_service(&self, server_connections: Vec<TcpStream>) {
-        let udp_socket = Arc::new(UdpSocket::bind(("0.0.0.0", self.port)).unwrap());
+    /// Opens every socket of the RP through `transport`.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn with_authenticator(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn authenticator(&self) -> &Authenticator {
+        &self.auth
+    }
+
+    pub fn with_query_limits(mut self, limits: QueryLimits) -> Self {
+        self.guard = QueryGuard::new(limits);
+        self
+    }
+
+    pub fn query_guard(&self) -> &QueryGuard {
+        &self.guard
+    }
+
+    /// Serves admin commands on `port` of the loopback interface.
+    pub fn with_admin_port(mut self, port: Option<u16>) -> Self {
+        self.admin_port = port;
+        self
+    }
+
+    /// Streams relayed by the RP, its neighbours being the connected content servers.
+    pub async fn status(&self) -> NodeStatus {
+        let mut neighbours = Vec::new();
+        for server in &self.content_servers {
+            if let Some(stream) = server.stream.lock().await.as_ref() {
+                neighbours.extend(stream.peer_addr().ok().map(Neighbour::from));
+            }
+        }
+
+        NodeStatus {
+            neighbours,
+            streams: self
+                .transmission_workers
+                .lock()
+                .await
+                .values()
+                .map(TransmissionChannel::status)
+                .collect(),
+        }
+    }
 
+    /// Asks a content server for its metrics about the video in `request`.
+    async fn ask_server(
+        &self,
+        server: &ServerConnection,
+        request: &[u8],
+    ) -> Result<(MetricsResponse, Neighbour), StreamingError> {
+        // The lock is held until the answer arrives so concurrent queries don't mix responses
+        let mut stream = server.stream.lock().await;
+        let Some(connection) = stream.as_mut() else {
+            return Err(StreamingError::ServerNotConnected(
+                server.endpoint.to_string(),
+            ));
+        };
         let mut buffer = [0; 1024];
-        println!(
-            "Video query service listening on port {}",
-            udp_socket.local_addr().unwrap().port()
-        );
 
-        let server_connections: Arc<Vec<Mutex<TcpStream>>> =
-            Arc::new(server_connections.into_iter().map(Mutex::new).collect());
+        let answer = runtime::with_timeout(settings::get().network.metrics_timeout(), async {
+            connection.write_all(request).await?;
+            connection.read(&mut buffer).await
+        })
+        .await;
 
-        std::thread::scope(|s| loop {
-            let (n, addr) = udp_socket.recv_from(&mut buffer).unwrap();
+        let n = match answer {
+            Ok(0) => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Server closed the metrics connection",
+            )),
+            answer => answer,
+        };
 
-            if n == 0 {
-                continue;
+        // A late answer would be taken for the next one, so the connection is made again
+        let n = match n {
+            Ok(n) => n,
+            Err(error) => {
+                *stream = None;
+                return Err(error.into());
             }
+        };
+
+        let server_ip = connection.peer_addr()?.ip();
+        let response: MetricsResponse = self.auth.open(&buffer[..n], server_ip)?;
+
+        let neighbour = Neighbour::new_with_port(server_ip, response.streaming_port());
 
-            let message = &buffer[..n];
+        Ok((response, neighbour))
+    }
 
-            let query: Query = bincode::deserialize(message).unwrap();
+    /// Asks every content server for its metrics about `video`, servers that fail to answer are skipped.
+    async fn ask_servers(
+        &self,
+        video: &str,
+    ) -> Result<Vec<(MetricsResponse, Neighbour)>, StreamingError> {
+        let request = MetricsRequest::new(video.to_string());
+        let request = self.auth.seal(&request);
 
-            let server_connections = Arc::clone(&server_connections);
+        let answers = join_all(
+            self.content_servers
+                .iter()
+                .map(|server| self.ask_server(server, &request)),
+        )
+        .await;
 
-            let udp_socket = Arc::clone(&udp_socket);
-            s.spawn(move || {
-                let video = query
-                    .query_file()
-                    .expect("Expected file on query")
-                    .to_string();
+        Ok(answers
+            .into_iter()
+            .filter_map(|answer| {
+                answer
+                    .map_err(|error| error!("Error receiving metrics from server {}", error))
+                    .ok()
+            })
+            .collect())
+    }
 
-                let workers = self.transmission_workers.lock().unwrap();
-                if workers.contains_key(&video) {
-                    let answer: Answer<Vec<Neighbour>> =
-                        Answer::from_message(query, Vec::new(), Status::Ok);
+    async fn answer_video_query(
+        &self,
+        query: Query,
+    ) -> Result<Answer<Vec<Neighbour>>, StreamingError> {
+        let video = query
+            .query_file()
+            .ok_or(StreamingError::NotAFileQuery)?
+            .to_string();
 
-                    let answer = bincode::serialize(&answer).expect("Error serializing packet");
+        if self.transmission_workers.lock().await.contains_key(&video) {
+            return Ok(Answer::from_message(query, Vec::new(), Status::Ok));
+        }
 
-                    udp_socket.send_to(&answer, addr).unwrap();
-                } else {
-                    let request = MetricsRequest::new(video.to_string());
-                    let request = bincode::serialize(&request).expect("Error serializing packet");
+        let answers: Vec<(MetricsResponse, Neighbour)> = self
+            .ask_servers(&video)
+            .await?
+            .into_iter()
+            .filter(|server| server.0.video_found())
+            .collect();
 
-                    for server in server_connections.iter() {
-                        let mut server = server.lock().unwrap();
-                        if server.write(&request).is_err() {
-                            continue;
-                        }
-                    }
+        let server_to_use = settings::get()
+            .selection
+            .policy
+            .choose(answers, |server| server.0.metric_calculation().into());
 
-                    let mut answers = Vec::new();
-                    let mut count = 0;
-                    while count < server_connections.len() {
-                        let mut buffer = [0; 1024];
-                        let mut server = server_connections[count].lock().unwrap();
-                        if server
-                            .set_read_timeout(Some(Duration::from_secs(1)))
-                            .is_err()
-                        {
-                            continue;
-                        }
-                        let n = server.read(&mut buffer);
-                        if n.is_err() {
-                            continue;
-                        }
-                        let n = n.unwrap();
-                        let _ = server.set_read_timeout(None);
-                        if n == 0 {
-                            continue;
-                        }
-                        let response: MetricsResponse =
-                            bincode::deserialize(&buffer).expect("Error deserializing packet");
+        let answer = if let Some(server) = server_to_use {
+            let server_to_use = server.1;
+            info!(server = ?server_to_use.address(), "Server chosen to contact");
+            Answer::from_message(query, vec![server_to_use], Status::Ok)
+        } else {
+            Answer::from_message(query, vec![], Status::VideoNotFound)
+        };
+
+        Ok(answer)
+    }
 
-                        let neighbour = Neighbour::new_with_port(
-                            server.peer_addr().unwrap().ip(),
-                            response.streaming_port(),
-                        );
-                        answers.push((response, neighbour));
+    /// Answers video queries, at most `network.max_concurrent_tasks` of them at a time.
+    async fn video_query_service(&self, udp_socket: DatagramSocket, shutdown: &Shutdown) {
+        let mut buffer = [0; 1024];
+        info!("Video query service listening on port {}", self.port);
+
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
 
-                        count += 1;
+        loop {
+            let (n, addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => continue,
+                result = udp_socket.recv_from(&mut buffer), if !tasks.is_full() => match result {
+                    Ok(result) => result,
+                    Err(error) => {
+                        error!("Error receiving query {}", error);
+                        continue;
                     }
-                    let mut answers: Vec<(MetricsResponse, Neighbour)> = answers
-                        .into_iter()
-                        .filter(|server| server.0.video_found())
-                        .collect();
-                    answers.sort_by(|s1, s2| {
-                        s2
-                            .0
-                            .metric_calculation()
-                            .partial_cmp(&s1.0.metric_calculation())
-                            .unwrap()
-                    });
-
-                    let server_to_use = answers.into_iter().last();
-
-                    let answer = if let Some(server) = server_to_use {
-                        let server_to_use = server.1;
-                        println!("Server chosen to contact {:?}", server_to_use);
-                        Answer::from_message(query, vec![server_to_use], Status::Ok)
+                },
+            };
+
+            // Every query makes the content servers compute metrics, floods are cut here
+            if n == 0 || !self.guard.allow(addr.ip()) {
+                continue;
+            }
+
+            let query: Query = match self.auth.open(&buffer[..n], addr.ip()) {
+                Ok(query) => query,
+                Err(error) => {
+                    let error = StreamingError::from(error);
+                    telemetry::count_error(error.kind());
+                    warn!("Dropping query from {:?}: {}", addr, error);
+                    continue;
+                }
+            };
+
+            let udp_socket = &udp_socket;
+            let span = info_span!("query", id = query.id(), from = %addr);
+            tasks.push(
+                async move {
+                    let answer = if let QueryType::Status = query.query_type() {
+                        self.auth.seal(&Answer::from_message(
+                            query,
+                            self.status().await,
+                            Status::Ok,
+                        ))
                     } else {
-                        Answer::from_message(query, vec![], Status::VideoNotFound)
+                        let started = Instant::now();
+                        let answer = match self.answer_video_query(query).await {
+                            Ok(answer) => answer,
+                            Err(error) => {
+                                telemetry::count_error(error.kind());
+                                error!("Error answering query from {:?}: {}", addr, error);
+                                return;
+                            }
+                        };
+
+                        let result = if answer.status().is_ok() {
+                            "found"
+                        } else {
+                            "not_found"
+                        };
+                        telemetry::query_answered(result, started.elapsed());
+
+                        if let Some(server) = answer.payload().and_then(|servers| servers.first()) {
+                            telemetry::registry()
+                                .counter(
+                                    "esr_rp_selections_total",
+                                    "Times each content server was chosen to stream a file",
+                                    &[("server", &SocketAddr::from(server.address()).to_string())],
+                                )
+                                .inc();
+                        }
+
+                        debug!("Sending answer: {:?}", &answer);
+                        self.auth.seal(&answer)
                     };
 
-                    println!("Sending answer: {:?}", &answer);
-                    let answer = bincode::serialize(&answer).expect("Error serializing packet");
+                    if let Err(error) = udp_socket.send_to(&answer, addr).await {
+                        error!("Error sending answer to {:?}: {}", addr, error);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+
+        tasks.drain().await;
+        info!("Video query service stopped");
+    }
+
+    /// Connects to the first of `addresses` that accepts the connection.
+    async fn connect_any(&self, addresses: &[SocketAddr]) -> std::io::Result<Stream> {
+        let mut last_error = None;
+
+        for address in addresses {
+            let connection = runtime::with_timeout(
+                settings::get().network.request_timeout(),
+                self.transport.connect(*address),
+            );
+
+            match connection.await {
+                Ok(stream) => return Ok(stream),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error.expect("Hosts resolve to at least one address"))
+    }
+
+    async fn connect(&self, server: &Endpoint) -> Result<Stream, StreamingError> {
+        let addresses = server.lookup().await?;
+        Ok(self.connect_any(&addresses).await?)
+    }
+
+    async fn connect_to_servers(&self) {
+        for server in &self.content_servers {
+            info!(server = %server.endpoint, "Connecting to server");
+
+            let connection = settings::get()
+                .retry
+                .retry(|| self.connect(&server.endpoint));
 
-                    let _ = udp_socket.send_to(&answer, addr).unwrap();
+            match connection.await {
+                Ok(stream) => *server.stream.lock().await = Some(stream),
+                Err(error) => error!("Error connecting to {}: {}", server.endpoint, error),
+            }
+        }
+    }
+
+    /// Connects again to the servers that need it every `network.resolve_interval_ms`.
+    async fn refresh_servers(&self, shutdown: &Shutdown) {
+        let mut interval = tokio::time::interval(settings::get().network.resolve_interval());
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            self.reconnect_servers().await;
+        }
+    }
+
+    /// Connects again to the servers that are not connected or whose host name
+    /// now resolves to other addresses.
+    async fn reconnect_servers(&self) {
+        for server in &self.content_servers {
+            let addresses = match server.endpoint.lookup().await {
+                Ok(addresses) => addresses,
+                Err(error) => {
+                    warn!("Keeping the connection to {}: {}", server.endpoint, error);
+                    continue;
+                }
+            };
+
+            let mut stream = server.stream.lock().await;
+            let connected = stream.as_ref().and_then(|stream| stream.peer_addr().ok());
+            if connected.is_some_and(|address| addresses.contains(&address)) {
+                continue;
+            }
+
+            match self.connect_any(&addresses).await {
+                Ok(connection) => {
+                    info!(server = %server.endpoint, address = ?connection.peer_addr().ok(), "Connected to server");
+                    *stream = Some(connection);
                 }
-            });
-        });
+                Err(error) => warn!("Error connecting to {}: {}", server.endpoint, error),
+            }
+        }
     }
 
-    pub fn run(&self) {
-        let server_connections: Vec<TcpStream> = self
-            .content_servers
-            .iter()
-            .map(|server| {
-                println!("Connecting to server: {:?}", server);
-                TcpStream::connect(server.address()).unwrap()
-            })
-            .collect();
+    /// Connects to the content servers and runs the query and streaming services on the current runtime.
+    pub async fn serve(&self, shutdown: &Shutdown) -> Result<(), StreamingError> {
+        let udp_socket = self.transport.bind_udp(self.port)?;
+        let listener = self.transport.listen(self.port).await?;
+
+        self.connect_to_servers().await;
+
+        let streaming_worker = StreamingWorker::new(
+            self.port,
+            &self.transmission_workers,
+            &self.auth,
+            &self.transport,
+        );
+
+        tokio::join!(
+            self.video_query_service(udp_socket, shutdown),
+            self.refresh_servers(shutdown),
+            streaming_worker.run(listener, shutdown),
+            admin::serve_on(self, self.admin_port, shutdown),
+            telemetry::serve_on(self.prometheus_port, shutdown),
+        );
 
-        std::thread::scope(|s| {
-            s.spawn(|| {
-                self.video_query_service(server_connections);
-            });
+        Ok(())
+    }
+
+    pub fn run(&self, shutdown: &Shutdown) -> Result<(), StreamingError> {
+        runtime::block_on(self.serve(shutdown))
+    }
+}
+
+impl Admin for RP {
+    async fn status(&self) -> serde_json::Value {
+        json!({
+            "port": self.port,
+            "servers": self
+                .content_servers
+                .iter()
+                .map(|server| server.endpoint.to_string())
+                .collect::<Vec<_>>(),
+            "node": self.status().await,
+            "queries": self.guard.counters(),
+            "rejected_messages": self.auth.rejected(),
+        })
+    }
+
+    async fn drop_session(&self, file: &str, client: SocketAddr) -> Result<(), AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.transmission_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .drop_session(file, client)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+
+    async fn record(&self, file: &str, recording: &str) -> Result<(), AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.transmission_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .start_recording(file, recording)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))
+    }
+
+    async fn stop_recording(&self, file: &str) -> Result<String, AdminError> {
+        StreamingWorker::new(
+            self.port,
+            &self.transmission_workers,
+            &self.auth,
+            &self.transport,
+        )
+        .stop_recording(file)
+        .await
+        .map_err(|error| AdminError::Failed(error.to_string()))?
+        .ok_or_else(|| AdminError::NotFound(format!("Recording of {}", file)))
+    }
+
+    /// Reads the key file and the settings again, and connects again to the
+    /// content servers that moved or lost their connection.
+    ///
+    /// Only the query limits are taken from the settings, the others are
+    /// read once at startup.
+    async fn reload(&self) -> Result<(), AdminError> {
+        let settings = self
+            .args
+            .settings()
+            .load()
+            .map_err(|error| AdminError::Failed(error.to_string()))?;
+        let keys = self
+            .args
+            .key_file()
+            .map(KeyRing::from_file)
+            .transpose()
+            .map_err(|error| AdminError::Failed(error.to_string()))?;
+
+        self.guard
+            .set_limits(self.args.query_limits_in(&settings.queries));
+        if let Some(keys) = keys {
+            self.auth.set_keys(keys);
+        }
+        self.reconnect_servers().await;
 
-            s.spawn(|| {
-                StreamingWorker::new(self.port, &self.transmission_workers).run();
-            });
-        });
+        Ok(())
     }
 }
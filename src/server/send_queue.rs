@@ -0,0 +1,488 @@
+//! Paced sending of a stream to each of its destinations.
+//!
+//! Every destination has its own queue, drained no faster than the rate its
+//! congestion controller allows and never above `streaming.max_bitrate_kbps`.
+//! Packets go out at once while a destination keeps up, the others wait in
+//! their queue. A queue that overflows drops its oldest frames, so a slow
+//! destination loses frames rather than holding back the others. Frames a
+//! queue dropped are neither counted as lost on the link nor sent again.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    message::nack::Nack,
+    server::retransmission,
+    settings,
+    telemetry::{self, Counter},
+    transport::DatagramSocket,
+};
+
+/// Time between two passes over the queues of a stream.
+pub const PACING_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Time over which a controller weighs what it sent against what was reported lost.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// Media packets a controller sends before reports are worth weighing.
+const MIN_PACKETS: u64 = 5;
+/// Share of the packets lost above which a link is congested.
+const HIGH_LOSS: f64 = 0.1;
+/// Share of the packets lost under which a link has room to spare.
+const LOW_LOSS: f64 = 0.02;
+/// Rate a controller never goes under, in bytes per second.
+const MIN_RATE: f64 = 8_000.0;
+/// Sending a little faster than this, a controller stops limiting a link.
+const UNLIMITED_ABOVE: f64 = 2.0;
+/// Time a destination may send at once after being idle.
+const BURST: Duration = Duration::from_millis(20);
+/// Frames dropped from a queue that are remembered, to tell them from losses on the link.
+const DROPPED_MEMORY: usize = 256;
+
+/// Loss and delay based rate of a link, in the spirit of Google Congestion Control.
+///
+/// Loss comes from the packets the destination reports lost, delay from the
+/// time packets wait in the queue. High loss cuts the rate in proportion,
+/// a queue growing past `streaming.target_queue_delay_ms` cuts it by a fixed
+/// share, and a link losing next to nothing gets a little more every update.
+///
+/// Only an unlimited link weighs the delay, its queue grows when the socket
+/// cannot keep up. The queue of a limited link grows because of the limit.
+#[derive(Debug)]
+pub struct CongestionController {
+    rate: Option<f64>,
+    cap: Option<f64>,
+    target_delay: Duration,
+    started: Instant,
+    bytes: u64,
+    packets: u64,
+    lost: HashSet<(u32, u16)>,
+    delay: Duration,
+}
+
+impl CongestionController {
+    /// Controls a link sending at most `cap` bytes per second, `None` sends as fast as it can until congested.
+    pub fn new(cap: Option<f64>, target_delay: Duration, now: Instant) -> Self {
+        Self {
+            rate: cap,
+            cap,
+            target_delay,
+            started: now,
+            bytes: 0,
+            packets: 0,
+            lost: HashSet::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Bytes per second the link may send, `None` if it is not limited.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    /// Records a packet sent after waiting `delay` in the queue.
+    pub fn on_sent(&mut self, bytes: usize, media: bool, delay: Duration) {
+        self.bytes += bytes as u64;
+        self.packets += media as u64;
+        self.delay = self.delay.max(delay);
+    }
+
+    /// Records the packets the destination reports lost, each counting once.
+    pub fn on_nack(&mut self, nack: &Nack) {
+        self.lost
+            .extend(nack.lost().iter().map(|&sequence| (nack.ssrc(), sequence)));
+    }
+
+    /// Adjusts the rate to what happened since the last update, at most once per `UPDATE_INTERVAL`.
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed < UPDATE_INTERVAL {
+            return;
+        }
+
+        if self.packets >= MIN_PACKETS {
+            let sent = self.bytes as f64 / elapsed.as_secs_f64();
+            let loss = (self.lost.len() as f64 / self.packets as f64).min(1.0);
+            let rate = self.rate.unwrap_or(sent);
+
+            self.rate = if loss > HIGH_LOSS {
+                Some(rate * (1.0 - loss / 2.0))
+            } else if self.rate.is_none() && self.delay > self.target_delay {
+                Some(rate * 0.85)
+            } else if loss < LOW_LOSS {
+                self.rate.map(|rate| rate * 1.08)
+            } else {
+                self.rate
+            };
+
+            self.rate = match (self.rate, self.cap) {
+                (Some(rate), Some(cap)) => Some(rate.clamp(MIN_RATE.min(cap), cap)),
+                (Some(rate), None) if rate > sent * UNLIMITED_ABOVE => None,
+                (Some(rate), None) => Some(rate.max(MIN_RATE)),
+                (None, _) => None,
+            };
+        }
+
+        self.started = now;
+        self.bytes = 0;
+        self.packets = 0;
+        self.lost.clear();
+        self.delay = Duration::ZERO;
+    }
+}
+
+#[derive(Debug)]
+struct Queued<P> {
+    packet: P,
+    since: Instant,
+    id: Option<(u32, u16)>,
+}
+
+/// Queue of a single destination and the tokens it has to send with.
+#[derive(Debug)]
+struct Link<P> {
+    queue: VecDeque<Queued<P>>,
+    dropped: VecDeque<(u32, u16)>,
+    tokens: f64,
+    refilled: Instant,
+    controller: CongestionController,
+}
+
+impl<P> Link<P>
+where
+    P: Deref,
+    P::Target: AsRef<[u8]>,
+{
+    fn new(cap: Option<f64>, target_delay: Duration, now: Instant) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            dropped: VecDeque::new(),
+            tokens: 0.0,
+            refilled: now,
+            controller: CongestionController::new(cap, target_delay, now),
+        }
+    }
+
+    /// Whether a packet may go out at `now`, the last one may overdraw the tokens.
+    fn may_send(&mut self, now: Instant) -> bool {
+        let Some(rate) = self.controller.rate() else {
+            return true;
+        };
+
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + rate * elapsed).min(rate * BURST.as_secs_f64());
+        self.refilled = now;
+
+        self.tokens > 0.0
+    }
+
+    fn sent(&mut self, bytes: usize, media: bool, delay: Duration) {
+        if self.controller.rate().is_some() {
+            self.tokens -= bytes as f64;
+        }
+
+        self.controller.on_sent(bytes, media, delay);
+    }
+
+    /// Queues `packet`, dropping a frame if the queue is full. Returns whether one was dropped.
+    ///
+    /// Parity goes first, it is of no use once frames of its block are gone.
+    /// Otherwise the oldest frame goes, the newest ones are the ones still worth showing.
+    fn push(&mut self, packet: P, id: Option<(u32, u16)>, capacity: usize, now: Instant) -> bool {
+        let full = self.queue.len() >= capacity.max(1);
+
+        if full {
+            let dropped = match self.queue.iter().position(|queued| queued.id.is_none()) {
+                Some(parity) => self.queue.remove(parity),
+                None => self.queue.pop_front(),
+            };
+
+            if let Some(id) = dropped.and_then(|queued| queued.id) {
+                if self.dropped.len() == DROPPED_MEMORY {
+                    self.dropped.pop_front();
+                }
+                self.dropped.push_back(id);
+            }
+        }
+
+        self.queue.push_back(Queued {
+            packet,
+            since: now,
+            id,
+        });
+
+        full
+    }
+
+    /// Feeds the controller what `nack` reports lost on the link, and returns it.
+    fn on_nack(&mut self, nack: &Nack) -> Nack {
+        let lost = nack
+            .lost()
+            .iter()
+            .copied()
+            .filter(|&sequence| !self.dropped.contains(&(nack.ssrc(), sequence)))
+            .collect();
+        let lost = Nack::new(nack.ssrc(), lost);
+
+        self.controller.on_nack(&lost);
+        lost
+    }
+}
+
+/// Send queues of every destination of a stream.
+#[derive(Debug)]
+pub struct SendQueues<P> {
+    links: Mutex<HashMap<SocketAddr, Link<P>>>,
+    capacity: usize,
+    cap: Option<f64>,
+    target_delay: Duration,
+    forwarded_packets: Arc<Counter>,
+    forwarded_bytes: Arc<Counter>,
+    dropped: Arc<Counter>,
+}
+
+impl<P> SendQueues<P>
+where
+    P: Deref + Clone,
+    P::Target: AsRef<[u8]>,
+{
+    pub fn new(file: &str) -> Self {
+        let streaming = &settings::get().streaming;
+        let (forwarded_packets, forwarded_bytes) = telemetry::forwarded(file);
+
+        Self {
+            links: Mutex::new(HashMap::new()),
+            capacity: streaming.send_queue,
+            cap: (streaming.max_bitrate_kbps > 0)
+                .then(|| streaming.max_bitrate_kbps as f64 * 1000.0 / 8.0),
+            target_delay: streaming.target_queue_delay(),
+            forwarded_packets,
+            forwarded_bytes,
+            dropped: telemetry::dropped_frames(file),
+        }
+    }
+
+    /// Sends `packet` to every destination that keeps up, in a single batch, and queues it for the others.
+    pub fn send(&self, socket: &DatagramSocket, packet: P, destinations: &[SocketAddr]) {
+        let now = Instant::now();
+        let bytes = (*packet).as_ref();
+        let id = bytes.get(8..).and_then(retransmission::media_id);
+        let media = id.is_some();
+        let mut links = self.links.lock().unwrap();
+
+        let mut ready = Vec::with_capacity(destinations.len());
+        for &destination in destinations {
+            let link = links
+                .entry(destination)
+                .or_insert_with(|| Link::new(self.cap, self.target_delay, now));
+
+            if link.queue.is_empty() && link.may_send(now) {
+                ready.push(destination);
+            } else if link.push(packet.clone(), id, self.capacity, now) {
+                self.dropped.inc();
+            }
+        }
+
+        let sent = socket.send_to_all(bytes, &ready);
+        for (n, destination) in ready.iter().enumerate() {
+            let Some(link) = links.get_mut(destination) else {
+                continue;
+            };
+
+            // A busy socket takes the first destinations only, the rest wait their turn
+            if n < sent {
+                link.sent(bytes.len(), media, Duration::ZERO);
+            } else if link.push(packet.clone(), id, self.capacity, now) {
+                self.dropped.inc();
+            }
+        }
+
+        self.forwarded_packets.add(sent as u64);
+        self.forwarded_bytes.add((bytes.len() * sent) as u64);
+    }
+
+    /// Sends what each queue is allowed to by now, and updates the controllers.
+    pub fn flush(&self, socket: &DatagramSocket) {
+        let now = Instant::now();
+        let mut links = self.links.lock().unwrap();
+
+        for (destination, link) in links.iter_mut() {
+            while !link.queue.is_empty() && link.may_send(now) {
+                let Some(queued) = link.queue.pop_front() else {
+                    break;
+                };
+
+                let bytes = (*queued.packet).as_ref();
+                if socket.send_to_all(bytes, &[*destination]) == 0 {
+                    link.queue.push_front(queued);
+                    break;
+                }
+
+                link.sent(bytes.len(), queued.id.is_some(), now - queued.since);
+                self.forwarded_packets.inc();
+                self.forwarded_bytes.add(bytes.len() as u64);
+            }
+
+            link.controller.update(now);
+        }
+    }
+
+    /// Feeds the packets `destination` reports lost to its controller.
+    ///
+    /// Returns the ones lost on the way, worth sending again, leaving out
+    /// those its queue dropped.
+    pub fn on_nack(&self, destination: SocketAddr, nack: &Nack) -> Nack {
+        match self.links.lock().unwrap().get_mut(&destination) {
+            Some(link) => link.on_nack(nack),
+            None => nack.clone(),
+        }
+    }
+
+    /// Bytes per second `destination` is held to, `None` if it is not limited.
+    pub fn rate(&self, destination: SocketAddr) -> Option<f64> {
+        self.links
+            .lock()
+            .unwrap()
+            .get(&destination)
+            .and_then(|link| link.controller.rate())
+    }
+
+    /// Drops the queue of a destination that left.
+    pub fn forget(&self, destination: SocketAddr) {
+        self.links.lock().unwrap().remove(&destination);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::message::nack::Nack;
+
+    use super::{CongestionController, Link, UPDATE_INTERVAL};
+
+    const TARGET: Duration = Duration::from_millis(100);
+
+    fn assert_rate(controller: &CongestionController, expected: f64) {
+        let rate = controller.rate().expect("Link is not limited");
+        assert!(
+            (rate - expected).abs() < 1.0,
+            "{} instead of {}",
+            rate,
+            expected
+        );
+    }
+
+    /// Sends 100 packets of 1000 bytes over one update, of which `lost` are reported lost.
+    fn interval(controller: &mut CongestionController, start: Instant, lost: u16, delay: Duration) {
+        for _ in 0..100 {
+            controller.on_sent(1000, true, delay);
+        }
+        controller.on_nack(&Nack::new(1, (0..lost).collect()));
+        controller.update(start + UPDATE_INTERVAL);
+    }
+
+    #[test]
+    fn losses_and_queueing_slow_links_down() {
+        let start = Instant::now();
+        let mut controller = CongestionController::new(None, TARGET, start);
+
+        interval(&mut controller, start, 0, Duration::ZERO);
+        assert_eq!(controller.rate(), None);
+
+        // Sent at 1 MB/s while the socket could not keep up
+        interval(&mut controller, start + UPDATE_INTERVAL, 0, TARGET * 2);
+        assert_rate(&controller, 850_000.0);
+
+        // and lost 40% of it
+        interval(
+            &mut controller,
+            start + UPDATE_INTERVAL * 2,
+            40,
+            Duration::ZERO,
+        );
+        assert_rate(&controller, 680_000.0);
+
+        // Reports of the same packets count once, and waiting for the limit is no congestion
+        controller.on_nack(&Nack::new(1, vec![0]));
+        interval(&mut controller, start + UPDATE_INTERVAL * 3, 1, TARGET * 2);
+        assert_rate(&controller, 680_000.0 * 1.08);
+    }
+
+    #[test]
+    fn links_back_to_health_are_no_longer_limited() {
+        let start = Instant::now();
+        let mut controller = CongestionController::new(None, TARGET, start);
+
+        interval(&mut controller, start, 50, Duration::ZERO);
+        assert!(controller.rate().is_some());
+
+        let mut now = start;
+        while controller.rate().is_some() {
+            now += UPDATE_INTERVAL;
+            // The stream only needs half of what it used to get
+            for _ in 0..100 {
+                controller.on_sent(500, true, Duration::ZERO);
+            }
+            controller.update(now + UPDATE_INTERVAL);
+            assert!(now - start < UPDATE_INTERVAL * 50);
+        }
+    }
+
+    #[test]
+    fn capped_links_stay_under_the_cap() {
+        let start = Instant::now();
+        let mut controller = CongestionController::new(Some(100_000.0), TARGET, start);
+
+        interval(&mut controller, start, 0, Duration::ZERO);
+        assert_rate(&controller, 100_000.0);
+
+        for n in 1..100 {
+            interval(
+                &mut controller,
+                start + UPDATE_INTERVAL * n,
+                90,
+                Duration::ZERO,
+            );
+        }
+        assert_rate(&controller, 8_000.0);
+    }
+
+    #[test]
+    fn full_queues_drop_parity_then_the_oldest_frames() {
+        let now = Instant::now();
+        let mut link: Link<Box<[u8]>> = Link::new(Some(1000.0), TARGET, now);
+
+        assert!(!link.push(Box::new([1]), Some((1, 1)), 3, now));
+        assert!(!link.push(Box::new([2]), None, 3, now));
+        assert!(!link.push(Box::new([3]), Some((1, 2)), 3, now));
+        assert!(link.push(Box::new([4]), Some((1, 3)), 3, now));
+        assert!(link.push(Box::new([5]), Some((1, 4)), 3, now));
+
+        let left: Vec<u8> = link.queue.iter().map(|queued| queued.packet[0]).collect();
+        assert_eq!(left, vec![3, 4, 5]);
+
+        // The frame dropped here was not lost on the link
+        let lost = link.on_nack(&Nack::new(1, vec![1, 2]));
+        assert_eq!(lost.lost(), &[2]);
+    }
+
+    #[test]
+    fn tokens_pace_the_queue() {
+        let start = Instant::now();
+        let mut link: Link<Box<[u8]>> = Link::new(Some(10_000.0), TARGET, start);
+
+        // Idle links may only send a short burst
+        let later = start + Duration::from_secs(1);
+        assert!(link.may_send(later));
+        link.sent(1000, true, Duration::ZERO);
+        assert!(!link.may_send(later));
+
+        assert!(!link.may_send(later + Duration::from_millis(50)));
+        assert!(link.may_send(later + Duration::from_millis(150)));
+    }
+}
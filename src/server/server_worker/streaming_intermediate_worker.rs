@@ -1,297 +1,1295 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
-    sync::{Arc, Mutex},
+    collections::{hash_map::Entry, HashMap},
+    future::Future,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
 };
 
 use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::{
-    message::rtsp::{RequestType, RtspRequest, RtspResponse},
-    server::transmission_channel::{ClientInfo, TransmissionChannel},
+    message::{
+        auth::Authenticator,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status, StreamingMode},
+        srtp::{KeyRequest, MediaKey},
+    },
+    o_node::neighbour::Neighbour,
+    runtime::{self, TaskPool},
+    server::{
+        edge_cache::EdgeCache,
+        errors::StreamingError,
+        server_worker::streaming_worker::{
+            transmission_worker, video_stream_info::VideoStreamInfo,
+        },
+        transmission_channel::{self, ClientInfo, TransmissionChannel, UpstreamLink},
+    },
+    settings,
+    shutdown::Shutdown,
+    telemetry,
+    transport::{Listener, Stream, Transport},
+    video::{recorder, rendition, video_stream::VideoStream},
 };
+
+/// Key of a channel that serves a single on demand viewer.
+fn on_demand_key(file: &str, client: SocketAddr) -> String {
+    format!("{}@{}", file, client)
+}
+
+/// Answer to a request to start or stop recording, given how it went.
+fn recording_status(result: std::io::Result<()>) -> Status {
+    match result {
+        Ok(()) => Status::Ok,
+        Err(error) => {
+            warn!("Refusing to record: {}", error);
+            match error.kind() {
+                ErrorKind::AlreadyExists
+                | ErrorKind::InvalidInput
+                | ErrorKind::PermissionDenied => Status::Forbidden,
+                _ => Status::ConnectionError,
+            }
+        }
+    }
+}
+
+/// Key a channel is kept under, shared ones are per rendition of the file.
+fn key_for(mode: StreamingMode, file: &str, rendition: Option<u32>, client: SocketAddr) -> String {
+    match mode {
+        StreamingMode::Broadcast => rendition::file(file, rendition),
+        StreamingMode::OnDemand => on_demand_key(file, client),
+    }
+}
+
+/// Finds the channel used by a client, on demand sessions take precedence over shared ones.
+///
+/// Shared channels of every rendition of the file are looked at, as clients
+/// may have been moved to another one.
+fn channel_key(
+    channels: &HashMap<String, TransmissionChannel>,
+    file: &str,
+    client: SocketAddr,
+) -> Option<String> {
+    let on_demand = on_demand_key(file, client);
+
+    if channels.contains_key(&on_demand) {
+        return Some(on_demand);
+    }
+
+    channels
+        .iter()
+        .find(|(_, channel)| {
+            channel.file() == file
+                && channel.mode() == StreamingMode::Broadcast
+                && channel.get_client_info(client).is_some()
+        })
+        .map(|(key, _)| key.clone())
+        .or_else(|| channels.contains_key(file).then(|| file.to_string()))
+}
+
+/// Channel kept under `key`, if it still is the one receiving through `link`.
+///
+/// Channels are let go of while upstream answers, so they may have been
+/// closed or replaced meanwhile.
+fn current<'c>(
+    channels: &'c mut HashMap<String, TransmissionChannel>,
+    key: &str,
+    link: &UpstreamLink,
+) -> Option<&'c mut TransmissionChannel> {
+    channels
+        .get_mut(key)
+        .filter(|channel| channel.link() == *link)
+}
+
+/// Any channel receiving `file`, whatever its rendition.
+fn any_channel<'c>(
+    channels: &'c HashMap<String, TransmissionChannel>,
+    file: &str,
+) -> Option<&'c TransmissionChannel> {
+    channels.values().find(|channel| channel.file() == file)
+}
+
 #[derive(Debug)]
 pub struct StreamingWorker<'a> {
     port: u16,
     transmission_workers: &'a Mutex<HashMap<String, TransmissionChannel>>,
+    auth: &'a Authenticator,
+    transport: &'a Transport,
+    cache: Option<&'a Arc<EdgeCache>>,
+    // Titles being set up, viewers of the same title wait for the first one to share its channel
+    setups: Mutex<HashMap<String, Arc<Mutex<()>>>>,
 }
 
-impl StreamingWorker<'_> {
+impl<'a> StreamingWorker<'a> {
     pub fn new(
         port: u16,
-        transmission_workers: &Mutex<HashMap<String, TransmissionChannel>>,
-    ) -> StreamingWorker {
-        StreamingWorker {
+        transmission_workers: &'a Mutex<HashMap<String, TransmissionChannel>>,
+        auth: &'a Authenticator,
+        transport: &'a Transport,
+    ) -> Self {
+        Self {
             port,
             transmission_workers,
+            auth,
+            transport,
+            cache: None,
+            setups: Mutex::new(HashMap::new()),
         }
     }
 
-    fn streaming_service_worker(&self, mut stream: TcpStream) {
+    /// Caches the titles relayed to on demand viewers in `cache`, and streams them from it.
+    pub fn with_cache(mut self, cache: &'a Arc<EdgeCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    async fn streaming_service_worker(&self, mut stream: Stream, shutdown: &Shutdown) {
+        // Admissions of the viewers a downstream relay serves, held until it disconnects
+        let mut admissions = Vec::new();
+
         loop {
             let mut buffer = [0; 1024];
-            let n = stream.read(&mut buffer).unwrap();
+            let n = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = stream.read(&mut buffer) => match result {
+                    Ok(n) => n,
+                    Err(error) => {
+                        error!("Error reading from downstream node {}", error);
+                        return;
+                    }
+                },
+            };
             if n == 0 {
-                continue;
+                info!("Downstream node closed the connection");
+                return;
             }
 
-            let message: RtspRequest = bincode::deserialize(&buffer[..n]).unwrap();
-            let answer = match message.request_type() {
-                RequestType::Setup => self.process_setup(&mut stream, message),
-                RequestType::Play => self.process_play(&mut stream, message),
-                RequestType::Teardown => self.process_teardown(&mut stream, message),
-                RequestType::Pause => self.process_pause(&mut stream, message),
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(error) => {
+                    error!("Error reading from downstream node {}", error);
+                    return;
+                }
+            };
+
+            let message: RtspRequest = match self.auth.open(&buffer[..n], peer.ip()) {
+                Ok(message) => message,
+                Err(error) => {
+                    let error = StreamingError::from(error);
+                    telemetry::count_error(error.kind());
+                    warn!("Dropping request: {}", error);
+                    continue;
+                }
             };
+            let seq_number = message.seq_number();
+            let span = info_span!(
+                "rtsp",
+                trace = message.trace_id(),
+                file = message.file_request(),
+                cseq = seq_number,
+                from = %peer,
+            );
 
-            let _ = stream.write(&answer).unwrap();
+            let answer = async {
+                let answer = match message.request_type() {
+                    RequestType::Admission => {
+                        self.process_admission(&mut admissions, message).await
+                    }
+                    RequestType::Setup => self.process_setup(&mut stream, message).await,
+                    _ if self.cached_session(peer, &message).is_some() => {
+                        self.process_cached(peer, message).await
+                    }
+                    RequestType::Play => self.process_play(&mut stream, message).await,
+                    RequestType::Teardown => self.process_teardown(&mut stream, message).await,
+                    RequestType::Pause => self.process_pause(&mut stream, message).await,
+                    RequestType::Switch => self.process_switch(&mut stream, message).await,
+                    RequestType::Record => self.process_record(&mut stream, message).await,
+                };
+
+                answer.unwrap_or_else(|error| {
+                    telemetry::count_error(error.kind());
+                    error!("Error processing request {}", error);
+                    RtspResponse::new(Status::ConnectionError, seq_number, seq_number)
+                })
+            }
+            .instrument(span)
+            .await;
+
+            if let Err(error) = stream.write_all(&self.auth.seal(&answer)).await {
+                error!("Error answering downstream node {}", error);
+                return;
+            }
         }
+
+        // Lets the downstream node know it will not receive anything else from us
+        let notification = RtspResponse::new(Status::ServiceUnavailable, 0, 0);
+        let _ = stream.write_all(&self.auth.seal(&notification)).await;
     }
 
-    fn process_pause(&self, stream: &mut TcpStream, request: RtspRequest) -> Vec<u8> {
-        let mut lock_guard = self.transmission_workers.lock().unwrap();
+    async fn process_admission(
+        &self,
+        admissions: &mut Vec<Stream>,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        // Viewers are admitted to a file, whichever rendition they are sent
+        let (upstream, mode, rendition) = {
+            let lock_guard = self.transmission_workers.lock().await;
+            let channel = lock_guard
+                .get(&rendition::file(
+                    request.file_request(),
+                    request.rendition(),
+                ))
+                .or_else(|| any_channel(&lock_guard, request.file_request()))
+                .ok_or_else(|| {
+                    StreamingError::ChannelNotFound(request.file_request().to_string())
+                })?;
 
-        let transmission_worker = lock_guard.get_mut(request.file_request());
+            (channel.upstream()?, channel.mode(), channel.rendition())
+        };
 
-        if let Some(transmission_worker) = transmission_worker {
-            let client_info = transmission_worker.get_client_info(SocketAddr::new(
-                stream.peer_addr().unwrap().ip(),
-                request.port_rtp(),
-            ));
+        let (answer, admission) = transmission_channel::request_admission(
+            self.transport,
+            upstream,
+            request.file_request(),
+            Some(mode),
+            rendition,
+            &request,
+            self.auth,
+        )
+        .await?;
 
-            if let Some(client_info) = client_info {
-                transmission_worker.remove_client_as_playable(client_info);
+        if answer.succeded() {
+            admissions.push(admission);
+        }
 
-                if !transmission_worker.has_worker() {
-                    let request_server = RtspRequest::new(
-                        RequestType::Pause,
-                        request.file_request().to_string(),
-                        request.seq_number(),
-                        transmission_worker.rtp_port(),
-                    );
+        Ok(answer)
+    }
 
-                    let _ = transmission_worker
-                        .send_server_request(request_server)
-                        .unwrap();
-                }
+    /// Key and upstream link of the channel `client` watches `file` on.
+    async fn find(&self, file: &str, client: SocketAddr) -> Option<(String, UpstreamLink)> {
+        let lock_guard = self.transmission_workers.lock().await;
+
+        let key = channel_key(&lock_guard, file, client)?;
+        let link = lock_guard.get(&key)?.link();
+
+        Some((key, link))
+    }
+
+    async fn process_pause(
+        &self,
+        stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let seq_number = request.seq_number();
+        let refused = || RtspResponse::new(Status::ConnectionError, seq_number, seq_number);
+
+        let client_address = SocketAddr::new(stream.peer_addr()?.ip(), request.port_rtp());
+        let Some((key, link)) = self.find(request.file_request(), client_address).await else {
+            return Ok(refused());
+        };
+        let mut upstream = link.lock().await;
+
+        let rtp_port = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+
+            let Some(transmission_worker) = current(&mut lock_guard, &key, &link) else {
+                return Ok(refused());
+            };
+            let Some(client_info) = transmission_worker.get_client_info(client_address) else {
+                return Ok(refused());
+            };
+
+            transmission_worker.remove_client_as_playable(client_info);
+
+            // Upstream stops sending once nobody here plays the stream
+            if transmission_worker.has_worker() {
+                None
+            } else {
+                Some(transmission_worker.rtp_port()?)
+            }
+        };
+
+        if let Some(rtp_port) = rtp_port {
+            let request_server = RtspRequest::new(
+                RequestType::Pause,
+                request.file_request().to_string(),
+                seq_number,
+                rtp_port,
+            )
+            .with_trace_id(request.trace_id());
+
+            let _ = upstream.request(request_server, self.auth).await?;
+        }
+
+        Ok(RtspResponse::new(Status::Ok, seq_number, seq_number))
+    }
+
+    /// Stops relaying `file` to `client`, closing the channel if nobody else watches it.
+    pub async fn drop_session(&self, file: &str, client: SocketAddr) -> Result<(), StreamingError> {
+        if self
+            .cache
+            .is_some_and(|cache| cache.remove_session(&on_demand_key(file, client)))
+        {
+            info!(
+                "Dropped session of {} watching {} from the cache",
+                client, file
+            );
+            return Ok(());
+        }
+
+        let closed = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+
+            let key = channel_key(&lock_guard, file, client)
+                .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+            let channel = lock_guard
+                .get_mut(&key)
+                .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+            let client_info = channel
+                .get_client_info(client)
+                .ok_or_else(|| StreamingError::ClientNotFound(client, file.to_string()))?;
+
+            channel.remove_client_to_room(client_info);
+            info!("Dropped session of {} watching {}", client, file);
+
+            if channel.has_clients() {
+                None
+            } else {
+                lock_guard.remove(&key)
+            }
+        };
+
+        if let Some(mut channel) = closed {
+            channel.close(self.auth).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_teardown(
+        &self,
+        stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let seq_number_client = request.seq_number();
+        let refused = || {
+            RtspResponse::new(
+                Status::ConnectionError,
+                seq_number_client,
+                seq_number_client,
+            )
+        };
+
+        let client_address = SocketAddr::new(stream.peer_addr()?.ip(), request.port_rtp());
+
+        // The channel is let go of before telling upstream, nobody else watches it
+        let (channel, client_info) = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+
+            let Some(key) = channel_key(&lock_guard, request.file_request(), client_address) else {
+                return Ok(refused());
+            };
+            let Some(transmission_worker) = lock_guard.get_mut(&key) else {
+                return Ok(refused());
+            };
+            let Some(client_info) = transmission_worker.get_client_info(client_address) else {
+                return Ok(refused());
+            };
+
+            transmission_worker.remove_client_to_room(client_info);
+
+            if transmission_worker.has_clients() {
+                return Ok(RtspResponse::new(
+                    Status::Ok,
+                    seq_number_client,
+                    seq_number_client,
+                ));
+            }
+
+            let Some(channel) = lock_guard.remove(&key) else {
+                return Ok(refused());
+            };
+            (channel, client_info)
+        };
+
+        let teardown = RtspRequest::new(
+            RequestType::Teardown,
+            request.file_request().to_string(),
+            seq_number_client,
+            channel.rtp_port()?,
+        )
+        .with_trace_id(request.trace_id());
+
+        let answer = channel.send_server_request(teardown, self.auth).await?;
+
+        if !answer.succeded() {
+            return Ok(answer);
+        }
+
+        Ok(RtspResponse::new(
+            Status::Ok,
+            seq_number_client,
+            client_info.session_id(),
+        ))
+    }
+
+    async fn process_play(
+        &self,
+        stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let client_address = SocketAddr::new(stream.peer_addr()?.ip(), request.port_rtp());
+        let (key, link) = self
+            .find(request.file_request(), client_address)
+            .await
+            .ok_or_else(|| StreamingError::ChannelNotFound(request.file_request().to_string()))?;
+
+        let address = ClientInfo::new(client_address, request.seq_number());
+
+        self.start(&key, &link, address, &request).await
+    }
+
+    /// Relays the channel kept under `key` to `client`, asking upstream to play it if nothing is relayed yet.
+    async fn start(
+        &self,
+        key: &str,
+        link: &UpstreamLink,
+        client: ClientInfo,
+        request: &RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let mut upstream = link.lock().await;
+
+        let play = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+            let channel = current(&mut lock_guard, key, link)
+                .ok_or_else(|| StreamingError::ChannelNotFound(key.to_string()))?;
+
+            if channel.has_worker() {
+                channel.add_client_as_playable(client)?;
 
                 let answer = RtspResponse::new(
                     crate::message::rtsp::Status::Ok,
                     request.seq_number(),
                     request.seq_number(),
                 );
-                bincode::serialize(&answer).unwrap()
-            } else {
-                bincode::serialize(&RtspResponse::new(
-                    crate::message::rtsp::Status::ConnectionError,
-                    request.seq_number(),
-                    request.seq_number(),
-                ))
-                .unwrap()
+                return Ok(answer);
             }
-        } else {
-            let answer = RtspResponse::new(
-                crate::message::rtsp::Status::ConnectionError,
-                request.seq_number(),
+
+            RtspRequest::new(
+                RequestType::Play,
+                channel.file().to_string(),
                 request.seq_number(),
-            );
-            bincode::serialize(&answer).unwrap()
+                channel.rtp_port()?,
+            )
+            .with_trace_id(request.trace_id())
+        };
+
+        let answer = upstream.request(play, self.auth).await?;
+
+        if !answer.succeded() {
+            return Ok(answer);
         }
+
+        current(&mut *self.transmission_workers.lock().await, key, link)
+            .ok_or_else(|| StreamingError::ChannelNotFound(key.to_string()))?
+            .create_worker(client)?;
+
+        Ok(answer)
     }
 
-    fn process_teardown(&self, stream: &mut TcpStream, request: RtspRequest) -> Vec<u8> {
-        let mut lock_guard = self.transmission_workers.lock().unwrap();
+    async fn process_switch(
+        &self,
+        stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let client_address = SocketAddr::new(stream.peer_addr()?.ip(), request.port_rtp());
 
-        let transmission_worker = lock_guard.get_mut(request.file_request());
+        self.switch_client(client_address, &request).await
+    }
+
+    /// Starts recording the stream relayed to the client under the name `request` gives, or stops it.
+    async fn process_record(
+        &self,
+        stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let lock_guard = self.transmission_workers.lock().await;
 
-        let seq_number_client = request.seq_number();
         let file = request.file_request();
+        let client_address = SocketAddr::new(stream.peer_addr()?.ip(), request.port_rtp());
+        let channel = channel_key(&lock_guard, file, client_address)
+            .and_then(|key| lock_guard.get(&key))
+            .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+        let client_info = channel
+            .get_client_info(client_address)
+            .ok_or_else(|| StreamingError::ClientNotFound(client_address, file.to_string()))?;
 
-        if let Some(transmission_worker) = transmission_worker {
-            let client_info = transmission_worker.get_client_info(SocketAddr::new(
-                stream.peer_addr().unwrap().ip(),
-                request.port_rtp(),
-            ));
+        let result = match request.recording() {
+            Some(name) => channel.start_recording(name),
+            None => channel.stop_recording().map(|_| ()),
+        };
+
+        Ok(RtspResponse::new(
+            recording_status(result),
+            request.seq_number(),
+            client_info.session_id(),
+        ))
+    }
+
+    /// Starts recording the stream of `file` relayed here to `name`.
+    pub async fn start_recording(&self, file: &str, name: &str) -> Result<(), StreamingError> {
+        let lock_guard = self.transmission_workers.lock().await;
+
+        let channel = lock_guard
+            .get(file)
+            .or_else(|| any_channel(&lock_guard, file))
+            .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+
+        channel
+            .start_recording(name)
+            .map_err(StreamingError::Recording)
+    }
+
+    /// Stops recording the stream of `file`, returns the name it was recorded under.
+    pub async fn stop_recording(&self, file: &str) -> Result<Option<String>, StreamingError> {
+        let lock_guard = self.transmission_workers.lock().await;
+
+        for channel in lock_guard.values().filter(|channel| {
+            channel.file() == file || rendition::file(channel.file(), channel.rendition()) == file
+        }) {
+            if let Some(name) = channel
+                .stop_recording()
+                .map_err(StreamingError::Recording)?
+            {
+                return Ok(Some(name));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Moves `client` to the rendition `request` asks for, without touching the other clients.
+    ///
+    /// On demand channels serve a single client, so the session upstream is
+    /// switched. Shared ones are left for the channel of the other rendition,
+    /// which is opened through the same upstream node if needed.
+    async fn switch_client(
+        &self,
+        client: SocketAddr,
+        request: &RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let file = request.file_request();
+        let seq_number = request.seq_number();
+
+        let (key, link) = self
+            .find(file, client)
+            .await
+            .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+
+        // Channels of a single client, on demand or behind the live stream, switch upstream
+        let single_client = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+            let channel = current(&mut lock_guard, &key, &link)
+                .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+            let client_info = channel
+                .get_client_info(client)
+                .ok_or_else(|| StreamingError::ClientNotFound(client, file.to_string()))?;
+
+            if channel.rendition() == request.rendition() {
+                return Ok(
+                    RtspResponse::new(Status::Ok, seq_number, client_info.session_id())
+                        .with_rendition(request.rendition()),
+                );
+            }
+
+            channel.mode() == StreamingMode::OnDemand || key == on_demand_key(file, client)
+        };
+
+        if single_client {
+            let mut upstream = link.lock().await;
+
+            let rtp_port = current(&mut *self.transmission_workers.lock().await, &key, &link)
+                .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?
+                .rtp_port()?;
+            let switch =
+                RtspRequest::new(RequestType::Switch, file.to_string(), seq_number, rtp_port)
+                    .with_trace_id(request.trace_id())
+                    .with_rendition(request.rendition());
+
+            let answer = upstream.request(switch, self.auth).await?;
 
-            if let Some(client_info) = client_info {
-                transmission_worker.remove_client_to_room(client_info);
-
-                if transmission_worker.has_clients() {
-                    let answer = RtspResponse::new(
-                        crate::message::rtsp::Status::Ok,
-                        request.seq_number(),
-                        request.seq_number(),
-                    );
-                    bincode::serialize(&answer).unwrap()
-                } else {
-                    let request = RtspRequest::new(
-                        RequestType::Teardown,
-                        request.file_request().to_string(),
-                        request.seq_number(),
-                        transmission_worker.rtp_port(),
-                    );
-
-                    let answer = transmission_worker.send_server_request(request).unwrap();
-                    let answer_decode: RtspResponse = bincode::deserialize(&answer).unwrap();
-
-                    lock_guard.remove(file);
-
-                    if !answer_decode.succeded() {
-                        return answer;
+            if answer.succeded() {
+                if let Some(channel) =
+                    current(&mut *self.transmission_workers.lock().await, &key, &link)
+                {
+                    channel.set_rendition(request.rendition());
+                    // The stream goes on with another file
+                    channel.stop_caching();
+                }
+            }
+
+            return Ok(answer);
+        }
+
+        let (playing, upstream, setup) = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+            let channel = current(&mut lock_guard, &key, &link)
+                .ok_or_else(|| StreamingError::ChannelNotFound(file.to_string()))?;
+
+            let setup = RtspRequest::new(
+                RequestType::Setup,
+                file.to_string(),
+                seq_number,
+                client.port(),
+            )
+            .with_mode(Some(StreamingMode::Broadcast))
+            .with_credentials(
+                request
+                    .credentials()
+                    .or_else(|| channel.credentials(client))
+                    .cloned(),
+            )
+            .with_trace_id(request.trace_id())
+            .with_rendition(request.rendition());
+
+            (
+                channel.is_playing(client),
+                Neighbour::from(channel.upstream()?),
+                setup,
+            )
+        };
+
+        let target = rendition::file(file, request.rendition());
+
+        // Setups of the other rendition wait, so the client shares the channel they open or the other way round
+        let answer = self
+            .one_at_a_time(
+                &target,
+                self.move_to(&target, &upstream, client, &setup, playing),
+            )
+            .await?;
+
+        if !answer.succeded() {
+            return Ok(answer);
+        }
+
+        let closed = {
+            let mut lock_guard = self.transmission_workers.lock().await;
+
+            match current(&mut lock_guard, &key, &link) {
+                Some(channel) => {
+                    if let Some(client_info) = channel.get_client_info(client) {
+                        channel.remove_client_to_room(client_info);
                     }
 
-                    bincode::serialize(&RtspResponse::new(
-                        crate::message::rtsp::Status::Ok,
-                        seq_number_client,
-                        client_info.session_id(),
-                    ))
-                    .unwrap()
+                    if channel.has_clients() {
+                        None
+                    } else {
+                        lock_guard.remove(&key)
+                    }
                 }
-            } else {
-                bincode::serialize(&RtspResponse::new(
-                    crate::message::rtsp::Status::ConnectionError,
-                    request.seq_number(),
-                    request.seq_number(),
-                ))
-                .unwrap()
+                None => None,
             }
-        } else {
-            let answer = RtspResponse::new(
-                crate::message::rtsp::Status::ConnectionError,
-                request.seq_number(),
-                request.seq_number(),
-            );
-            bincode::serialize(&answer).unwrap()
+        };
+
+        if let Some(mut channel) = closed {
+            channel.close(self.auth).await?;
         }
+        info!("Moved {} from {} to {}", client, key, target);
+
+        Ok(
+            RtspResponse::new(Status::Ok, seq_number, answer.session_id())
+                .with_rendition(request.rendition()),
+        )
     }
 
-    fn process_play(&self, stream: &mut TcpStream, request: RtspRequest) -> Vec<u8> {
-        let mut lock_guard = self.transmission_workers.lock().unwrap();
+    /// Adds `client` to the channel kept under `target`, opening it through `upstream` if there is none.
+    ///
+    /// The client is played the stream if `playing`.
+    async fn move_to(
+        &self,
+        target: &str,
+        upstream: &Neighbour,
+        client: SocketAddr,
+        setup: &RtspRequest,
+        playing: bool,
+    ) -> Result<RtspResponse, StreamingError> {
+        let (key, answer) = match self.join_channel(client, setup, target).await? {
+            Some(answer) => (target.to_string(), answer),
+            None => {
+                let (mut channel, answer) = self.open_channel(upstream, setup).await?;
 
-        let transmission_worker = lock_guard
-            .get_mut(request.file_request())
-            .expect("Expected Connection Channel");
+                if !answer.succeded() {
+                    return Ok(answer);
+                }
 
-        let port = transmission_worker.rtp_port();
+                channel.add_client_to_room(ClientInfo::new(client, answer.session_id()));
+                channel.set_credentials(client, setup.credentials().cloned());
 
-        let address = ClientInfo::new(
-            SocketAddr::new(stream.peer_addr().unwrap().ip(), request.port_rtp()),
-            request.seq_number(),
-        );
+                let key = key_for(
+                    answer.mode(),
+                    setup.file_request(),
+                    setup.rendition(),
+                    client,
+                );
+                self.insert(key.clone(), channel).await?;
 
-        if transmission_worker.has_worker() {
-            transmission_worker.add_client_as_playable(address);
+                (key, answer)
+            }
+        };
 
-            let answer = RtspResponse::new(
-                crate::message::rtsp::Status::Ok,
-                request.seq_number(),
-                request.seq_number(),
-            );
-            bincode::serialize(&answer).unwrap()
-        } else {
-            let request = RtspRequest::new(
-                RequestType::Play,
-                request.file_request().to_string(),
-                request.seq_number(),
-                port,
+        if !answer.succeded() || !playing {
+            return Ok(answer);
+        }
+
+        let link = self
+            .transmission_workers
+            .lock()
+            .await
+            .get(&key)
+            .map(TransmissionChannel::link)
+            .ok_or_else(|| StreamingError::ChannelNotFound(key.clone()))?;
+        let played = self
+            .start(
+                &key,
+                &link,
+                ClientInfo::new(client, answer.session_id()),
+                setup,
+            )
+            .await?;
+
+        if !played.succeded() {
+            return Ok(played);
+        }
+
+        Ok(answer)
+    }
+
+    /// Moves the clients reporting heavy losses to the next lower rendition, one step at a time.
+    ///
+    /// Only the congested branch of the tree changes, the other clients of
+    /// its channel keep the rendition they had.
+    async fn downgrade_congested(&self) {
+        let percent = settings::get().streaming.downgrade_loss_percent;
+        if percent == 0 {
+            return;
+        }
+
+        let congested: Vec<_> = self
+            .transmission_workers
+            .lock()
+            .await
+            .values()
+            .filter_map(|channel| {
+                let lower = rendition::below(channel.renditions(), channel.rendition())?;
+                let file = channel.file().to_string();
+
+                Some(
+                    channel
+                        .congested(percent)
+                        .into_iter()
+                        .map(move |client| (client, file.clone(), lower)),
+                )
+            })
+            .flatten()
+            .collect();
+
+        for (client, file, lower) in congested {
+            info!(
+                "{} reports heavy losses, downgrading it to {}",
+                client, lower
             );
 
-            let answer = transmission_worker.send_server_request(request).unwrap();
-            let answer_decode: RtspResponse = bincode::deserialize(&answer).unwrap();
+            let switch = RtspRequest::new(RequestType::Switch, file, 0, client.port())
+                .with_rendition(Some(lower));
 
-            if !answer_decode.succeded() {
-                return answer;
+            if let Err(error) = self.switch_client(client, &switch).await {
+                telemetry::count_error(error.kind());
+                warn!("Error downgrading {}: {}", client, error);
             }
+        }
+    }
 
-            transmission_worker.create_worker(address);
+    /// Runs `work` once no other setup of `title` is running.
+    ///
+    /// Viewers of a live broadcast set up one at a time share the channel the
+    /// first one opens.
+    async fn one_at_a_time<T>(&self, title: &str, work: impl Future<Output = T>) -> T {
+        let setup = self
+            .setups
+            .lock()
+            .await
+            .entry(title.to_string())
+            .or_default()
+            .clone();
+        let guard = setup.lock().await;
 
-            answer
+        let result = work.await;
+
+        drop(guard);
+        let mut setups = self.setups.lock().await;
+        // Nobody else is waiting to set up the title
+        if Arc::strong_count(&setup) == 2 {
+            setups.remove(title);
         }
+
+        result
     }
 
-    fn process_setup(&self, client_stream: &mut TcpStream, mut request: RtspRequest) -> Vec<u8> {
-        let mut lock_guard = self.transmission_workers.lock().unwrap();
+    /// Keeps `channel` under `key`, unless another channel was set up as `key` while it was opened.
+    ///
+    /// The session opened upstream for `channel` is then torn down, rather
+    /// than leaving the viewers of the other channel without a stream.
+    async fn insert(
+        &self,
+        key: String,
+        mut channel: TransmissionChannel,
+    ) -> Result<(), StreamingError> {
+        let upstream = channel.upstream().ok();
 
-        let channel = lock_guard.get_mut(request.file_request());
+        let key = match self.transmission_workers.lock().await.entry(key) {
+            Entry::Vacant(entry) => {
+                info!(key = %entry.key(), upstream = ?upstream, "Channel created");
+                entry.insert(channel);
+                return Ok(());
+            }
+            Entry::Occupied(entry) => entry.key().clone(),
+        };
 
-        if let Some(channel) = channel {
-            let session_id = rand::thread_rng().gen();
+        warn!(key = %key, "Another channel was set up meanwhile, tearing this one down");
+        channel.close(self.auth).await?;
+
+        Err(StreamingError::ChannelTaken(key))
+    }
 
-            channel.add_client_to_room(ClientInfo::new(
-                SocketAddr::new(client_stream.peer_addr().unwrap().ip(), request.port_rtp()),
-                session_id,
+    /// Sets up a viewer, without holding up the other requests while upstream answers.
+    ///
+    /// Setups of the same title are done one at a time, so viewers of a live
+    /// broadcast share the channel the first one opens.
+    async fn process_setup(
+        &self,
+        client_stream: &mut Stream,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let seq_number = request.seq_number();
+        if request.port_rtp() == 0 {
+            return Ok(RtspResponse::new(
+                Status::BadRequest,
+                seq_number,
+                seq_number,
             ));
-            println!(
-                "Client added to session as I am already streaming with session_id as: {}",
-                session_id
-            );
+        }
 
-            let answer = RtspResponse::new(
-                crate::message::rtsp::Status::Ok,
-                request.seq_number(),
-                request.seq_number(),
-            );
+        // Titles name files of the cache and of recordings, they can't reach outside their folder
+        let title = rendition::file(request.file_request(), request.rendition());
+        if !recorder::is_valid_name(&title) {
+            warn!("Refusing to set up {}", title);
+            return Ok(RtspResponse::new(
+                Status::FileNotFound,
+                seq_number,
+                seq_number,
+            ));
+        }
+
+        // Viewers behind the live stream get a channel of their own, keyed by client
+        if !request.time_shift().is_zero() {
+            return self.setup(client_stream, request, &title).await;
+        }
+
+        self.one_at_a_time(&title, self.setup(client_stream, request, &title))
+            .await
+    }
+
+    async fn setup(
+        &self,
+        client_stream: &mut Stream,
+        mut request: RtspRequest,
+        title: &str,
+    ) -> Result<RtspResponse, StreamingError> {
+        let client_address = SocketAddr::new(client_stream.peer_addr()?.ip(), request.port_rtp());
+
+        // Only live broadcast channels are shared, on demand viewers and those
+        // watching behind the live stream always get their own
+        if request.mode() != Some(StreamingMode::OnDemand) && request.time_shift().is_zero() {
+            if let Some(answer) = self.join_channel(client_address, &request, title).await? {
+                return Ok(answer);
+            }
+        }
+
+        if let Some(answer) = self.setup_cached(client_address, &request).await? {
+            return Ok(answer);
+        }
+
+        // A node already relaying the file is not given a path, it reuses its own upstream,
+        // and one that cached it the content server it came from
+        let relayed_from = any_channel(
+            &*self.transmission_workers.lock().await,
+            request.file_request(),
+        )
+        .and_then(|channel| channel.upstream().ok());
+        let server_to_contact = request
+            .next_server()
+            .or_else(|| relayed_from.map(Neighbour::from))
+            .or_else(|| {
+                self.cache
+                    .and_then(|cache| cache.origin(title))
+                    .map(Neighbour::from)
+            })
+            .ok_or_else(|| StreamingError::NoServerToContact(request.file_request().to_string()))?;
+
+        let (mut channel, answer) = self.open_channel(&server_to_contact, &request).await?;
+
+        if !answer.succeded() {
+            return Ok(answer);
+        }
+
+        let client_info = ClientInfo::new(client_address, answer.session_id());
 
-            bincode::serialize(&answer).unwrap()
+        channel.add_client_to_room(client_info);
+        channel.set_credentials(client_address, request.credentials().cloned());
+
+        // Paths end with the content server
+        if let (Some(cache), Some(frames)) = (self.cache, answer.frames()) {
+            if answer.mode() == StreamingMode::OnDemand && request.time_shift().is_zero() {
+                let origin = request
+                    .servers_to_connect()
+                    .first()
+                    .unwrap_or(&server_to_contact)
+                    .address()
+                    .into();
+                channel.cache_to(cache.fill(title, frames, origin));
+            }
+        }
+
+        let key = if request.time_shift().is_zero() {
+            key_for(
+                answer.mode(),
+                request.file_request(),
+                request.rendition(),
+                client_address,
+            )
         } else {
-            let server_to_contact = request.next_server().expect("Expected server to contact");
-            let server_stream = TcpStream::connect(server_to_contact.address()).unwrap();
+            on_demand_key(request.file_request(), client_address)
+        };
 
-            let udp_socket = Arc::new(UdpSocket::bind(("0.0.0.0", 0)).unwrap());
+        self.insert(key, channel).await?;
 
-            let port = udp_socket.local_addr().unwrap().port();
+        Ok(answer)
+    }
 
-            let mut channel = TransmissionChannel::new(server_stream, udp_socket, vec![]);
+    /// Adds `client` to the live broadcast of `title` this node already relays, once upstream admits it.
+    ///
+    /// Returns `None` when the title is not relayed, for a channel to be opened.
+    async fn join_channel(
+        &self,
+        client: SocketAddr,
+        request: &RtspRequest,
+        title: &str,
+    ) -> Result<Option<RtspResponse>, StreamingError> {
+        let (link, upstream, file, mode, rendition) = {
+            let lock_guard = self.transmission_workers.lock().await;
+            match lock_guard.get(title) {
+                Some(channel) => (
+                    channel.link(),
+                    channel.upstream()?,
+                    channel.file().to_string(),
+                    channel.mode(),
+                    channel.rendition(),
+                ),
+                None => return Ok(None),
+            }
+        };
 
-            let request_server = RtspRequest::new_with_servers(
-                RequestType::Setup,
-                request.file_request().to_string(),
-                request.seq_number(),
-                port,
-                request.servers_to_connect().clone(),
-            );
-            println!(
-                "Contacting server: {:?},  with {:?}",
-                server_to_contact.address(),
-                request_server
-            );
+        // Only the content server knows whether this viewer may watch the stream
+        let (answer, admission) = transmission_channel::request_admission(
+            self.transport,
+            upstream,
+            &file,
+            Some(mode),
+            rendition,
+            request,
+            self.auth,
+        )
+        .await?;
+
+        if !answer.succeded() {
+            return Ok(Some(answer));
+        }
+
+        let mut lock_guard = self.transmission_workers.lock().await;
+        // The last viewer left while upstream was answering, the channel is opened again
+        let Some(channel) = current(&mut lock_guard, title, &link) else {
+            return Ok(None);
+        };
+
+        let session_id = rand::thread_rng().gen();
+
+        channel.add_client_to_room(ClientInfo::new(client, session_id));
+        channel.admit(client, admission);
+        channel.set_credentials(client, request.credentials().cloned());
+        info!(
+            "Client added to session as I am already streaming with session_id as: {}",
+            session_id
+        );
+
+        Ok(Some(
+            RtspResponse::new(Status::Ok, request.seq_number(), session_id)
+                .with_mode(channel.mode())
+                .with_media_keys(answer.media_keys().to_vec())
+                .with_rendition(channel.rendition())
+                .with_renditions(channel.renditions().to_vec()),
+        ))
+    }
+
+    /// Streams `request`'s title to `client` from the cache, once its content server admits the viewer.
+    ///
+    /// Returns `None` when the title is not cached, or the content server
+    /// broadcasts it, for the session to be relayed as usual.
+    async fn setup_cached(
+        &self,
+        client: SocketAddr,
+        request: &RtspRequest,
+    ) -> Result<Option<RtspResponse>, StreamingError> {
+        let Some(cache) = self.cache else {
+            return Ok(None);
+        };
+        if request.mode() == Some(StreamingMode::Broadcast) || !request.time_shift().is_zero() {
+            return Ok(None);
+        }
+
+        let title = rendition::file(request.file_request(), request.rendition());
+        let Some((path, origin)) = cache.lookup(&title) else {
+            return Ok(None);
+        };
+
+        let (answer, admission) = transmission_channel::request_admission(
+            self.transport,
+            origin,
+            request.file_request(),
+            request.mode(),
+            request.rendition(),
+            request,
+            self.auth,
+        )
+        .await?;
+
+        if !answer.succeded() {
+            return Ok(Some(answer));
+        }
+        if answer.mode() == StreamingMode::Broadcast {
+            return Ok(None);
+        }
+
+        let media_key = MediaKey::generate();
+        let video_info =
+            VideoStreamInfo::new(&title, VideoStream::open(&path)?, Vec::new(), &media_key);
+        let channel = transmission_worker::TransmissionChannel::new(
+            Arc::new(self.transport.bind_udp(0)?),
+            Arc::new(video_info),
+            StreamingMode::OnDemand,
+        )
+        .with_title(request.file_request());
 
-            let answer = channel.send_server_request(request_server).unwrap();
+        let key = on_demand_key(request.file_request(), client);
+        info!(key = %key, "Streaming {} from the cache", title);
+        cache.add_session(key, &title, Arc::new(channel), admission);
 
-            let answer_decode: RtspResponse = bincode::deserialize(&answer).unwrap();
+        Ok(Some(
+            RtspResponse::new(Status::Ok, request.seq_number(), rand::thread_rng().gen())
+                .with_mode(StreamingMode::OnDemand)
+                .with_media_keys(media_key.wrap(request.key_shares()))
+                .with_rendition(request.rendition()),
+        ))
+    }
+
+    /// Session streamed from the cache to the sender of `request`, connected from `peer`.
+    fn cached_session(
+        &self,
+        peer: SocketAddr,
+        request: &RtspRequest,
+    ) -> Option<Arc<transmission_worker::TransmissionChannel>> {
+        let client = SocketAddr::new(peer.ip(), request.port_rtp());
 
-            if !answer_decode.succeded() {
-                return answer;
+        self.cache?
+            .session(&on_demand_key(request.file_request(), client))
+    }
+
+    /// Plays, pauses, records or ends a session streamed from the cache.
+    ///
+    /// Sessions stay in the rendition they were set up in.
+    async fn process_cached(
+        &self,
+        peer: SocketAddr,
+        request: RtspRequest,
+    ) -> Result<RtspResponse, StreamingError> {
+        let file = request.file_request();
+        let client = SocketAddr::new(peer.ip(), request.port_rtp());
+        let (Some(cache), Some(channel)) = (self.cache, self.cached_session(peer, &request)) else {
+            return Err(StreamingError::ChannelNotFound(file.to_string()));
+        };
+
+        let status = match request.request_type() {
+            RequestType::Play => {
+                channel.add_client((client.ip(), client.port()));
+                channel.start();
+                Status::Ok
+            }
+            RequestType::Pause => {
+                channel.remove_client((client.ip(), client.port()));
+                Status::Ok
             }
+            RequestType::Teardown => {
+                cache.remove_session(&on_demand_key(file, client));
+                Status::Ok
+            }
+            RequestType::Record => recording_status(match request.recording() {
+                Some(name) => channel.start_recording(name),
+                None => channel.stop_recording().map(|_| ()),
+            }),
+            RequestType::Setup | RequestType::Switch | RequestType::Admission => Status::Forbidden,
+        };
 
-            let client_info = ClientInfo::new(
-                SocketAddr::new(client_stream.peer_addr().unwrap().ip(), request.port_rtp()),
-                answer_decode.session_id(),
-            );
+        Ok(RtspResponse::new(
+            status,
+            request.seq_number(),
+            request.seq_number(),
+        ))
+    }
+
+    /// Opens a channel receiving `request`'s file from `upstream`, along with upstream's answer.
+    async fn open_channel(
+        &self,
+        upstream: &Neighbour,
+        request: &RtspRequest,
+    ) -> Result<(TransmissionChannel, RtspResponse), StreamingError> {
+        let server_stream = runtime::with_timeout(
+            settings::get().network.request_timeout(),
+            self.transport.connect(upstream.address()),
+        )
+        .await?;
+
+        let udp_socket = Arc::new(self.transport.bind_udp(0)?);
+
+        let port = udp_socket.local_addr()?.port();
+
+        let mut channel = TransmissionChannel::new(
+            request.file_request().to_string(),
+            server_stream,
+            udp_socket,
+            vec![],
+        )
+        .with_transport(self.transport.clone())
+        .with_rendition(request.rendition());
+
+        // Only relays reading the stream ask for its key, the viewers' are sealed for them
+        let key_request = self.keeps_keys().then(KeyRequest::new);
+        let mut key_shares = request.key_shares().to_vec();
+        key_shares.extend(key_request.as_ref().map(KeyRequest::share));
+
+        let request_server = RtspRequest::new_with_servers(
+            RequestType::Setup,
+            request.file_request().to_string(),
+            request.seq_number(),
+            port,
+            request.servers_to_connect().clone(),
+        )
+        .with_mode(request.mode())
+        .with_credentials(request.credentials().cloned())
+        .with_trace_id(request.trace_id())
+        .with_rendition(request.rendition())
+        .with_time_shift(request.time_shift())
+        .with_key_shares(key_shares);
+        debug!(
+            "Contacting server: {:?},  with {:?}",
+            upstream.address(),
+            request_server
+        );
+
+        let answer = channel
+            .send_server_request(request_server, self.auth)
+            .await?;
+
+        channel.set_mode(answer.mode());
+        channel.set_media_key(key_request.and_then(|request| request.open(answer.media_keys())));
+        channel.set_renditions(answer.renditions().to_vec());
+
+        Ok((channel, answer))
+    }
+
+    /// Whether this relay reads the streams it relays, to cache or record them.
+    ///
+    /// Stream keys are otherwise sealed for the viewers, and relays forward them
+    /// without being able to open them.
+    fn keeps_keys(&self) -> bool {
+        self.cache.is_some_and(|cache| cache.is_enabled())
+            || settings::get().streaming.record_on_relays
+    }
 
-            channel.add_client_to_room(client_info);
+    /// Relays streams to downstream nodes, at most `network.max_concurrent_tasks` connections at a time.
+    pub async fn run(&self, tcp_socket: Listener, shutdown: &Shutdown) {
+        info!("Streaming service listening on port {}", self.port);
 
-            println!("Channel created {:?}", channel);
-            lock_guard.insert(request.file_request().to_string(), channel);
+        // Downgrades wait on the connections like any request, so they run
+        // alongside the connections instead of keeping them from being polled
+        tokio::join!(
+            self.serve_connections(tcp_socket, shutdown),
+            self.downgrade_on_reports(shutdown),
+        );
+
+        self.close_channels().await;
+    }
 
-            answer
+    async fn serve_connections(&self, tcp_socket: Listener, shutdown: &Shutdown) {
+        let mut tasks = TaskPool::new(settings::get().network.max_concurrent_tasks);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tasks.next() => {}
+                result = tcp_socket.accept(), if !tasks.is_full() => match result {
+                    Ok((stream, _)) => tasks.push(self.streaming_service_worker(stream, shutdown)),
+                    Err(error) => error!("Error accepting connection {}", error),
+                },
+            }
         }
+
+        tasks.drain().await;
     }
 
-    pub fn run(&self) {
-        std::thread::scope(|s| {
-            let tcp_socket = TcpListener::bind(("0.0.0.0", self.port)).unwrap();
-            println!(
-                "Streaming service listening on port {}",
-                tcp_socket.local_addr().unwrap().port()
-            );
+    /// Downgrades the congested clients every `streaming.report_interval_ms`, until shutdown.
+    async fn downgrade_on_reports(&self, shutdown: &Shutdown) {
+        let mut reports = tokio::time::interval(
+            settings::get()
+                .streaming
+                .report_interval()
+                .max(Duration::from_millis(1)),
+        );
 
-            for stream in tcp_socket.incoming() {
-                let stream = stream.unwrap();
-                s.spawn(move || {
-                    self.streaming_service_worker(stream);
-                });
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = reports.tick() => self.downgrade_congested().await,
             }
-        });
+        }
+    }
+
+    async fn close_channels(&self) {
+        if let Some(cache) = self.cache {
+            cache.close_sessions();
+        }
+
+        let channels: Vec<_> = self.transmission_workers.lock().await.drain().collect();
+
+        for (key, mut channel) in channels {
+            info!("Closing channel {}", key);
+            if let Err(error) = channel.close(self.auth).await {
+                error!("Error sending teardown upstream for {}: {}", key, error);
+            }
+        }
     }
 }
@@ -1,16 +1,32 @@
 use std::{
     collections::HashMap,
-    io::{Read, Write},
-    net::{IpAddr, TcpStream, UdpSocket},
+    io::ErrorKind,
+    net::IpAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
 use crate::{
-    message::rtsp::{RequestType, RtspRequest, RtspResponse, Status},
-    server::server_worker::streaming_worker::video_stream_info::VideoStreamInfo,
-    video::video_stream::VideoStream,
+    message::{
+        auth::Authenticator,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status, StreamingMode},
+        srtp::{MasterKey, MediaKey},
+    },
+    server::{
+        access::{AccessControl, Session},
+        errors::StreamingError,
+        server_worker::streaming_worker::video_stream_info::VideoStreamInfo,
+        StreamingPolicy,
+    },
+    settings,
+    shutdown::Shutdown,
+    telemetry,
+    transport::{Stream, Transport},
+    video::{rendition, video_stream::VideoStream},
 };
 
 use transmission_worker::TransmissionChannel;
@@ -30,78 +46,256 @@ struct ClientInfo {
     ip_address: IpAddr,
     rtp_port: u16,
     session_id: u32,
+    mode: StreamingMode,
+    video_file: String,
+    rendition: Option<u32>,
+    time_shift: Duration,
+    media_key: MediaKey,
+}
+
+impl ClientInfo {
+    /// File actually streamed, the rendition of the file the viewer asked for.
+    fn stream_file(&self) -> String {
+        rendition::file(&self.video_file, self.rendition)
+    }
 }
 
 #[derive(Debug)]
 pub struct StreamingWorker<'a> {
-    rtsp_socket: TcpStream,
+    rtsp_socket: Stream,
     server_state: ServerState,
     client_info: Option<ClientInfo>,
     video_workers: &'a Mutex<HashMap<String, Arc<TransmissionChannel>>>,
+    on_demand_sessions: &'a Mutex<HashMap<u32, Arc<TransmissionChannel>>>,
+    streaming_policy: &'a StreamingPolicy,
+    auth: &'a Authenticator,
+    master_key: &'a MasterKey,
+    access: &'a AccessControl,
+    session: Option<Session<'a>>,
+    admissions: Vec<Session<'a>>,
+    transport: Transport,
 }
 
 impl<'a> StreamingWorker<'a> {
     pub fn new(
-        rtsp_socket: TcpStream,
+        rtsp_socket: Stream,
         video_workers: &'a Mutex<HashMap<String, Arc<TransmissionChannel>>>,
+        on_demand_sessions: &'a Mutex<HashMap<u32, Arc<TransmissionChannel>>>,
+        streaming_policy: &'a StreamingPolicy,
+        auth: &'a Authenticator,
+        master_key: &'a MasterKey,
+        access: &'a AccessControl,
     ) -> Self {
         Self {
             rtsp_socket,
             server_state: ServerState::Init,
             client_info: None,
             video_workers,
+            on_demand_sessions,
+            streaming_policy,
+            auth,
+            master_key,
+            access,
+            session: None,
+            admissions: Vec::new(),
+            transport: Transport::default(),
         }
     }
 
-    fn handle_client(&mut self, video_file: &str) -> std::io::Result<()> {
-        let mut lock = self.video_workers.lock().unwrap();
+    /// Transport the rtp sockets of new channels are bound through.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Channel without clients yet, only broadcasts keep a history to watch them behind the live stream.
+    fn create_channel(
+        transport: &Transport,
+        title: &str,
+        video_file: &str,
+        mode: StreamingMode,
+        media_key: &MediaKey,
+        position: u32,
+    ) -> std::io::Result<Arc<TransmissionChannel>> {
+        let mut stream = VideoStream::new(video_file)?;
+        stream.skip_to(position)?;
+
+        let streaming = &settings::get().streaming;
+        let history = match mode {
+            StreamingMode::Broadcast => {
+                streaming.frames_in(Duration::from_secs(streaming.time_shift_window_s))
+            }
+            StreamingMode::OnDemand => 0,
+        };
+
+        let video_info = Arc::new(
+            VideoStreamInfo::new(video_file, stream, Vec::new(), media_key).with_history(history),
+        );
+
+        let rtp_socket = Arc::new(transport.bind_udp(0)?);
+
+        Ok(Arc::new(
+            TransmissionChannel::new(rtp_socket, video_info, mode).with_title(title),
+        ))
+    }
 
-        let worker = lock.get(video_file);
-        let client_info = self.client_info.as_ref().unwrap();
+    /// Starts sending the stream to this connection's viewer, new channels start at `position`.
+    fn handle_client(&mut self, position: u32) -> Result<(), StreamingError> {
+        let client_info = self
+            .client_info
+            .as_ref()
+            .ok_or(StreamingError::SessionNotSetup)?;
 
         let address = (client_info.ip_address, client_info.rtp_port);
+        let video_file = &client_info.stream_file();
 
-        if let Some(worker) = worker {
-            worker.add_client(address);
-        } else {
-            let addresses = vec![address];
+        match client_info.mode {
+            StreamingMode::Broadcast => {
+                let mut lock = self.video_workers.lock().unwrap();
 
-            let stream = VideoStream::new(video_file)?;
+                let worker = match lock.get(video_file) {
+                    Some(worker) => Arc::clone(worker),
+                    None => {
+                        let worker = Self::create_channel(
+                            &self.transport,
+                            &client_info.video_file,
+                            video_file,
+                            client_info.mode,
+                            &client_info.media_key,
+                            position,
+                        )?;
+                        lock.insert(video_file.to_string(), Arc::clone(&worker));
+                        worker
+                    }
+                };
+
+                let behind = settings::get().streaming.frames_in(client_info.time_shift);
+                if behind > 0 {
+                    let behind = worker.add_delayed_client(address, behind);
+                    info!("Watching {} frames behind the live stream", behind);
+                } else {
+                    worker.add_client(address);
+                }
+                worker.start();
+            }
+            StreamingMode::OnDemand => {
+                let mut lock = self.on_demand_sessions.lock().unwrap();
+
+                // Resuming a paused session keeps the position where the viewer stopped
+                let session = match lock.get(&client_info.session_id) {
+                    Some(session) => Arc::clone(session),
+                    None => {
+                        let session = Self::create_channel(
+                            &self.transport,
+                            &client_info.video_file,
+                            video_file,
+                            client_info.mode,
+                            &client_info.media_key,
+                            position,
+                        )?;
+                        lock.insert(client_info.session_id, Arc::clone(&session));
+                        session
+                    }
+                };
 
-            let video_info = Arc::new(VideoStreamInfo::new(stream, addresses));
+                session.add_client(address);
+                session.start();
+            }
+        }
+        Ok(())
+    }
 
-            let rtp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0")?);
+    /// Stops sending the stream to this connection's viewer.
+    fn release_client(&self) -> Result<(), StreamingError> {
+        let client_info = self
+            .client_info
+            .as_ref()
+            .ok_or(StreamingError::SessionNotSetup)?;
 
-            let worker = Arc::new(TransmissionChannel::new(rtp_socket, video_info));
+        let address = (client_info.ip_address, client_info.rtp_port);
 
-            let worker_clone = Arc::clone(&worker);
+        match client_info.mode {
+            StreamingMode::Broadcast => {
+                let mut lock = self.video_workers.lock().unwrap();
+                let video_file = client_info.stream_file();
 
-            std::thread::spawn(move || {
-                worker_clone.run();
-            });
+                let worker = lock
+                    .get(&video_file)
+                    .ok_or_else(|| StreamingError::ChannelNotFound(video_file.clone()))?;
+
+                if worker.remove_client(address) == 0 {
+                    debug!("Removing worker");
+                    lock.remove(&video_file);
+                }
+            }
+            StreamingMode::OnDemand => {
+                let mut lock = self.on_demand_sessions.lock().unwrap();
 
-            lock.insert(video_file.to_string(), worker);
+                if let Some(session) = lock.remove(&client_info.session_id) {
+                    info!("Removing on demand session {}", client_info.session_id);
+                    session.remove_client(address);
+                }
+            }
         }
+
         Ok(())
     }
 
-    fn process_rtsp_request(&mut self, request: RtspRequest) -> std::io::Result<()> {
+    async fn process_rtsp_request(&mut self, request: RtspRequest) -> Result<(), StreamingError> {
         match request.request_type() {
             RequestType::Setup => {
+                // Nothing can be streamed to port 0, relays ask for admissions with ADMISSION
+                if request.port_rtp() == 0 {
+                    let response = RtspResponse::new(Status::BadRequest, request.seq_number(), 0);
+                    return Ok(self.reply_rtsp(response).await?);
+                }
+
                 if let ServerState::Init = self.server_state {
-                    println!("Processing setup");
+                    debug!("Processing setup");
 
                     let mut rng = rand::thread_rng();
 
                     let session_id = rng.gen_range(100000..999999);
+                    Span::current().record("session", session_id);
+
+                    let session = match self
+                        .access
+                        .open_session(request.credentials(), request.file_request())
+                    {
+                        Ok(session) => session,
+                        Err(error) => {
+                            warn!("Refusing setup: {}", error);
+                            let response =
+                                RtspResponse::new(error.status(), request.seq_number(), 0);
+
+                            return Ok(self.reply_rtsp(response).await?);
+                        }
+                    };
+
+                    let mode = self
+                        .streaming_policy
+                        .mode_for(request.file_request(), request.mode());
+                    info!("Streaming {} in {} mode", request.file_request(), mode);
+
+                    // Viewers of a broadcast share its stream, so they must share its key too
+                    let media_key = match mode {
+                        StreamingMode::Broadcast => self.master_key.derive(request.file_request()),
+                        StreamingMode::OnDemand => MediaKey::generate(),
+                    };
 
                     self.client_info = Some(ClientInfo {
-                        ip_address: self.rtsp_socket.peer_addr().unwrap().ip(),
+                        ip_address: self.rtsp_socket.peer_addr()?.ip(),
                         rtp_port: request.port_rtp(),
                         session_id,
+                        mode,
+                        video_file: request.file_request().to_string(),
+                        rendition: request.rendition(),
+                        time_shift: request.time_shift(),
+                        media_key: media_key.clone(),
                     });
 
-                    if !VideoStream::file_exists(request.file_request()) {
+                    let video_file = rendition::file(request.file_request(), request.rendition());
+                    if !VideoStream::file_exists(&video_file) {
                         let response = RtspResponse::new(
                             Status::FileNotFound,
                             request.seq_number(),
@@ -109,106 +303,363 @@ impl<'a> StreamingWorker<'a> {
                         );
 
                         self.client_info = None;
-                        self.reply_rtsp(response)?;
+                        return Ok(self.reply_rtsp(response).await?);
                     }
 
-                    let response = RtspResponse::new(Status::Ok, request.seq_number(), session_id);
+                    // Relays cache the titles they relay whole to on demand viewers
+                    let frames = match mode {
+                        StreamingMode::Broadcast => None,
+                        StreamingMode::OnDemand => VideoStream::count_frames(&video_file).ok(),
+                    };
+
+                    let response = RtspResponse::new(Status::Ok, request.seq_number(), session_id)
+                        .with_mode(mode)
+                        .with_media_keys(media_key.wrap(request.key_shares()))
+                        .with_rendition(request.rendition())
+                        .with_renditions(rendition::list(request.file_request()))
+                        .with_frames(frames);
 
                     self.server_state = ServerState::Ready;
+                    self.session = Some(session);
 
-                    self.reply_rtsp(response)?;
+                    self.reply_rtsp(response).await?;
+                } else {
+                    self.refuse(&request).await?;
                 }
             }
+            // Sessions play from Ready, which a setup or a pause leaves them in
             RequestType::Play => {
                 if let ServerState::Ready = self.server_state {
-                    self.process_play(request)?;
+                    self.process_play(request).await?;
+                } else {
+                    self.refuse(&request).await?;
                 }
             }
             RequestType::Teardown => {
-                println!("Processing teardown");
+                debug!("Processing teardown");
+
+                let session_id = self
+                    .client_info
+                    .as_ref()
+                    .ok_or(StreamingError::SessionNotSetup)?
+                    .session_id;
 
-                let client_info = self.client_info.as_ref().unwrap();
+                let response = RtspResponse::new(Status::Ok, request.seq_number(), session_id);
+
+                self.release_client()?;
+                self.client_info = None;
+                self.session = None;
+                self.server_state = ServerState::Init;
+
+                self.reply_rtsp(response).await?;
+            }
+            RequestType::Pause => {
+                debug!("Processing Pause");
+
+                let client_info = self
+                    .client_info
+                    .as_ref()
+                    .ok_or(StreamingError::SessionNotSetup)?;
 
                 let response =
                     RtspResponse::new(Status::Ok, request.seq_number(), client_info.session_id);
 
                 let address = (client_info.ip_address, client_info.rtp_port);
 
-                let mut lock = self.video_workers.lock().unwrap();
+                match client_info.mode {
+                    StreamingMode::Broadcast => {
+                        let lock = self.video_workers.lock().unwrap();
+                        let video_file = client_info.stream_file();
 
-                let worker = lock.get(request.file_request()).unwrap();
+                        let worker = lock
+                            .get(&video_file)
+                            .ok_or(StreamingError::ChannelNotFound(video_file))?;
+                        worker.remove_client(address);
+                    }
+                    StreamingMode::OnDemand => {
+                        let lock = self.on_demand_sessions.lock().unwrap();
 
-                if worker.remove_client(address) == 0 {
-                    println!("Removing worker");
-                    lock.remove(request.file_request()).unwrap();
+                        if let Some(session) = lock.get(&client_info.session_id) {
+                            session.remove_client(address);
+                        }
+                    }
                 }
 
-                self.reply_rtsp(response)?;
+                self.server_state = ServerState::Ready;
+                self.reply_rtsp(response).await?;
             }
-            RequestType::Pause => {
-                println!("Processing Pause");
+            RequestType::Switch => self.process_switch(request).await?,
+            RequestType::Record => self.process_record(request).await?,
+            // Relays ask for admission before letting a viewer join a stream they already relay
+            RequestType::Admission => self.process_admission(request).await?,
+        }
+        Ok(())
+    }
+
+    /// Answers a request the session does not take in its current state.
+    async fn refuse(&mut self, request: &RtspRequest) -> std::io::Result<()> {
+        warn!(
+            "Refusing {} while {:?}",
+            request.request_type(),
+            self.server_state
+        );
+        let session_id = self.client_info.as_ref().map_or(0, |info| info.session_id);
+        let response = RtspResponse::new(Status::MethodNotValid, request.seq_number(), session_id);
+
+        self.reply_rtsp(response).await
+    }
 
-                let client_info = self.client_info.as_ref().unwrap();
+    /// Starts recording the stream the viewer is sent under the name `request` gives, or stops it.
+    async fn process_record(&mut self, request: RtspRequest) -> Result<(), StreamingError> {
+        let client_info = self
+            .client_info
+            .as_ref()
+            .ok_or(StreamingError::SessionNotSetup)?;
+        let session_id = client_info.session_id;
+        let video_file = client_info.stream_file();
 
-                let response =
-                    RtspResponse::new(Status::Ok, request.seq_number(), client_info.session_id);
+        if let Err(error) = self
+            .access
+            .authorize_recording(request.credentials(), &client_info.video_file)
+        {
+            warn!("Refusing to record: {}", error);
+            let response = RtspResponse::new(error.status(), request.seq_number(), session_id);
 
-                let address = (client_info.ip_address, client_info.rtp_port);
+            return Ok(self.reply_rtsp(response).await?);
+        }
+
+        let channel = match client_info.mode {
+            StreamingMode::Broadcast => {
+                self.video_workers.lock().unwrap().get(&video_file).cloned()
+            }
+            StreamingMode::OnDemand => self
+                .on_demand_sessions
+                .lock()
+                .unwrap()
+                .get(&session_id)
+                .cloned(),
+        }
+        .ok_or(StreamingError::ChannelNotFound(video_file))?;
+
+        let result = match request.recording() {
+            Some(name) => channel.start_recording(name),
+            None => channel.stop_recording().map(|_| ()),
+        };
+
+        let status = match result {
+            Ok(()) => Status::Ok,
+            Err(error) => {
+                warn!("Refusing to record: {}", error);
+                match error.kind() {
+                    ErrorKind::AlreadyExists | ErrorKind::InvalidInput => Status::Forbidden,
+                    _ => Status::ConnectionError,
+                }
+            }
+        };
+
+        let response = RtspResponse::new(status, request.seq_number(), session_id);
+
+        Ok(self.reply_rtsp(response).await?)
+    }
+
+    /// Moves the viewer to another rendition of its file, carrying on from the current frame.
+    ///
+    /// On demand sessions keep their channel and only change what is read from
+    /// disk. Broadcast viewers join the channel of the other rendition.
+    async fn process_switch(&mut self, request: RtspRequest) -> Result<(), StreamingError> {
+        let client_info = self
+            .client_info
+            .as_ref()
+            .ok_or(StreamingError::SessionNotSetup)?;
+        let session_id = client_info.session_id;
+        let address = (client_info.ip_address, client_info.rtp_port);
+
+        let video_file = rendition::file(&client_info.video_file, request.rendition());
+        if !VideoStream::file_exists(&video_file) {
+            let response =
+                RtspResponse::new(Status::FileNotFound, request.seq_number(), session_id);
+
+            return Ok(self.reply_rtsp(response).await?);
+        }
+
+        match client_info.mode {
+            StreamingMode::Broadcast => {
+                let position = self
+                    .video_workers
+                    .lock()
+                    .unwrap()
+                    .get(&client_info.stream_file())
+                    .filter(|worker| worker.clients().contains(&address.into()))
+                    .map(|worker| worker.position());
+
+                match position {
+                    Some(position) => {
+                        self.release_client()?;
+                        self.set_rendition(request.rendition());
+                        self.handle_client(position)?;
+                    }
+                    // Not receiving anything yet, the next play starts the new rendition
+                    None => self.set_rendition(request.rendition()),
+                }
+            }
+            StreamingMode::OnDemand => {
+                if let Some(session) = self.on_demand_sessions.lock().unwrap().get(&session_id) {
+                    session.switch_stream(VideoStream::new(&video_file)?)?;
+                }
+                self.set_rendition(request.rendition());
+            }
+        }
+        info!("Switched to {}", video_file);
+
+        let response = RtspResponse::new(Status::Ok, request.seq_number(), session_id)
+            .with_rendition(request.rendition());
 
-                let lock = self.video_workers.lock().unwrap();
+        Ok(self.reply_rtsp(response).await?)
+    }
 
-                let worker = lock.get(request.file_request()).unwrap();
-                worker.remove_client(address);
+    fn set_rendition(&mut self, rendition: Option<u32>) {
+        if let Some(client_info) = self.client_info.as_mut() {
+            client_info.rendition = rendition;
+        }
+    }
 
-                self.reply_rtsp(response)?;
+    /// Counts a viewer joining through a relay against its user, until the relay disconnects.
+    async fn process_admission(&mut self, request: RtspRequest) -> Result<(), StreamingError> {
+        let status = match self
+            .access
+            .open_session(request.credentials(), request.file_request())
+        {
+            Ok(session) => {
+                self.admissions.push(session);
+                Status::Ok
             }
+            Err(error) => {
+                warn!("Refusing admission: {}", error);
+                error.status()
+            }
+        };
+
+        // Relays serving the viewer from their cache only do so for on demand sessions
+        let mode = self
+            .streaming_policy
+            .mode_for(request.file_request(), request.mode());
+        let mut response = RtspResponse::new(status, request.seq_number(), 0).with_mode(mode);
+
+        // Viewers joining a broadcast on a relay need its key, which the relay cannot read
+        if status == Status::Ok && mode == StreamingMode::Broadcast {
+            let media_key = self.master_key.derive(request.file_request());
+            response = response.with_media_keys(media_key.wrap(request.key_shares()));
         }
-        Ok(())
+
+        Ok(self.reply_rtsp(response).await?)
     }
 
-    fn process_play(&mut self, request: RtspRequest) -> std::io::Result<()> {
-        println!("Processing play");
-        let client_info = self.client_info.as_mut().unwrap();
+    async fn process_play(&mut self, request: RtspRequest) -> Result<(), StreamingError> {
+        debug!("Processing play");
+        let client_info = self
+            .client_info
+            .as_ref()
+            .ok_or(StreamingError::SessionNotSetup)?;
         let session_id = client_info.session_id;
+        let mode = client_info.mode;
 
         self.server_state = ServerState::Playing;
 
-        if self.handle_client(request.file_request()).is_err() {
+        if let Err(error) = self.handle_client(0) {
+            error!("Error starting transmission {}", error);
             let response =
                 RtspResponse::new(Status::ConnectionError, request.seq_number(), session_id);
 
-            return self.reply_rtsp(response);
+            return Ok(self.reply_rtsp(response).await?);
         }
 
-        let response = RtspResponse::new(Status::Ok, request.seq_number(), session_id);
+        let response =
+            RtspResponse::new(Status::Ok, request.seq_number(), session_id).with_mode(mode);
 
-        self.reply_rtsp(response)
+        Ok(self.reply_rtsp(response).await?)
     }
 
-    pub fn reply_rtsp(&mut self, response: RtspResponse) -> std::io::Result<()> {
-        let response = bincode::serialize(&response).expect("Error serializing packet");
+    pub async fn reply_rtsp(&mut self, response: RtspResponse) -> std::io::Result<()> {
+        let response = self.auth.seal(&response);
 
-        self.rtsp_socket.write_all(&response)
+        self.rtsp_socket.write_all(&response).await
     }
 
-    pub fn run(&mut self) {
+    pub async fn run(&mut self, shutdown: &Shutdown) {
         let mut buffer = [0; 1024];
+
         loop {
-            let n = self.rtsp_socket.read(&mut buffer).unwrap();
+            let n = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                result = self.rtsp_socket.read(&mut buffer) => match result {
+                    Ok(n) => n,
+                    Err(error) => {
+                        error!("Error reading request {}", error);
+                        return;
+                    }
+                },
+            };
             if n == 0 {
-                continue;
+                info!("Viewer closed the connection");
+                if self.client_info.is_some() && self.release_client().is_err() {
+                    error!("Error releasing the viewer's session");
+                }
+                return;
             }
 
-            let request = bincode::deserialize(&buffer).expect("Error deserializing packet");
+            let peer = match self.rtsp_socket.peer_addr() {
+                Ok(peer) => peer.ip(),
+                Err(error) => {
+                    error!("Error reading request {}", error);
+                    return;
+                }
+            };
 
-            match self.process_rtsp_request(request) {
-                Ok(_) => {
-                    println!("Request processed successfully")
+            let request: RtspRequest = match self.auth.open(&buffer[..n], peer) {
+                Ok(request) => request,
+                Err(error) => {
+                    let error = StreamingError::from(error);
+                    telemetry::count_error(error.kind());
+                    warn!("Dropping request: {}", error);
+                    continue;
                 }
+            };
+            let seq_number = request.seq_number();
+            let span = info_span!(
+                "rtsp",
+                trace = request.trace_id(),
+                file = request.file_request(),
+                cseq = seq_number,
+                session = field::Empty,
+            );
+            if let Some(client_info) = &self.client_info {
+                span.record("session", client_info.session_id);
+            }
+
+            let result = self
+                .process_rtsp_request(request)
+                .instrument(span.clone())
+                .await;
+
+            match result {
+                Ok(_) => span.in_scope(|| debug!("Request processed successfully")),
                 Err(error) => {
-                    println!("Error processing request {}", error);
+                    telemetry::count_error(error.kind());
+                    span.in_scope(|| error!("Error processing request {}", error));
+                    let session_id = self.client_info.as_ref().map_or(0, |info| info.session_id);
+                    let response =
+                        RtspResponse::new(Status::ConnectionError, seq_number, session_id);
+                    if self.reply_rtsp(response).await.is_err() {
+                        return;
+                    }
                 }
             }
         }
+
+        // Lets the viewer know the stream is over before the connection is closed
+        let session_id = self.client_info.as_ref().map_or(0, |info| info.session_id);
+        let _ = self
+            .reply_rtsp(RtspResponse::new(Status::ServiceUnavailable, 0, session_id))
+            .await;
     }
 }
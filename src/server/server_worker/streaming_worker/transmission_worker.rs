@@ -1,45 +1,172 @@
 use std::{
-    net::{IpAddr, UdpSocket},
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+use crate::{
+    message::{nack::Nack, rtsp::StreamingMode},
+    server::send_queue::PACING_INTERVAL,
+    settings,
+    transport::DatagramSocket,
+    video::video_stream::VideoStream,
 };
 
 use super::video_stream_info::VideoStreamInfo;
 
 #[derive(Debug)]
 pub struct TransmissionChannel {
-    rtp_socket: Arc<UdpSocket>,
+    rtp_socket: Arc<DatagramSocket>,
     video_client_addrs: Arc<VideoStreamInfo>,
+    mode: StreamingMode,
+    title: String,
+    running: AtomicBool,
+    stopped: AtomicBool,
+    handle: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl TransmissionChannel {
-    pub fn new(rtp_socket: Arc<UdpSocket>, video_client_addrs: Arc<VideoStreamInfo>) -> Self {
+    pub fn new(
+        rtp_socket: Arc<DatagramSocket>,
+        video_client_addrs: Arc<VideoStreamInfo>,
+        mode: StreamingMode,
+    ) -> Self {
         Self {
             rtp_socket,
             video_client_addrs,
+            mode,
+            title: String::new(),
+            running: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            handle: Mutex::new(None),
         }
     }
 
-    pub fn run(&self) {
-        loop {
-            std::thread::sleep(Duration::from_secs_f64(0.05));
+    /// Title the viewers set up, whichever rendition of it is sent.
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = title.to_string();
+        self
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Spawns the transmission task unless one is already running for this channel.
+    pub fn start(self: &Arc<Self>) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let channel = Arc::clone(self);
+        let handle = tokio::spawn(async move {
+            channel.run().await;
+        });
+
+        *self.handle.lock().unwrap() = Some(handle);
+    }
+
+    /// Stops the transmission for good.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    async fn run(&self) {
+        let mut interval = tokio::time::interval(settings::get().streaming.frame_interval());
+        let mut pacing = tokio::time::interval(PACING_INTERVAL);
+
+        // Frames are sent without waiting, so the socket must be known to be writable first
+        if let Err(error) = self.rtp_socket.writable().await {
+            error!("Error waiting for the rtp socket {}", error);
+        }
+
+        let mut buffer = [0; 1024];
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            // Packets reported lost are sent again while waiting for the next frame, and
+            // clients that could not keep up are sent what their queue allows
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = pacing.tick() => {
+                    self.video_client_addrs.flush(&self.rtp_socket);
+                    continue;
+                }
+                result = self.rtp_socket.recv_from(&mut buffer) => {
+                    match result {
+                        Ok((n, from)) => self.answer_feedback(&buffer[..n], from),
+                        Err(error) => debug!("Error receiving feedback {}", error),
+                    }
+                    continue;
+                }
+            }
 
             if !self.video_client_addrs.has_clients() {
-                println!("Worker stopped running: There are no more clients");
-                break;
+                self.running.store(false, Ordering::SeqCst);
+
+                // A client may have been added after the check above, in that case
+                // whoever wins the flag keeps transmitting.
+                if !self.video_client_addrs.has_clients()
+                    || self.running.swap(true, Ordering::SeqCst)
+                {
+                    info!("Worker stopped running: There are no more clients");
+                    break;
+                }
             }
 
             if self.video_client_addrs.send_data(&self.rtp_socket).is_err() {
-                println!("Reached the end of the video");
+                info!("Reached the end of the video");
+                self.running.store(false, Ordering::SeqCst);
                 break;
             }
         }
     }
 
+    fn answer_feedback(&self, packet: &[u8], from: SocketAddr) {
+        if let Some(nack) = packet.get(8..).and_then(Nack::decode) {
+            self.video_client_addrs
+                .retransmit(&nack, from, &self.rtp_socket);
+        }
+    }
+
+    pub fn position(&self) -> u32 {
+        self.video_client_addrs.position()
+    }
+
+    /// Keeps the channel, its viewers and their key, sending `video_stream` from now on.
+    pub fn switch_stream(&self, video_stream: VideoStream) -> std::io::Result<()> {
+        self.video_client_addrs.switch_stream(video_stream)
+    }
+
     pub fn add_client(&self, client: (IpAddr, u16)) -> usize {
         self.video_client_addrs.add_client(client)
     }
 
+    /// Adds a client watching `behind` frames behind the live stream, see `VideoStreamInfo::add_delayed_client`.
+    pub fn add_delayed_client(&self, client: (IpAddr, u16), behind: usize) -> usize {
+        self.video_client_addrs.add_delayed_client(client, behind)
+    }
+
+    pub fn start_recording(&self, name: &str) -> std::io::Result<()> {
+        self.video_client_addrs.start_recording(name)
+    }
+
+    pub fn stop_recording(&self) -> std::io::Result<Option<String>> {
+        self.video_client_addrs.stop_recording()
+    }
+
+    pub fn recording(&self) -> Option<String> {
+        self.video_client_addrs.recording()
+    }
+
     pub fn remove_client(&self, client: (IpAddr, u16)) -> usize {
         self.video_client_addrs.remove_client(client)
     }
@@ -47,4 +174,12 @@ impl TransmissionChannel {
     pub fn has_clients(&self) -> bool {
         self.video_client_addrs.has_clients()
     }
+
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        self.video_client_addrs.clients()
+    }
+
+    pub fn mode(&self) -> StreamingMode {
+        self.mode
+    }
 }
@@ -1,48 +1,238 @@
 use std::{
-    net::{IpAddr, UdpSocket},
-    sync::Mutex,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
 };
 
-use crate::video::video_stream::VideoStream;
+use tracing::error;
+
+use crate::{
+    message::{
+        fec::FecEncoder,
+        nack::Nack,
+        rtp,
+        srtp::{MediaKey, SrtpSender},
+    },
+    server::{
+        fan_out::SubscriberList, retransmission::RetransmissionBuffer, send_queue::SendQueues,
+        time_shift::TimeShiftBuffer,
+    },
+    settings,
+    telemetry::{self, Counter},
+    transport::DatagramSocket,
+    video::{packet_source, recorder::Recorder, video_stream::VideoStream},
+};
 
 #[derive(Debug)]
 pub struct VideoStreamInfo {
     video_stream: Mutex<VideoStream>,
-    clients: Mutex<Vec<(IpAddr, u16)>>,
+    clients: SubscriberList,
+    media: SrtpSender,
+    fec: Option<Mutex<FecEncoder>>,
+    sent: Mutex<RetransmissionBuffer<Arc<[u8]>>>,
+    queues: SendQueues<Arc<[u8]>>,
+    history: Mutex<TimeShiftBuffer<Arc<[u8]>>>,
+    recorder: Mutex<Option<Recorder>>,
+    retransmitted: Arc<Counter>,
 }
 
 impl VideoStreamInfo {
-    pub fn new(video_stream: VideoStream, clients: Vec<(IpAddr, u16)>) -> Self {
+    pub fn new(
+        file: &str,
+        video_stream: VideoStream,
+        clients: Vec<(IpAddr, u16)>,
+        media_key: &MediaKey,
+    ) -> Self {
+        let (retransmitted, _) = telemetry::repairs(file);
+        let streaming = &settings::get().streaming;
+        let fec_block = streaming.fec_block;
+
         Self {
             video_stream: Mutex::new(video_stream),
-            clients: Mutex::new(clients),
+            clients: SubscriberList::new(clients.into_iter().map(SocketAddr::from).collect()),
+            media: media_key.sender(),
+            fec: (fec_block > 0).then(|| Mutex::new(FecEncoder::new(fec_block))),
+            sent: Mutex::new(RetransmissionBuffer::new(streaming.retransmission_buffer)),
+            queues: SendQueues::new(file),
+            history: Mutex::new(TimeShiftBuffer::new(0)),
+            recorder: Mutex::new(None),
+            retransmitted,
+        }
+    }
+
+    /// Keeps the last `frames` sent, for viewers watching behind the live stream.
+    pub fn with_history(mut self, frames: usize) -> Self {
+        self.history = Mutex::new(TimeShiftBuffer::new(frames));
+        self
+    }
+
+    /// Sends the next frame, encrypted, to every client without waiting on busy sockets.
+    ///
+    /// The parity of the block follows the frame that completes it. Clients
+    /// that cannot keep up get it later through their send queue, see `flush`.
+    /// Viewers behind the live stream are sent an older frame in its place.
+    pub fn send_data(&self, rtp_socket: &DatagramSocket) -> std::io::Result<()> {
+        let packet = self.video_stream.lock().unwrap().next_packet()?;
+        let data = packet.transmit_data();
+        self.record(&data, packet.payload());
+
+        let packet = self.media.protect(&data);
+        let parity = self
+            .fec
+            .as_ref()
+            .and_then(|fec| fec.lock().unwrap().push(&packet));
+
+        let packet: Arc<[u8]> = packet_source::frame(&packet).into();
+        let behind = self.history.lock().unwrap().push(Arc::clone(&packet));
+        for (frame, viewers) in behind {
+            self.queues.send(rtp_socket, frame, &viewers);
+        }
+
+        let clients = self.clients.snapshot();
+        let parity = parity.map(|parity| Arc::from(packet_source::frame(&parity)));
+        for packet in std::iter::once(packet).chain(parity) {
+            self.queues.send(rtp_socket, Arc::clone(&packet), &clients);
+
+            self.sent.lock().unwrap().insert(packet);
         }
+
+        Ok(())
+    }
+
+    /// Writes the frame sent as `packet` to the recording, if any. A recording that fails is stopped.
+    fn record(&self, packet: &[u8], frame: &[u8]) {
+        let mut recorder = self.recorder.lock().unwrap();
+
+        let (Some(current), Some(id)) = (recorder.as_mut(), rtp::packet_id(packet)) else {
+            return;
+        };
+
+        if let Err(error) = current.write(id, frame) {
+            error!("Error recording to {}: {}", current.name(), error);
+            *recorder = None;
+        }
+    }
+
+    /// Starts writing every frame sent to `name`, in the videos folder.
+    pub fn start_recording(&self, name: &str) -> std::io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+
+        if let Some(current) = recorder.as_ref() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Already recording to {}", current.name()),
+            ));
+        }
+
+        *recorder = Some(Recorder::create(name)?);
+        Ok(())
+    }
+
+    /// Ends the recording, returns its name if there was one.
+    pub fn stop_recording(&self) -> std::io::Result<Option<String>> {
+        let Some(mut recorder) = self.recorder.lock().unwrap().take() else {
+            return Ok(None);
+        };
+
+        recorder.finish()?;
+        Ok(Some(recorder.name().to_string()))
     }
 
-    pub fn send_data(&self, rtp_socket: &UdpSocket) -> std::io::Result<()> {
-        let mut video_lock = self.video_stream.lock().unwrap();
-        let packet = video_lock.receive_next_packet()?;
+    pub fn recording(&self) -> Option<String> {
+        self.recorder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|recorder| recorder.name().to_string())
+    }
+
+    /// Sends what the send queues of slow clients are allowed to by now.
+    pub fn flush(&self, rtp_socket: &DatagramSocket) {
+        self.queues.flush(rtp_socket);
+    }
 
-        for client in self.clients.lock().unwrap().iter() {
-            let _ = rtp_socket.send_to(&packet, client).unwrap();
+    /// Sends the packets `nack` reports lost again, if `client` still watches and they are kept.
+    ///
+    /// The report also tells the congestion controller of the client how its link fares,
+    /// frames its send queue dropped are not sent again.
+    pub fn retransmit(&self, nack: &Nack, client: SocketAddr, rtp_socket: &DatagramSocket) {
+        if !self.clients().contains(&client) {
+            return;
         }
 
+        let nack = self.queues.on_nack(client, nack);
+
+        // Viewers behind the live stream lose frames older than those sent lately
+        let packets: Vec<_> = {
+            let sent = self.sent.lock().unwrap();
+            let history = self.history.lock().unwrap();
+            nack.lost()
+                .iter()
+                .filter_map(|&sequence| {
+                    sent.get(nack.ssrc(), sequence)
+                        .or_else(|| history.get(nack.ssrc(), sequence))
+                })
+                .collect()
+        };
+
+        self.retransmitted.add(packets.len() as u64);
+
+        for packet in packets {
+            self.queues.send(rtp_socket, packet, &[client]);
+        }
+    }
+
+    /// Frames sent so far.
+    pub fn position(&self) -> u32 {
+        self.video_stream.lock().unwrap().frame_num()
+    }
+
+    /// Carries on from the current frame with `video_stream`, another rendition of the file.
+    pub fn switch_stream(&self, mut video_stream: VideoStream) -> std::io::Result<()> {
+        let mut current = self.video_stream.lock().unwrap();
+
+        video_stream.skip_to(current.frame_num())?;
+        *current = video_stream;
+
         Ok(())
     }
 
     pub fn add_client(&self, client: (IpAddr, u16)) -> usize {
-        let mut lock = self.clients.lock().unwrap();
-        lock.push(client);
-        lock.len()
+        self.clients.add(client.into())
+    }
+
+    /// Adds a client watching `behind` frames behind the live stream, as far as the history goes.
+    ///
+    /// Returns how many frames behind it really is, clients that cannot be
+    /// behind at all watch the live stream.
+    pub fn add_delayed_client(&self, client: (IpAddr, u16), behind: usize) -> usize {
+        let mut history = self.history.lock().unwrap();
+
+        if behind == 0 || history.capacity() == 0 {
+            drop(history);
+            self.add_client(client);
+            return 0;
+        }
+
+        history.join(client.into(), behind)
     }
 
+    /// Stops sending to `client`, live or behind, returns how many clients are left.
     pub fn remove_client(&self, client: (IpAddr, u16)) -> usize {
-        let mut lock = self.clients.lock().unwrap();
-        lock.retain(|c| c != &client);
-        lock.len()
+        self.queues.forget(client.into());
+        self.history.lock().unwrap().leave(client.into());
+        self.clients.remove(client.into());
+
+        self.clients().len()
     }
 
     pub fn has_clients(&self) -> bool {
-        return !self.clients.lock().unwrap().is_empty();
+        !self.clients().is_empty()
+    }
+
+    /// Clients of the live stream, followed by those behind it.
+    pub fn clients(&self) -> Vec<SocketAddr> {
+        let mut clients = self.clients.snapshot().to_vec();
+        clients.extend(self.history.lock().unwrap().viewers());
+        clients
     }
 }
@@ -0,0 +1,163 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    ops::Deref,
+};
+
+use crate::server::retransmission;
+
+/// Last frames of a broadcast, for the viewers watching it behind the live stream.
+///
+/// Frames are kept as sent, size prefix included. Each viewer is handed the
+/// frames in order, one for every frame sent live, so it stays as far behind
+/// as it joined. Viewers asking to be further behind than the buffer goes
+/// start at its oldest frame.
+#[derive(Debug)]
+pub struct TimeShiftBuffer<P> {
+    frames: VecDeque<P>,
+    first: u64,
+    capacity: usize,
+    viewers: HashMap<SocketAddr, u64>,
+}
+
+impl<P> TimeShiftBuffer<P>
+where
+    P: Deref + Clone,
+    P::Target: AsRef<[u8]>,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            first: 0,
+            capacity,
+            viewers: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Adds a viewer `behind` frames behind the live stream, returns how far behind it really is.
+    pub fn join(&mut self, viewer: SocketAddr, behind: usize) -> usize {
+        let behind = behind.min(self.frames.len());
+        let end = self.first + self.frames.len() as u64;
+
+        self.viewers.insert(viewer, end - behind as u64);
+        behind
+    }
+
+    /// Returns false if `viewer` was not watching.
+    pub fn leave(&mut self, viewer: SocketAddr) -> bool {
+        self.viewers.remove(&viewer).is_some()
+    }
+
+    pub fn viewers(&self) -> Vec<SocketAddr> {
+        self.viewers.keys().copied().collect()
+    }
+
+    /// Keeps `frame`, just sent live, and returns the frames due to the viewers meanwhile.
+    ///
+    /// Viewers at the same point of the stream are grouped, so they are sent their frame at once.
+    pub fn push(&mut self, frame: P) -> Vec<(P, Vec<SocketAddr>)> {
+        let mut due: BTreeMap<u64, Vec<SocketAddr>> = BTreeMap::new();
+
+        for (viewer, next) in self.viewers.iter_mut() {
+            if *next < self.first + self.frames.len() as u64 {
+                due.entry(*next).or_default().push(*viewer);
+                *next += 1;
+            }
+        }
+
+        let due = due
+            .into_iter()
+            .filter_map(|(index, viewers)| {
+                let frame = self.frames.get((index - self.first) as usize)?;
+                Some((frame.clone(), viewers))
+            })
+            .collect();
+
+        if self.capacity > 0 {
+            if self.frames.len() == self.capacity {
+                self.frames.pop_front();
+                self.first += 1;
+            }
+            self.frames.push_back(frame);
+        }
+
+        due
+    }
+
+    /// Frame sent as packet `sequence` of stream `ssrc`, if still kept.
+    pub fn get(&self, ssrc: u32, sequence: u16) -> Option<P> {
+        self.frames
+            .iter()
+            .rev()
+            .find(|frame| {
+                (***frame)
+                    .as_ref()
+                    .get(8..)
+                    .and_then(retransmission::media_id)
+                    == Some((ssrc, sequence))
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::{message::rtp::RtpPacketBuilder, video::packet_source};
+
+    use super::TimeShiftBuffer;
+
+    fn frame(sequence: u16) -> Vec<u8> {
+        packet_source::frame(
+            &RtpPacketBuilder::new(&[sequence as u8; 20], 26)
+                .sequence_number(sequence)
+                .build()
+                .transmit_data(),
+        )
+    }
+
+    /// Pushes `frame` and returns which frame each viewer got.
+    fn push(buffer: &mut TimeShiftBuffer<Vec<u8>>, sequence: u16) -> Vec<(u8, Vec<SocketAddr>)> {
+        buffer
+            .push(frame(sequence))
+            .into_iter()
+            .map(|(frame, viewers)| (frame[8 + 12], viewers))
+            .collect()
+    }
+
+    #[test]
+    fn viewers_stay_as_far_behind_as_they_joined() {
+        let early: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let late: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let mut buffer = TimeShiftBuffer::new(3);
+
+        for sequence in 0..5 {
+            assert!(push(&mut buffer, sequence).is_empty());
+        }
+
+        // Only 3 frames are kept, frames 2 to 4
+        assert_eq!(buffer.join(early, 10), 3);
+        assert_eq!(buffer.join(late, 1), 1);
+
+        assert_eq!(
+            push(&mut buffer, 5),
+            vec![(2, vec![early]), (4, vec![late])]
+        );
+        assert_eq!(
+            push(&mut buffer, 6),
+            vec![(3, vec![early]), (5, vec![late])]
+        );
+
+        assert_eq!(buffer.get(0, 6).map(|frame| frame[8 + 12]), Some(6));
+        assert_eq!(buffer.get(0, 3), None);
+
+        assert!(buffer.leave(early));
+        assert!(!buffer.leave(early));
+        assert_eq!(push(&mut buffer, 7), vec![(6, vec![late])]);
+    }
+}
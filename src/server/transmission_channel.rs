@@ -1,14 +1,50 @@
 use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream, UdpSocket},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
 
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::JoinHandle,
+};
+use tracing::{debug, error, warn};
+
 use crate::{
-    message::rtsp::RtspRequest,
-    video::{packet_source::PacketSource, video_stream::VideoStream},
+    message::{
+        auth::Authenticator,
+        credentials::Credentials,
+        nack::{self, LossTracker, Nack},
+        rtp::RtpPacket,
+        rtsp::{RequestType, RtspRequest, RtspResponse, StreamingMode},
+        srtp::{MediaKey, SrtpReceiver},
+    },
+    o_node::export::StreamStatus,
+    runtime,
+    server::{
+        edge_cache::CacheFill,
+        errors::StreamingError,
+        fan_out::{BufferPool, SharedPacket, SubscriberList},
+        retransmission::{self, RetransmissionBuffer},
+        send_queue::{SendQueues, PACING_INTERVAL},
+    },
+    settings,
+    telemetry::{self, Counter},
+    transport::{DatagramSocket, Stream, Transport},
+    video::{
+        packet_source::{self, MAX_PACKET_SIZE},
+        recorder::Recorder,
+        rendition,
+    },
 };
 
+/// Packets a worker forwards before the losses its subscribers report are weighed.
+const MIN_REPORTED_PACKETS: u64 = 20;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct ClientInfo {
     address: SocketAddr,
@@ -32,55 +68,274 @@ impl ClientInfo {
     }
 }
 
+/// Connection a channel sends its requests upstream through.
+///
+/// It is shared with the channel, so requests can be sent without holding
+/// the table of channels while upstream answers. Links compare equal when
+/// they are the same connection, which tells whether a channel was replaced.
+#[derive(Debug, Clone)]
+pub struct UpstreamLink {
+    stream: Arc<tokio::sync::Mutex<Stream>>,
+    peer: Option<SocketAddr>,
+}
+
+impl UpstreamLink {
+    fn new(stream: Stream) -> Self {
+        Self {
+            peer: stream.peer_addr().ok(),
+            stream: Arc::new(tokio::sync::Mutex::new(stream)),
+        }
+    }
+
+    /// Address of the node at the other end.
+    pub fn peer(&self) -> std::io::Result<SocketAddr> {
+        self.peer
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected))
+    }
+
+    /// Holds the link, other requests on it wait until the guard is dropped.
+    ///
+    /// Holding it while deciding what to ask upstream keeps the requests in
+    /// the order they were decided in.
+    pub async fn lock(&self) -> LinkGuard<'_> {
+        LinkGuard {
+            stream: self.stream.lock().await,
+            link: self,
+        }
+    }
+
+    /// Sends `request` upstream once the link is free, see `LinkGuard::request`.
+    pub async fn request(
+        &self,
+        request: RtspRequest,
+        auth: &Authenticator,
+    ) -> Result<RtspResponse, StreamingError> {
+        self.lock().await.request(request, auth).await
+    }
+}
+
+/// Exclusive use of an `UpstreamLink`.
+#[derive(Debug)]
+pub struct LinkGuard<'l> {
+    stream: tokio::sync::MutexGuard<'l, Stream>,
+    link: &'l UpstreamLink,
+}
+
+impl LinkGuard<'_> {
+    /// Sends `request` upstream and waits for the answer, for at most `network.request_timeout_ms`.
+    pub async fn request(
+        &mut self,
+        request: RtspRequest,
+        auth: &Authenticator,
+    ) -> Result<RtspResponse, StreamingError> {
+        let server_stream = &mut *self.stream;
+        let mut buffer = [0; 1024];
+
+        let n = runtime::with_timeout(settings::get().network.request_timeout(), async {
+            server_stream.write_all(&auth.seal(&request)).await?;
+            server_stream.read(&mut buffer).await
+        })
+        .await?;
+
+        Ok(auth.open(&buffer[..n], self.link.peer()?.ip())?)
+    }
+}
+
+impl PartialEq for UpstreamLink {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.stream, &other.stream)
+    }
+}
+
 #[derive(Debug)]
 pub struct TransmissionChannel {
-    server_stream: TcpStream,
-    udp_socket: Arc<UdpSocket>,
+    file: String,
+    link: UpstreamLink,
+    udp_socket: Arc<DatagramSocket>,
     clients: Vec<ClientInfo>,
     worker: Option<Arc<TransmissionChannelWorker>>,
+    worker_handle: Option<JoinHandle<()>>,
+    mode: StreamingMode,
+    media_key: Option<MediaKey>,
+    rendition: Option<u32>,
+    renditions: Vec<u32>,
+    admissions: HashMap<SocketAddr, Stream>,
+    credentials: HashMap<SocketAddr, Credentials>,
+    cache_fill: Option<CacheFill>,
+    transport: Transport,
 }
 
 impl TransmissionChannel {
     pub fn new(
-        server_stream: TcpStream,
-        udp_socket: Arc<UdpSocket>,
+        file: String,
+        server_stream: Stream,
+        udp_socket: Arc<DatagramSocket>,
         clients: Vec<ClientInfo>,
     ) -> Self {
         Self {
-            server_stream,
+            file,
+            link: UpstreamLink::new(server_stream),
             udp_socket,
             clients,
             worker: None,
+            worker_handle: None,
+            mode: StreamingMode::default(),
+            media_key: None,
+            rendition: None,
+            renditions: Vec::new(),
+            admissions: HashMap::new(),
+            credentials: HashMap::new(),
+            cache_fill: None,
+            transport: Transport::default(),
         }
     }
 
-    pub fn create_worker(&mut self, client: ClientInfo) {
+    /// Transport admissions are requested upstream through.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Rendition of the file the channel receives, `None` being the file itself.
+    pub fn with_rendition(mut self, rendition: Option<u32>) -> Self {
+        self.rendition = rendition;
+        self
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn rendition(&self) -> Option<u32> {
+        self.rendition
+    }
+
+    pub fn set_rendition(&mut self, rendition: Option<u32>) {
+        self.rendition = rendition;
+    }
+
+    /// Lower qualities upstream has the file in, best first.
+    pub fn set_renditions(&mut self, renditions: Vec<u32>) {
+        self.renditions = renditions;
+    }
+
+    pub fn renditions(&self) -> &[u32] {
+        &self.renditions
+    }
+
+    pub fn set_mode(&mut self, mode: StreamingMode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> StreamingMode {
+        self.mode
+    }
+
+    /// Stream key, only kept by relays that cache or record what they relay.
+    pub fn set_media_key(&mut self, media_key: Option<MediaKey>) {
+        self.media_key = media_key;
+    }
+
+    /// Address of the node this channel receives the stream from.
+    pub fn upstream(&self) -> std::io::Result<SocketAddr> {
+        self.link.peer()
+    }
+
+    /// Connection to the upstream node, to send it requests once the channel is let go of.
+    pub fn link(&self) -> UpstreamLink {
+        self.link.clone()
+    }
+
+    /// Caches the stream as it is relayed, from when the worker starts.
+    pub fn cache_to(&mut self, fill: Option<CacheFill>) {
+        self.cache_fill = fill;
+    }
+
+    /// Stops caching the stream, what was cached of it is deleted.
+    pub fn stop_caching(&mut self) {
+        self.cache_fill = None;
+
+        if let Some(worker) = self.worker.as_ref() {
+            *worker.cache_fill.lock().unwrap() = None;
+        }
+    }
+
+    /// Starts relaying to `client` what the upstream node sends, from the host it was set up with.
+    pub fn create_worker(&mut self, client: ClientInfo) -> std::io::Result<()> {
         let socket_clone = Arc::clone(&self.udp_socket);
+        let cache_fill = self
+            .media_key
+            .as_ref()
+            .zip(self.cache_fill.take())
+            .map(|(media_key, fill)| (media_key.receiver(), fill));
+
+        let worker = Arc::new(
+            TransmissionChannelWorker::new(
+                &self.file,
+                socket_clone,
+                self.upstream()?.ip(),
+                vec![client.address],
+            )
+            .with_cache_fill(cache_fill),
+        );
+
+        self.worker = Some(Arc::clone(&worker));
+
+        self.worker_handle = Some(tokio::spawn(async move {
+            worker.run().await;
+        }));
+        Ok(())
+    }
+
+    fn stop_worker(&mut self) {
+        self.worker = None;
+
+        if let Some(handle) = self.worker_handle.take() {
+            handle.abort();
+        }
+    }
 
-        let worker = Arc::new(TransmissionChannelWorker::new(
-            socket_clone,
-            vec![client.address],
-        ));
+    /// Stops forwarding and tells the upstream node this channel is no longer needed.
+    pub async fn close(&mut self, auth: &Authenticator) -> Result<(), StreamingError> {
+        self.stop_worker();
+        self.clients.clear();
 
-        self.worker = Some(worker);
+        let request = RtspRequest::new(
+            RequestType::Teardown,
+            self.file.clone(),
+            0,
+            self.rtp_port()?,
+        );
 
-        let worker_clone = Arc::clone(self.worker.as_ref().unwrap());
+        self.send_server_request(request, auth).await?;
 
-        std::thread::spawn(move || {
-            worker_clone.run();
-        });
+        Ok(())
     }
 
-    pub fn send_server_request(&mut self, request: RtspRequest) -> std::io::Result<Vec<u8>> {
-        let _ = self
-            .server_stream
-            .write(&bincode::serialize(&request).unwrap())?;
+    /// Sends `request` upstream and waits for the answer, see `UpstreamLink::request`.
+    pub async fn send_server_request(
+        &self,
+        request: RtspRequest,
+        auth: &Authenticator,
+    ) -> Result<RtspResponse, StreamingError> {
+        self.link.request(request, auth).await
+    }
 
-        let mut buffer = [0; 1024];
+    /// Keeps the admission of a viewer for as long as it stays in the room.
+    pub fn admit(&mut self, client: SocketAddr, admission: Stream) {
+        self.admissions.insert(client, admission);
+    }
 
-        let n = self.server_stream.read(&mut buffer)?;
-        let answer: Vec<u8> = buffer[..n].to_vec();
-        Ok(answer)
+    /// Keeps who a viewer is, to set it up elsewhere when it changes rendition.
+    pub fn set_credentials(&mut self, client: SocketAddr, credentials: Option<Credentials>) {
+        match credentials {
+            Some(credentials) => self.credentials.insert(client, credentials),
+            None => self.credentials.remove(&client),
+        };
+    }
+
+    pub fn credentials(&self, client: SocketAddr) -> Option<&Credentials> {
+        self.credentials.get(&client)
     }
 
     pub fn add_client_to_room(&mut self, client: ClientInfo) {
@@ -91,26 +346,38 @@ impl TransmissionChannel {
         return self
             .clients
             .iter()
-            .find(|cl| cl.address == address).copied();
+            .find(|cl| cl.address == address)
+            .copied();
     }
 
     pub fn remove_client_to_room(&mut self, client: ClientInfo) {
         self.clients.retain(|cl| cl != &client);
+        self.admissions.remove(&client.address);
+        self.credentials.remove(&client.address);
 
         self.remove_client_as_playable(client);
     }
 
-    pub fn add_client_as_playable(&mut self, client: ClientInfo) {
-        self.worker.as_ref().unwrap().add_client(client.address);
+    /// Relays the stream to `client` too, the channel must already be relaying it.
+    pub fn add_client_as_playable(&mut self, client: ClientInfo) -> Result<(), StreamingError> {
+        let worker = self
+            .worker
+            .as_ref()
+            .ok_or_else(|| StreamingError::ChannelNotFound(self.file.clone()))?;
+
+        worker.add_client(client.address);
+        Ok(())
     }
 
     pub fn remove_client_as_playable(&mut self, client: ClientInfo) {
-        let worker = self.worker.as_ref().unwrap();
+        let Some(worker) = self.worker.as_ref() else {
+            return;
+        };
 
         worker.remove_client(client.address);
 
         if !worker.has_clients() {
-            self.worker = None;
+            self.stop_worker();
         }
     }
 
@@ -118,76 +385,425 @@ impl TransmissionChannel {
         !self.clients.is_empty()
     }
 
+    /// Whether the stream is being relayed to `client`, rather than paused or not played yet.
+    pub fn is_playing(&self, client: SocketAddr) -> bool {
+        self.worker
+            .as_ref()
+            .is_some_and(|worker| worker.subscribers.snapshot().contains(&client))
+    }
+
+    /// Viewers that reported more than `percent` of the stream lost lately, see `TransmissionChannelWorker::congested`.
+    pub fn congested(&self, percent: u8) -> Vec<SocketAddr> {
+        self.worker
+            .as_ref()
+            .map(|worker| worker.congested(percent))
+            .unwrap_or_default()
+    }
+
     pub fn has_worker(&self) -> bool {
         self.worker.is_some()
     }
 
-    pub fn rtp_port(&self) -> u16 {
-        self.udp_socket.local_addr().unwrap().port()
+    /// Starts recording the stream to `name` while it is relayed, see `TransmissionChannelWorker::start_recording`.
+    pub fn start_recording(&self, name: &str) -> std::io::Result<()> {
+        let Some(worker) = self.worker.as_ref() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not being relayed", self.file),
+            ));
+        };
+        let Some(media_key) = self.media_key.as_ref() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} was relayed without its key", self.file),
+            ));
+        };
+
+        worker.start_recording(name, media_key.receiver())
+    }
+
+    /// Ends the recording, returns its name if there was one.
+    pub fn stop_recording(&self) -> std::io::Result<Option<String>> {
+        match self.worker.as_ref() {
+            Some(worker) => worker.stop_recording(),
+            None => Ok(None),
+        }
+    }
+
+    pub fn rtp_port(&self) -> std::io::Result<u16> {
+        Ok(self.udp_socket.local_addr()?.port())
+    }
+
+    /// Where the stream comes from and goes to, along with what was relayed so far.
+    pub fn status(&self) -> StreamStatus {
+        let (packets, bytes) = self
+            .worker
+            .as_ref()
+            .map(|worker| worker.relayed())
+            .unwrap_or_default();
+
+        StreamStatus {
+            file: rendition::file(&self.file, self.rendition),
+            upstream: self.upstream().ok(),
+            viewers: self.clients.iter().map(|client| client.address).collect(),
+            packets,
+            bytes,
+        }
+    }
+}
+
+/// Asks `upstream` to admit the viewer sending `request` to `file`.
+///
+/// The viewer counts as a session of its user until the returned connection is closed.
+pub async fn request_admission(
+    transport: &Transport,
+    upstream: SocketAddr,
+    file: &str,
+    mode: Option<StreamingMode>,
+    rendition: Option<u32>,
+    request: &RtspRequest,
+    auth: &Authenticator,
+) -> Result<(RtspResponse, Stream), StreamingError> {
+    let mut stream = runtime::with_timeout(
+        settings::get().network.request_timeout(),
+        transport.connect(upstream),
+    )
+    .await?;
+
+    // Nothing is streamed for an admission, it has no rtp port
+    let admission = RtspRequest::new(
+        RequestType::Admission,
+        file.to_string(),
+        request.seq_number(),
+        0,
+    )
+    .with_mode(mode)
+    .with_credentials(request.credentials().cloned())
+    .with_trace_id(request.trace_id())
+    .with_rendition(rendition)
+    .with_key_shares(request.key_shares().to_vec());
+
+    let mut buffer = [0; 1024];
+    let n = runtime::with_timeout(settings::get().network.request_timeout(), async {
+        stream.write_all(&auth.seal(&admission)).await?;
+        stream.read(&mut buffer).await
+    })
+    .await?;
+
+    let response = auth.open(&buffer[..n], upstream.ip())?;
+
+    Ok((response, stream))
+}
+
+impl Drop for TransmissionChannel {
+    fn drop(&mut self) {
+        self.stop_worker();
     }
 }
 
+/// Forwards a stream to the subscribers of a relay, repairing what they lose.
+///
+/// Subscribers report lost packets to the worker, which sends them again from
+/// its own buffer or asks upstream for the ones it does not have. Gaps in what
+/// the worker receives are asked for upstream too, so the buffer fills up.
+///
+/// What subscribers report lost is also tallied, to find the ones the stream
+/// is too heavy for, and drives the congestion controller of their send queue.
+///
+/// The stream can be recorded and cached along the way, both end with the worker.
 #[derive(Debug)]
 pub struct TransmissionChannelWorker {
-    socket: Arc<UdpSocket>,
-    video_stream: Option<Mutex<VideoStream>>,
-    addresses: Mutex<Vec<SocketAddr>>,
+    socket: Arc<DatagramSocket>,
+    subscribers: SubscriberList,
+    buffers: Arc<BufferPool>,
+    sent: Mutex<RetransmissionBuffer<SharedPacket>>,
+    losses: Mutex<LossTracker>,
+    upstream_host: IpAddr,
+    upstream: Mutex<Option<SocketAddr>>,
+    reported: Mutex<HashMap<SocketAddr, HashSet<(u32, u16)>>>,
+    reported_over: AtomicU64,
+    queues: SendQueues<SharedPacket>,
+    recorder: Mutex<Option<(SrtpReceiver, Recorder)>>,
+    cache_fill: Mutex<Option<(SrtpReceiver, CacheFill)>>,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    retransmitted: Arc<Counter>,
+    nacks_sent: Arc<Counter>,
 }
 
 impl TransmissionChannelWorker {
-    pub fn new(socket: Arc<UdpSocket>, addresses: Vec<SocketAddr>) -> Self {
+    /// Worker relaying what `upstream_host` sends to `addresses`, datagrams from other hosts are dropped.
+    pub fn new(
+        file: &str,
+        socket: Arc<DatagramSocket>,
+        upstream_host: IpAddr,
+        addresses: Vec<SocketAddr>,
+    ) -> Self {
+        let (retransmitted, nacks_sent) = telemetry::repairs(file);
+        let streaming = &settings::get().streaming;
+
         Self {
             socket,
-            addresses: Mutex::new(addresses),
-            video_stream: None,
+            subscribers: SubscriberList::new(addresses),
+            buffers: BufferPool::new(MAX_PACKET_SIZE as usize + 8, streaming.pooled_buffers),
+            sent: Mutex::new(RetransmissionBuffer::new(streaming.retransmission_buffer)),
+            losses: Mutex::new(LossTracker::new(
+                streaming.repair_timeout(),
+                streaming.nack_interval(),
+            )),
+            upstream_host,
+            upstream: Mutex::new(None),
+            reported: Mutex::new(HashMap::new()),
+            reported_over: AtomicU64::new(0),
+            queues: SendQueues::new(file),
+            recorder: Mutex::new(None),
+            cache_fill: Mutex::new(None),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            retransmitted,
+            nacks_sent,
         }
     }
 
-    pub fn with_video_stream(
-        socket: Arc<UdpSocket>,
-        addresses: Vec<SocketAddr>,
-        video_stream: VideoStream,
-    ) -> Self {
-        Self {
-            socket,
-            addresses: Mutex::new(addresses),
-            video_stream: Some(Mutex::new(video_stream)),
+    /// Writes the frames received to the cache, decrypted with the receiver, until the title is whole.
+    pub fn with_cache_fill(self, cache_fill: Option<(SrtpReceiver, CacheFill)>) -> Self {
+        *self.cache_fill.lock().unwrap() = cache_fill;
+        self
+    }
+
+    /// Packets and bytes received from upstream since the worker started.
+    pub fn relayed(&self) -> (u64, u64) {
+        (
+            self.packets.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Subscribers that reported more than `percent` of the packets forwarded since the last call lost.
+    ///
+    /// Reports are only weighed once enough packets went out to tell.
+    pub fn congested(&self, percent: u8) -> Vec<SocketAddr> {
+        let forwarded = self.reported_over.load(Ordering::Relaxed);
+        if forwarded < MIN_REPORTED_PACKETS {
+            return Vec::new();
         }
+
+        self.reported_over.store(0, Ordering::Relaxed);
+        let reported = std::mem::take(&mut *self.reported.lock().unwrap());
+
+        reported
+            .into_iter()
+            .filter(|(_, lost)| lost.len() as u64 * 100 > forwarded * percent as u64)
+            .map(|(subscriber, _)| subscriber)
+            .collect()
     }
 
     pub fn add_client(&self, client: SocketAddr) {
-        let mut lock = self.addresses.lock().unwrap();
-        lock.push(client);
+        self.subscribers.add(client);
+    }
+
+    /// Starts writing every frame received to `name`, in the videos folder, decrypted with `media`.
+    pub fn start_recording(&self, name: &str, media: SrtpReceiver) -> std::io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+
+        if let Some((_, current)) = recorder.as_ref() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Already recording to {}", current.name()),
+            ));
+        }
+
+        *recorder = Some((media, Recorder::create(name)?));
+        Ok(())
+    }
+
+    /// Ends the recording, returns its name if there was one.
+    pub fn stop_recording(&self) -> std::io::Result<Option<String>> {
+        let Some((_, mut recorder)) = self.recorder.lock().unwrap().take() else {
+            return Ok(None);
+        };
+
+        recorder.finish()?;
+        Ok(Some(recorder.name().to_string()))
     }
 
     pub fn remove_client(&self, client: SocketAddr) {
-        let mut lock = self.addresses.lock().unwrap();
-        lock.retain(|&x| x != client);
+        self.queues.forget(client);
+        self.subscribers.remove(client);
     }
 
     pub fn has_clients(&self) -> bool {
-        let lock = self.addresses.lock().unwrap();
-        !lock.is_empty()
+        !self.subscribers.is_empty()
     }
 
-    pub fn run(&self) {
-        println!("Listening on {}", self.socket.local_addr().unwrap());
+    /// Forwards every packet received from upstream until the task is aborted.
+    pub async fn run(&self) {
+        debug!("Listening on {:?}", self.socket.local_addr());
+
+        let mut repairs = tokio::time::interval(settings::get().streaming.nack_interval());
+        let mut pacing = tokio::time::interval(PACING_INTERVAL);
+
         loop {
-            let packet = if let Some(video_stream) = &self.video_stream {
-                let mut lock = video_stream.lock().unwrap();
-                lock.receive_next_packet()
-            } else {
-                self.socket.receive_next_packet()
-            };
-
-            if let Ok(packet) = packet {
-                let addresses = self.addresses.lock().unwrap();
-                for client in addresses.iter() {
-                    self.socket.send_to(&packet, client).unwrap();
+            let mut buffer = self.buffers.take();
+
+            tokio::select! {
+                _ = repairs.tick() => self.ask_upstream(self.losses.lock().unwrap().due(Instant::now())),
+                _ = pacing.tick() => self.queues.flush(&self.socket),
+                result = self.socket.recv_from(buffer.spare_mut()) => match result {
+                    Ok((n, from)) => {
+                        buffer.set_len(n);
+                        self.receive(buffer.share(), from);
+                    }
+                    Err(error) => {
+                        telemetry::count_error("network");
+                        error!("Error receiving packet {}", error)
+                    }
+                },
+            }
+        }
+    }
+
+    fn receive(&self, packet: SharedPacket, from: SocketAddr) {
+        let Some(rtp) = packet.get(8..) else {
+            return;
+        };
+
+        if nack::is_nack(rtp) {
+            if let Some(nack) = Nack::decode(rtp) {
+                self.answer(nack, from);
+            }
+            return;
+        }
+
+        // The stream comes from the node the channel was set up with, its port
+        // changes when upstream switches the channel to another rendition
+        if from.ip() != self.upstream_host {
+            return;
+        }
+
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        *self.upstream.lock().unwrap() = Some(from);
+
+        let id = retransmission::media_id(rtp);
+        if let Some(id) = id {
+            self.losses.lock().unwrap().observe(id, Instant::now());
+        }
+
+        // Repairs asked for by a subscriber reach every one of them, none had the packet
+        if !self
+            .sent
+            .lock()
+            .unwrap()
+            .insert(SharedPacket::clone(&packet))
+        {
+            return;
+        }
+
+        if let Some(id) = id {
+            self.reported_over.fetch_add(1, Ordering::Relaxed);
+            self.record(id, rtp);
+            self.fill_cache(id, rtp);
+        }
+
+        self.queues
+            .send(&self.socket, packet, &self.subscribers.snapshot());
+    }
+
+    /// Writes the frame of media packet `id` to the recording, if any. A recording that fails is stopped.
+    fn record(&self, id: (u32, u16), packet: &[u8]) {
+        let mut recorder = self.recorder.lock().unwrap();
+
+        let Some((media, current)) = recorder.as_mut() else {
+            return;
+        };
+
+        let frame = match media.unprotect(packet) {
+            Ok(rtp) => RtpPacket::decode(&rtp),
+            Err(error) => {
+                warn!("Not recording packet {:?}: {}", id, error);
+                return;
+            }
+        };
+
+        if let Err(error) = current.write(id, frame.payload()) {
+            error!("Error recording to {}: {}", current.name(), error);
+            *recorder = None;
+        }
+    }
+
+    /// Writes the frame of media packet `id` to the cache, if it is being filled.
+    fn fill_cache(&self, id: (u32, u16), packet: &[u8]) {
+        let mut cache_fill = self.cache_fill.lock().unwrap();
+
+        let Some((media, fill)) = cache_fill.as_mut() else {
+            return;
+        };
+
+        let filling = match media.unprotect(packet) {
+            Ok(rtp) => fill.write(id, RtpPacket::decode(&rtp).payload()),
+            Err(error) => {
+                warn!("Not caching packet {:?}: {}", id, error);
+                true
+            }
+        };
+
+        if !filling {
+            *cache_fill = None;
+        }
+    }
+
+    /// Sends a subscriber the packets it lost, asking upstream for the ones not kept.
+    fn answer(&self, nack: Nack, from: SocketAddr) {
+        if !self.subscribers.snapshot().contains(&from) {
+            return;
+        }
+
+        // Requests for the same packet repeat until it arrives, each loss counts once
+        self.reported
+            .lock()
+            .unwrap()
+            .entry(from)
+            .or_default()
+            .extend(nack.lost().iter().map(|&sequence| (nack.ssrc(), sequence)));
+        // Frames its send queue dropped are not worth sending again
+        let nack = self.queues.on_nack(from, &nack);
+
+        // Packets received and no longer kept are not asked for, upstream would
+        // take the request for a loss on its link to this relay
+        let mut missing = Vec::new();
+        let mut kept = Vec::new();
+        {
+            let sent = self.sent.lock().unwrap();
+            let losses = self.losses.lock().unwrap();
+            for &sequence in nack.lost() {
+                match sent.get(nack.ssrc(), sequence) {
+                    Some(packet) => kept.push(packet),
+                    None if losses.is_missing((nack.ssrc(), sequence)) => missing.push(sequence),
+                    None => {}
                 }
-            } else {
-                println!("Error receiving packet");
             }
         }
+
+        self.retransmitted.add(kept.len() as u64);
+        for packet in kept {
+            self.queues.send(&self.socket, packet, &[from]);
+        }
+
+        if !missing.is_empty() {
+            self.ask_upstream(vec![Nack::new(nack.ssrc(), missing)]);
+        }
+    }
+
+    fn ask_upstream(&self, nacks: Vec<Nack>) {
+        let Some(upstream) = *self.upstream.lock().unwrap() else {
+            return;
+        };
+
+        for nack in nacks {
+            self.socket
+                .send_to_all(&packet_source::frame(&nack.encode()), &[upstream]);
+            self.nacks_sent.inc();
+        }
     }
 }
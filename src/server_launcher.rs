@@ -1,5 +1,11 @@
 use clap::Parser;
-use esr_lib::server::Server;
+use esr_lib::{
+    logging,
+    message::{auth::Authenticator, rtsp::StreamingMode},
+    server::{errors::StreamingError, Server, StreamingPolicy},
+    settings::SettingsArgs,
+    shutdown::Shutdown,
+};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -8,12 +14,55 @@ struct Args {
 
     #[clap(short, long, default_value = "8555")]
     metrics_port: u16,
+
+    /// Mode used for files without a per file mode when the client does not ask for one
+    #[clap(short, long, default_value = "broadcast")]
+    default_mode: StreamingMode,
+
+    /// Files that are streamed on demand, with a playback position per viewer
+    #[clap(short, long)]
+    on_demand: Vec<String>,
+
+    /// File with the keys used to authenticate messages
+    #[clap(short, long)]
+    key_file: Option<String>,
+
+    /// File with the users, groups and the files each of them may watch
+    #[clap(short, long)]
+    access_policy: Option<String>,
+
+    /// Port of the admin interface, only reachable from this host
+    #[clap(long)]
+    admin_port: Option<u16>,
+
+    /// Port to serve Prometheus metrics on, under /metrics
+    #[clap(long)]
+    prometheus_port: Option<u16>,
+
+    /// Which logs to show, such as `debug` or `warn,esr_lib::server=trace`, overrides RUST_LOG
+    #[clap(long)]
+    log: Option<String>,
+
+    #[command(flatten)]
+    settings: SettingsArgs,
 }
 
-fn main() {
+fn main() -> Result<(), StreamingError> {
     let args = Args::parse();
 
-    Server::new(args.metrics_port, args.streaming_port)
-        .expect("Error creating server")
-        .run();
+    logging::init(args.log.as_deref()).expect("Invalid log filter");
+    args.settings.init().expect("Error reading the settings");
+
+    let shutdown = Shutdown::on_signals().expect("Error setting signal handler");
+
+    let streaming_policy = StreamingPolicy::new(args.default_mode, args.on_demand);
+
+    let auth = Authenticator::from_key_file(args.key_file).expect("Error reading the key file");
+
+    Server::new(args.metrics_port, args.streaming_port, streaming_policy)?
+        .with_authenticator(auth)
+        .with_access_policy_file(args.access_policy)?
+        .with_admin_port(args.admin_port)
+        .with_prometheus_port(args.prometheus_port)
+        .run(&shutdown)
 }
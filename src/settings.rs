@@ -0,0 +1,584 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::Deserialize;
+use thiserror::Error;
+use toml::{Table, Value};
+use tracing::warn;
+
+use crate::o_node::query_guard::{QueryLimits, QueryLimitsError};
+
+/// Prefix of the environment variables that override settings, as `ESR_<SECTION>_<KEY>`.
+pub const ENV_PREFIX: &str = "ESR_";
+
+/// Environment variable with the settings file, used when `--config` is not given.
+pub const CONFIG_VAR: &str = "ESR_CONFIG";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("Error reading the settings file {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Invalid settings: {0}")]
+    Invalid(#[from] toml::de::Error),
+    #[error("Invalid override {0}, expected section.key=value")]
+    InvalidOverride(String),
+    #[error("Invalid settings: {0}")]
+    InvalidQueries(#[from] QueryLimitsError),
+    #[error("Settings were already in use when initialised")]
+    AlreadyInitialised,
+}
+
+/// Tunables shared by every binary, documented with their defaults in `esr.example.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub network: NetworkSettings,
+    pub queries: QuerySettings,
+    pub streaming: StreamingSettings,
+    pub retry: RetryPolicy,
+    pub selection: SelectionSettings,
+    pub cache: CacheSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSettings {
+    /// Port of neighbours and servers given without one.
+    pub default_port: u16,
+    /// Maximum time a request to another node may take before it is abandoned.
+    pub request_timeout_ms: u64,
+    /// How long a node waits for its neighbours to answer a forwarded query.
+    pub query_timeout_ms: u64,
+    /// How long the RP waits for the metrics of each content server.
+    pub metrics_timeout_ms: u64,
+    /// How long the bootstrapper waits for the status of each node.
+    pub status_timeout_ms: u64,
+    /// Maximum number of queries or connections a single service handles at the same time.
+    pub max_concurrent_tasks: usize,
+    /// How often host names of the topology and content servers are looked up again.
+    pub resolve_interval_ms: u64,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            default_port: 8000,
+            request_timeout_ms: 5000,
+            query_timeout_ms: 1000,
+            metrics_timeout_ms: 1000,
+            status_timeout_ms: 1000,
+            max_concurrent_tasks: 512,
+            resolve_interval_ms: 30000,
+        }
+    }
+}
+
+impl NetworkSettings {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_millis(self.query_timeout_ms)
+    }
+
+    pub fn metrics_timeout(&self) -> Duration {
+        Duration::from_millis(self.metrics_timeout_ms)
+    }
+
+    pub fn status_timeout(&self) -> Duration {
+        Duration::from_millis(self.status_timeout_ms)
+    }
+
+    pub fn resolve_interval(&self) -> Duration {
+        Duration::from_millis(self.resolve_interval_ms)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuerySettings {
+    /// Queries per second accepted from each source.
+    pub rate: f64,
+    /// Queries a source may send at once before being rate limited.
+    pub burst: f64,
+    /// How long a query id is remembered to drop its copies.
+    pub duplicate_ttl_ms: u64,
+    /// Hops a new query may travel through the overlay before nodes stop forwarding it.
+    pub max_hops: u8,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        let limits = QueryLimits::default();
+
+        Self {
+            rate: limits.rate,
+            burst: limits.burst,
+            duplicate_ttl_ms: limits.duplicate_ttl.as_millis() as u64,
+            max_hops: 16,
+        }
+    }
+}
+
+impl QuerySettings {
+    pub fn limits(&self) -> QueryLimits {
+        QueryLimits {
+            rate: self.rate,
+            burst: self.burst,
+            duplicate_ttl: Duration::from_millis(self.duplicate_ttl_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamingSettings {
+    /// Time between the frames sent by a content server.
+    pub frame_interval_ms: u64,
+    /// Idle packet buffers kept by each relay.
+    pub pooled_buffers: usize,
+    /// Folder with the videos of a content server.
+    pub videos_dir: PathBuf,
+    /// Media packets covered by each parity packet a content server sends, 0 sends none.
+    pub fec_block: u8,
+    /// Packets each hop keeps to send again when they are reported lost.
+    pub retransmission_buffer: usize,
+    /// How long a lost packet is asked for before it is given up, 0 never asks.
+    pub repair_timeout_ms: u64,
+    /// Time between two requests for the same lost packet.
+    pub nack_interval_ms: u64,
+    /// Share of the packets a viewer reports lost, in percent, that makes relays
+    /// move it to a lower rendition. 0 never does.
+    pub downgrade_loss_percent: u8,
+    /// Time over which relays weigh the losses viewers report.
+    pub report_interval_ms: u64,
+    /// Highest rate a stream is sent to each destination at, in kbit/s. 0 leaves it to congestion control.
+    pub max_bitrate_kbps: u64,
+    /// Packets queued for each destination before its oldest frames are dropped.
+    pub send_queue: usize,
+    /// Time packets may wait in a send queue before the link counts as congested.
+    pub target_queue_delay_ms: u64,
+    /// How far behind a broadcast viewers may watch it, in seconds. 0 keeps no history.
+    pub time_shift_window_s: u64,
+    /// Whether relays ask for the keys of the streams they relay, to record them.
+    pub record_on_relays: bool,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            frame_interval_ms: 50,
+            pooled_buffers: 8,
+            videos_dir: PathBuf::from("videos"),
+            fec_block: 0,
+            retransmission_buffer: 128,
+            repair_timeout_ms: 250,
+            nack_interval_ms: 50,
+            downgrade_loss_percent: 10,
+            report_interval_ms: 1000,
+            max_bitrate_kbps: 0,
+            send_queue: 64,
+            target_queue_delay_ms: 100,
+            time_shift_window_s: 30,
+            record_on_relays: false,
+        }
+    }
+}
+
+impl StreamingSettings {
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_millis(self.frame_interval_ms)
+    }
+
+    pub fn repair_timeout(&self) -> Duration {
+        Duration::from_millis(self.repair_timeout_ms)
+    }
+
+    pub fn nack_interval(&self) -> Duration {
+        Duration::from_millis(self.nack_interval_ms)
+    }
+
+    pub fn report_interval(&self) -> Duration {
+        Duration::from_millis(self.report_interval_ms)
+    }
+
+    pub fn target_queue_delay(&self) -> Duration {
+        Duration::from_millis(self.target_queue_delay_ms)
+    }
+
+    /// Frames a content server sends over `duration`.
+    pub fn frames_in(&self, duration: Duration) -> usize {
+        (duration.as_millis() / self.frame_interval_ms.max(1) as u128) as usize
+    }
+}
+
+/// How connections to the bootstrapper and content servers are retried.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Tries before giving up, 1 never retries.
+    pub attempts: u32,
+    /// Wait after the first failure, doubled after each of the next ones.
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff_ms: 500,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after the failed `attempt`, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+
+    /// Calls `operation` until it succeeds or the attempts run out, returning its last result.
+    pub fn retry_blocking<T, E: Display>(
+        &self,
+        mut operation: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(error) if attempt < self.attempts => {
+                    warn!(attempt, %error, "Attempt failed, retrying");
+                    std::thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Same as `retry_blocking` for async operations.
+    pub async fn retry<T, E: Display, F: Future<Output = Result<T, E>>>(
+        &self,
+        mut operation: impl FnMut() -> F,
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(error) if attempt < self.attempts => {
+                    warn!(attempt, %error, "Attempt failed, retrying");
+                    tokio::time::sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionSettings {
+    /// How the RP picks the content server of a video.
+    pub policy: SelectionPolicy,
+}
+
+/// How one of the servers that have a video is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionPolicy {
+    /// The one with the lowest metric.
+    #[default]
+    BestMetric,
+    /// The first one, in the order the servers were given.
+    First,
+    /// Any of them, spreading the load evenly.
+    Random,
+}
+
+impl SelectionPolicy {
+    /// Picks one of `candidates`, better ones having a lower `metric`.
+    pub fn choose<T>(self, candidates: Vec<T>, metric: impl Fn(&T) -> f64) -> Option<T> {
+        match self {
+            SelectionPolicy::BestMetric => candidates
+                .into_iter()
+                .min_by(|c1, c2| metric(c1).total_cmp(&metric(c2))),
+            SelectionPolicy::First => candidates.into_iter().next(),
+            SelectionPolicy::Random if candidates.is_empty() => None,
+            SelectionPolicy::Random => {
+                let chosen = rand::thread_rng().gen_range(0..candidates.len());
+                candidates.into_iter().nth(chosen)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Disk space relays may fill with the titles they relay, in megabytes. 0 caches nothing.
+    pub quota_mb: u64,
+    /// Folder relays keep the cached titles in.
+    pub dir: PathBuf,
+    /// Which titles make room for new ones once the quota is reached.
+    pub policy: EvictionPolicy,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            quota_mb: 0,
+            dir: PathBuf::from("cache"),
+            policy: EvictionPolicy::default(),
+        }
+    }
+}
+
+impl CacheSettings {
+    pub fn quota_bytes(&self) -> u64 {
+        self.quota_mb * 1024 * 1024
+    }
+}
+
+/// Which cached title is evicted to make room for a new one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// The one watched the longest ago.
+    #[default]
+    Lru,
+    /// The one watched the fewest times, the one watched the longest ago among those.
+    Lfu,
+}
+
+impl Settings {
+    /// Reads the settings layer by layer, each one overriding the ones before:
+    /// the defaults, `file`, the `ESR_<SECTION>_<KEY>` variables of `env` and
+    /// `overrides` given as `section.key=value`.
+    pub fn layered(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+        overrides: &[String],
+    ) -> Result<Self, SettingsError> {
+        let mut table = match file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|error| SettingsError::Read(path.display().to_string(), error))?
+                .parse::<Table>()?,
+            None => Table::new(),
+        };
+
+        for (name, value) in env {
+            let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_VAR {
+                continue;
+            }
+            if let Some((section, key)) = setting.split_once('_') {
+                set(
+                    &mut table,
+                    &section.to_lowercase(),
+                    &key.to_lowercase(),
+                    &value,
+                );
+            }
+        }
+
+        for setting in overrides {
+            let (section, key, value) = setting
+                .split_once('=')
+                .and_then(|(name, value)| {
+                    let (section, key) = name.split_once('.')?;
+                    Some((section.trim(), key.trim(), value.trim()))
+                })
+                .ok_or_else(|| SettingsError::InvalidOverride(setting.clone()))?;
+            set(&mut table, section, key, value);
+        }
+
+        let settings: Self = Value::Table(table).try_into()?;
+        settings.queries.limits().validate()?;
+
+        Ok(settings)
+    }
+}
+
+/// Sets `section.key`, reading `value` as a number or boolean when it is one.
+fn set(table: &mut Table, section: &str, key: &str, value: &str) {
+    let value = if let Ok(integer) = value.parse() {
+        Value::Integer(integer)
+    } else if let Ok(float) = value.parse() {
+        Value::Float(float)
+    } else if let Ok(boolean) = value.parse() {
+        Value::Boolean(boolean)
+    } else {
+        Value::String(value.to_string())
+    };
+
+    let section = table
+        .entry(section)
+        .or_insert_with(|| Value::Table(Table::new()));
+    if !section.is_table() {
+        *section = Value::Table(Table::new());
+    }
+    if let Value::Table(section) = section {
+        section.insert(key.to_string(), value);
+    }
+}
+
+/// Command line flags of the settings, shared by every binary.
+#[derive(Debug, Default, clap::Args)]
+pub struct SettingsArgs {
+    /// TOML file with the settings, `ESR_CONFIG` if missing
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Overrides a setting of the file and the environment, can be repeated
+    #[clap(long = "set", global = true, value_name = "SECTION.KEY=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+impl SettingsArgs {
+    /// Reads the settings from the file, the process environment and the flags.
+    pub fn load(&self) -> Result<Settings, SettingsError> {
+        let file = self
+            .config
+            .clone()
+            .or_else(|| std::env::var_os(CONFIG_VAR).map(PathBuf::from));
+
+        Settings::layered(file.as_deref(), std::env::vars(), &self.overrides)
+    }
+
+    /// Loads the settings and makes them the ones of the process.
+    pub fn init(&self) -> Result<(), SettingsError> {
+        init(self.load()?)
+    }
+}
+
+/// Makes `settings` the ones returned by `get`, must happen before they are first read.
+pub fn init(settings: Settings) -> Result<(), SettingsError> {
+    SETTINGS
+        .set(settings)
+        .map_err(|_| SettingsError::AlreadyInitialised)
+}
+
+/// Settings of the process, the defaults if `init` was not called.
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{RetryPolicy, SelectionPolicy, Settings, SettingsError};
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn example_file_documents_the_defaults() {
+        let example: Settings = toml::from_str(include_str!("../esr.example.toml")).unwrap();
+
+        assert_eq!(example, Settings::default());
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let file = std::env::temp_dir().join("esr_settings_layers.toml");
+        std::fs::write(
+            &file,
+            "[network]\nquery_timeout_ms = 2000\ndefault_port = 9000\n\n[queries]\nrate = 5.5\n",
+        )
+        .unwrap();
+
+        let settings = Settings::layered(
+            Some(&file),
+            env(&[
+                ("ESR_NETWORK_QUERY_TIMEOUT_MS", "3000"),
+                ("ESR_SELECTION_POLICY", "random"),
+                ("ESR_CONFIG", "ignored.toml"),
+                ("HOME", "/root"),
+            ]),
+            &["network.query_timeout_ms=4000".to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(settings.network.query_timeout(), Duration::from_secs(4));
+        assert_eq!(settings.network.default_port, 9000);
+        assert_eq!(settings.queries.rate, 5.5);
+        assert_eq!(settings.selection.policy, SelectionPolicy::Random);
+        assert_eq!(settings.streaming.frame_interval_ms, 50);
+    }
+
+    #[test]
+    fn unknown_and_malformed_settings_are_rejected() {
+        assert!(matches!(
+            Settings::layered(None, env(&[("ESR_NETWORK_TIMEOUT", "1")]), &[]),
+            Err(SettingsError::Invalid(_))
+        ));
+        assert!(matches!(
+            Settings::layered(None, Vec::new(), &["retry.attempts=many".to_string()]),
+            Err(SettingsError::Invalid(_))
+        ));
+        assert!(matches!(
+            Settings::layered(None, Vec::new(), &["attempts=3".to_string()]),
+            Err(SettingsError::InvalidOverride(_))
+        ));
+        assert!(matches!(
+            Settings::layered(None, Vec::new(), &["queries.rate=0".to_string()]),
+            Err(SettingsError::InvalidQueries(_))
+        ));
+    }
+
+    #[test]
+    fn selection_policies_pick_the_expected_candidate() {
+        let candidates = vec![("a", 3.0), ("b", 1.0), ("c", 2.0)];
+
+        let best = SelectionPolicy::BestMetric.choose(candidates.clone(), |c| c.1);
+        let first = SelectionPolicy::First.choose(candidates.clone(), |c| c.1);
+        let random = SelectionPolicy::Random.choose(candidates.clone(), |c| c.1);
+
+        assert_eq!(best, Some(("b", 1.0)));
+        assert_eq!(first, Some(("a", 3.0)));
+        assert!(candidates.contains(&random.unwrap()));
+        assert_eq!(
+            SelectionPolicy::Random.choose(Vec::<f64>::new(), |c| *c),
+            None
+        );
+    }
+
+    #[test]
+    fn operations_are_retried_until_they_succeed() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff_ms: 1,
+        };
+        let mut calls = 0;
+
+        let result = policy.retry_blocking(|| {
+            calls += 1;
+            if calls < 3 {
+                Err("refused")
+            } else {
+                Ok(calls)
+            }
+        });
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(policy.backoff(3), Duration::from_millis(4));
+        assert_eq!(
+            RetryPolicy::default().retry_blocking(|| Err::<(), _>("refused")),
+            Err("refused")
+        );
+    }
+}
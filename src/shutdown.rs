@@ -0,0 +1,55 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+use tracing::info;
+
+/// Cancellation token shared by every task of a component.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token that is triggered on SIGINT or SIGTERM.
+    pub fn on_signals() -> Result<Self, ctrlc::Error> {
+        let shutdown = Self::new();
+        let shutdown_clone = shutdown.clone();
+
+        ctrlc::set_handler(move || {
+            info!("Shutdown requested");
+            shutdown_clone.trigger();
+        })?;
+
+        Ok(shutdown)
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Completes once a shutdown is requested.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.notify.notified();
+
+            if self.is_triggered() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info};
+
+use crate::{
+    runtime::{self, TaskPool},
+    settings,
+    shutdown::Shutdown,
+    transport,
+};
+
+/// Maximum number of scrapes served at the same time.
+const MAX_SCRAPES: usize = 8;
+
+/// Buckets, in seconds, of the latency histograms.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Monotonic count, such as packets forwarded.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that goes up and down, such as open sessions.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn add(&self, delta: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + delta).to_bits())
+            });
+    }
+
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Distribution of observed values, such as query latencies.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Vec<Counter>,
+    sum: Gauge,
+    count: Counter,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            buckets: bounds.iter().map(|_| Counter::default()).collect(),
+            sum: Gauge::default(),
+            count: Counter::default(),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.inc();
+            }
+        }
+
+        self.sum.add(value);
+        self.count.inc();
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.get()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+/// Every series of a metric, by their rendered labels.
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    series: BTreeMap<String, Metric>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Writes a sample, `extra` being a label added to the series' own.
+fn sample(out: &mut String, name: &str, labels: &str, extra: Option<String>, value: f64) {
+    let labels = match (labels.is_empty(), extra) {
+        (true, None) => String::new(),
+        (true, Some(extra)) => format!("{{{}}}", extra),
+        (false, None) => format!("{{{}}}", labels),
+        (false, Some(extra)) => format!("{{{},{}}}", labels, extra),
+    };
+
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+/// Metrics of a component, rendered in the Prometheus text format.
+///
+/// Series are created the first time they are asked for and live as long as
+/// the registry, callers on hot paths keep the returned handle.
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Registry {
+    fn metric(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: &'static str,
+        labels: &[(&str, &str)],
+        create: impl FnOnce() -> Metric,
+    ) -> Metric {
+        let mut families = self.families.lock().unwrap();
+
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        assert_eq!(
+            family.kind, kind,
+            "{} registered as a {}",
+            name, family.kind
+        );
+
+        family
+            .series
+            .entry(render_labels(labels))
+            .or_insert_with(create)
+            .clone()
+    }
+
+    pub fn counter(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Counter> {
+        match self.metric(name, help, "counter", labels, || {
+            Metric::Counter(Arc::default())
+        }) {
+            Metric::Counter(counter) => counter,
+            _ => unreachable!("Kinds are checked on registration"),
+        }
+    }
+
+    pub fn gauge(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+    ) -> Arc<Gauge> {
+        match self.metric(
+            name,
+            help,
+            "gauge",
+            labels,
+            || Metric::Gauge(Arc::default()),
+        ) {
+            Metric::Gauge(gauge) => gauge,
+            _ => unreachable!("Kinds are checked on registration"),
+        }
+    }
+
+    /// Histogram with the given bucket bounds, those of the first registration are kept.
+    pub fn histogram(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+    ) -> Arc<Histogram> {
+        let create = || Metric::Histogram(Arc::new(Histogram::new(bounds)));
+
+        match self.metric(name, help, "histogram", labels, create) {
+            Metric::Histogram(histogram) => histogram,
+            _ => unreachable!("Kinds are checked on registration"),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, family) in self.families.lock().unwrap().iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+
+            for (labels, metric) in &family.series {
+                match metric {
+                    Metric::Counter(counter) => {
+                        sample(&mut out, name, labels, None, counter.get() as f64)
+                    }
+                    Metric::Gauge(gauge) => sample(&mut out, name, labels, None, gauge.get()),
+                    Metric::Histogram(histogram) => {
+                        let bucket = format!("{}_bucket", name);
+
+                        for (bound, count) in histogram.bounds.iter().zip(&histogram.buckets) {
+                            let le = Some(format!("le=\"{}\"", bound));
+                            sample(&mut out, &bucket, labels, le, count.get() as f64);
+                        }
+                        let le = Some("le=\"+Inf\"".to_string());
+                        sample(&mut out, &bucket, labels, le, histogram.count() as f64);
+
+                        let sum = format!("{}_sum", name);
+                        sample(&mut out, &sum, labels, None, histogram.sum.get());
+                        let count = format!("{}_count", name);
+                        sample(&mut out, &count, labels, None, histogram.count() as f64);
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Registry shared by every part of the running binary.
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Packets and bytes sent to the viewers of `file`, by relays and servers alike.
+pub fn forwarded(file: &str) -> (Arc<Counter>, Arc<Counter>) {
+    let labels = [("file", file)];
+
+    (
+        registry().counter(
+            "esr_forwarded_packets_total",
+            "Packets sent to viewers and downstream nodes, by file",
+            &labels,
+        ),
+        registry().counter(
+            "esr_forwarded_bytes_total",
+            "Bytes sent to viewers and downstream nodes, by file",
+            &labels,
+        ),
+    )
+}
+
+/// Packets of `file` sent again after being reported lost, and reports passed upstream.
+pub fn repairs(file: &str) -> (Arc<Counter>, Arc<Counter>) {
+    let labels = [("file", file)];
+
+    (
+        registry().counter(
+            "esr_retransmitted_packets_total",
+            "Packets sent again after a viewer or downstream node reported them lost, by file",
+            &labels,
+        ),
+        registry().counter(
+            "esr_nacks_sent_total",
+            "Reports of lost packets sent upstream, by file",
+            &labels,
+        ),
+    )
+}
+
+/// Frames of `file` a send queue dropped because its destination could not keep up.
+pub fn dropped_frames(file: &str) -> Arc<Counter> {
+    registry().counter(
+        "esr_dropped_frames_total",
+        "Packets dropped from full send queues, by file",
+        &[("file", file)],
+    )
+}
+
+/// Counts an on demand session a relay set up, by whether it was served from its cache.
+pub fn cache_lookup(result: &str) {
+    registry()
+        .counter(
+            "esr_cache_lookups_total",
+            "On demand sessions set up by relays with a cache, by whether the title was cached",
+            &[("result", result)],
+        )
+        .inc();
+}
+
+/// Titles relays removed from their cache to make room for others.
+pub fn cache_evictions() -> Arc<Counter> {
+    registry().counter(
+        "esr_cache_evictions_total",
+        "Titles removed from relay caches to make room for others",
+        &[],
+    )
+}
+
+/// Counts a query by how it was answered and observes how long answering it took.
+pub fn query_answered(result: &str, elapsed: Duration) {
+    registry()
+        .counter(
+            "esr_queries_total",
+            "Video queries answered, by result",
+            &[("result", result)],
+        )
+        .inc();
+    registry()
+        .histogram(
+            "esr_query_duration_seconds",
+            "Time taken to answer video queries",
+            &[],
+            &LATENCY_BUCKETS,
+        )
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Counts an error of the given kind.
+pub fn count_error(kind: &str) {
+    registry()
+        .counter(
+            "esr_errors_total",
+            "Errors raised while handling traffic, by kind",
+            &[("kind", kind)],
+        )
+        .inc();
+}
+
+async fn scrape(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let n = runtime::with_timeout(
+        settings::get().network.request_timeout(),
+        stream.read(&mut buffer),
+    )
+    .await?;
+    let request = String::from_utf8_lossy(&buffer[..n]);
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let response = match path {
+        "/metrics" => {
+            let body = registry().render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves the metrics over HTTP on `port`, under `/metrics`.
+pub async fn serve(port: u16, shutdown: &Shutdown) {
+    let listener = match transport::listen_any(port).and_then(TcpListener::from_std) {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Error binding the metrics exporter socket: {}", error);
+            return;
+        }
+    };
+    info!("Metrics exporter listening on port {}", port);
+
+    let mut tasks = TaskPool::new(MAX_SCRAPES);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = tasks.next() => {}
+            result = listener.accept(), if !tasks.is_full() => match result {
+                Ok((stream, _)) => tasks.push(async move {
+                    if let Err(error) = scrape(stream).await {
+                        error!("Error serving metrics {}", error);
+                    }
+                }),
+                Err(error) => error!("Error accepting metrics connection {}", error),
+            },
+        }
+    }
+
+    tasks.drain().await;
+}
+
+/// Serves the metrics on `port` if there is one, otherwise waits for the shutdown.
+pub async fn serve_on(port: Option<u16>, shutdown: &Shutdown) {
+    match port {
+        Some(port) => serve(port, shutdown).await,
+        None => shutdown.cancelled().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Registry;
+
+    #[test]
+    fn series_are_shared_and_rendered_by_labels() {
+        let registry = Registry::default();
+
+        let counter = registry.counter("esr_packets_total", "Packets", &[("file", "a\"b")]);
+        counter.add(3);
+        registry
+            .counter("esr_packets_total", "Packets", &[("file", "a\"b")])
+            .inc();
+        registry.gauge("esr_sessions", "Sessions", &[]).set(2.0);
+
+        let text = registry.render();
+
+        assert!(text.contains("# TYPE esr_packets_total counter\n"));
+        assert!(text.contains("esr_packets_total{file=\"a\\\"b\"} 4\n"));
+        assert!(text.contains("esr_sessions 2\n"));
+    }
+
+    #[test]
+    fn histograms_count_values_in_every_bucket_above_them() {
+        let registry = Registry::default();
+        let histogram = registry.histogram("esr_latency_seconds", "Latency", &[], &[0.1, 1.0]);
+
+        histogram.observe(0.0625);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+
+        let text = registry.render();
+
+        assert!(text.contains("esr_latency_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("esr_latency_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("esr_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("esr_latency_seconds_sum 2.5625\n"));
+        assert!(text.contains("esr_latency_seconds_count 3\n"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn names_keep_their_kind() {
+        let registry = Registry::default();
+
+        registry.counter("esr_sessions", "Sessions", &[]);
+        registry.gauge("esr_sessions", "Sessions", &[]);
+    }
+}
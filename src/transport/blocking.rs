@@ -0,0 +1,97 @@
+//! Sockets of a `Transport` used from outside a runtime, such as by the video player.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+};
+
+use super::{
+    canonical, first_address, reachable_from,
+    sim::{SimSocket, SimStream},
+};
+
+#[derive(Debug)]
+pub enum Stream {
+    Os(TcpStream),
+    Simulated(SimStream),
+}
+
+impl Stream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Os(stream) => stream.peer_addr().map(canonical),
+            Stream::Simulated(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Os(stream) => stream.read(buffer),
+            Stream::Simulated(stream) => Ok(stream.read_blocking(buffer)),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Os(stream) => stream.write(data),
+            Stream::Simulated(stream) => stream.write_blocking(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Os(stream) => stream.flush(),
+            Stream::Simulated(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DatagramSocket {
+    Os(UdpSocket),
+    Simulated(SimSocket),
+}
+
+impl DatagramSocket {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            DatagramSocket::Os(socket) => socket.local_addr(),
+            DatagramSocket::Simulated(socket) => Ok(socket.local_addr()),
+        }
+    }
+
+    pub fn send_to(&self, data: &[u8], to: impl ToSocketAddrs) -> io::Result<usize> {
+        match self {
+            DatagramSocket::Os(socket) => {
+                let to = reachable_from(&socket.local_addr()?, first_address(to)?);
+                socket.send_to(data, to)
+            }
+            DatagramSocket::Simulated(socket) => Ok(socket.send_to(data, first_address(to)?)),
+        }
+    }
+
+    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            DatagramSocket::Os(socket) => socket
+                .recv_from(buffer)
+                .map(|(n, from)| (n, canonical(from))),
+            DatagramSocket::Simulated(socket) => Ok(socket.recv_from_blocking(buffer)),
+        }
+    }
+
+    pub fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buffer).map(|(n, _)| n)
+    }
+
+    /// Reads the next datagram without taking it from the socket.
+    pub fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DatagramSocket::Os(socket) => socket.peek(buffer),
+            DatagramSocket::Simulated(socket) => Ok(socket.peek_blocking(buffer).0),
+        }
+    }
+}
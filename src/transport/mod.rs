@@ -0,0 +1,401 @@
+//! Sockets used by the overlay, either from the OS or from a `SimNetwork`.
+//!
+//! Components take a `Transport` and open every socket through it, so a whole
+//! overlay can run inside one process over a simulated network in tests.
+//!
+//! Sockets of the OS are dual stack: they accept IPv6 and IPv4 peers alike, and
+//! report IPv4 peers by their IPv4 address rather than as mapped IPv6 ones.
+
+use std::{
+    borrow::Cow,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::server::fan_out;
+
+pub mod blocking;
+pub mod sim;
+
+use sim::{Host, SimListener, SimSocket, SimStream};
+
+/// Where the sockets of a component come from.
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    /// Sockets of the operating system, bound to every interface.
+    #[default]
+    Os,
+    /// Sockets of a host of a `SimNetwork`.
+    Simulated(Host),
+}
+
+impl Transport {
+    /// Binds a datagram socket, `port` 0 picks any free one.
+    pub fn bind_udp(&self, port: u16) -> io::Result<DatagramSocket> {
+        match self {
+            Transport::Os => {
+                let socket = bind_any_udp(port)?;
+                socket.set_nonblocking(true)?;
+                Ok(DatagramSocket::Os(UdpSocket::from_std(socket)?))
+            }
+            Transport::Simulated(host) => host.bind_udp(port).map(DatagramSocket::Simulated),
+        }
+    }
+
+    pub async fn listen(&self, port: u16) -> io::Result<Listener> {
+        match self {
+            Transport::Os => Ok(Listener::Os(TcpListener::from_std(listen_any(port)?)?)),
+            Transport::Simulated(host) => host.listen(port).map(Listener::Simulated),
+        }
+    }
+
+    pub async fn connect(&self, address: impl Into<SocketAddr>) -> io::Result<Stream> {
+        match self {
+            Transport::Os => Ok(Stream::Os(TcpStream::connect(address.into()).await?)),
+            Transport::Simulated(host) => host.connect(address.into()).await.map(Stream::Simulated),
+        }
+    }
+
+    /// Connects from outside a runtime, blocking the thread.
+    pub fn connect_blocking(&self, address: impl ToSocketAddrs) -> io::Result<blocking::Stream> {
+        match self {
+            Transport::Os => Ok(blocking::Stream::Os(std::net::TcpStream::connect(address)?)),
+            Transport::Simulated(host) => {
+                let address = first_address(address)?;
+                host.connect_blocking(address)
+                    .map(blocking::Stream::Simulated)
+            }
+        }
+    }
+
+    /// Binds a datagram socket used from outside a runtime.
+    pub fn bind_udp_blocking(&self, port: u16) -> io::Result<blocking::DatagramSocket> {
+        match self {
+            Transport::Os => Ok(blocking::DatagramSocket::Os(bind_any_udp(port)?)),
+            Transport::Simulated(host) => {
+                host.bind_udp(port).map(blocking::DatagramSocket::Simulated)
+            }
+        }
+    }
+}
+
+/// Binds `port` on every interface of both IP versions, or of IPv4 alone on hosts without IPv6.
+fn bind_any(kind: Type, port: u16) -> io::Result<Socket> {
+    let bind = |domain: Domain, address: SocketAddr| -> io::Result<Socket> {
+        let socket = Socket::new(domain, kind, None)?;
+        if domain == Domain::IPV6 {
+            socket.set_only_v6(false)?;
+        }
+        // Same as `std`, so restarted listeners don't wait for old connections to expire
+        #[cfg(unix)]
+        if kind == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        socket.bind(&address.into())?;
+        Ok(socket)
+    };
+
+    match bind(Domain::IPV6, (Ipv6Addr::UNSPECIFIED, port).into()) {
+        Err(error) if without_ipv6(&error) => {
+            bind(Domain::IPV4, (Ipv4Addr::UNSPECIFIED, port).into())
+        }
+        result => result,
+    }
+}
+
+/// Whether `error` means the host has no IPv6, other errors like ports in use hold for IPv4 too.
+fn without_ipv6(error: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    if error.raw_os_error() == Some(libc::EAFNOSUPPORT) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::Unsupported
+    )
+}
+
+pub(crate) fn bind_any_udp(port: u16) -> io::Result<std::net::UdpSocket> {
+    Ok(bind_any(Type::DGRAM, port)?.into())
+}
+
+/// Listens on `port` of every interface, the listener is left non blocking for tokio.
+pub(crate) fn listen_any(port: u16) -> io::Result<std::net::TcpListener> {
+    let socket = bind_any(Type::STREAM, port)?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// `address` with IPv4 mapped IPv6 addresses turned back into IPv4 ones.
+pub(crate) fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// `destination` as a socket bound to `local` can send to it.
+fn reachable_from(local: &SocketAddr, destination: SocketAddr) -> SocketAddr {
+    match destination {
+        SocketAddr::V4(v4) if local.is_ipv6() => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => destination,
+    }
+}
+
+fn first_address(address: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to"))
+}
+
+#[derive(Debug)]
+pub enum DatagramSocket {
+    Os(UdpSocket),
+    Simulated(SimSocket),
+}
+
+impl DatagramSocket {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            DatagramSocket::Os(socket) => socket.local_addr(),
+            DatagramSocket::Simulated(socket) => Ok(socket.local_addr()),
+        }
+    }
+
+    pub async fn send_to(&self, data: &[u8], to: impl Into<SocketAddr>) -> io::Result<usize> {
+        match self {
+            DatagramSocket::Os(socket) => {
+                let to = reachable_from(&socket.local_addr()?, to.into());
+                socket.send_to(data, to).await
+            }
+            DatagramSocket::Simulated(socket) => Ok(socket.send_to(data, to.into())),
+        }
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            DatagramSocket::Os(socket) => {
+                let (n, from) = socket.recv_from(buffer).await?;
+                Ok((n, canonical(from)))
+            }
+            DatagramSocket::Simulated(socket) => Ok(socket.recv_from(buffer).await),
+        }
+    }
+
+    pub async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buffer).await.map(|(n, _)| n)
+    }
+
+    /// Waits until a datagram can be sent, simulated sockets always can.
+    pub async fn writable(&self) -> io::Result<()> {
+        match self {
+            DatagramSocket::Os(socket) => socket.writable().await,
+            DatagramSocket::Simulated(_) => Ok(()),
+        }
+    }
+
+    /// Sends `packet` to every destination without waiting, see `fan_out::send_to_all`.
+    pub fn send_to_all(&self, packet: &[u8], destinations: &[SocketAddr]) -> usize {
+        match self {
+            DatagramSocket::Os(socket) => {
+                let Ok(local) = socket.local_addr() else {
+                    return 0;
+                };
+
+                let destinations =
+                    if local.is_ipv6() && destinations.iter().any(SocketAddr::is_ipv4) {
+                        Cow::Owned(
+                            destinations
+                                .iter()
+                                .map(|destination| reachable_from(&local, *destination))
+                                .collect(),
+                        )
+                    } else {
+                        Cow::Borrowed(destinations)
+                    };
+
+                fan_out::send_to_all(socket, packet, &destinations)
+            }
+            DatagramSocket::Simulated(socket) => {
+                for destination in destinations {
+                    socket.send_to(packet, *destination);
+                }
+                destinations.len()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Os(TcpListener),
+    Simulated(SimListener),
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Os(listener) => listener.local_addr(),
+            Listener::Simulated(listener) => Ok(listener.local_addr()),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Os(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Stream::Os(stream), canonical(address)))
+            }
+            Listener::Simulated(listener) => {
+                let (stream, address) = listener.accept().await;
+                Ok((Stream::Simulated(stream), address))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Os(TcpStream),
+    Simulated(SimStream),
+}
+
+impl Stream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Os(stream) => stream.local_addr(),
+            Stream::Simulated(stream) => Ok(stream.local_addr()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Os(stream) => stream.peer_addr().map(canonical),
+            Stream::Simulated(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Os(stream) => Pin::new(stream).poll_read(cx, buffer),
+            Stream::Simulated(stream) => Pin::new(stream).poll_read(cx, buffer),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Os(stream) => Pin::new(stream).poll_write(cx, data),
+            Stream::Simulated(stream) => Pin::new(stream).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Os(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Simulated(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Os(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Simulated(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use socket2::{Domain, Socket, Type};
+
+    use crate::runtime;
+
+    use super::Transport;
+
+    #[test]
+    fn sockets_of_the_os_serve_both_ip_versions() {
+        runtime::block_on(async {
+            let socket = Transport::Os.bind_udp(0).unwrap();
+            let port = socket.local_addr().unwrap().port();
+
+            for loopback in [
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ] {
+                let peer = tokio::net::UdpSocket::bind((loopback, 0)).await.unwrap();
+                peer.send_to(b"ping", (loopback, port)).await.unwrap();
+
+                // IPv4 peers are not seen as mapped IPv6 addresses, and can be answered
+                let mut buffer = [0; 16];
+                let (n, from) = socket.recv_from(&mut buffer).await.unwrap();
+                assert_eq!(&buffer[..n], b"ping");
+                assert_eq!(from, peer.local_addr().unwrap());
+
+                socket.send_to(b"pong", from).await.unwrap();
+                let n = peer.recv(&mut buffer).await.unwrap();
+                assert_eq!(&buffer[..n], b"pong");
+            }
+
+            let v4 = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let v6 = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let destinations = [v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+            assert_eq!(socket.send_to_all(b"frame", &destinations), 2);
+            for peer in [v4, v6] {
+                let mut buffer = [0; 16];
+                let n = peer.recv(&mut buffer).await.unwrap();
+                assert_eq!(&buffer[..n], b"frame");
+            }
+
+            let listener = Transport::Os.listen(0).await.unwrap();
+            let address = SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                listener.local_addr().unwrap().port(),
+            );
+            let (client, accepted) =
+                tokio::join!(Transport::Os.connect(address), listener.accept());
+            let (stream, from) = accepted.unwrap();
+            assert_eq!(from, client.unwrap().local_addr().unwrap());
+            assert_eq!(stream.peer_addr().unwrap(), from);
+        });
+    }
+
+    #[test]
+    fn ports_taken_for_ipv6_are_not_bound_for_ipv4_alone() {
+        let taken = Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap();
+        taken.set_only_v6(true).unwrap();
+        taken
+            .bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).into())
+            .unwrap();
+        let port = taken.local_addr().unwrap().as_socket().unwrap().port();
+
+        let error = Transport::Os.bind_udp_blocking(port).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,852 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+use super::Transport;
+
+/// First port handed out to sockets bound to port 0.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// What happens to the traffic between two hosts.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Time datagrams take to cross the link, and connections to be established.
+    pub delay: Duration,
+    /// Extra delay of up to this much added to each datagram, which reorders them.
+    pub jitter: Duration,
+    /// Probability of a datagram being lost, from 0 to 1.
+    pub loss: f64,
+}
+
+impl LinkConditions {
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+}
+
+/// What the network did with the datagrams sent through it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub delivered: u64,
+    /// Dropped by a lossy link or a partition.
+    pub lost: u64,
+    /// Sent to an address nobody was bound to.
+    pub unreachable: u64,
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    rng: StdRng,
+    default_link: LinkConditions,
+    links: HashMap<(IpAddr, IpAddr), LinkConditions>,
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    sockets: HashMap<SocketAddr, Arc<Mailbox>>,
+    listeners: HashMap<SocketAddr, Arc<Backlog>>,
+    next_port: HashMap<IpAddr, u16>,
+    sequence: u64,
+    traffic: Traffic,
+}
+
+/// Links are the same in both directions.
+fn link_key(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// Loopback and unspecified addresses reach the sending host itself.
+fn resolve(from: IpAddr, to: SocketAddr) -> SocketAddr {
+    if to.ip().is_loopback() || to.ip().is_unspecified() {
+        SocketAddr::new(from, to.port())
+    } else {
+        to
+    }
+}
+
+impl NetworkState {
+    /// Conditions between `a` and `b`, `None` if they are partitioned.
+    fn conditions(&self, a: IpAddr, b: IpAddr) -> Option<LinkConditions> {
+        if a == b {
+            return Some(LinkConditions::default());
+        }
+
+        let key = link_key(a, b);
+        if self.partitions.contains(&key) {
+            return None;
+        }
+
+        Some(self.links.get(&key).copied().unwrap_or(self.default_link))
+    }
+
+    /// Picks a free port of `ip`, `in_use` telling which ones are taken.
+    fn ephemeral_port(
+        &mut self,
+        ip: IpAddr,
+        in_use: impl Fn(&Self, SocketAddr) -> bool,
+    ) -> io::Result<u16> {
+        let ports = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+
+        for _ in 0..ports {
+            let next = self.next_port.entry(ip).or_insert(FIRST_EPHEMERAL_PORT);
+            let port = *next;
+            *next = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+
+            if !in_use(self, SocketAddr::new(ip, port)) {
+                return Ok(port);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No free ports left",
+        ))
+    }
+}
+
+/// Network of simulated hosts living in this process.
+///
+/// Every pair of hosts is joined by a link with its own delay, jitter and loss,
+/// which can also be cut to partition the network. Losses and jitter come from
+/// a generator seeded on creation, so the same datagrams sent in the same order
+/// meet the same fate. Streams are reliable and only pay the delay when connecting.
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                rng: StdRng::seed_from_u64(seed),
+                default_link: LinkConditions::default(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                sockets: HashMap::new(),
+                listeners: HashMap::new(),
+                next_port: HashMap::new(),
+                sequence: 0,
+                traffic: Traffic::default(),
+            })),
+        }
+    }
+
+    /// Transport of the host with address `ip`, hosts need no registration.
+    pub fn host(&self, ip: impl Into<IpAddr>) -> Transport {
+        Transport::Simulated(Host {
+            network: self.clone(),
+            ip: ip.into(),
+        })
+    }
+
+    /// Conditions of the links not given their own.
+    pub fn set_default_link(&self, conditions: LinkConditions) {
+        self.state().default_link = conditions;
+    }
+
+    pub fn set_link(&self, a: impl Into<IpAddr>, b: impl Into<IpAddr>, conditions: LinkConditions) {
+        self.state()
+            .links
+            .insert(link_key(a.into(), b.into()), conditions);
+    }
+
+    /// Cuts the link between `a` and `b`, in both directions.
+    pub fn partition(&self, a: impl Into<IpAddr>, b: impl Into<IpAddr>) {
+        self.state().partitions.insert(link_key(a.into(), b.into()));
+    }
+
+    pub fn heal(&self, a: impl Into<IpAddr>, b: impl Into<IpAddr>) {
+        self.state()
+            .partitions
+            .remove(&link_key(a.into(), b.into()));
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        self.state().traffic
+    }
+
+    fn state(&self) -> MutexGuard<'_, NetworkState> {
+        self.state.lock().unwrap()
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        let to = resolve(from.ip(), to);
+        let mut state = self.state();
+
+        let Some(mailbox) = state.sockets.get(&to).cloned() else {
+            state.traffic.unreachable += 1;
+            return;
+        };
+
+        let Some(conditions) = state.conditions(from.ip(), to.ip()) else {
+            state.traffic.lost += 1;
+            return;
+        };
+
+        if conditions.loss > 0.0 && state.rng.gen_bool(conditions.loss.min(1.0)) {
+            state.traffic.lost += 1;
+            return;
+        }
+
+        let jitter = if conditions.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            conditions.jitter.mul_f64(state.rng.gen())
+        };
+
+        state.sequence += 1;
+        state.traffic.delivered += 1;
+        let sequence = state.sequence;
+        drop(state);
+
+        mailbox.push(Datagram {
+            deliver_at: Instant::now() + conditions.delay + jitter,
+            sequence,
+            from,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Time a connection from `from` to `to` takes to be established.
+    fn connection_delay(&self, from: IpAddr, to: SocketAddr) -> io::Result<Duration> {
+        let to = resolve(from, to);
+
+        self.state()
+            .conditions(from, to.ip())
+            .map(|conditions| conditions.delay)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "Host unreachable"))
+    }
+
+    /// Hands one end of a new stream to the listener at `to` and returns the other.
+    fn open_stream(&self, from: IpAddr, to: SocketAddr) -> io::Result<SimStream> {
+        let to = resolve(from, to);
+        let mut state = self.state();
+
+        if state.conditions(from, to.ip()).is_none() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Host unreachable"));
+        }
+
+        let backlog = state
+            .listeners
+            .get(&to)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        let port = state.ephemeral_port(from, |_, _| false)?;
+        drop(state);
+
+        let (local, remote) = SimStream::pair(SocketAddr::new(from, port), to);
+        backlog.push(remote);
+
+        Ok(local)
+    }
+}
+
+/// Host of a `SimNetwork`, which sockets are bound to.
+#[derive(Debug, Clone)]
+pub struct Host {
+    network: SimNetwork,
+    ip: IpAddr,
+}
+
+impl Host {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.network
+    }
+
+    pub fn bind_udp(&self, port: u16) -> io::Result<SimSocket> {
+        let mut state = self.network.state();
+
+        let port = match port {
+            0 => state.ephemeral_port(self.ip, |state, address| {
+                state.sockets.contains_key(&address)
+            })?,
+            port => port,
+        };
+        let address = SocketAddr::new(self.ip, port);
+
+        if state.sockets.contains_key(&address) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        let mailbox = Arc::new(Mailbox::default());
+        state.sockets.insert(address, Arc::clone(&mailbox));
+
+        Ok(SimSocket {
+            network: self.network.clone(),
+            address,
+            mailbox,
+        })
+    }
+
+    pub fn listen(&self, port: u16) -> io::Result<SimListener> {
+        let mut state = self.network.state();
+
+        let port = match port {
+            0 => state.ephemeral_port(self.ip, |state, address| {
+                state.listeners.contains_key(&address)
+            })?,
+            port => port,
+        };
+        let address = SocketAddr::new(self.ip, port);
+
+        if state.listeners.contains_key(&address) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        let backlog = Arc::new(Backlog::default());
+        state.listeners.insert(address, Arc::clone(&backlog));
+
+        Ok(SimListener {
+            network: self.network.clone(),
+            address,
+            backlog,
+        })
+    }
+
+    pub async fn connect(&self, to: SocketAddr) -> io::Result<SimStream> {
+        tokio::time::sleep(self.network.connection_delay(self.ip, to)?).await;
+
+        self.network.open_stream(self.ip, to)
+    }
+
+    pub fn connect_blocking(&self, to: SocketAddr) -> io::Result<SimStream> {
+        std::thread::sleep(self.network.connection_delay(self.ip, to)?);
+
+        self.network.open_stream(self.ip, to)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Datagram {
+    deliver_at: Instant,
+    sequence: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl Datagram {
+    /// Copies the datagram into `buffer`, what does not fit is lost.
+    fn copy_to(&self, buffer: &mut [u8]) -> (usize, SocketAddr) {
+        let n = self.data.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&self.data[..n]);
+
+        (n, self.from)
+    }
+}
+
+impl PartialEq for Datagram {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Datagram {}
+
+impl PartialOrd for Datagram {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Datagram {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.sequence).cmp(&(other.deliver_at, other.sequence))
+    }
+}
+
+enum Due {
+    Now(Datagram),
+    At(Instant),
+    Never,
+}
+
+/// Datagrams on their way to a socket, ordered by arrival.
+#[derive(Debug, Default)]
+struct Mailbox {
+    queue: Mutex<BinaryHeap<Reverse<Datagram>>>,
+    arrived: Notify,
+    ready: Condvar,
+}
+
+impl Mailbox {
+    fn push(&self, datagram: Datagram) {
+        self.queue.lock().unwrap().push(Reverse(datagram));
+        self.arrived.notify_waiters();
+        self.ready.notify_all();
+    }
+
+    /// The first datagram if it already arrived, removed from the queue unless `peek`.
+    fn due(queue: &mut BinaryHeap<Reverse<Datagram>>, peek: bool) -> Due {
+        match queue.peek() {
+            Some(Reverse(datagram)) if datagram.deliver_at <= Instant::now() => {
+                if peek {
+                    Due::Now(datagram.clone())
+                } else {
+                    Due::Now(queue.pop().expect("The queue is not empty").0)
+                }
+            }
+            Some(Reverse(datagram)) => Due::At(datagram.deliver_at),
+            None => Due::Never,
+        }
+    }
+
+    async fn receive(&self) -> Datagram {
+        loop {
+            let arrived = self.arrived.notified();
+            tokio::pin!(arrived);
+            // Registered before looking at the queue so no push goes unnoticed
+            arrived.as_mut().enable();
+
+            let due = Self::due(&mut self.queue.lock().unwrap(), false);
+            match due {
+                Due::Now(datagram) => return datagram,
+                Due::At(at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(at.into()) => {}
+                        _ = arrived => {}
+                    }
+                }
+                Due::Never => arrived.await,
+            }
+        }
+    }
+
+    fn receive_blocking(&self, peek: bool) -> Datagram {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            queue = match Self::due(&mut queue, peek) {
+                Due::Now(datagram) => return datagram,
+                Due::At(at) => {
+                    let wait = at.saturating_duration_since(Instant::now());
+                    self.ready.wait_timeout(queue, wait).unwrap().0
+                }
+                Due::Never => self.ready.wait(queue).unwrap(),
+            };
+        }
+    }
+}
+
+/// Datagram socket bound to an address of a `SimNetwork`.
+#[derive(Debug)]
+pub struct SimSocket {
+    network: SimNetwork,
+    address: SocketAddr,
+    mailbox: Arc<Mailbox>,
+}
+
+impl SimSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Sends `data` to `to`, it never waits and never fails, like UDP it may be lost.
+    pub fn send_to(&self, data: &[u8], to: SocketAddr) -> usize {
+        self.network.send(self.address, to, data);
+        data.len()
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> (usize, SocketAddr) {
+        self.mailbox.receive().await.copy_to(buffer)
+    }
+
+    pub fn recv_from_blocking(&self, buffer: &mut [u8]) -> (usize, SocketAddr) {
+        self.mailbox.receive_blocking(false).copy_to(buffer)
+    }
+
+    /// Reads the next datagram without taking it from the queue.
+    pub fn peek_blocking(&self, buffer: &mut [u8]) -> (usize, SocketAddr) {
+        self.mailbox.receive_blocking(true).copy_to(buffer)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.state().sockets.remove(&self.address);
+    }
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+    reader: Option<Waker>,
+}
+
+/// One direction of a stream.
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+
+        state.data.extend(data);
+        if let Some(reader) = state.reader.take() {
+            reader.wake();
+        }
+        self.readable.notify_all();
+
+        Ok(data.len())
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.closed = true;
+        if let Some(reader) = state.reader.take() {
+            reader.wake();
+        }
+        self.readable.notify_all();
+    }
+
+    fn take(state: &mut PipeState, buffer: &mut [u8]) -> usize {
+        let n = state.data.len().min(buffer.len());
+        for (byte, data) in buffer.iter_mut().zip(state.data.drain(..n)) {
+            *byte = data;
+        }
+        n
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buffer: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.data.is_empty() && !state.closed {
+            state.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = Self::take(&mut state, buffer.initialize_unfilled());
+        buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+
+    fn read_blocking(&self, buffer: &mut [u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+
+        while state.data.is_empty() && !state.closed {
+            state = self.readable.wait(state).unwrap();
+        }
+
+        Self::take(&mut state, buffer)
+    }
+}
+
+/// Reliable, ordered stream between two hosts of a `SimNetwork`.
+///
+/// Dropping either end closes the stream, the other end reads what was already
+/// sent and then sees the end of the stream, and its writes fail.
+#[derive(Debug)]
+pub struct SimStream {
+    local: SocketAddr,
+    peer: SocketAddr,
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl SimStream {
+    fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+        let (a_to_b, b_to_a) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+
+        let a_end = Self {
+            local: a,
+            peer: b,
+            incoming: Arc::clone(&b_to_a),
+            outgoing: Arc::clone(&a_to_b),
+        };
+        let b_end = Self {
+            local: b,
+            peer: a,
+            incoming: a_to_b,
+            outgoing: b_to_a,
+        };
+
+        (a_end, b_end)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn read_blocking(&self, buffer: &mut [u8]) -> usize {
+        self.incoming.read_blocking(buffer)
+    }
+
+    pub fn write_blocking(&self, data: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(data)
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.incoming.poll_read(cx, buffer)
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.outgoing.write(data))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Connections waiting to be accepted.
+#[derive(Debug, Default)]
+struct Backlog {
+    pending: Mutex<VecDeque<SimStream>>,
+    arrived: Notify,
+}
+
+impl Backlog {
+    fn push(&self, stream: SimStream) {
+        self.pending.lock().unwrap().push_back(stream);
+        self.arrived.notify_waiters();
+    }
+}
+
+/// Accepts the streams opened to an address of a `SimNetwork`.
+#[derive(Debug)]
+pub struct SimListener {
+    network: SimNetwork,
+    address: SocketAddr,
+    backlog: Arc<Backlog>,
+}
+
+impl SimListener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub async fn accept(&self) -> (SimStream, SocketAddr) {
+        loop {
+            let arrived = self.backlog.arrived.notified();
+            tokio::pin!(arrived);
+            arrived.as_mut().enable();
+
+            let stream = self.backlog.pending.lock().unwrap().pop_front();
+            if let Some(stream) = stream {
+                let peer = stream.peer_addr();
+                return (stream, peer);
+            }
+
+            arrived.await;
+        }
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.network.state().listeners.remove(&self.address);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{LinkConditions, SimNetwork, Traffic};
+    use crate::transport::Transport;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn host(network: &SimNetwork, ip: IpAddr) -> super::Host {
+        match network.host(ip) {
+            Transport::Simulated(host) => host,
+            Transport::Os => unreachable!("Networks only hand out simulated hosts"),
+        }
+    }
+
+    /// Sends `count` numbered datagrams from A to B and returns the numbers received.
+    fn numbers_received(network: &SimNetwork, count: u8) -> Vec<u8> {
+        let sender = host(network, A).bind_udp(0).unwrap();
+        let receiver = host(network, B).bind_udp(7000).unwrap();
+
+        for number in 0..count {
+            sender.send_to(&[number], SocketAddr::new(B, 7000));
+        }
+        std::thread::sleep(Duration::from_millis(30));
+
+        let delivered = network.traffic().delivered as usize;
+        (0..delivered)
+            .map(|_| {
+                let mut buffer = [0; 1];
+                receiver.recv_from_blocking(&mut buffer);
+                buffer[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lossy_links_drop_the_same_datagrams_for_the_same_seed() {
+        let lossy = LinkConditions::default().with_loss(0.3);
+        let runs: Vec<(Vec<u8>, Traffic)> = (0..2)
+            .map(|_| {
+                let network = SimNetwork::new(7);
+                network.set_link(A, B, lossy);
+                (numbers_received(&network, 100), network.traffic())
+            })
+            .collect();
+
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].1.lost > 10 && runs[0].1.lost < 60);
+        assert_eq!(runs[0].1.delivered + runs[0].1.lost, 100);
+    }
+
+    #[test]
+    fn jitter_reorders_datagrams() {
+        let network = SimNetwork::new(1);
+        network.set_default_link(LinkConditions::default().with_jitter(Duration::from_millis(20)));
+
+        let received = numbers_received(&network, 50);
+
+        assert_eq!(received.len(), 50);
+        assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[tokio::test]
+    async fn datagrams_take_the_link_delay() {
+        let network = SimNetwork::new(1);
+        network.set_link(
+            A,
+            B,
+            LinkConditions::default().with_delay(Duration::from_millis(50)),
+        );
+        let sender = host(&network, A).bind_udp(0).unwrap();
+        let receiver = host(&network, B).bind_udp(7000).unwrap();
+
+        let sent = Instant::now();
+        sender.send_to(b"hello", "127.0.0.1:1".parse().unwrap());
+        sender.send_to(b"hello", SocketAddr::new(B, 7000));
+
+        let mut buffer = [0; 16];
+        let (n, from) = receiver.recv_from(&mut buffer).await;
+
+        assert!(sent.elapsed() >= Duration::from_millis(50));
+        assert_eq!(&buffer[..n], b"hello");
+        assert_eq!(from, sender.local_addr());
+        assert_eq!(network.traffic().unreachable, 1);
+    }
+
+    #[tokio::test]
+    async fn partitions_cut_datagrams_and_new_connections() {
+        let network = SimNetwork::new(1);
+        let listener = host(&network, B).listen(8554).unwrap();
+        let sender = host(&network, A).bind_udp(0).unwrap();
+        let _receiver = host(&network, B).bind_udp(7000).unwrap();
+
+        network.partition(B, A);
+        sender.send_to(b"lost", SocketAddr::new(B, 7000));
+        let refused = host(&network, A).connect(listener.local_addr()).await;
+
+        assert_eq!(network.traffic().lost, 1);
+        assert_eq!(refused.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+
+        network.heal(A, B);
+        assert!(host(&network, A)
+            .connect(listener.local_addr())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn streams_carry_data_both_ways_until_closed() {
+        let network = SimNetwork::new(1);
+        let listener = host(&network, B).listen(8554).unwrap();
+
+        let mut client = host(&network, A)
+            .connect(SocketAddr::new(B, 8554))
+            .await
+            .unwrap();
+        let (mut server, peer) = listener.accept().await;
+        assert_eq!(peer, client.local_addr());
+        assert_eq!(server.peer_addr(), client.local_addr());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 16];
+        let n = server.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"ping");
+
+        server.write_all(b"pong").await.unwrap();
+        drop(server);
+        let n = client.read(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"pong");
+        assert_eq!(client.read(&mut buffer).await.unwrap(), 0);
+        assert!(client.write_all(b"again").await.is_err());
+
+        let refused = host(&network, A).connect(SocketAddr::new(B, 9000)).await;
+        assert_eq!(
+            refused.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+    }
+}
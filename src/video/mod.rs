@@ -1,2 +1,4 @@
 pub mod video_stream;
-pub mod packet_source;
\ No newline at end of file
+pub mod packet_source;
+pub mod rendition;
+pub mod recorder;
\ No newline at end of file
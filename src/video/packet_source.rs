@@ -1,5 +1,17 @@
 use std::net::UdpSocket;
 
+/// Largest payload that fits in a single UDP datagram.
+pub const MAX_PACKET_SIZE: u64 = 65507;
+
+/// Prefixes `packet` with its size, as expected by `PacketSource`.
+pub fn frame(packet: &[u8]) -> Vec<u8> {
+    let size = packet.len() as u64;
+
+    let mut framed = bincode::serialize(&size).expect("Error serializing size");
+    framed.extend(packet);
+    framed
+}
+
 pub trait PacketSource {
     fn receive_next_packet(&self) -> std::io::Result<Vec<u8>>;
 }
@@ -8,14 +20,23 @@ impl PacketSource for UdpSocket {
     fn receive_next_packet(&self) -> std::io::Result<Vec<u8>> {
         let mut buffer_size = [0; 8];
 
-        self.peek(&mut buffer_size)
-            .expect("Error getting size of buffer");
+        self.peek(&mut buffer_size)?;
+
+        let size: u64 = bincode::deserialize(&buffer_size)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
 
-        let size: u64 = bincode::deserialize(&buffer_size).expect("Error deserializing size");
+        if size > MAX_PACKET_SIZE {
+            // Consumes the datagram, otherwise it would be peeked forever
+            self.recv(&mut buffer_size)?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Packet size {} is bigger than a datagram", size),
+            ));
+        }
 
         let mut buffer = vec![0; (size + 8) as usize];
 
-        let n = self.recv(&mut buffer).expect("Error receiving packet");
+        let n = self.recv(&mut buffer)?;
 
         let buffer = &buffer[0..n];
 
@@ -0,0 +1,146 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::{error, info};
+
+use crate::settings;
+
+/// Suffix of recordings still being written, which are not servable yet.
+pub const IN_PROGRESS: &str = ".part";
+
+/// Longest frame the 5 digit length prefix can describe.
+const MAX_FRAME: usize = 99_999;
+
+/// Writes a stream to the videos folder, in the format `VideoStream` reads.
+///
+/// Frames go to `<name>.part` and the file only takes its name once the
+/// recording is over, so it never gets served half written. Frames are kept
+/// in stream order, those arriving after a later one are left out.
+#[derive(Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    name: String,
+    file: Option<BufWriter<File>>,
+    last: Option<(u32, u16)>,
+    frames: u32,
+}
+
+/// Whether `name` is a plain file name, which can only name a video in the folder it is looked up in.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with(IN_PROGRESS)
+        && !name.contains(['/', '\\'])
+}
+
+impl Recorder {
+    /// Starts recording to `name`, which must be a plain file name not used by any other video.
+    pub fn create(name: &str) -> std::io::Result<Self> {
+        Self::create_in(&settings::get().streaming.videos_dir, name)
+    }
+
+    /// Starts recording to `name` in `dir` rather than the videos folder.
+    pub fn create_in(dir: &Path, name: &str) -> std::io::Result<Self> {
+        if !is_valid_name(name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid recording name {}", name),
+            ));
+        }
+
+        if dir.join(name).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", name),
+            ));
+        }
+
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(format!("{}{}", name, IN_PROGRESS)))?;
+        info!("Recording to {}", name);
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            file: Some(BufWriter::new(file)),
+            last: None,
+            frames: 0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Frames written so far.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Appends the frame sent as packet `id`, unless a later one was already written.
+    pub fn write(&mut self, (ssrc, sequence): (u32, u16), frame: &[u8]) -> std::io::Result<()> {
+        if let Some((last_ssrc, last_sequence)) = self.last {
+            let ahead = sequence.wrapping_sub(last_sequence);
+            if last_ssrc == ssrc && (ahead == 0 || ahead > u16::MAX / 2) {
+                return Ok(());
+            }
+        }
+
+        if frame.len() > MAX_FRAME {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Frame of {} bytes is too long to record", frame.len()),
+            ));
+        }
+
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+
+        file.write_all(format!("{:05}", frame.len()).as_bytes())?;
+        file.write_all(frame)?;
+
+        self.last = Some((ssrc, sequence));
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Ends the recording, making it servable under its name.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+
+        file.flush()?;
+        fs::rename(self.in_progress(), self.dir.join(&self.name))?;
+        info!("Recorded {} frames to {}", self.frames, self.name);
+
+        Ok(())
+    }
+
+    /// Ends the recording, deleting what was written.
+    pub fn discard(mut self) -> std::io::Result<()> {
+        if self.file.take().is_some() {
+            fs::remove_file(self.in_progress())?;
+        }
+
+        Ok(())
+    }
+
+    fn in_progress(&self) -> PathBuf {
+        self.dir.join(format!("{}{}", self.name, IN_PROGRESS))
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(error) = self.finish() {
+            error!("Error finishing the recording {}: {}", self.name, error);
+        }
+    }
+}
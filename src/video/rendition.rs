@@ -0,0 +1,90 @@
+//! Renditions of a title, copies of its video at a lower quality.
+//!
+//! A rendition sits next to its title in `streaming.videos_dir`, named after
+//! it with its quality in between: `movie@240.Mjpeg` is `movie.Mjpeg` at 240
+//! lines. The title itself is the best quality there is, and is what `None`
+//! stands for.
+
+use std::fs;
+
+use crate::settings;
+
+/// File holding `title` at `quality`.
+pub fn file(title: &str, quality: Option<u32>) -> String {
+    let Some(quality) = quality else {
+        return title.to_string();
+    };
+
+    match title.rsplit_once('.') {
+        Some((name, extension)) => format!("{}@{}.{}", name, quality, extension),
+        None => format!("{}@{}", title, quality),
+    }
+}
+
+/// Quality of `file` if it is a rendition of `title`.
+fn quality_of(title: &str, file: &str) -> Option<u32> {
+    let (name, extension) = title.rsplit_once('.').unwrap_or((title, ""));
+    let rest = file.strip_prefix(name)?.strip_prefix('@')?;
+
+    let quality = match extension {
+        "" => rest,
+        extension => rest.strip_suffix(extension)?.strip_suffix('.')?,
+    };
+
+    quality.parse().ok()
+}
+
+/// Qualities `title` is available in besides its own, best first.
+pub fn list(title: &str) -> Vec<u32> {
+    let Ok(entries) = fs::read_dir(&settings::get().streaming.videos_dir) else {
+        return Vec::new();
+    };
+
+    let mut qualities: Vec<u32> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|file| quality_of(title, &file))
+        .collect();
+
+    qualities.sort_unstable_by(|a, b| b.cmp(a));
+    qualities.dedup();
+    qualities
+}
+
+/// Next quality of `renditions` below `current`, if there is one.
+pub fn below(renditions: &[u32], current: Option<u32>) -> Option<u32> {
+    renditions
+        .iter()
+        .copied()
+        .filter(|&quality| current.is_none_or(|current| quality < current))
+        .max()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{below, file, quality_of};
+
+    #[test]
+    fn renditions_are_named_after_their_title() {
+        assert_eq!(file("movie.Mjpeg", None), "movie.Mjpeg");
+        assert_eq!(file("movie.Mjpeg", Some(240)), "movie@240.Mjpeg");
+        assert_eq!(file("my.movie.Mjpeg", Some(240)), "my.movie@240.Mjpeg");
+        assert_eq!(file("movie", Some(240)), "movie@240");
+
+        assert_eq!(quality_of("movie.Mjpeg", "movie@240.Mjpeg"), Some(240));
+        assert_eq!(quality_of("movie", "movie@240"), Some(240));
+        assert_eq!(quality_of("movie.Mjpeg", "movie.Mjpeg"), None);
+        assert_eq!(quality_of("movie.Mjpeg", "movie@hd.Mjpeg"), None);
+        assert_eq!(quality_of("movie.Mjpeg", "movie2@240.Mjpeg"), None);
+    }
+
+    #[test]
+    fn downgrades_go_one_quality_at_a_time() {
+        let renditions = [480, 240];
+
+        assert_eq!(below(&renditions, None), Some(480));
+        assert_eq!(below(&renditions, Some(480)), Some(240));
+        assert_eq!(below(&renditions, Some(360)), Some(240));
+        assert_eq!(below(&renditions, Some(240)), None);
+        assert_eq!(below(&[], None), None);
+    }
+}
@@ -1,12 +1,16 @@
 use std::{
     fs::File,
-    io::{Read, Seek},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
 };
 
-use crate::message::rtp::RtpPacketBuilder;
+use crate::{
+    message::rtp::{RtpPacket, RtpPacketBuilder},
+    settings,
+    video::{packet_source, recorder},
+};
 
 const PACKET_TYPE: u8 = 26;
-const VIDEOS_FOULDER: &str = "videos";
 
 #[derive(Debug)]
 pub struct VideoStream {
@@ -17,7 +21,12 @@ pub struct VideoStream {
 
 impl VideoStream {
     pub fn new(file_name: &str) -> std::io::Result<Self> {
-        let file = File::open(format!("{}/{}", VIDEOS_FOULDER, file_name))?;
+        Self::open(&settings::get().streaming.videos_dir.join(file_name))
+    }
+
+    /// Stream of a file outside the videos folder.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
 
         let metadata = file.metadata()?;
         let file_size = metadata.len();
@@ -29,35 +38,61 @@ impl VideoStream {
         })
     }
 
+    /// Whether `file_name` is a video of the videos folder, names reaching outside of it never are.
     pub fn file_exists(file_name: &str) -> bool {
-        let path = format!("{}/{}", VIDEOS_FOULDER, file_name);
-        std::path::Path::new(&path).exists()
+        if !recorder::is_valid_name(file_name) {
+            return false;
+        }
+
+        settings::get()
+            .streaming
+            .videos_dir
+            .join(file_name)
+            .exists()
     }
 
-    pub fn receive_next_packet(&mut self) -> std::io::Result<Vec<u8>> {
-        let data = self.next_frame()?;
-        let frame_number = self.frame_num();
+    /// Files that can be streamed, recordings still being written left out.
+    pub fn available() -> std::io::Result<Vec<String>> {
+        Ok(std::fs::read_dir(&settings::get().streaming.videos_dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|file| !file.ends_with(recorder::IN_PROGRESS))
+            .collect())
+    }
 
-        let packet = RtpPacketBuilder::new(&data, PACKET_TYPE)
-            .sequence_number(frame_number as u16)
-            .build();
+    /// Frames in the file, counted without reading them.
+    pub fn count_frames(file_name: &str) -> std::io::Result<u32> {
+        let mut stream = Self::new(file_name)?;
+        let mut frames = 0;
 
-        let encode = packet.transmit_data();
+        while stream.file.stream_position()? < stream.file_size {
+            let frame_length = stream.read_length()?;
+            stream.file.seek(SeekFrom::Current(frame_length as i64))?;
+            frames += 1;
+        }
 
-        let size = encode.len() as u64;
+        Ok(frames)
+    }
 
-        let size_encoded = bincode::serialize(&size).expect("Error serializing size");
+    pub fn receive_next_packet(&mut self) -> std::io::Result<Vec<u8>> {
+        let packet = self.next_packet()?;
 
-        let mut encoded = size_encoded;
-        encoded.extend(encode);
+        Ok(packet_source::frame(&packet.transmit_data()))
+    }
 
-        Ok(encoded)
+    /// Next frame as an RTP packet, without the size prefix.
+    pub fn next_packet(&mut self) -> std::io::Result<RtpPacket> {
+        let data = self.next_frame()?;
+        let frame_number = self.frame_num();
+
+        Ok(RtpPacketBuilder::new(&data, PACKET_TYPE)
+            .sequence_number(frame_number as u16)
+            .build())
     }
 
     fn loop_file(&mut self) -> std::io::Result<()> {
         let current_position = self.file.stream_position()?;
         if current_position == self.file_size {
-            self.file.seek(std::io::SeekFrom::Start(0))?;
+            self.file.seek(SeekFrom::Start(0))?;
         }
 
         Ok(())
@@ -66,12 +101,7 @@ impl VideoStream {
     pub fn next_frame(&mut self) -> std::io::Result<Vec<u8>> {
         self.loop_file()?;
 
-        let mut buffer = [0; 5];
-        self.file.read_exact(&mut buffer)?;
-
-        let buffer = String::from_utf8_lossy(&buffer);
-
-        let frame_length = buffer.parse::<usize>().unwrap();
+        let frame_length = self.read_length()?;
 
         let mut buffer = vec![0; frame_length];
 
@@ -82,6 +112,27 @@ impl VideoStream {
         Ok(buffer)
     }
 
+    /// Reads the length prefix of the next frame.
+    fn read_length(&mut self) -> std::io::Result<usize> {
+        let mut buffer = [0; 5];
+        self.file.read_exact(&mut buffer)?;
+
+        let buffer = String::from_utf8_lossy(&buffer);
+
+        buffer.parse::<usize>().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid frame length")
+        })
+    }
+
+    /// Skips ahead to `frame`, so the next packet is the one after it.
+    pub fn skip_to(&mut self, frame: u32) -> std::io::Result<()> {
+        while self.frame_num < frame {
+            self.next_frame()?;
+        }
+
+        Ok(())
+    }
+
     pub fn frame_num(&self) -> u32 {
         self.frame_num
     }
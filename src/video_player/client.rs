@@ -1,20 +1,33 @@
 use std::{
     io::{Read, Write},
-    net::{TcpStream, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use thiserror::Error;
+use tracing::{debug, warn};
 
 use crate::{
     message::{
         self,
         answer::Answer,
+        auth::{AuthError, Authenticator},
+        credentials::Credentials,
+        fec::FecDecoder,
+        nack::LossTracker,
         query::Query,
-        rtp::RtpPacket,
-        rtsp::{RequestType, RtspRequest, RtspResponse},
+        rtp::{self, RtpPacket},
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status, StreamingMode},
+        srtp::{KeyRequest, MediaKey, SrtpReceiver},
         Message,
     },
     o_node::neighbour::Neighbour,
+    settings,
+    transport::{
+        blocking::{DatagramSocket, Stream},
+        Transport,
+    },
+    video::packet_source,
 };
 
 use super::{Args, VideoPlayerComponent};
@@ -27,15 +40,24 @@ pub enum RequestError {
     ActionNotPossible(String),
     #[error("Error connecting to server{0}")]
     ConnectionError(String),
+    #[error("Unauthenticated message: {0}")]
+    Unauthenticated(#[from] AuthError),
+    #[error("Access denied: missing or invalid credentials")]
+    Unauthorized,
+    #[error("Access denied: not allowed to watch {0} right now")]
+    Forbidden(String),
 }
 
 #[derive(Debug)]
 struct ServerConnection {
-    server_socket: TcpStream,
-    udp_socket: Option<UdpSocket>,
+    server_socket: Stream,
+    udp_socket: Option<DatagramSocket>,
     session_id: Option<u32>,
     stop_transmission: bool,
     sequence_number: u32,
+    media: Option<SrtpReceiver>,
+    fec: Mutex<FecDecoder>,
+    losses: Mutex<LossTracker>,
 }
 
 #[derive(Debug, Default)]
@@ -44,8 +66,16 @@ pub struct Client {
     server_port: u16,
     rtp_port: u16,
     video_file: String,
+    mode: Option<StreamingMode>,
+    rendition: Option<u32>,
+    renditions: Vec<u32>,
+    time_shift: Duration,
     server_connection: Option<ServerConnection>,
     servers_to_connect: Vec<Neighbour>,
+    auth: Authenticator,
+    credentials: Option<Credentials>,
+    trace_id: u32,
+    transport: Transport,
 }
 
 impl VideoPlayerComponent for Client {
@@ -58,6 +88,18 @@ impl VideoPlayerComponent for Client {
             init.rtp_port,
             init.video_file.clone(),
         )
+        .with_mode(init.mode)
+        .with_rendition(init.rendition)
+        .with_time_shift(Duration::from_secs(init.time_shift))
+        .with_authenticator(
+            Authenticator::from_key_file(init.key_file.as_ref())
+                .expect("Error reading the key file"),
+        )
+        .with_credentials(
+            init.user
+                .clone()
+                .map(|user| Credentials::new(user, init.token.clone().unwrap_or_default())),
+        )
     }
 }
 
@@ -72,6 +114,48 @@ impl Client {
         }
     }
 
+    pub fn with_mode(mut self, mode: Option<StreamingMode>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Quality to set up the stream in, `None` asks for the file itself.
+    pub fn with_rendition(mut self, rendition: Option<u32>) -> Self {
+        self.rendition = rendition;
+        self
+    }
+
+    /// How far behind the live broadcast to watch, as far as the content server keeps it.
+    pub fn with_time_shift(mut self, time_shift: Duration) -> Self {
+        self.time_shift = time_shift;
+        self
+    }
+
+    pub fn with_authenticator(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Opens the connection to the server and the rtp socket through `transport`.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Error reported for a request the server did not accept.
+    pub fn refused(&self, status: Status) -> RequestError {
+        match status {
+            Status::Unauthorized => RequestError::Unauthorized,
+            Status::Forbidden => RequestError::Forbidden(self.video_file.clone()),
+            _ => RequestError::FailedRequest,
+        }
+    }
+
     pub fn make_request(&mut self, request: RequestType) -> Result<RtspResponse, RequestError> {
         let server_connection =
             self.server_connection
@@ -88,9 +172,13 @@ impl Client {
             seq_number,
             self.rtp_port,
             self.servers_to_connect.clone(),
-        );
+        )
+        .with_mode(self.mode)
+        .with_credentials(self.credentials.clone())
+        .with_trace_id(self.trace_id)
+        .with_rendition(self.rendition);
 
-        let request = bincode::serialize(&request).expect("Error serializing packet");
+        let request = self.auth.seal(&request);
 
         let tcp_socket = &mut server_connection.server_socket;
 
@@ -104,7 +192,30 @@ impl Client {
             .read(&mut buffer)
             .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
 
-        Ok(bincode::deserialize(&buffer[..n]).expect("Error deserializing packet"))
+        let peer = tcp_socket
+            .peer_addr()
+            .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
+
+        Ok(self.auth.open(&buffer[..n], peer.ip())?)
+    }
+
+    /// Moves the session to another quality of the file, the stream carries on from where it is.
+    pub fn switch_rendition(&mut self, rendition: Option<u32>) -> Result<(), RequestError> {
+        let current = std::mem::replace(&mut self.rendition, rendition);
+
+        let response = self.make_request(RequestType::Switch)?;
+
+        if !response.succeded() {
+            self.rendition = current;
+            return Err(self.refused(response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Lower qualities the file is also available in, best first, known once set up.
+    pub fn renditions(&self) -> &[u32] {
+        &self.renditions
     }
 
     pub fn session_id(&self) -> Option<u32> {
@@ -129,14 +240,18 @@ impl Client {
             self.video_file.clone(),
             sequence_number + 1,
             self.rtp_port,
-        );
+        )
+        .with_mode(self.mode)
+        .with_credentials(self.credentials.clone())
+        .with_trace_id(self.trace_id)
+        .with_rendition(self.rendition);
 
         self.send_rtsp_packet(message)?;
 
         let response = self.receive_rtsp_packet()?;
 
         if !response.succeded() {
-            return Err(RequestError::FailedRequest.into());
+            return Err(self.refused(response.status()).into());
         }
 
         let server_connection =
@@ -154,31 +269,37 @@ impl Client {
 
     pub fn find_video(
         &self,
-        udp_socket: &UdpSocket,
+        udp_socket: &DatagramSocket,
     ) -> Result<Answer<Vec<Neighbour>>, RequestError> {
-        let query = Query::new_file_query(&self.video_file, None);
+        let query = Query::new_file_query(&self.video_file, None)
+            .with_credentials(self.credentials.clone());
 
-        let query_encode = bincode::serialize(&query).unwrap();
+        let query_encode = self.auth.seal(&query);
 
         let _ = udp_socket
             .send_to(&query_encode, (self.server_name.as_str(), self.server_port))
             .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
 
         let mut buffer = [0; 1024];
-        let n = udp_socket
-            .recv(&mut buffer)
+        let (n, addr) = udp_socket
+            .recv_from(&mut buffer)
             .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
-        let answer = bincode::deserialize(&buffer[..n]).unwrap();
+        let answer = self.auth.open(&buffer[..n], addr.ip())?;
 
         Ok(answer)
     }
 
     pub fn setup(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let udp_socket = UdpSocket::bind(("0.0.0.0", self.rtp_port))
+        let udp_socket = self
+            .transport
+            .bind_udp_blocking(self.rtp_port)
             .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
 
         let answer = self.find_video(&udp_socket)?;
-        println!("Answer found {:?}", answer);
+        debug!("Answer found {:?}", answer);
+
+        // The session is logged under the id of the query that found it on every hop
+        self.trace_id = answer.id();
 
         if !answer.status().is_ok() {
             return Err(RequestError::FailedRequest.into());
@@ -191,16 +312,27 @@ impl Client {
 
         let seq_number = 1;
 
+        // The key of the stream is sealed for this session only, relays can't read it
+        let key_request = KeyRequest::new();
+
         let message = RtspRequest::new_with_servers(
             message::rtsp::RequestType::Setup,
             self.video_file.clone(),
             seq_number,
             self.rtp_port,
             servers_to_connect.clone(),
-        );
-
-        println!("Message to server {:?}", message);
-        let server_socket = TcpStream::connect((self.server_name.as_str(), self.server_port))
+        )
+        .with_mode(self.mode)
+        .with_credentials(self.credentials.clone())
+        .with_trace_id(self.trace_id)
+        .with_rendition(self.rendition)
+        .with_time_shift(self.time_shift)
+        .with_key_shares(vec![key_request.share()]);
+
+        debug!("Message to server {:?}", message);
+        let server_socket = self
+            .transport
+            .connect_blocking((self.server_name.as_str(), self.server_port))
             .map_err(|_| RequestError::FailedRequest)?;
 
         self.server_connection = Some(ServerConnection {
@@ -209,57 +341,120 @@ impl Client {
             session_id: None,
             stop_transmission: false,
             sequence_number: 1,
+            media: None,
+            fec: Mutex::new(FecDecoder::new()),
+            losses: Mutex::new(LossTracker::new(
+                settings::get().streaming.repair_timeout(),
+                settings::get().streaming.nack_interval(),
+            )),
         });
 
         self.send_rtsp_packet(message)?;
         let response = self.receive_rtsp_packet()?;
-        println!("Response from server {:?}", response);
+        debug!("Response from server {:?}", response);
 
         if !response.succeded() {
             self.server_connection = None;
-            return Err(RequestError::FailedRequest.into());
+            return Err(self.refused(response.status()).into());
         }
 
-        self.server_connection
-            .as_mut()
-            .ok_or(RequestError::ActionNotPossible(
-                "Client must have a connection with server".to_string(),
-            ))?
-            .session_id = Some(response.session_id());
+        let server_connection =
+            self.server_connection
+                .as_mut()
+                .ok_or(RequestError::ActionNotPossible(
+                    "Client must have a connection with server".to_string(),
+                ))?;
+
+        server_connection.session_id = Some(response.session_id());
+        server_connection.media = key_request
+            .open(response.media_keys())
+            .as_ref()
+            .map(MediaKey::receiver);
+        self.renditions = response.renditions().to_vec();
 
         Ok(())
     }
 
+    /// Waits for the next packet, dropping the ones that are malformed or fail to decrypt.
+    ///
+    /// Packets lost on the way are asked for again to whoever sent the stream,
+    /// and returned once they arrive or the parity of their block does, so
+    /// they may come after later ones.
     pub fn receive_rtp_packet(&self) -> Result<RtpPacket, RequestError> {
-        let mut buffer_size = [0; 8];
-
-        let udp_socket = &self
-            .server_connection
-            .as_ref()
-            .ok_or(RequestError::ActionNotPossible(
-                "Client must have a connection with server".to_string(),
-            ))?
-            .udp_socket
-            .as_ref()
-            .ok_or(RequestError::ActionNotPossible(
-                "Client must have a connection with server".to_string(),
-            ))?;
-
-        udp_socket.peek(&mut buffer_size).map_err(|_| {
-            RequestError::ActionNotPossible("Client must have a connection with server".to_string())
-        })?;
-
-        let size: u64 = bincode::deserialize(&buffer_size).expect("Error deserializing size");
-
-        let mut buffer = vec![0; (size + 8) as usize];
-
-        let n = udp_socket
-            .recv(&mut buffer)
-            .expect("Error receiving packet");
+        let server_connection =
+            self.server_connection
+                .as_ref()
+                .ok_or(RequestError::ActionNotPossible(
+                    "Client must have a connection with server".to_string(),
+                ))?;
 
-        let buffer = &buffer[8..n];
+        let udp_socket =
+            server_connection
+                .udp_socket
+                .as_ref()
+                .ok_or(RequestError::ActionNotPossible(
+                    "Client must have a connection with server".to_string(),
+                ))?;
 
-        Ok(RtpPacket::decode(buffer))
+        loop {
+            let mut buffer_size = [0; 8];
+
+            udp_socket.peek(&mut buffer_size).map_err(|_| {
+                RequestError::ActionNotPossible(
+                    "Client must have a connection with server".to_string(),
+                )
+            })?;
+
+            let size: u64 = bincode::deserialize(&buffer_size)
+                .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
+
+            if size > packet_source::MAX_PACKET_SIZE {
+                // Consumes the datagram, otherwise it would be peeked forever
+                udp_socket
+                    .recv(&mut buffer_size)
+                    .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
+                warn!("Dropping packet of size {}, bigger than a datagram", size);
+                continue;
+            }
+
+            let mut buffer = vec![0; (size + 8) as usize];
+
+            let (n, from) = udp_socket
+                .recv_from(&mut buffer)
+                .map_err(|err| RequestError::ConnectionError(err.to_string()))?;
+
+            if n < buffer_size.len() {
+                warn!("Dropping packet of {} bytes, too short for its size", n);
+                continue;
+            }
+
+            let Some(buffer) = server_connection.fec.lock().unwrap().receive(&buffer[8..n]) else {
+                continue;
+            };
+
+            let nacks = {
+                let mut losses = server_connection.losses.lock().unwrap();
+                if let Some(id) = rtp::packet_id(&buffer) {
+                    losses.observe(id, Instant::now());
+                }
+                losses.due(Instant::now())
+            };
+            for nack in nacks {
+                if let Err(error) = udp_socket.send_to(&packet_source::frame(&nack.encode()), from)
+                {
+                    warn!("Error asking for lost packets: {}", error);
+                }
+            }
+
+            let Some(media) = &server_connection.media else {
+                return Ok(RtpPacket::decode(&buffer));
+            };
+
+            match media.unprotect(&buffer) {
+                Ok(packet) => return Ok(RtpPacket::decode(&packet)),
+                Err(error) => warn!("Dropping packet: {}", error),
+            }
+        }
     }
 
     fn send_rtsp_packet(&mut self, packet: RtspRequest) -> std::io::Result<()> {
@@ -268,19 +463,19 @@ impl Client {
             .as_mut()
             .unwrap()
             .server_socket
-            .write_all(&bincode::serialize(&packet).expect("Error serializing packet"));
+            .write_all(&self.auth.seal(&packet));
     }
 
     fn receive_rtsp_packet(&mut self) -> std::io::Result<RtspResponse> {
         let mut buffer = [0; 1024];
 
-        let n = self
-            .server_connection
-            .as_mut()
-            .unwrap()
-            .server_socket
-            .read(&mut buffer)?;
+        let server_socket = &mut self.server_connection.as_mut().unwrap().server_socket;
+
+        let n = server_socket.read(&mut buffer)?;
+        let peer = server_socket.peer_addr()?.ip();
 
-        Ok(bincode::deserialize(&buffer[..n]).expect("Error deserializing packet"))
+        self.auth
+            .open(&buffer[..n], peer)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
     }
 }
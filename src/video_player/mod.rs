@@ -9,8 +9,9 @@ use std::io::Write;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 use std::thread;
+use tracing::{error, info};
 
-use crate::message;
+use crate::{message, settings::SettingsArgs};
 
 use self::client::{Client, RequestError};
 use self::video_widgets::VideoWidgets;
@@ -18,6 +19,11 @@ use self::video_widgets::VideoWidgets;
 const CACHE_DIRECTORY: &str = "tmp";
 const CACHE_EXTENSION: &str = "Mjpeg";
 
+#[cfg(unix)]
+const SIGINT: i32 = 2;
+#[cfg(unix)]
+const SIGTERM: i32 = 15;
+
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(short = 's', long, default_value = "0.0.0.0")]
@@ -28,6 +34,39 @@ pub struct Args {
     rtp_port: u16,
     #[clap(short, long, default_value = "movie.Mjpeg")]
     video_file: String,
+    /// Streaming mode to ask for, by default the content server decides
+    #[clap(short, long)]
+    mode: Option<message::rtsp::StreamingMode>,
+    /// Lower quality to watch the video in, such as 240 for `movie@240.Mjpeg`
+    #[clap(long)]
+    rendition: Option<u32>,
+    /// Seconds behind the live broadcast to watch it, as far as the content server keeps it
+    #[clap(long, default_value = "0")]
+    time_shift: u64,
+    /// File with the keys used to authenticate messages
+    #[clap(short, long)]
+    key_file: Option<String>,
+    /// User to watch the video as, when the content server restricts access
+    #[clap(short, long)]
+    user: Option<String>,
+    /// Token of the user
+    #[clap(short, long, requires = "user")]
+    token: Option<String>,
+    /// Which logs to show, such as `debug` or `warn,esr_lib::video_player=trace`, overrides RUST_LOG
+    #[clap(long)]
+    log: Option<String>,
+    #[command(flatten)]
+    settings: SettingsArgs,
+}
+
+impl Args {
+    pub fn log_filter(&self) -> Option<&str> {
+        self.log.as_deref()
+    }
+
+    pub fn settings(&self) -> &SettingsArgs {
+        &self.settings
+    }
 }
 
 trait VideoPlayerComponent {
@@ -68,17 +107,17 @@ impl VideoPlayer {
                     .write()
                     .expect("Error acquiring the client's writing lock");
                 if let Err(error) = lock.setup() {
-                    eprintln!("Error setting up");
+                    error!("Error setting up");
                     widgets.set_label_text(&format!("State: Idle ({})", error));
                 }
             }
             VideoPlayerAction::Play => {
                 widgets.set_label_text("State: Playing");
-                if VideoPlayer::play(client, widgets).is_err() {
-                    eprintln!("Error playing video");
-                    widgets.set_label_text("State: Idle (Error playing video)");
+                if let Err(error) = VideoPlayer::play(client, widgets) {
+                    error!("Error playing video");
+                    widgets.set_label_text(&format!("State: Idle ({})", error));
                 }
-                println!("Play");
+                info!("Play");
             }
             VideoPlayerAction::Teardown => {
                 if client
@@ -87,7 +126,7 @@ impl VideoPlayer {
                     .stop_transmition()
                     .is_err()
                 {
-                    eprintln!("Error stopping transmission");
+                    error!("Error stopping transmission");
                     widgets.set_label_text("State: Error stopping transmission");
                 } else {
                     widgets.set_label_text("State: Idle");
@@ -140,6 +179,29 @@ impl VideoPlayer {
         );
     }
 
+    /// Tears down the session with the server before quitting on SIGINT or SIGTERM.
+    #[cfg(unix)]
+    fn register_shutdown(app: &Application, client: &Arc<RwLock<Client>>) {
+        for signal in [SIGINT, SIGTERM] {
+            let app = app.clone();
+            let client = Arc::clone(client);
+
+            gtk::glib::unix_signal_add_local(signal, move || {
+                let mut lock = client
+                    .write()
+                    .expect("Error acquiring the client's writing lock");
+
+                if lock.is_stopped() == Some(false) && lock.stop_transmition().is_err() {
+                    error!("Error stopping transmission");
+                }
+                drop(lock);
+
+                app.quit();
+                gtk::glib::ControlFlow::Break
+            });
+        }
+    }
+
     fn setup(app: &Application, init: Args) {
         app.connect_activate(move |app| {
             let window = ApplicationWindow::builder()
@@ -152,6 +214,8 @@ impl VideoPlayer {
             let widgets = Rc::new(VideoWidgets::new(&window));
             let client = Arc::new(RwLock::new(Client::from_init(&init)));
 
+            #[cfg(unix)]
+            Self::register_shutdown(app, &client);
             Self::register_callbacks(client, widgets);
 
             window.show_all();
@@ -166,7 +230,7 @@ impl VideoPlayer {
 
         let answer = lock.make_request(message::rtsp::RequestType::Play)?;
         if !answer.succeded() {
-            return Err(RequestError::FailedRequest);
+            return Err(lock.refused(answer.status()));
         }
 
         let session_id = lock.session_id().ok_or(RequestError::FailedRequest)?;
@@ -183,12 +247,21 @@ impl VideoPlayer {
                 .expect("Expected client to be connected to server")
             {
                 if let Err(error) = tx.send(None) {
-                    println!("Error sending path to another channel {}", error);
+                    error!("Error sending path to another channel {}", error);
                 }
                 break;
             }
 
-            let packet = lock.receive_rtp_packet().expect("Error receiving packet");
+            let packet = match lock.receive_rtp_packet() {
+                Ok(packet) => packet,
+                Err(error) => {
+                    error!("Error receiving packet {}", error);
+                    if let Err(error) = tx.send(None) {
+                        error!("Error sending path to another channel {}", error);
+                    }
+                    break;
+                }
+            };
             drop(lock);
 
             let data = packet.payload();
@@ -200,7 +273,7 @@ impl VideoPlayer {
                         .expect("Error sending path to another channel");
                 }
                 Err(error) => {
-                    println!("Error storing file {}", error)
+                    error!("Error storing file {}", error)
                 }
             }
         });
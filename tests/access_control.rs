@@ -0,0 +1,214 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use esr_lib::{
+    message::{
+        credentials::Credentials,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status},
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode, Node},
+    server::{access::AccessPolicy, Server, StreamingPolicy},
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+const POLICY: &str = r#"{
+    "users": {
+        "alice": { "token": "a", "files": ["movie.Mjpeg"], "max_sessions": 1 },
+        "bob": { "token": "b", "files": ["other.Mjpeg"] },
+        "carol": { "token": "c", "groups": ["staff"] }
+    },
+    "groups": {
+        "staff": { "files": ["*"], "record": true, "max_sessions": 1 }
+    }
+}"#;
+
+fn init() -> PathBuf {
+    common::init("esr_tp_access_control", |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), b"00004abcd").unwrap()
+    })
+}
+
+fn credentials(user: &str, token: &str) -> Option<Credentials> {
+    Some(Credentials::new(user.to_string(), token.to_string()))
+}
+
+/// Sends `request` and returns the status it is answered with.
+fn send(stream: &mut TcpStream, request: RtspRequest) -> Status {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).unwrap();
+    let response: RtspResponse = bincode::deserialize(&buffer[..n]).unwrap();
+
+    response.status()
+}
+
+/// Sets up a session and returns the connection that keeps it open.
+fn setup(
+    port: u16,
+    rtp_port: u16,
+    servers: Vec<Neighbour>,
+    credentials: Option<Credentials>,
+) -> (TcpStream, Status) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let request = RtspRequest::new_with_servers(
+        RequestType::Setup,
+        "movie.Mjpeg".to_string(),
+        1,
+        rtp_port,
+        servers,
+    )
+    .with_credentials(credentials);
+    let status = send(&mut stream, request);
+
+    (stream, status)
+}
+
+#[test]
+fn content_server_enforces_the_policy() {
+    let dir = init();
+    std::fs::write(dir.join("policy.json"), POLICY).unwrap();
+
+    let shutdown = Shutdown::new();
+    let policy = AccessPolicy::from_file(dir.join("policy.json")).unwrap();
+    let server = Server::new(18641, 18640, StreamingPolicy::default())
+        .unwrap()
+        .with_access_policy(Some(policy));
+    let relay = StdNode::new(18642, &[]);
+    let server_address = vec![Neighbour::new_with_port(
+        "127.0.0.1".parse().unwrap(),
+        18640,
+    )];
+
+    std::thread::scope(|s| {
+        s.spawn(|| server.run(&shutdown));
+        s.spawn(|| relay.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        assert_eq!(setup(18640, 18650, vec![], None).1, Status::Unauthorized);
+        assert_eq!(
+            setup(18640, 18650, vec![], credentials("alice", "b")).1,
+            Status::Unauthorized
+        );
+        assert_eq!(
+            setup(18640, 18650, vec![], credentials("bob", "b")).1,
+            Status::Forbidden
+        );
+
+        let (first, status) = setup(18640, 18650, vec![], credentials("alice", "a"));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            setup(18640, 18651, vec![], credentials("alice", "a")).1,
+            Status::Forbidden
+        );
+
+        // Closing the connection ends the session
+        drop(first);
+        std::thread::sleep(Duration::from_millis(100));
+        let (second, status) = setup(18640, 18650, vec![], credentials("alice", "a"));
+        assert_eq!(status, Status::Ok);
+        drop(second);
+        std::thread::sleep(Duration::from_millis(100));
+
+        // The first viewer makes the relay set up the stream with the content server
+        let (_carol, status) = setup(
+            18642,
+            18652,
+            server_address.clone(),
+            credentials("carol", "c"),
+        );
+        assert_eq!(status, Status::Ok);
+
+        // Later viewers share the relayed stream, but the content server still decides
+        assert_eq!(
+            setup(18642, 18653, vec![], credentials("carol", "c")).1,
+            Status::Forbidden
+        );
+        assert_eq!(
+            setup(18642, 18653, vec![], credentials("bob", "b")).1,
+            Status::Forbidden
+        );
+        assert_eq!(setup(18642, 18653, vec![], None).1, Status::Unauthorized);
+
+        let (alice, status) = setup(18642, 18653, vec![], credentials("alice", "a"));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            setup(18640, 18654, vec![], credentials("alice", "a")).1,
+            Status::Forbidden
+        );
+
+        drop(alice);
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn content_server_records_only_for_users_allowed_to() {
+    let dir = init();
+    std::fs::write(dir.join("recording_policy.json"), POLICY).unwrap();
+
+    let shutdown = Shutdown::new();
+    let policy = AccessPolicy::from_file(dir.join("recording_policy.json")).unwrap();
+    let server = Server::new(18645, 18644, StreamingPolicy::default())
+        .unwrap()
+        .with_access_policy(Some(policy));
+
+    let request = |request_type, seq_number, recording: Option<&str>, credentials| {
+        RtspRequest::new(request_type, "movie.Mjpeg".to_string(), seq_number, 18655)
+            .with_recording(recording.map(str::to_string))
+            .with_credentials(credentials)
+    };
+
+    std::thread::scope(|s| {
+        s.spawn(|| server.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let (mut alice, status) = setup(18644, 18655, vec![], credentials("alice", "a"));
+        assert_eq!(status, Status::Ok);
+        let play = request(RequestType::Play, 2, None, credentials("alice", "a"));
+        assert_eq!(send(&mut alice, play), Status::Ok);
+
+        // Watching a file does not allow recording it
+        let record = request(RequestType::Record, 3, Some("alice.Mjpeg"), None);
+        assert_eq!(send(&mut alice, record), Status::Unauthorized);
+        let record = request(
+            RequestType::Record,
+            4,
+            Some("alice.Mjpeg"),
+            credentials("alice", "a"),
+        );
+        assert_eq!(send(&mut alice, record), Status::Forbidden);
+        assert!(!dir.join("videos").join("alice.Mjpeg").exists());
+
+        let (mut carol, status) = setup(18644, 18656, vec![], credentials("carol", "c"));
+        assert_eq!(status, Status::Ok);
+        let play = request(RequestType::Play, 2, None, credentials("carol", "c"));
+        assert_eq!(send(&mut carol, play), Status::Ok);
+
+        let record = request(
+            RequestType::Record,
+            3,
+            Some("carol.Mjpeg"),
+            credentials("carol", "c"),
+        );
+        assert_eq!(send(&mut carol, record), Status::Ok);
+        let stop = request(RequestType::Record, 4, None, credentials("carol", "c"));
+        assert_eq!(send(&mut carol, stop), Status::Ok);
+
+        drop((alice, carol));
+        shutdown.trigger();
+    });
+}
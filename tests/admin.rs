@@ -0,0 +1,128 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use esr_lib::{
+    admin::{self, AdminCommand, AdminResponse},
+    message::auth::Authenticator,
+    o_node::{neighbour::Neighbour, std_node::StdNode, Node},
+    server::rp::{RPArgs, RP},
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+fn init() -> PathBuf {
+    common::init("esr_tp_admin", |_| {})
+}
+
+fn local(port: u16) -> Neighbour {
+    Neighbour::new_with_port("127.0.0.1".parse().unwrap(), port)
+}
+
+fn admin_address(port: u16) -> SocketAddr {
+    ([127, 0, 0, 1], port).into()
+}
+
+#[test]
+fn admin_interface_shows_and_changes_node_state() {
+    init();
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18680, &[local(18681), local(18682)]).with_admin_port(Some(18683));
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let address = admin_address(18683);
+        let AdminResponse::Ok(status) = admin::send(address, &AdminCommand::Status).unwrap() else {
+            panic!("Status was not answered");
+        };
+        assert_eq!(status["port"], 18680);
+        assert_eq!(status["node"]["neighbours"].as_array().unwrap().len(), 2);
+        assert_eq!(status["queries"]["duplicates"], 0);
+
+        let disconnect = AdminCommand::Disconnect(local(18681));
+        assert_eq!(
+            admin::send(address, &disconnect).unwrap(),
+            AdminResponse::Ok(serde_json::Value::Null)
+        );
+        assert_eq!(node.neighbours(), vec![local(18682)]);
+        assert!(matches!(
+            admin::send(address, &disconnect).unwrap(),
+            AdminResponse::Error(_)
+        ));
+
+        // Without a bootstrapper there is nothing to reload from
+        assert!(matches!(
+            admin::send(address, &AdminCommand::Reload).unwrap(),
+            AdminResponse::Error(_)
+        ));
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn rp_reloads_its_key_file() {
+    let key_file = init().join("rp_keys.json");
+    std::fs::write(&key_file, r#"{ "shared": "old" }"#).unwrap();
+
+    let shutdown = Shutdown::new();
+    let args = RPArgs::new(18686, Vec::new()).with_key_file(Some(key_file.display().to_string()));
+    let auth = Authenticator::from_key_file(args.key_file()).unwrap();
+    let rp = RP::new(args)
+        .with_authenticator(auth)
+        .with_admin_port(Some(18687));
+
+    std::thread::scope(|s| {
+        s.spawn(|| rp.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let address = admin_address(18687);
+        std::fs::write(&key_file, r#"{ "shared": "new" }"#).unwrap();
+        assert_eq!(
+            admin::send(address, &AdminCommand::Reload).unwrap(),
+            AdminResponse::Ok(serde_json::Value::Null)
+        );
+        assert_eq!(rp.authenticator().keys().shared.as_deref(), Some("new"));
+
+        // Keys that can't be read leave the ones in use alone
+        std::fs::write(&key_file, "{").unwrap();
+        assert!(matches!(
+            admin::send(address, &AdminCommand::Reload).unwrap(),
+            AdminResponse::Error(_)
+        ));
+        assert_eq!(rp.authenticator().keys().shared.as_deref(), Some("new"));
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn unknown_admin_commands_are_answered_with_an_error() {
+    use std::io::{BufRead, BufReader, Write};
+
+    init();
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18684, &[]).with_admin_port(Some(18685));
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let mut stream = std::net::TcpStream::connect(admin_address(18685)).unwrap();
+        stream.write_all(b"restart\nstatus\n").unwrap();
+
+        let mut lines = BufReader::new(stream).lines();
+        let unknown: AdminResponse = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        let status: AdminResponse = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+
+        assert_eq!(
+            unknown,
+            AdminResponse::Error("Unknown command: restart".to_string())
+        );
+        assert!(matches!(status, AdminResponse::Ok(_)));
+
+        shutdown.trigger();
+    });
+}
@@ -0,0 +1,65 @@
+use std::{net::UdpSocket, time::Duration};
+
+use esr_lib::{
+    message::{
+        answer::Answer,
+        auth::{Authenticator, KeyRing},
+        query::Query,
+        Status,
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode, Node},
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+fn shared(key: &str) -> Authenticator {
+    Authenticator::new(KeyRing {
+        shared: Some(key.to_string()),
+        ..Default::default()
+    })
+}
+
+/// Sends `packet` to the node and returns its answer, if any.
+fn exchange(socket: &UdpSocket, port: u16, packet: &[u8]) -> Option<Vec<u8>> {
+    socket.send_to(packet, ("127.0.0.1", port)).unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = socket.recv(&mut buffer).ok()?;
+    Some(buffer[..n].to_vec())
+}
+
+#[test]
+fn node_only_answers_authenticated_queries() {
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18630, &[]).with_authenticator(shared("secret"));
+    let client = shared("secret");
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let query = Query::new_file_query("missing.Mjpeg", None);
+
+        let unsigned = bincode::serialize(&query).unwrap();
+        assert!(exchange(&socket, 18630, &unsigned).is_none());
+
+        let signed = client.seal(&query);
+        let answer = exchange(&socket, 18630, &signed).expect("Signed query was not answered");
+        let answer: Answer<Vec<Neighbour>> =
+            client.open(&answer, "127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(answer.status(), Status::VideoNotFound);
+
+        // The same bytes sent again are a replay
+        assert!(exchange(&socket, 18630, &signed).is_none());
+        assert_eq!(node.authenticator().rejected(), 2);
+
+        shutdown.trigger();
+    });
+}
@@ -0,0 +1,201 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use esr_lib::{
+    admin::Admin,
+    message::{
+        answer::Answer,
+        query::Query,
+        rtp::{self, RtpPacket},
+        rtsp::{RequestType, RtspRequest, Status, StreamingMode},
+        srtp::{KeyRequest, SrtpReceiver},
+        Status as QueryStatus,
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{CacheSettings, Settings, StreamingSettings},
+    shutdown::Shutdown,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        DatagramSocket, Stream, Transport,
+    },
+};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, OTHER_CLIENT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+/// Frames of the title, each holding its number.
+const FRAMES: usize = 100;
+
+fn init() -> PathBuf {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            ..Default::default()
+        },
+        cache: CacheSettings {
+            quota_mb: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_caching", settings, |videos| {
+        let title: String = (0..FRAMES).map(|n| format!("00004{:04}", n)).collect();
+        std::fs::write(videos.join("movie.Mjpeg"), title).unwrap();
+    })
+}
+
+/// Whether the relay says it can stream `file` itself.
+async fn relay_has(transport: &Transport, file: &str) -> bool {
+    let socket = transport.bind_udp(0).unwrap();
+    let query = Query::new_file_query(file, None);
+    socket
+        .send_to(
+            &bincode::serialize(&query).unwrap(),
+            SocketAddr::new(RELAY, NODE_PORT),
+        )
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let (n, _) = runtime::with_timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+        .await
+        .unwrap();
+    let answer: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n]).unwrap();
+
+    answer.status() == QueryStatus::Ok
+}
+
+/// An on demand viewer of `movie.Mjpeg` through the relay.
+struct Viewer {
+    stream: Stream,
+    rtp_socket: DatagramSocket,
+    media: SrtpReceiver,
+}
+
+impl Viewer {
+    /// Sets up the session with the path to the content server, if given, and plays it.
+    async fn watch(transport: &Transport, servers: Vec<Neighbour>) -> Self {
+        let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+        let mut stream = transport
+            .connect(SocketAddr::new(RELAY, NODE_PORT))
+            .await
+            .unwrap();
+
+        let key_request = KeyRequest::new();
+        let setup = RtspRequest::new_with_servers(
+            RequestType::Setup,
+            "movie.Mjpeg".to_string(),
+            1,
+            RTP_PORT,
+            servers,
+        )
+        .with_mode(Some(StreamingMode::OnDemand))
+        .with_key_shares(vec![key_request.share()]);
+        let response = request(&mut stream, setup).await;
+        assert_eq!(response.status(), Status::Ok);
+        let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+        let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, RTP_PORT);
+        assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+        Self {
+            stream,
+            rtp_socket,
+            media,
+        }
+    }
+
+    /// Sequence number and number of the next frame.
+    async fn next(&self) -> (u16, usize) {
+        let mut buffer = [0; 1024];
+        let (n, _) = runtime::with_timeout(
+            Duration::from_secs(5),
+            self.rtp_socket.recv_from(&mut buffer),
+        )
+        .await
+        .expect("Stream stopped reaching the viewer");
+
+        let packet = &buffer[8..n];
+        let frame = RtpPacket::decode(&self.media.unprotect(packet).unwrap());
+
+        (
+            rtp::packet_id(packet).unwrap().1,
+            String::from_utf8_lossy(frame.payload()).parse().unwrap(),
+        )
+    }
+
+    async fn teardown(mut self) {
+        let teardown = RtspRequest::new(
+            RequestType::Teardown,
+            "movie.Mjpeg".to_string(),
+            3,
+            RTP_PORT,
+        );
+        assert_eq!(
+            request(&mut self.stream, teardown).await.status(),
+            Status::Ok
+        );
+    }
+}
+
+#[test]
+fn relays_stream_the_titles_they_cached_whole() {
+    let dir = init();
+
+    let network = SimNetwork::new(17);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let first = network.host(CLIENT);
+    let second = network.host(OTHER_CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+                assert!(!relay_has(&second, "movie.Mjpeg").await);
+
+                // The first viewer is relayed the whole title from the content server
+                let viewer =
+                    Viewer::watch(&first, vec![Neighbour::new_with_port(SERVER, 9001)]).await;
+                for _ in 0..FRAMES + 10 {
+                    viewer.next().await;
+                }
+                viewer.teardown().await;
+
+                let cache = Admin::status(&relay).await["cache"].clone();
+                assert_eq!(cache["misses"], 1);
+                assert_eq!(cache["titles"]["movie.Mjpeg"]["bytes"], (FRAMES * 9) as u64);
+                assert!(relay_has(&second, "movie.Mjpeg").await);
+
+                // The second one gets it from the relay, with no path to the content server
+                let viewer = Viewer::watch(&second, Vec::new()).await;
+                for expected in 0..FRAMES {
+                    let (sequence, frame) = viewer.next().await;
+                    assert_eq!(sequence as usize, expected + 1);
+                    assert_eq!(frame, expected);
+                }
+
+                let cache = Admin::status(&relay).await["cache"].clone();
+                assert_eq!(cache["hits"], 1);
+                assert_eq!(cache["sessions"], 1);
+
+                viewer.teardown().await;
+                assert_eq!(Admin::status(&relay).await["cache"]["sessions"], 0);
+
+                shutdown.trigger();
+            },
+        );
+    });
+
+    assert!(dir.join("cache").join("movie.Mjpeg").exists());
+}
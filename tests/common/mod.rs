@@ -0,0 +1,66 @@
+// Each test binary uses its own share of these
+#![allow(dead_code)]
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::Once,
+    time::Duration,
+};
+
+use esr_lib::{
+    message::rtsp::{RtspRequest, RtspResponse},
+    settings::{self, Settings},
+    transport::Stream,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const STARTUP: Duration = Duration::from_millis(300);
+
+// Hosts of the simulated networks
+pub const RELAY: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+pub const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 20));
+pub const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+pub const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2));
+
+pub const NODE_PORT: u16 = 8554;
+pub const RTP_PORT: u16 = 7000;
+
+/// Empties the scratch directory `name`, fills its `videos` folder with `videos` and makes it
+/// the folder of the content servers, with the relay cache next to it, the other settings
+/// keeping their defaults.
+///
+/// Only the first call of each binary does anything, so its tests all share the same directory.
+pub fn init(name: &str, videos: impl FnOnce(&Path)) -> PathBuf {
+    init_with(name, Settings::default(), videos)
+}
+
+/// Same as `init`, with `settings` instead of the defaults.
+pub fn init_with(name: &str, mut settings: Settings, videos: impl FnOnce(&Path)) -> PathBuf {
+    let dir = std::env::temp_dir().join(name);
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("videos")).unwrap();
+        videos(&dir.join("videos"));
+
+        settings.streaming.videos_dir = dir.join("videos");
+        settings.cache.dir = dir.join("cache");
+        settings::init(settings).unwrap();
+    });
+
+    dir
+}
+
+/// Sends `request` and waits for its answer.
+pub async fn request(stream: &mut Stream, request: RtspRequest) -> RtspResponse {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
@@ -0,0 +1,396 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use esr_lib::{
+    admin::Admin,
+    message::{
+        nack::LossTracker,
+        rtp,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status, StreamingMode},
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{self, Settings, StreamingSettings},
+    shutdown::Shutdown,
+    telemetry,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        DatagramSocket, Stream, Transport,
+    },
+    video::packet_source,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, OTHER_CLIENT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+const THIRD_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 3));
+const SLOW_SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 21));
+
+/// Frames the healthy viewer must get, one after the other.
+const FRAMES: usize = 300;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_congestion", settings, |videos| {
+        let frame = format!("00900{}", "x".repeat(900));
+        for file in ["movie.Mjpeg", "other.Mjpeg"] {
+            std::fs::write(videos.join(file), frame.repeat(10)).unwrap();
+        }
+    });
+}
+
+/// Starts watching the broadcast of `movie.Mjpeg` through the relay.
+async fn watch(transport: &Transport) -> (Stream, DatagramSocket) {
+    let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+    let mut stream = transport
+        .connect(SocketAddr::new(RELAY, NODE_PORT))
+        .await
+        .unwrap();
+
+    let setup = RtspRequest::new_with_servers(
+        RequestType::Setup,
+        "movie.Mjpeg".to_string(),
+        1,
+        RTP_PORT,
+        vec![Neighbour::new_with_port(SERVER, 9001)],
+    )
+    .with_mode(Some(StreamingMode::Broadcast));
+    assert_eq!(request(&mut stream, setup).await.status(), Status::Ok);
+
+    let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, RTP_PORT);
+    assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+    (stream, rtp_socket)
+}
+
+/// Next packet, with the stream and sequence number it came with.
+async fn next(rtp_socket: &DatagramSocket) -> Option<((u32, u16), SocketAddr)> {
+    let mut buffer = [0; 2048];
+    let (n, from) = runtime::with_timeout(
+        Duration::from_millis(500),
+        rtp_socket.recv_from(&mut buffer),
+    )
+    .await
+    .ok()?;
+
+    Some((rtp::packet_id(&buffer[8..n]).unwrap(), from))
+}
+
+/// Two viewers watch the same broadcast, the last hop of one of them loses half its packets.
+#[test]
+fn slow_viewers_do_not_hold_back_the_others() {
+    init();
+
+    let network = SimNetwork::new(14);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network.set_link(
+        RELAY,
+        CLIENT,
+        LinkConditions::default()
+            .with_delay(Duration::from_millis(1))
+            .with_loss(0.5),
+    );
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let slow = network.host(CLIENT);
+    let healthy = network.host(OTHER_CLIENT);
+    let done = AtomicBool::new(false);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (_stream, rtp_socket) = watch(&slow).await;
+
+                let streaming = &settings::get().streaming;
+                let mut losses =
+                    LossTracker::new(streaming.repair_timeout(), streaming.nack_interval());
+
+                // The relay may hold this viewer back to a trickle
+                while !done.load(Ordering::SeqCst) {
+                    let Some((id, from)) = next(&rtp_socket).await else {
+                        continue;
+                    };
+
+                    losses.observe(id, Instant::now());
+                    for nack in losses.due(Instant::now()) {
+                        rtp_socket
+                            .send_to(&packet_source::frame(&nack.encode()), from)
+                            .await
+                            .unwrap();
+                    }
+                }
+            },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (_stream, rtp_socket) = watch(&healthy).await;
+
+                let mut sequences = Vec::with_capacity(FRAMES);
+                while sequences.len() < FRAMES {
+                    let ((_, sequence), _) = next(&rtp_socket)
+                        .await
+                        .expect("Stream stopped reaching the healthy viewer");
+                    sequences.push(sequence);
+                }
+
+                let gaps = sequences
+                    .windows(2)
+                    .filter(|pair| pair[1] != pair[0].wrapping_add(1))
+                    .count();
+                assert_eq!(gaps, 0, "Healthy viewer lost frames");
+
+                done.store(true, Ordering::SeqCst);
+                shutdown.trigger();
+            },
+        );
+    });
+
+    // The queue of the slow viewer overflowed
+    assert!(telemetry::dropped_frames("movie.Mjpeg").get() > 0);
+}
+
+/// Setups waiting on a slow content server leave the relay free for the others.
+#[test]
+fn slow_upstreams_do_not_hold_up_other_setups() {
+    init();
+
+    let network = SimNetwork::new(15);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network.set_link(
+        RELAY,
+        SLOW_SERVER,
+        LinkConditions::default().with_delay(Duration::from_secs(1)),
+    );
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let slow_server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SLOW_SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let stalled = network.host(OTHER_CLIENT);
+    let first = network.host(CLIENT);
+    let second = network.host(THIRD_CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { slow_server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let stalled = async {
+                    let mut stream = stalled
+                        .connect(SocketAddr::new(RELAY, NODE_PORT))
+                        .await
+                        .unwrap();
+                    let setup = RtspRequest::new_with_servers(
+                        RequestType::Setup,
+                        "other.Mjpeg".to_string(),
+                        1,
+                        RTP_PORT,
+                        vec![Neighbour::new_with_port(SLOW_SERVER, 9001)],
+                    );
+                    request(&mut stream, setup).await
+                };
+
+                let quick = async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let start = tokio::time::Instant::now();
+
+                    // Viewers setting up the same title at once share the channel the first one opens
+                    let (first, second) = tokio::join!(watch(&first), watch(&second));
+                    assert!(start.elapsed() < Duration::from_millis(500));
+
+                    let status = Admin::status(&server).await;
+                    assert_eq!(
+                        status["broadcasts"]["movie.Mjpeg"]
+                            .as_array()
+                            .unwrap()
+                            .len(),
+                        1
+                    );
+
+                    (first, second)
+                };
+
+                let (answer, _) = tokio::join!(stalled, quick);
+                assert_eq!(answer.status(), Status::Ok);
+
+                shutdown.trigger();
+            },
+        );
+    });
+}
+
+/// Content server taking a second to answer each request, it streams nothing.
+async fn answer_slowly(transport: &Transport, shutdown: &Shutdown) {
+    let listener = transport.listen(9001).await.unwrap();
+
+    tokio::select! {
+        _ = shutdown.cancelled() => {}
+        _ = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+
+            while let Ok(n @ 1..) = stream.read(&mut buffer).await {
+                let request: RtspRequest = bincode::deserialize(&buffer[..n]).unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+
+                let answer = RtspResponse::new(Status::Ok, request.seq_number(), 1)
+                    .with_mode(StreamingMode::Broadcast);
+                stream
+                    .write_all(&bincode::serialize(&answer).unwrap())
+                    .await
+                    .unwrap();
+            }
+        } => {}
+    }
+}
+
+/// Requests waiting on a slow content server hold up neither the other channels nor the admin commands.
+#[test]
+fn slow_upstreams_do_not_hold_up_other_requests() {
+    init();
+
+    let network = SimNetwork::new(16);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let slow_server = network.host(SLOW_SERVER);
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let stalled = network.host(OTHER_CLIENT);
+    let viewer = network.host(CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            answer_slowly(&slow_server, &shutdown),
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let mut stream = stalled
+                    .connect(SocketAddr::new(RELAY, NODE_PORT))
+                    .await
+                    .unwrap();
+                let setup = RtspRequest::new_with_servers(
+                    RequestType::Setup,
+                    "other.Mjpeg".to_string(),
+                    1,
+                    RTP_PORT,
+                    vec![Neighbour::new_with_port(SLOW_SERVER, 9001)],
+                );
+                assert_eq!(request(&mut stream, setup).await.status(), Status::Ok);
+
+                // The relay waits on the slow server to play it
+                let play =
+                    RtspRequest::new(RequestType::Play, "other.Mjpeg".to_string(), 2, RTP_PORT);
+                let stalled = request(&mut stream, play);
+
+                let quick = async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let start = tokio::time::Instant::now();
+
+                    let (mut stream, _rtp_socket) = watch(&viewer).await;
+                    let pause = RtspRequest::new(
+                        RequestType::Pause,
+                        "movie.Mjpeg".to_string(),
+                        3,
+                        RTP_PORT,
+                    );
+                    assert_eq!(request(&mut stream, pause).await.status(), Status::Ok);
+
+                    Admin::drop_session(&relay, "movie.Mjpeg", SocketAddr::new(CLIENT, RTP_PORT))
+                        .await
+                        .unwrap();
+                    assert!(start.elapsed() < Duration::from_millis(500));
+                };
+
+                let (answer, _) = tokio::join!(stalled, quick);
+                assert_eq!(answer.status(), Status::Ok);
+
+                shutdown.trigger();
+            },
+        );
+    });
+}
+
+/// A second setup of the same on demand session is refused, instead of replacing the channel of the first.
+#[test]
+fn setups_do_not_replace_channels() {
+    init();
+
+    let network = SimNetwork::new(17);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let viewer = network.host(CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let rtp_socket = viewer.bind_udp(RTP_PORT).unwrap();
+                let mut stream = viewer
+                    .connect(SocketAddr::new(RELAY, NODE_PORT))
+                    .await
+                    .unwrap();
+
+                for (seq_number, status) in [(1, Status::Ok), (2, Status::ConnectionError)] {
+                    let setup = RtspRequest::new_with_servers(
+                        RequestType::Setup,
+                        "movie.Mjpeg".to_string(),
+                        seq_number,
+                        RTP_PORT,
+                        vec![Neighbour::new_with_port(SERVER, 9001)],
+                    )
+                    .with_mode(Some(StreamingMode::OnDemand));
+                    assert_eq!(request(&mut stream, setup).await.status(), status);
+                }
+
+                // The first session still streams
+                let play =
+                    RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 3, RTP_PORT);
+                assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+                assert!(next(&rtp_socket).await.is_some());
+
+                shutdown.trigger();
+            },
+        );
+    });
+}
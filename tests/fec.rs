@@ -0,0 +1,143 @@
+use std::{collections::BTreeSet, net::SocketAddr, time::Duration};
+
+use esr_lib::{
+    message::{
+        fec::{self, FecDecoder},
+        rtp::RtpPacket,
+        rtsp::{RequestType, RtspRequest, Status},
+        srtp::KeyRequest,
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{Settings, StreamingSettings},
+    shutdown::Shutdown,
+    transport::sim::{LinkConditions, SimNetwork},
+};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+/// Frames the client watches before counting what it got.
+const FRAMES: u16 = 100;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            fec_block: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_fec", settings, |videos| {
+        // Frames of different sizes, so lengths have to be rebuilt too
+        let movie: String = (1..=20)
+            .map(|size| format!("{:05}{}", size, "x".repeat(size)))
+            .collect();
+        std::fs::write(videos.join("movie.Mjpeg"), movie).unwrap();
+    });
+}
+
+/// Parity goes out after every pair of frames, and both hops of the stream lose packets.
+#[test]
+fn viewers_rebuild_packets_lost_on_the_way() {
+    init();
+
+    let network = SimNetwork::new(5);
+    let lossy = LinkConditions::default()
+        .with_delay(Duration::from_millis(1))
+        .with_loss(0.05);
+    network.set_link(SERVER, RELAY, lossy);
+    network.set_link(RELAY, CLIENT, lossy);
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let transport = network.host(CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+                let mut stream = transport
+                    .connect(SocketAddr::new(RELAY, NODE_PORT))
+                    .await
+                    .unwrap();
+
+                let key_request = KeyRequest::new();
+                let setup = RtspRequest::new_with_servers(
+                    RequestType::Setup,
+                    "movie.Mjpeg".to_string(),
+                    1,
+                    RTP_PORT,
+                    vec![Neighbour::new_with_port(SERVER, 9001)],
+                )
+                .with_key_shares(vec![key_request.share()]);
+                let response = request(&mut stream, setup).await;
+                assert_eq!(response.status(), Status::Ok);
+                let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+                let play =
+                    RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, RTP_PORT);
+                assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+                let mut decoder = FecDecoder::new();
+                let mut received = BTreeSet::new();
+                let mut delivered = BTreeSet::new();
+                let mut parity = 0;
+
+                while delivered.last().is_none_or(|last| *last < FRAMES) {
+                    let mut buffer = [0; 1024];
+                    let n =
+                        runtime::with_timeout(Duration::from_secs(5), rtp_socket.recv(&mut buffer))
+                            .await
+                            .expect("Stream stopped reaching the client");
+                    let packet = &buffer[8..n];
+
+                    if fec::is_parity(packet) {
+                        parity += 1;
+                    } else {
+                        received.insert(u16::from_be_bytes([packet[2], packet[3]]));
+                    }
+
+                    if let Some(packet) = decoder.receive(packet) {
+                        let frame = RtpPacket::decode(&media.unprotect(&packet).unwrap());
+                        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+                        assert_eq!(
+                            frame.payload(),
+                            "x".repeat((sequence as usize - 1) % 20 + 1).as_bytes()
+                        );
+                        delivered.insert(sequence);
+                    }
+                }
+
+                let received = received.range(..=FRAMES).count();
+                let delivered = delivered.range(..=FRAMES).count();
+
+                assert!(parity > 0, "Relay dropped the parity packets");
+                assert!(decoder.recovered() > 0);
+                assert!(received < FRAMES as usize, "Nothing was lost");
+                assert!(
+                    delivered > received && delivered >= FRAMES as usize - 2,
+                    "Only {} of {} frames delivered, {} received",
+                    delivered,
+                    FRAMES,
+                    received
+                );
+
+                shutdown.trigger();
+            },
+        );
+    });
+
+    assert!(network.traffic().lost > 0);
+}
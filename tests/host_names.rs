@@ -0,0 +1,161 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    thread,
+    time::Duration,
+};
+
+use clap::Parser;
+use esr_lib::{
+    message::{answer::Answer, auth::KeyConfiguration, query::Query, Message},
+    o_node::{
+        bootstraper_node::BootstraperNode,
+        neighbour::Neighbour,
+        std_node::StdNode,
+        topology::{Topology, TopologyError},
+    },
+    resolve::ResolveError,
+    runtime,
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    settings::{NetworkSettings, Settings},
+    shutdown::Shutdown,
+    transport::Transport,
+};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Every node is on `localhost`, so they join by name.
+const TOPOLOGY: &str = r#"{
+    "bootstrapper": "boot",
+    "nodes": {
+        "boot": { "interfaces": ["localhost"], "port": 18731, "links": [{ "node": "a" }] },
+        "a": { "interfaces": ["localhost"], "port": 18732, "links": [{ "node": "boot" }, { "node": "b" }] },
+        "b": { "interfaces": ["localhost"], "port": 18733, "links": [{ "node": "a" }] }
+    }
+}"#;
+
+mod common;
+
+/// Names are looked up again often, so tests don't wait for servers to be found.
+fn init() {
+    let settings = Settings {
+        network: NetworkSettings {
+            resolve_interval_ms: 100,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_host_names", settings, |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), "00004abcd").unwrap()
+    });
+}
+
+#[test]
+fn nodes_join_a_topology_written_with_host_names() {
+    init();
+
+    let topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+    let bootstraper =
+        BootstraperNode::new(18730, 18731, topology, KeyConfiguration::default()).unwrap();
+
+    let shutdown = Shutdown::new();
+    thread::scope(|scope| {
+        scope.spawn(|| runtime::block_on(bootstraper.serve(&shutdown)).unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        let answer = StdNode::ask_neighbours(
+            &Transport::Os,
+            "localhost:18730".to_string(),
+            Some("a".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            answer.payload().unwrap().neighbours,
+            vec![
+                Neighbour::new_with_port(LOCALHOST, 18731),
+                Neighbour::new_with_port(LOCALHOST, 18733),
+            ]
+        );
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn hosts_that_do_not_resolve_are_errors() {
+    init();
+
+    let topology =
+        Topology::from_reader(TOPOLOGY.replacen("localhost", "boot.invalid", 1).as_bytes())
+            .unwrap();
+
+    match BootstraperNode::new(18740, 18741, topology, KeyConfiguration::default()) {
+        Err(TopologyError::Resolve(ResolveError::Lookup { name, .. })) => {
+            assert_eq!(name, "boot.invalid")
+        }
+        other => panic!("Expected a resolution error, got {:?}", other.err()),
+    }
+
+    assert!(RPArgs::try_parse_from(["rp", "--servers", "not a host:8555"]).is_err());
+}
+
+#[test]
+fn rp_reaches_servers_by_name_once_they_are_up() {
+    init();
+
+    let rp = RP::new(RPArgs::parse_from([
+        "rp",
+        "--port",
+        "18750",
+        "--servers",
+        "localhost:18751",
+    ]));
+    let server = Server::new(18751, 18752, StreamingPolicy::default()).unwrap();
+    let shutdown = Shutdown::new();
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { rp.serve(&shutdown).await.unwrap() },
+            async {
+                // Started after the RP gave up on its first connection
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                server.serve(&shutdown).await.unwrap()
+            },
+            async {
+                let socket = Transport::Os.bind_udp(0).unwrap();
+                let query =
+                    bincode::serialize(&Query::new_file_query("movie.Mjpeg", None)).unwrap();
+                let mut servers = None;
+
+                for _ in 0..20 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    socket
+                        .send_to(&query, SocketAddr::new(LOCALHOST, 18750))
+                        .await
+                        .unwrap();
+
+                    let mut buffer = [0; 1024];
+                    let n = runtime::with_timeout(Duration::from_secs(2), socket.recv(&mut buffer))
+                        .await
+                        .unwrap();
+                    let answer: Answer<Vec<Neighbour>> =
+                        bincode::deserialize(&buffer[..n]).unwrap();
+                    if answer.status().is_ok() {
+                        servers = answer.payload().cloned();
+                        break;
+                    }
+                }
+
+                assert_eq!(
+                    servers,
+                    Some(vec![Neighbour::new_with_port(LOCALHOST, 18752)])
+                );
+                shutdown.trigger();
+            },
+        );
+    });
+}
@@ -0,0 +1,171 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use esr_lib::{
+    message::{
+        answer::Answer,
+        auth::KeyConfiguration,
+        query::Query,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status},
+        Message,
+    },
+    o_node::{
+        bootstraper_node::BootstraperNode, neighbour::Neighbour, std_node::StdNode,
+        topology::Topology,
+    },
+    runtime,
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    shutdown::Shutdown,
+    transport::{Stream, Transport},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+use common::STARTUP;
+
+const LOCALHOST: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+const BOOTSTRAPING_PORT: u16 = 18720;
+const BOOTSTRAPER_PORT: u16 = 18721;
+const RELAY_PORT: u16 = 18722;
+const RP_PORT: u16 = 18723;
+const METRICS_PORT: u16 = 18724;
+const STREAMING_PORT: u16 = 18725;
+
+/// The client enters through the bootstrapper, which reaches the RP through `a`.
+const TOPOLOGY: &str = r#"{
+    "bootstrapper": "boot",
+    "nodes": {
+        "boot": { "interfaces": ["::1"], "port": 18721, "links": [{ "node": "a" }] },
+        "a": { "interfaces": ["::1"], "port": 18722, "links": [{ "node": "boot" }, { "node": "rp" }] },
+        "rp": { "role": "rp", "interfaces": ["::1"], "port": 18723, "links": [{ "node": "a" }] }
+    }
+}"#;
+
+async fn relay(shutdown: &Shutdown) {
+    let answer = tokio::task::spawn_blocking(|| {
+        StdNode::ask_neighbours(
+            &Transport::Os,
+            SocketAddr::new(LOCALHOST, BOOTSTRAPING_PORT).to_string(),
+            Some("a".to_string()),
+        )
+        .map_err(|error| error.to_string())
+    })
+    .await
+    .unwrap()
+    .expect("Relay could not join the overlay");
+
+    let neighbours = answer.payload().unwrap().neighbours.clone();
+    StdNode::new(RELAY_PORT, &neighbours)
+        .serve(shutdown)
+        .await
+        .unwrap();
+}
+
+async fn request(stream: &mut Stream, request: RtspRequest) -> RtspResponse {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+/// Finds the movie and watches a few frames of it, all over `::1`.
+async fn watch_movie() {
+    let transport = Transport::Os;
+    let bootstraper = SocketAddr::new(LOCALHOST, BOOTSTRAPER_PORT);
+
+    let socket = transport.bind_udp(0).unwrap();
+    let query = Query::new_file_query("movie.Mjpeg", None);
+    socket
+        .send_to(&bincode::serialize(&query).unwrap(), bootstraper)
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let (n, _) = runtime::with_timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+        .await
+        .unwrap();
+    let answer: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n]).unwrap();
+    assert_eq!(
+        answer.payload().cloned(),
+        Some(vec![
+            Neighbour::new_with_port(LOCALHOST, STREAMING_PORT),
+            Neighbour::new_with_port(LOCALHOST, RP_PORT),
+            Neighbour::new_with_port(LOCALHOST, RELAY_PORT),
+        ])
+    );
+
+    let rtp_socket = transport.bind_udp(0).unwrap();
+    let rtp_port = rtp_socket.local_addr().unwrap().port();
+    let mut stream = transport.connect(bootstraper).await.unwrap();
+
+    let setup = RtspRequest::new_with_servers(
+        RequestType::Setup,
+        "movie.Mjpeg".to_string(),
+        1,
+        rtp_port,
+        answer.payload().unwrap().clone(),
+    );
+    assert_eq!(request(&mut stream, setup).await.status(), Status::Ok);
+
+    let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, rtp_port);
+    assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+    for _ in 0..5 {
+        let (n, from) =
+            runtime::with_timeout(Duration::from_secs(5), rtp_socket.recv_from(&mut buffer))
+                .await
+                .expect("No frames relayed to the client");
+
+        assert!(n > 8);
+        assert_eq!(from.ip(), LOCALHOST);
+    }
+}
+
+#[test]
+fn overlay_runs_over_ipv6() {
+    common::init("esr_tp_ipv6_overlay", |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), "00004abcd".repeat(100)).unwrap()
+    });
+
+    let shutdown = Shutdown::new();
+    let topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+    let bootstraper = BootstraperNode::new(
+        BOOTSTRAPING_PORT,
+        BOOTSTRAPER_PORT,
+        topology,
+        KeyConfiguration::default(),
+    )
+    .unwrap();
+    let server = Server::new(METRICS_PORT, STREAMING_PORT, StreamingPolicy::default()).unwrap();
+    let rp = RP::new(RPArgs::new(
+        RP_PORT,
+        vec![format!("[::1]:{}", METRICS_PORT).parse().unwrap()],
+    ));
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { bootstraper.serve(&shutdown).await.unwrap() },
+            async { server.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP / 2).await;
+                rp.serve(&shutdown).await.unwrap()
+            },
+            relay(&shutdown),
+            async {
+                tokio::time::sleep(STARTUP).await;
+                watch_movie().await;
+                shutdown.trigger();
+            },
+        );
+    });
+}
@@ -0,0 +1,78 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    thread,
+    time::Duration,
+};
+
+use esr_lib::{
+    message::{auth::KeyConfiguration, Message},
+    o_node::{
+        bootstraper_node::BootstraperNode, neighbour::Neighbour, std_node::StdNode,
+        topology::Topology,
+    },
+    runtime,
+    shutdown::Shutdown,
+    transport::Transport,
+};
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const BOOTSTRAPING_PORT: u16 = 18700;
+
+/// Every node shares the loopback interface, only their ports tell them apart.
+const TOPOLOGY: &str = r#"{
+    "bootstrapper": "boot",
+    "nodes": {
+        "a": { "interfaces": ["10.0.0.2"], "links": [{ "node": "boot" }, { "node": "b" }] },
+        "b": { "interfaces": ["10.0.0.3"], "links": [{ "node": "a" }] },
+        "boot": { "interfaces": ["10.0.0.1"], "links": [{ "node": "a" }] }
+    }
+}"#;
+
+fn join(node_id: Option<&str>) -> Result<Vec<Neighbour>, String> {
+    let answer = StdNode::ask_neighbours(
+        &Transport::Os,
+        format!("127.0.0.1:{}", BOOTSTRAPING_PORT),
+        node_id.map(String::from),
+    )
+    .map_err(|error| error.to_string())?;
+
+    Ok(answer.payload().unwrap().neighbours.clone())
+}
+
+#[test]
+fn nodes_on_the_same_address_join_by_id() {
+    let topology = Topology::from_reader(TOPOLOGY.as_bytes())
+        .unwrap()
+        .on_loopback(LOCALHOST, 18701);
+    let bootstraper = BootstraperNode::new(
+        BOOTSTRAPING_PORT,
+        topology.nodes["boot"].port,
+        topology,
+        KeyConfiguration::default(),
+    )
+    .unwrap();
+
+    let shutdown = Shutdown::new();
+    thread::scope(|scope| {
+        scope.spawn(|| runtime::block_on(bootstraper.serve(&shutdown)).unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        // Ports are given in name order: a, b and boot
+        assert_eq!(
+            join(Some("a")),
+            Ok(vec![
+                Neighbour::new_with_port(LOCALHOST, 18703),
+                Neighbour::new_with_port(LOCALHOST, 18702),
+            ])
+        );
+        assert_eq!(
+            join(Some("b")),
+            Ok(vec![Neighbour::new_with_port(LOCALHOST, 18701)])
+        );
+
+        assert!(join(None).is_err(), "Nodes without an id are ambiguous");
+        assert!(join(Some("c")).is_err(), "c is not in the topology");
+
+        shutdown.trigger();
+    });
+}
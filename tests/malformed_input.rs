@@ -0,0 +1,166 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, UdpSocket},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use esr_lib::{
+    message::{
+        answer::Answer,
+        metrics::{MetricsRequest, MetricsResponse},
+        query::Query,
+        rtsp::{self, RequestType, RtspRequest, RtspResponse},
+        Status,
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode, Node},
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+const GARBAGE: [&[u8]; 4] = [&[], &[0xff; 3], &[0xff; 64], &[0x42; 1024]];
+
+fn init() -> PathBuf {
+    common::init("esr_tp_malformed_input", |_| {})
+}
+
+fn query_video(port: u16, garbage: &[&[u8]]) -> Answer<Vec<Neighbour>> {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    for packet in garbage {
+        socket.send_to(packet, ("127.0.0.1", port)).unwrap();
+    }
+
+    let query = Query::new_file_query("missing.Mjpeg", None);
+    socket
+        .send_to(&bincode::serialize(&query).unwrap(), ("127.0.0.1", port))
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = socket.recv(&mut buffer).unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+fn rtsp_after_garbage(port: u16, request: RtspRequest) -> RtspResponse {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    stream.write_all(&[0xff; 64]).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+#[test]
+fn node_survives_garbage() {
+    init();
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18600, &[]);
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let answer = query_video(18600, &GARBAGE);
+        assert_eq!(answer.status(), Status::VideoNotFound);
+
+        // A peer that connects and leaves must not take the streaming service down
+        drop(TcpStream::connect(("127.0.0.1", 18600)).unwrap());
+
+        let setup = RtspRequest::new(RequestType::Setup, "../missing.Mjpeg".to_string(), 1, 18601);
+        let response = rtsp_after_garbage(18600, setup);
+        assert_eq!(response.status(), rtsp::Status::FileNotFound);
+
+        // Nothing can be streamed to port 0
+        let setup = RtspRequest::new(RequestType::Setup, "missing.Mjpeg".to_string(), 1, 0);
+        let response = rtsp_after_garbage(18600, setup);
+        assert_eq!(response.status(), rtsp::Status::BadRequest);
+
+        let play = RtspRequest::new(RequestType::Play, "missing.Mjpeg".to_string(), 1, 18601);
+        let response = rtsp_after_garbage(18600, play);
+        assert_eq!(response.status(), rtsp::Status::ConnectionError);
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn rp_survives_garbage() {
+    init();
+    let shutdown = Shutdown::new();
+    let rp = RP::new(RPArgs::parse_from(["rp", "--port", "18610"]));
+
+    std::thread::scope(|s| {
+        s.spawn(|| rp.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let answer = query_video(18610, &GARBAGE);
+        assert_eq!(answer.status(), Status::VideoNotFound);
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn server_survives_garbage() {
+    let dir = init();
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(18621, 18620, StreamingPolicy::default()).unwrap();
+
+    std::thread::scope(|s| {
+        s.spawn(|| server.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let setup = RtspRequest::new(RequestType::Setup, "missing.Mjpeg".to_string(), 1, 18622);
+        let response = rtsp_after_garbage(18620, setup);
+        assert_eq!(response.status(), rtsp::Status::FileNotFound);
+
+        let setup = RtspRequest::new(RequestType::Setup, "missing.Mjpeg".to_string(), 1, 0);
+        let response = rtsp_after_garbage(18620, setup);
+        assert_eq!(response.status(), rtsp::Status::BadRequest);
+
+        // Files outside the videos folder are never served
+        std::fs::write(dir.join("outside.Mjpeg"), "000021").unwrap();
+        let setup = RtspRequest::new(RequestType::Setup, "../outside.Mjpeg".to_string(), 1, 18622);
+        let response = rtsp_after_garbage(18620, setup);
+        assert_eq!(response.status(), rtsp::Status::FileNotFound);
+
+        let teardown =
+            RtspRequest::new(RequestType::Teardown, "missing.Mjpeg".to_string(), 1, 18622);
+        let response = rtsp_after_garbage(18620, teardown);
+        assert_eq!(response.status(), rtsp::Status::ConnectionError);
+
+        let mut metrics = TcpStream::connect(("127.0.0.1", 18621)).unwrap();
+        metrics.write_all(&[0xff; 64]).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let request = MetricsRequest::new("missing.Mjpeg".to_string());
+        metrics
+            .write_all(&bincode::serialize(&request).unwrap())
+            .unwrap();
+
+        let mut buffer = [0; 1024];
+        let n = metrics.read(&mut buffer).unwrap();
+        let response: MetricsResponse = bincode::deserialize(&buffer[..n]).unwrap();
+        assert!(!response.video_found());
+
+        shutdown.trigger();
+    });
+}
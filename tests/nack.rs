@@ -0,0 +1,245 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use esr_lib::{
+    message::{
+        fec::FecDecoder,
+        nack::LossTracker,
+        rtp::{self, RtpPacket, RtpPacketBuilder},
+        rtsp::{RequestType, RtspRequest, Status},
+        srtp::KeyRequest,
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{transmission_channel::TransmissionChannelWorker, Server, StreamingPolicy},
+    settings::{self, Settings, StreamingSettings},
+    shutdown::Shutdown,
+    telemetry,
+    transport::sim::{LinkConditions, SimNetwork},
+    video::packet_source,
+};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+const ROGUE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 66));
+
+/// Frames that must all reach the client.
+const FRAMES: u16 = 100;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            repair_timeout_ms: 200,
+            nack_interval_ms: 20,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_nack", settings, |videos| {
+        for file in ["edge.Mjpeg", "origin.Mjpeg"] {
+            std::fs::write(videos.join(file), "00004abcd".repeat(10)).unwrap();
+        }
+    });
+}
+
+/// Watches `file` from the server through the relay, reporting lost packets
+/// like a viewer does, and returns the frames lost on the way and those never
+/// repaired, counting from the first frame received.
+fn watch(network: &SimNetwork, file: &str) -> (usize, Vec<u16>) {
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let transport = network.host(CLIENT);
+
+    let mut frames = (0, Vec::new());
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+                let mut stream = transport
+                    .connect(SocketAddr::new(RELAY, NODE_PORT))
+                    .await
+                    .unwrap();
+
+                let key_request = KeyRequest::new();
+                let setup = RtspRequest::new_with_servers(
+                    RequestType::Setup,
+                    file.to_string(),
+                    1,
+                    RTP_PORT,
+                    vec![Neighbour::new_with_port(SERVER, 9001)],
+                )
+                .with_key_shares(vec![key_request.share()]);
+                let response = request(&mut stream, setup).await;
+                assert_eq!(response.status(), Status::Ok);
+                let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+                let play = RtspRequest::new(RequestType::Play, file.to_string(), 2, RTP_PORT);
+                assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+                let streaming = &settings::get().streaming;
+                let mut losses =
+                    LossTracker::new(streaming.repair_timeout(), streaming.nack_interval());
+                let mut copies = FecDecoder::new();
+                let mut received = BTreeSet::new();
+                let mut delivered = BTreeSet::new();
+
+                // Frames after the last one counted leave time for its repair
+                while delivered.last().is_none_or(|last| *last < FRAMES + 20) {
+                    let mut buffer = [0; 1024];
+                    let (n, from) = runtime::with_timeout(
+                        Duration::from_secs(5),
+                        rtp_socket.recv_from(&mut buffer),
+                    )
+                    .await
+                    .expect("Stream stopped reaching the client");
+
+                    let Some(packet) = copies.receive(&buffer[8..n]) else {
+                        continue;
+                    };
+                    let (ssrc, sequence) = rtp::packet_id(&packet).unwrap();
+
+                    if !delivered.insert(sequence) {
+                        continue;
+                    }
+                    if delivered.last() == Some(&sequence) {
+                        received.insert(sequence);
+                    }
+                    media
+                        .unprotect(&packet)
+                        .expect("Repaired packet was altered");
+
+                    losses.observe((ssrc, sequence), Instant::now());
+                    for nack in losses.due(Instant::now()) {
+                        rtp_socket
+                            .send_to(&packet_source::frame(&nack.encode()), from)
+                            .await
+                            .unwrap();
+                    }
+                }
+
+                let first = *delivered.first().unwrap();
+                frames = (
+                    (first..=FRAMES).filter(|s| !received.contains(s)).count(),
+                    (first..=FRAMES)
+                        .filter(|s| !delivered.contains(s))
+                        .collect(),
+                );
+                shutdown.trigger();
+            },
+        );
+    });
+
+    frames
+}
+
+#[test]
+fn relays_repair_losses_from_their_own_buffer() {
+    init();
+
+    let network = SimNetwork::new(7);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network.set_link(
+        RELAY,
+        CLIENT,
+        LinkConditions::default()
+            .with_delay(Duration::from_millis(1))
+            .with_loss(0.05),
+    );
+
+    let (lost, missing) = watch(&network, "edge.Mjpeg");
+    let (retransmitted, nacks_sent) = telemetry::repairs("edge.Mjpeg");
+
+    assert!(lost > 0, "Nothing was lost");
+    assert!(missing.is_empty(), "Frames never repaired: {:?}", missing);
+    assert!(retransmitted.get() > 0);
+    // The relay had every packet, the content server was never asked
+    assert_eq!(nacks_sent.get(), 0);
+}
+
+#[test]
+fn relays_ask_upstream_for_what_they_lost() {
+    init();
+
+    let network = SimNetwork::new(8);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network.set_link(
+        SERVER,
+        RELAY,
+        LinkConditions::default()
+            .with_delay(Duration::from_millis(1))
+            .with_loss(0.05),
+    );
+
+    let (lost, missing) = watch(&network, "origin.Mjpeg");
+    let (retransmitted, nacks_sent) = telemetry::repairs("origin.Mjpeg");
+
+    assert!(lost > 0, "Nothing was lost");
+    assert!(missing.is_empty(), "Frames never repaired: {:?}", missing);
+    assert!(nacks_sent.get() > 0);
+    assert!(retransmitted.get() > 0);
+}
+
+#[test]
+fn relays_only_forward_what_their_upstream_sends() {
+    init();
+
+    let network = SimNetwork::new(24);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    runtime::block_on(async {
+        let socket = Arc::new(network.host(RELAY).bind_udp(0).unwrap());
+        let relay = SocketAddr::new(RELAY, socket.local_addr().unwrap().port());
+        let viewer = network.host(CLIENT).bind_udp(RTP_PORT).unwrap();
+
+        let worker = Arc::new(TransmissionChannelWorker::new(
+            "edge.Mjpeg",
+            socket,
+            SERVER,
+            vec![SocketAddr::new(CLIENT, RTP_PORT)],
+        ));
+        let task = tokio::spawn({
+            let worker = Arc::clone(&worker);
+            async move { worker.run().await }
+        });
+
+        // A host that is not upstream sends its packet first
+        for (sequence, host, payload) in [(1, ROGUE, b"rogue"), (2, SERVER, b"media")] {
+            let packet = RtpPacketBuilder::new(payload, 26)
+                .sequence_number(sequence)
+                .timestamp(0)
+                .build();
+            network
+                .host(host)
+                .bind_udp(0)
+                .unwrap()
+                .send_to(&packet_source::frame(&packet.transmit_data()), relay)
+                .await
+                .unwrap();
+        }
+
+        let mut buffer = [0; 1024];
+        let n = runtime::with_timeout(Duration::from_secs(5), viewer.recv(&mut buffer))
+            .await
+            .unwrap();
+        assert_eq!(RtpPacket::decode(&buffer[8..n]).payload(), b"media");
+        assert_eq!(worker.relayed().0, 1);
+
+        task.abort();
+    });
+}
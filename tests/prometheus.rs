@@ -0,0 +1,57 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, UdpSocket},
+    time::Duration,
+};
+
+use esr_lib::{
+    message::query::Query,
+    o_node::{std_node::StdNode, Node},
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+/// Sends an HTTP GET for `path` and returns the whole response.
+fn get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn node_metrics_are_served_in_the_prometheus_format() {
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18690, &[]).with_prometheus_port(Some(18691));
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = Query::new_file_query("missing.Mjpeg", None);
+        socket
+            .send_to(&bincode::serialize(&query).unwrap(), ("127.0.0.1", 18690))
+            .unwrap();
+        socket.recv(&mut [0; 1024]).expect("Query was not answered");
+
+        let response = get(18691, "/metrics");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("# TYPE esr_queries_total counter\n"));
+        assert!(response.contains("esr_queries_total{result=\"not_found\"} 1\n"));
+        assert!(response.contains("esr_query_duration_seconds_count 1\n"));
+
+        assert!(get(18691, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.trigger();
+    });
+}
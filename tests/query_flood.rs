@@ -0,0 +1,133 @@
+use std::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+use esr_lib::{
+    message::{answer::Answer, query::Query, Status},
+    o_node::{neighbour::Neighbour, query_guard::QueryLimits, std_node::StdNode, Node},
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+fn local(port: u16) -> Neighbour {
+    Neighbour::new_with_port("127.0.0.1".parse().unwrap(), port)
+}
+
+fn client_socket(timeout: Duration) -> UdpSocket {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    socket
+}
+
+/// Sends `query` to the node and returns its answer, if any.
+fn ask(socket: &UdpSocket, port: u16, query: &Query) -> Option<Answer<Vec<Neighbour>>> {
+    socket
+        .send_to(&bincode::serialize(query).unwrap(), ("127.0.0.1", port))
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = socket.recv(&mut buffer).ok()?;
+    bincode::deserialize(&buffer[..n]).ok()
+}
+
+#[test]
+fn queries_do_not_loop_around_cycles() {
+    let shutdown = Shutdown::new();
+    // A square, every query reaches the opposite node twice and its origin again
+    let nodes = [
+        StdNode::new(18660, &[local(18661), local(18663)]),
+        StdNode::new(18661, &[local(18660), local(18662)]),
+        StdNode::new(18662, &[local(18661), local(18663)]),
+        StdNode::new(18663, &[local(18662), local(18660)]),
+    ];
+
+    std::thread::scope(|s| {
+        for node in &nodes {
+            s.spawn(|| node.run(&shutdown));
+        }
+        std::thread::sleep(STARTUP);
+
+        let socket = client_socket(Duration::from_secs(10));
+        let start = Instant::now();
+        let answer = ask(
+            &socket,
+            18660,
+            &Query::new_file_query("missing.Mjpeg", None),
+        )
+        .expect("Query was not answered");
+
+        assert_eq!(answer.status(), Status::VideoNotFound);
+        // Copies are answered right away instead of waiting for the neighbours to time out
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        let duplicates: u64 = nodes
+            .iter()
+            .map(|node| node.query_guard().counters().duplicates())
+            .sum();
+        assert!(duplicates >= 2, "Only {} duplicates dropped", duplicates);
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn queries_stop_at_the_hop_limit() {
+    let shutdown = Shutdown::new();
+    let last = client_socket(Duration::from_millis(500));
+    let last_port = last.local_addr().unwrap().port();
+
+    let first = StdNode::new(18664, &[local(18665)]);
+    let second = StdNode::new(18665, &[local(18664), local(last_port)]);
+
+    std::thread::scope(|s| {
+        s.spawn(|| first.run(&shutdown));
+        s.spawn(|| second.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let socket = client_socket(Duration::from_secs(5));
+        let query = Query::new_file_query("missing.Mjpeg", None).with_hop_limit(1);
+        let answer = ask(&socket, 18664, &query).expect("Query was not answered");
+
+        assert_eq!(answer.status(), Status::VideoNotFound);
+        assert_eq!(second.query_guard().counters().hop_limited(), 1);
+
+        let mut buffer = [0; 1024];
+        assert!(last.recv(&mut buffer).is_err());
+
+        shutdown.trigger();
+    });
+}
+
+#[test]
+fn floods_from_a_source_are_rate_limited() {
+    let shutdown = Shutdown::new();
+    let node = StdNode::new(18666, &[]).with_query_limits(QueryLimits {
+        rate: 10.0,
+        burst: 5.0,
+        ..Default::default()
+    });
+
+    std::thread::scope(|s| {
+        s.spawn(|| node.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let socket = client_socket(Duration::from_millis(500));
+        for _ in 0..50 {
+            let query = Query::new_file_query("missing.Mjpeg", None);
+            socket
+                .send_to(&bincode::serialize(&query).unwrap(), ("127.0.0.1", 18666))
+                .unwrap();
+        }
+
+        let mut buffer = [0; 1024];
+        let answers = std::iter::from_fn(|| socket.recv(&mut buffer).ok()).count();
+
+        assert!((5..10).contains(&answers), "{} queries answered", answers);
+        assert!(node.query_guard().counters().rate_limited() >= 40);
+
+        shutdown.trigger();
+    });
+}
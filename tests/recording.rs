@@ -0,0 +1,257 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
+
+use esr_lib::{
+    admin::Admin,
+    message::{
+        rtp::{self, RtpPacket},
+        rtsp::{RequestType, RtspRequest, Status, StreamingMode},
+        srtp::{KeyRequest, SrtpReceiver},
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{Settings, StreamingSettings},
+    shutdown::Shutdown,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        DatagramSocket, Stream, Transport,
+    },
+    video::video_stream::VideoStream,
+};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, OTHER_CLIENT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+/// Frames of the title, each holding its number.
+const FRAMES: usize = 100;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            record_on_relays: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_recording", settings, |videos| {
+        let title: String = (0..FRAMES).map(|n| format!("00004{:04}", n)).collect();
+        std::fs::write(videos.join("movie.Mjpeg"), title).unwrap();
+    });
+}
+
+fn record(file: &str, seq_number: u32, recording: Option<&str>) -> RtspRequest {
+    RtspRequest::new(RequestType::Record, file.to_string(), seq_number, RTP_PORT)
+        .with_recording(recording.map(str::to_string))
+}
+
+/// A viewer of a file broadcast through the relay.
+struct Viewer {
+    stream: Stream,
+    rtp_socket: DatagramSocket,
+    media: SrtpReceiver,
+}
+
+impl Viewer {
+    async fn watch(transport: &Transport, file: &str, time_shift: Duration) -> Self {
+        let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+        let mut stream = transport
+            .connect(SocketAddr::new(RELAY, NODE_PORT))
+            .await
+            .unwrap();
+
+        let key_request = KeyRequest::new();
+        let setup = RtspRequest::new_with_servers(
+            RequestType::Setup,
+            file.to_string(),
+            1,
+            RTP_PORT,
+            vec![Neighbour::new_with_port(SERVER, 9001)],
+        )
+        .with_mode(Some(StreamingMode::Broadcast))
+        .with_time_shift(time_shift)
+        .with_key_shares(vec![key_request.share()]);
+        let response = request(&mut stream, setup).await;
+        assert_eq!(response.status(), Status::Ok);
+        let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+        let play = RtspRequest::new(RequestType::Play, file.to_string(), 2, RTP_PORT);
+        assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+        Self {
+            stream,
+            rtp_socket,
+            media,
+        }
+    }
+
+    /// Sequence number and content of the next frame.
+    async fn next(&self) -> (u16, Vec<u8>) {
+        let mut buffer = [0; 1024];
+        let (n, _) = runtime::with_timeout(
+            Duration::from_secs(5),
+            self.rtp_socket.recv_from(&mut buffer),
+        )
+        .await
+        .expect("Stream stopped reaching the viewer");
+
+        let packet = &buffer[8..n];
+        let frame = RtpPacket::decode(&self.media.unprotect(packet).unwrap());
+
+        (rtp::packet_id(packet).unwrap().1, frame.payload().to_vec())
+    }
+}
+
+/// Numbers of the frames of a recording, in the order they were written.
+fn recorded(file: &str) -> Vec<usize> {
+    let mut stream = VideoStream::new(file).unwrap();
+    let mut frames = Vec::new();
+
+    // Recordings loop like any other file, the first frame is read once more at the end
+    while frames.len() < 2 || frames.first() != frames.last() {
+        let frame = stream.next_frame().unwrap();
+        frames.push(String::from_utf8(frame).unwrap().parse().unwrap());
+    }
+    frames.pop();
+
+    frames
+}
+
+fn assert_in_order(frames: &[usize]) {
+    assert!(!frames.is_empty());
+    for pair in frames.windows(2) {
+        assert_eq!(pair[1], (pair[0] + 1) % FRAMES, "Frames out of order");
+    }
+}
+
+#[test]
+fn relays_record_what_viewers_ask_for_and_servers_serve_it() {
+    init();
+
+    let network = SimNetwork::new(15);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let transport = network.host(CLIENT);
+    let other = network.host(OTHER_CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let mut viewer = Viewer::watch(&transport, "movie.Mjpeg", Duration::ZERO).await;
+                for _ in 0..10 {
+                    viewer.next().await;
+                }
+
+                let start = record("movie.Mjpeg", 3, Some("relayed.Mjpeg"));
+                assert_eq!(
+                    request(&mut viewer.stream, start).await.status(),
+                    Status::Ok
+                );
+
+                let again = record("movie.Mjpeg", 4, Some("other.Mjpeg"));
+                assert_eq!(
+                    request(&mut viewer.stream, again).await.status(),
+                    Status::Forbidden
+                );
+
+                for _ in 0..50 {
+                    viewer.next().await;
+                }
+
+                let stop = record("movie.Mjpeg", 5, None);
+                assert_eq!(request(&mut viewer.stream, stop).await.status(), Status::Ok);
+
+                // The recording can be watched right away
+                let replay = Viewer::watch(&other, "relayed.Mjpeg", Duration::ZERO).await;
+                replay.next().await;
+
+                shutdown.trigger();
+            },
+        );
+    });
+
+    let frames = recorded("relayed.Mjpeg");
+    assert!(frames.len() >= 50, "Only {} frames recorded", frames.len());
+    assert_in_order(&frames);
+}
+
+#[test]
+fn viewers_join_behind_the_live_broadcast() {
+    init();
+
+    let network = SimNetwork::new(16);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let live = network.host(CLIENT);
+    let behind = network.host(OTHER_CLIENT);
+    let latest = AtomicU16::new(0);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let viewer = Viewer::watch(&live, "movie.Mjpeg", Duration::ZERO).await;
+                loop {
+                    tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        (sequence, _) = viewer.next() => latest.store(sequence, Ordering::SeqCst),
+                    }
+                }
+            },
+            async {
+                tokio::time::sleep(STARTUP * 2).await;
+
+                server.record("movie.Mjpeg", "served.Mjpeg").await.unwrap();
+                assert!(server.record("movie.Mjpeg", "other.Mjpeg").await.is_err());
+
+                // 100 frames behind, the live viewer got more than that already
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let viewer =
+                    Viewer::watch(&behind, "movie.Mjpeg", Duration::from_millis(500)).await;
+
+                for _ in 0..50 {
+                    let (sequence, _) = viewer.next().await;
+                    let gap = latest.load(Ordering::SeqCst).wrapping_sub(sequence);
+                    assert!((90..=115).contains(&gap), "{} frames behind", gap);
+                }
+
+                assert_eq!(
+                    server.stop_recording("movie.Mjpeg").await.unwrap(),
+                    "served.Mjpeg"
+                );
+                let status = server.status().await;
+                assert!(status["files"]
+                    .as_array()
+                    .unwrap()
+                    .contains(&"served.Mjpeg".into()));
+
+                shutdown.trigger();
+            },
+        );
+    });
+
+    assert_in_order(&recorded("served.Mjpeg"));
+}
@@ -0,0 +1,323 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use esr_lib::{
+    admin::{Admin, AdminError},
+    message::{
+        nack::LossTracker,
+        rtp::{self, RtpPacket},
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status, StreamingMode},
+        srtp::{KeyRequest, SrtpReceiver},
+    },
+    o_node::{neighbour::Neighbour, std_node::StdNode},
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{self, Settings, StreamingSettings},
+    shutdown::Shutdown,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        DatagramSocket, Stream, Transport,
+    },
+    video::packet_source,
+};
+
+mod common;
+
+use common::{request, CLIENT, NODE_PORT, OTHER_CLIENT, RELAY, RTP_PORT, SERVER, STARTUP};
+
+/// Frames of the title are all `H`, those of its rendition all `L`.
+const HIGH: u8 = b'H';
+const LOW: u8 = b'L';
+
+/// Stream and sequence number of each frame a viewer got.
+type Frames = Vec<(u32, u16)>;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            report_interval_ms: 200,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_renditions", settings, |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), "00004HHHH".repeat(10)).unwrap();
+        std::fs::write(videos.join("movie@240.Mjpeg"), "00002LL".repeat(10)).unwrap();
+    });
+}
+
+/// A viewer of `movie.Mjpeg` through the relay, with its rtp socket and stream key.
+struct Viewer {
+    stream: Stream,
+    rtp_socket: DatagramSocket,
+    media: SrtpReceiver,
+}
+
+impl Viewer {
+    async fn watch(transport: &Transport, mode: StreamingMode) -> (Self, RtspResponse) {
+        let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+        let mut stream = transport
+            .connect(SocketAddr::new(RELAY, NODE_PORT))
+            .await
+            .unwrap();
+
+        let key_request = KeyRequest::new();
+        let setup = RtspRequest::new_with_servers(
+            RequestType::Setup,
+            "movie.Mjpeg".to_string(),
+            1,
+            RTP_PORT,
+            vec![Neighbour::new_with_port(SERVER, 9001)],
+        )
+        .with_mode(Some(mode))
+        .with_key_shares(vec![key_request.share()]);
+        let response = request(&mut stream, setup).await;
+        assert_eq!(response.status(), Status::Ok);
+        let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+        let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, RTP_PORT);
+        assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+        let viewer = Self {
+            stream,
+            rtp_socket,
+            media,
+        };
+
+        (viewer, response)
+    }
+
+    /// Next frame, with the stream and sequence number it came with.
+    async fn next(&self) -> ((u32, u16), u8, SocketAddr) {
+        let mut buffer = [0; 1024];
+        let (n, from) = runtime::with_timeout(
+            Duration::from_secs(5),
+            self.rtp_socket.recv_from(&mut buffer),
+        )
+        .await
+        .expect("Stream stopped reaching the viewer");
+
+        let packet = &buffer[8..n];
+        let frame = RtpPacket::decode(&self.media.unprotect(packet).unwrap());
+
+        (rtp::packet_id(packet).unwrap(), frame.payload()[0], from)
+    }
+}
+
+/// Switches a viewer watching in `mode` to the rendition, returns the frames it saw before and after.
+fn switch(network: &SimNetwork, mode: StreamingMode) -> (Frames, Frames) {
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let transport = network.host(CLIENT);
+
+    let mut frames = (Vec::new(), Vec::new());
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (mut viewer, response) = Viewer::watch(&transport, mode).await;
+                assert_eq!(response.rendition(), None);
+                assert_eq!(response.renditions(), &[240]);
+
+                while frames.0.len() < 20 {
+                    let (id, quality, _) = viewer.next().await;
+                    assert_eq!(quality, HIGH);
+                    frames.0.push(id);
+                }
+
+                let switch =
+                    RtspRequest::new(RequestType::Switch, "movie.Mjpeg".to_string(), 3, RTP_PORT)
+                        .with_rendition(Some(240));
+                let response = request(&mut viewer.stream, switch).await;
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(response.rendition(), Some(240));
+
+                // Frames already on their way may still arrive
+                while frames.1.len() < 20 {
+                    let (id, quality, _) = viewer.next().await;
+                    if quality == LOW {
+                        frames.1.push(id);
+                    } else {
+                        assert!(frames.1.is_empty(), "Old rendition came back");
+                    }
+                }
+
+                let missing =
+                    RtspRequest::new(RequestType::Switch, "movie.Mjpeg".to_string(), 4, RTP_PORT)
+                        .with_rendition(Some(720));
+                let response = request(&mut viewer.stream, missing).await;
+                assert_eq!(response.status(), Status::FileNotFound);
+
+                shutdown.trigger();
+            },
+        );
+    });
+
+    frames
+}
+
+#[test]
+fn broadcast_viewers_switch_to_the_channel_of_the_rendition() {
+    init();
+
+    let network = SimNetwork::new(11);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let (before, after) = switch(&network, StreamingMode::Broadcast);
+
+    // The rendition is a stream of its own, under the same key
+    assert_ne!(before[0].0, after[0].0);
+}
+
+#[test]
+fn on_demand_sessions_carry_on_in_the_rendition() {
+    init();
+
+    let network = SimNetwork::new(12);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let (before, after) = switch(&network, StreamingMode::OnDemand);
+
+    // Same stream, picking up where the title was
+    assert_eq!(before[0].0, after[0].0);
+    let last = before.last().unwrap().1;
+    assert!(after[0].1 > last && after[0].1 - last < 10);
+}
+
+#[test]
+fn on_demand_sessions_are_only_dropped_for_their_title() {
+    init();
+
+    let network = SimNetwork::new(14);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let transport = network.host(CLIENT);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (viewer, _) = Viewer::watch(&transport, StreamingMode::OnDemand).await;
+                viewer.next().await;
+
+                let status = Admin::status(&server).await;
+                let sessions = status["on_demand"].as_object().unwrap();
+                assert_eq!(sessions.len(), 1);
+                let relay: SocketAddr =
+                    serde_json::from_value(sessions.values().next().unwrap()[0].clone()).unwrap();
+
+                assert!(matches!(
+                    server.drop_session("movie@240.Mjpeg", relay).await,
+                    Err(AdminError::NotFound(_))
+                ));
+                assert_eq!(
+                    Admin::status(&server).await["on_demand"]
+                        .as_object()
+                        .unwrap()
+                        .len(),
+                    1
+                );
+
+                server.drop_session("movie.Mjpeg", relay).await.unwrap();
+                assert!(Admin::status(&server).await["on_demand"]
+                    .as_object()
+                    .unwrap()
+                    .is_empty());
+
+                shutdown.trigger();
+            },
+        );
+    });
+}
+
+/// Two viewers watch the same broadcast, only one of them loses packets on its last hop.
+#[test]
+fn relays_downgrade_only_the_congested_branch() {
+    init();
+
+    let network = SimNetwork::new(13);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network.set_link(
+        RELAY,
+        CLIENT,
+        LinkConditions::default()
+            .with_delay(Duration::from_millis(1))
+            .with_loss(0.3),
+    );
+
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let relay = StdNode::new(NODE_PORT, &[]).with_transport(network.host(RELAY));
+    let congested = network.host(CLIENT);
+    let healthy = network.host(OTHER_CLIENT);
+    let done = AtomicBool::new(false);
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { server.serve(&shutdown).await.unwrap() },
+            async { relay.serve(&shutdown).await.unwrap() },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (viewer, _) = Viewer::watch(&congested, StreamingMode::Broadcast).await;
+
+                let streaming = &settings::get().streaming;
+                let mut losses =
+                    LossTracker::new(streaming.repair_timeout(), streaming.nack_interval());
+                let mut low = 0;
+
+                while low < 20 {
+                    let (id, quality, from) = viewer.next().await;
+                    if quality == LOW {
+                        low += 1;
+                    }
+
+                    losses.observe(id, Instant::now());
+                    for nack in losses.due(Instant::now()) {
+                        viewer
+                            .rtp_socket
+                            .send_to(&packet_source::frame(&nack.encode()), from)
+                            .await
+                            .unwrap();
+                    }
+                }
+
+                done.store(true, Ordering::SeqCst);
+            },
+            async {
+                tokio::time::sleep(STARTUP).await;
+
+                let (viewer, _) = Viewer::watch(&healthy, StreamingMode::Broadcast).await;
+
+                while !done.load(Ordering::SeqCst) {
+                    let (_, quality, _) = viewer.next().await;
+                    assert_eq!(quality, HIGH, "Healthy viewer was downgraded");
+                }
+
+                shutdown.trigger();
+            },
+        );
+    });
+}
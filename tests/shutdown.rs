@@ -0,0 +1,150 @@
+// The video player is left out: it stops from a glib signal handler of the running gtk
+// application, which needs a display, and its client is private to it.
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread::ScopedJoinHandle,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use esr_lib::{
+    message::rtsp::{RequestType, RtspRequest, RtspResponse, Status},
+    o_node::{bootstraper_node::BootstraperNode, config::Configuration, std_node::StdNode, Node},
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+const STOP_DEADLINE: Duration = Duration::from_secs(5);
+
+fn init() {
+    common::init("esr_tp_shutdown", |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), "00004abcd".repeat(10)).unwrap()
+    });
+}
+
+fn wait_until_finished<T>(handle: &ScopedJoinHandle<T>, name: &str) {
+    let start = Instant::now();
+    while !handle.is_finished() {
+        assert!(
+            start.elapsed() < STOP_DEADLINE,
+            "{} did not stop after the shutdown was triggered",
+            name
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn rtsp_request(stream: &mut TcpStream, request: RtspRequest) -> RtspResponse {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+#[test]
+fn server_stops_and_notifies_viewers() {
+    init();
+    let shutdown = Shutdown::new();
+    let server = Server::new(18555, 18554, StreamingPolicy::default()).unwrap();
+
+    std::thread::scope(|s| {
+        let handle = s.spawn(|| server.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let mut viewer = TcpStream::connect(("127.0.0.1", 18554)).unwrap();
+        let setup = RtspRequest::new(RequestType::Setup, "movie.Mjpeg".to_string(), 1, 18556);
+        assert!(rtsp_request(&mut viewer, setup).succeded());
+
+        let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, 18556);
+        assert!(rtsp_request(&mut viewer, play).succeded());
+
+        shutdown.trigger();
+        wait_until_finished(&handle, "server");
+
+        let mut buffer = [0; 1024];
+        let n = viewer.read(&mut buffer).unwrap();
+        let notification: RtspResponse = bincode::deserialize(&buffer[..n]).unwrap();
+        assert_eq!(notification.status(), Status::ServiceUnavailable);
+    });
+}
+
+#[test]
+fn rp_stops() {
+    init();
+    let shutdown = Shutdown::new();
+    let rp = RP::new(RPArgs::parse_from(["rp", "--port", "18564"]));
+
+    std::thread::scope(|s| {
+        let handle = s.spawn(|| rp.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        shutdown.trigger();
+        wait_until_finished(&handle, "rp");
+    });
+}
+
+#[test]
+fn nodes_stop() {
+    init();
+    let topology = std::env::temp_dir().join("esr_tp_shutdown_topology.json");
+    std::fs::write(&topology, r#"{ "0.0.0.0": [], "127.0.0.1": [] }"#).unwrap();
+
+    let shutdown = Shutdown::new();
+    let bootstraper = BootstraperNode::from_configuration(Configuration::parse_from([
+        "node",
+        "18574",
+        "bootstraper",
+        "18575",
+        topology.to_str().unwrap(),
+    ]))
+    .unwrap();
+
+    std::thread::scope(|s| {
+        let bootstraper_handle = s.spawn(|| bootstraper.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let node = StdNode::from_configuration(Configuration::parse_from([
+            "node",
+            "18576",
+            "non-bootstraper",
+            "127.0.0.1:18575",
+        ]))
+        .unwrap();
+
+        std::thread::scope(|s| {
+            let node_handle = s.spawn(|| node.run(&shutdown));
+            std::thread::sleep(STARTUP);
+
+            shutdown.trigger();
+            wait_until_finished(&node_handle, "node");
+        });
+
+        wait_until_finished(&bootstraper_handle, "bootstraper");
+    });
+}
+
+#[test]
+fn ports_in_use_are_reported_instead_of_panicking() {
+    init();
+    let shutdown = Shutdown::new();
+    let _streaming = std::net::TcpListener::bind(("0.0.0.0", 18584)).unwrap();
+    let _queries = std::net::UdpSocket::bind(("0.0.0.0", 18586)).unwrap();
+
+    let server = Server::new(18585, 18584, StreamingPolicy::default()).unwrap();
+    let rp = RP::new(RPArgs::parse_from(["rp", "--port", "18586"]));
+    let node = StdNode::new(18586, &[]);
+
+    assert!(server.run(&shutdown).is_err());
+    assert!(rp.run(&shutdown).is_err());
+    assert!(node.run(&shutdown).is_err());
+}
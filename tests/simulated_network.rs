@@ -0,0 +1,234 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use clap::Parser;
+use esr_lib::{
+    message::{
+        answer::Answer,
+        auth::KeyConfiguration,
+        query::Query,
+        rtsp::{RequestType, RtspRequest, RtspResponse, Status},
+        Message,
+    },
+    o_node::{
+        bootstraper_node::BootstraperNode, neighbour::Neighbour, std_node::StdNode,
+        topology::Topology,
+    },
+    runtime,
+    server::{
+        rp::{RPArgs, RP},
+        Server, StreamingPolicy,
+    },
+    shutdown::Shutdown,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        Stream, Transport,
+    },
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod common;
+use common::STARTUP;
+
+const BOOTSTRAPER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+const C: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4));
+const RP_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 10));
+const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 20));
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+
+const BOOTSTRAPING_PORT: u16 = 8000;
+const NODE_PORT: u16 = 8554;
+const RTP_PORT: u16 = 7000;
+
+/// The client enters through the bootstrapper, which reaches the RP through
+/// `a`, or through `b` and `c`.
+const TOPOLOGY: &str = r#"{
+    "bootstrapper": "boot",
+    "nodes": {
+        "boot": { "interfaces": ["10.0.0.1"], "links": [{ "node": "a" }, { "node": "b" }] },
+        "a": { "interfaces": ["10.0.0.2"], "links": [{ "node": "boot" }, { "node": "rp" }] },
+        "b": { "interfaces": ["10.0.0.3"], "links": [{ "node": "boot" }, { "node": "c" }] },
+        "c": { "interfaces": ["10.0.0.4"], "links": [{ "node": "b" }, { "node": "rp" }] },
+        "rp": { "role": "rp", "interfaces": ["10.0.0.10"], "links": [{ "node": "a" }, { "node": "c" }] }
+    }
+}"#;
+
+/// Joins the overlay through the bootstrapper and serves until `shutdown`.
+async fn relay(transport: Transport, shutdown: &Shutdown) {
+    let bootstraper = transport.clone();
+    let answer = tokio::task::spawn_blocking(move || {
+        StdNode::ask_neighbours(
+            &bootstraper,
+            SocketAddr::new(BOOTSTRAPER, BOOTSTRAPING_PORT).to_string(),
+            None,
+        )
+        .map_err(|error| error.to_string())
+    })
+    .await
+    .unwrap()
+    .expect("Relay could not join the overlay");
+
+    let neighbours = answer.payload().unwrap().neighbours.clone();
+    StdNode::new(NODE_PORT, &neighbours)
+        .with_transport(transport)
+        .serve(shutdown)
+        .await
+        .unwrap();
+}
+
+/// Runs the whole overlay on `network`, and `client` once it is up.
+fn run_overlay<F: std::future::Future<Output = ()>>(
+    network: &SimNetwork,
+    client: impl FnOnce(Transport) -> F,
+) {
+    common::init("esr_tp_simulated_network", |videos| {
+        std::fs::write(videos.join("movie.Mjpeg"), "00004abcd".repeat(100)).unwrap()
+    });
+
+    let shutdown = Shutdown::new();
+    let topology = Topology::from_reader(TOPOLOGY.as_bytes()).unwrap();
+    let bootstraper = BootstraperNode::new(
+        BOOTSTRAPING_PORT,
+        NODE_PORT,
+        topology,
+        KeyConfiguration::default(),
+    )
+    .unwrap()
+    .with_transport(network.host(BOOTSTRAPER));
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+    let rp = RP::new(RPArgs::parse_from([
+        "rp",
+        "--port",
+        "8554",
+        "--servers",
+        "10.0.0.20:9000",
+    ]))
+    .with_transport(network.host(RP_IP));
+    let client = client(network.host(CLIENT));
+
+    runtime::block_on(async {
+        tokio::join!(
+            async { bootstraper.serve(&shutdown).await.unwrap() },
+            async { server.serve(&shutdown).await.unwrap() },
+            async { rp.serve(&shutdown).await.unwrap() },
+            relay(network.host(A), &shutdown),
+            relay(network.host(B), &shutdown),
+            relay(network.host(C), &shutdown),
+            async {
+                tokio::time::sleep(STARTUP).await;
+                client.await;
+                shutdown.trigger();
+            },
+        );
+    });
+}
+
+/// Asks the bootstrapper for the path to the movie.
+async fn find_movie(transport: &Transport) -> Vec<Neighbour> {
+    let socket = transport.bind_udp(0).unwrap();
+    let query = Query::new_file_query("movie.Mjpeg", None);
+
+    socket
+        .send_to(
+            &bincode::serialize(&query).unwrap(),
+            SocketAddr::new(BOOTSTRAPER, NODE_PORT),
+        )
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let (n, _) = runtime::with_timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+        .await
+        .unwrap();
+    let answer: Answer<Vec<Neighbour>> = bincode::deserialize(&buffer[..n]).unwrap();
+    assert!(answer.status().is_ok(), "Movie not found");
+
+    answer.payload().unwrap().clone()
+}
+
+async fn request(stream: &mut Stream, request: RtspRequest) -> RtspResponse {
+    stream
+        .write_all(&bincode::serialize(&request).unwrap())
+        .await
+        .unwrap();
+
+    let mut buffer = [0; 1024];
+    let n = stream.read(&mut buffer).await.unwrap();
+    bincode::deserialize(&buffer[..n]).unwrap()
+}
+
+#[test]
+fn client_watches_a_movie_through_the_overlay() {
+    let network = SimNetwork::new(1);
+    network.set_default_link(
+        LinkConditions::default()
+            .with_delay(Duration::from_millis(2))
+            .with_jitter(Duration::from_millis(1)),
+    );
+
+    run_overlay(&network, |transport| async move {
+        let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+        let servers = find_movie(&transport).await;
+        assert_eq!(
+            servers.first(),
+            Some(&Neighbour::new_with_port(SERVER, 9001))
+        );
+
+        let mut stream = transport
+            .connect(SocketAddr::new(BOOTSTRAPER, NODE_PORT))
+            .await
+            .unwrap();
+        let setup = RtspRequest::new_with_servers(
+            RequestType::Setup,
+            "movie.Mjpeg".to_string(),
+            1,
+            RTP_PORT,
+            servers,
+        );
+        assert_eq!(request(&mut stream, setup).await.status(), Status::Ok);
+
+        let play = RtspRequest::new(RequestType::Play, "movie.Mjpeg".to_string(), 2, RTP_PORT);
+        assert_eq!(request(&mut stream, play).await.status(), Status::Ok);
+
+        for _ in 0..5 {
+            let mut buffer = [0; 1024];
+            let (n, from) =
+                runtime::with_timeout(Duration::from_secs(5), rtp_socket.recv_from(&mut buffer))
+                    .await
+                    .expect("No frames relayed to the client");
+
+            assert!(n > 8);
+            assert_eq!(from.ip(), BOOTSTRAPER);
+        }
+    });
+
+    assert!(network.traffic().delivered > 0);
+}
+
+#[test]
+fn queries_route_around_partitions() {
+    let network = SimNetwork::new(2);
+    network.partition(A, RP_IP);
+
+    run_overlay(&network, |transport| async move {
+        let servers = find_movie(&transport).await;
+
+        assert_eq!(
+            servers,
+            vec![
+                Neighbour::new_with_port(SERVER, 9001),
+                Neighbour::new_with_port(RP_IP, NODE_PORT),
+                Neighbour::new_with_port(C, NODE_PORT),
+                Neighbour::new_with_port(B, NODE_PORT),
+            ]
+        );
+    });
+
+    assert!(network.traffic().lost > 0);
+}
@@ -0,0 +1,181 @@
+use std::{net::SocketAddr, time::Duration};
+
+use esr_lib::{
+    message::{
+        rtp::RtpPacket,
+        rtsp::{RequestType, RtspRequest, Status, StreamingMode},
+        srtp::{KeyRequest, SrtpReceiver},
+    },
+    runtime,
+    server::{Server, StreamingPolicy},
+    settings::{Settings, StreamingSettings},
+    shutdown::Shutdown,
+    transport::{
+        sim::{LinkConditions, SimNetwork},
+        DatagramSocket, Stream, Transport,
+    },
+};
+
+mod common;
+
+use common::{request, CLIENT, OTHER_CLIENT, RTP_PORT, SERVER, STARTUP};
+
+/// Frames of the title, each holding its number.
+const FRAMES: usize = 1000;
+
+fn init() {
+    let settings = Settings {
+        streaming: StreamingSettings {
+            frame_interval_ms: 5,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    common::init_with("esr_tp_streaming_modes", settings, |videos| {
+        let title: String = (0..FRAMES).map(|n| format!("00004{:04}", n)).collect();
+        std::fs::write(videos.join("movie.Mjpeg"), title).unwrap();
+    });
+}
+
+/// An on demand viewer of `movie.Mjpeg`, straight from the content server.
+struct Viewer {
+    stream: Stream,
+    rtp_socket: DatagramSocket,
+    media: SrtpReceiver,
+    seq_number: u32,
+}
+
+impl Viewer {
+    async fn setup(transport: &Transport) -> Self {
+        let rtp_socket = transport.bind_udp(RTP_PORT).unwrap();
+        let mut stream = transport
+            .connect(SocketAddr::new(SERVER, 9001))
+            .await
+            .unwrap();
+
+        let key_request = KeyRequest::new();
+        let setup = RtspRequest::new(RequestType::Setup, "movie.Mjpeg".to_string(), 1, RTP_PORT)
+            .with_mode(Some(StreamingMode::OnDemand))
+            .with_key_shares(vec![key_request.share()]);
+        let response = request(&mut stream, setup).await;
+        assert_eq!(response.status(), Status::Ok);
+        let media = key_request.open(response.media_keys()).unwrap().receiver();
+
+        Self {
+            stream,
+            rtp_socket,
+            media,
+            seq_number: 1,
+        }
+    }
+
+    /// Sends a request of `request_type` for the session, returns the answer's status.
+    async fn send(&mut self, request_type: RequestType) -> Status {
+        self.seq_number += 1;
+        let message = RtspRequest::new(
+            request_type,
+            "movie.Mjpeg".to_string(),
+            self.seq_number,
+            RTP_PORT,
+        );
+
+        request(&mut self.stream, message).await.status()
+    }
+
+    /// Number of the next frame, `None` if nothing arrives within `timeout`.
+    async fn next(&self, timeout: Duration) -> Option<usize> {
+        let mut buffer = [0; 1024];
+        let (n, _) = runtime::with_timeout(timeout, self.rtp_socket.recv_from(&mut buffer))
+            .await
+            .ok()?;
+
+        let frame = RtpPacket::decode(&self.media.unprotect(&buffer[8..n]).unwrap());
+        Some(String::from_utf8_lossy(frame.payload()).parse().unwrap())
+    }
+
+    /// Frames still on their way, the number of the last one.
+    async fn drain(&self) -> Option<usize> {
+        let mut last = None;
+        while let Some(frame) = self.next(Duration::from_millis(100)).await {
+            last = Some(frame);
+        }
+        last
+    }
+}
+
+fn run(network: &SimNetwork, viewers: impl std::future::Future<Output = ()>) {
+    let shutdown = Shutdown::new();
+    let server = Server::new(9000, 9001, StreamingPolicy::default())
+        .unwrap()
+        .with_transport(network.host(SERVER));
+
+    runtime::block_on(async {
+        tokio::join!(async { server.serve(&shutdown).await.unwrap() }, async {
+            tokio::time::sleep(STARTUP).await;
+            viewers.await;
+            shutdown.trigger();
+        });
+    });
+}
+
+fn network(seed: u64) -> SimNetwork {
+    let network = SimNetwork::new(seed);
+    network.set_default_link(LinkConditions::default().with_delay(Duration::from_millis(1)));
+    network
+}
+
+#[test]
+fn on_demand_viewers_keep_their_own_position() {
+    init();
+
+    let network = network(30);
+    let (first, second) = (network.host(CLIENT), network.host(OTHER_CLIENT));
+
+    run(&network, async {
+        let mut first = Viewer::setup(&first).await;
+        assert_eq!(first.send(RequestType::Play).await, Status::Ok);
+        for expected in 0..50 {
+            assert_eq!(first.next(Duration::from_secs(5)).await, Some(expected));
+        }
+
+        // The second viewer starts from the beginning, the first one carries on
+        let mut second = Viewer::setup(&second).await;
+        assert_eq!(second.send(RequestType::Play).await, Status::Ok);
+        assert_eq!(second.next(Duration::from_secs(5)).await, Some(0));
+        assert_eq!(first.next(Duration::from_secs(5)).await, Some(50));
+    });
+}
+
+#[test]
+fn paused_sessions_resume_from_the_frame_they_were_paused_at() {
+    init();
+
+    let network = network(31);
+    let viewer = network.host(CLIENT);
+
+    run(&network, async {
+        let mut viewer = Viewer::setup(&viewer).await;
+
+        // Sessions are set up once, and played again only once paused
+        assert_eq!(
+            viewer.send(RequestType::Setup).await,
+            Status::MethodNotValid
+        );
+
+        assert_eq!(viewer.send(RequestType::Play).await, Status::Ok);
+        assert_eq!(viewer.send(RequestType::Play).await, Status::MethodNotValid);
+        for expected in 0..20 {
+            assert_eq!(viewer.next(Duration::from_secs(5)).await, Some(expected));
+        }
+
+        assert_eq!(viewer.send(RequestType::Pause).await, Status::Ok);
+        let paused_at = viewer.drain().await.unwrap_or(19);
+
+        assert_eq!(viewer.send(RequestType::Play).await, Status::Ok);
+        assert_eq!(
+            viewer.next(Duration::from_secs(5)).await,
+            Some(paused_at + 1)
+        );
+    });
+}
@@ -0,0 +1,86 @@
+use clap::Parser;
+use esr_lib::{
+    o_node::{
+        bootstraper_node::BootstraperNode, config::Configuration, neighbour::Neighbour,
+        std_node::StdNode, Node,
+    },
+    shutdown::Shutdown,
+};
+
+mod common;
+use common::STARTUP;
+
+const TOPOLOGY: &str = r#"{
+    "bootstrapper": "boot",
+    "nodes": {
+        "boot": {
+            "interfaces": ["127.0.0.1"],
+            "port": 18670,
+            "links": [{ "node": "o1", "bandwidth": 100 }]
+        },
+        "o1": {
+            "interfaces": ["127.0.0.2"],
+            "port": 18672,
+            "links": [{ "node": "boot", "bandwidth": 100 }, { "node": "rp" }]
+        },
+        "rp": {
+            "role": "rp",
+            "interfaces": ["127.0.0.3"],
+            "port": 18673,
+            "links": [{ "node": "o1" }]
+        }
+    }
+}"#;
+
+#[test]
+fn bootstrapper_exports_the_live_topology() {
+    let topology = std::env::temp_dir().join("esr_tp_export_topology.json");
+    std::fs::write(&topology, TOPOLOGY).unwrap();
+
+    let shutdown = Shutdown::new();
+    let bootstraper = BootstraperNode::from_configuration(Configuration::parse_from([
+        "node",
+        "18670",
+        "bootstraper",
+        "18671",
+        topology.to_str().unwrap(),
+    ]))
+    .unwrap();
+    let relay = StdNode::new(
+        18672,
+        &[Neighbour::new_with_port(
+            "127.0.0.1".parse().unwrap(),
+            18670,
+        )],
+    );
+
+    std::thread::scope(|s| {
+        s.spawn(|| bootstraper.run(&shutdown));
+        s.spawn(|| relay.run(&shutdown));
+        std::thread::sleep(STARTUP);
+
+        let configured = BootstraperNode::ask_snapshot("127.0.0.1:18671", false).unwrap();
+        assert!(configured.live.is_none());
+        assert_eq!(configured.topology.nodes.len(), 3);
+
+        let snapshot = BootstraperNode::ask_snapshot("127.0.0.1:18671", true).unwrap();
+        let live = snapshot.live.as_ref().unwrap();
+
+        assert!(live["boot"].status.is_some());
+        let o1 = live["o1"].status.as_ref().expect("Relay did not report");
+        assert_eq!(o1.neighbours.len(), 1);
+        assert!(o1.streams.is_empty());
+        assert!(live["o1"].rtt_ms.is_some());
+
+        // Nothing runs at the RP's address
+        assert!(live["rp"].status.is_none());
+
+        let dot = snapshot.to_dot();
+        assert!(dot.contains(
+            r#""rp" [label="rp\nrp\n127.0.0.3:18673", shape=hexagon, style=dashed, color=gray];"#
+        ));
+        assert!(dot.contains(r#""boot" -> "o1" [dir=none, label="cost 1, 100 Mbit/s"];"#));
+
+        shutdown.trigger();
+    });
+}